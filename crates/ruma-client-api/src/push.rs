@@ -5,8 +5,8 @@ pub use ruma_common::push::RuleKind;
 use ruma_common::{
     push::{
         Action, AnyPushRule, AnyPushRuleRef, ConditionalPushRule, ConditionalPushRuleInit,
-        HttpPusherData, PatternedPushRule, PatternedPushRuleInit, PushCondition, SimplePushRule,
-        SimplePushRuleInit,
+        HttpPusherData, PatternedPushRule, PatternedPushRuleInit, PushCondition, PushRuleChange,
+        SimplePushRule, SimplePushRuleInit,
     },
     serde::{JsonObject, StringEnum},
 };
@@ -319,3 +319,109 @@ pub enum RuleScope {
     #[doc(hidden)]
     _Custom(PrivOwnedStr),
 }
+
+/// A request to apply a [`PushRuleChange`] with the client-server API.
+///
+/// The changes computed with [`Ruleset::diff()`] can be converted to requests with
+/// [`PushRuleChangeRequest::new()`]. The requests must be sent in the same order as the changes.
+///
+/// [`Ruleset::diff()`]: ruma_common::push::Ruleset::diff
+#[derive(Clone, Debug)]
+#[allow(clippy::large_enum_variant)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub enum PushRuleChangeRequest {
+    /// Create, modify or move a push rule.
+    Set(set_pushrule::v3::Request),
+
+    /// Delete a push rule.
+    Delete(delete_pushrule::v3::Request),
+
+    /// Enable or disable a push rule.
+    SetEnabled(set_pushrule_enabled::v3::Request),
+
+    /// Set the actions of a push rule.
+    SetActions(set_pushrule_actions::v3::Request),
+}
+
+impl PushRuleChangeRequest {
+    /// Creates the request to apply the given change to the push rules of the given scope.
+    pub fn new(scope: RuleScope, change: PushRuleChange) -> Self {
+        match change {
+            PushRuleChange::Added { rule, after, before }
+            | PushRuleChange::Moved { rule, after, before } => {
+                let mut request = set_pushrule::v3::Request::new(scope, rule);
+                request.after = after;
+                request.before = before;
+                Self::Set(request)
+            }
+            PushRuleChange::Modified { rule } => {
+                Self::Set(set_pushrule::v3::Request::new(scope, rule))
+            }
+            PushRuleChange::Removed { kind, rule_id } => {
+                Self::Delete(delete_pushrule::v3::Request::new(scope, kind, rule_id))
+            }
+            PushRuleChange::EnabledChanged { kind, rule_id, enabled } => Self::SetEnabled(
+                set_pushrule_enabled::v3::Request::new(scope, kind, rule_id, enabled),
+            ),
+            PushRuleChange::ActionsChanged { kind, rule_id, actions } => Self::SetActions(
+                set_pushrule_actions::v3::Request::new(scope, kind, rule_id, actions),
+            ),
+        }
+    }
+}
+
+impl From<PushRuleChange> for PushRuleChangeRequest {
+    /// Creates the request to apply the given change to the global push rules.
+    fn from(change: PushRuleChange) -> Self {
+        Self::new(RuleScope::Global, change)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches2::assert_matches;
+    use ruma_common::{
+        push::{Action, NewPatternedPushRule, NewPushRule, RuleKind, Ruleset},
+        user_id,
+    };
+
+    use super::{PushRuleChangeRequest, RuleScope};
+
+    #[test]
+    fn diff_to_requests() {
+        let keyword = |rule_id: &str| {
+            NewPushRule::Content(NewPatternedPushRule::new(
+                rule_id.to_owned(),
+                rule_id.to_owned(),
+                vec![Action::Notify],
+            ))
+        };
+
+        let mut old = Ruleset::server_default(user_id!("@jj:server.name"));
+        old.insert(keyword("joke"), None, None).unwrap();
+        old.insert(keyword("saloon"), None, None).unwrap();
+
+        let mut new = old.clone();
+        new.remove(RuleKind::Content, "saloon").unwrap();
+        new.insert(keyword("horse"), Some("joke"), None).unwrap();
+        new.set_enabled(RuleKind::Content, "joke", false).unwrap();
+
+        let requests: Vec<_> =
+            old.diff(&new).into_iter().map(PushRuleChangeRequest::from).collect();
+        assert_eq!(requests.len(), 3);
+
+        assert_matches!(&requests[0], PushRuleChangeRequest::Delete(request));
+        assert_eq!(request.scope, RuleScope::Global);
+        assert_eq!(request.kind, RuleKind::Content);
+        assert_eq!(request.rule_id, "saloon");
+
+        assert_matches!(&requests[1], PushRuleChangeRequest::Set(request));
+        assert_eq!(request.rule.rule_id(), "horse");
+        assert_eq!(request.after.as_deref(), Some("joke"));
+        assert_eq!(request.before, None);
+
+        assert_matches!(&requests[2], PushRuleChangeRequest::SetEnabled(request));
+        assert_eq!(request.rule_id, "joke");
+        assert!(!request.enabled);
+    }
+}
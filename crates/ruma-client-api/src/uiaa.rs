@@ -137,6 +137,21 @@ impl AuthData {
         }
     }
 
+    /// Sets the value of the `session` field.
+    pub fn set_session(&mut self, session: String) {
+        match self {
+            Self::Password(x) => x.session = Some(session),
+            Self::ReCaptcha(x) => x.session = Some(session),
+            Self::EmailIdentity(x) => x.session = Some(session),
+            Self::Msisdn(x) => x.session = Some(session),
+            Self::Dummy(x) => x.session = Some(session),
+            Self::RegistrationToken(x) => x.session = Some(session),
+            Self::FallbackAcknowledgement(x) => x.session = session,
+            Self::Terms(x) => x.session = Some(session),
+            Self::_Custom(x) => x.session = Some(session),
+        }
+    }
+
     /// Returns the associated data.
     ///
     /// The returned JSON object won't contain the `type` and `session` fields, use
@@ -1,4 +1,6 @@
 use std::{
+    collections::BTreeMap,
+    fmt,
     sync::{Arc, Mutex},
     time::Duration,
 };
@@ -6,8 +8,10 @@ use std::{
 use assign::assign;
 use async_stream::try_stream;
 use futures_core::stream::Stream;
+use futures_util::lock::Mutex as AsyncMutex;
 use ruma_client_api::{
     account::register::{self, RegistrationKind},
+    discovery::get_capabilities::Capabilities,
     session::login::{self, v3::LoginInfo},
     sync::sync_events,
     uiaa::UserIdentifier,
@@ -17,21 +21,35 @@ use ruma_common::{
     presence::PresenceState,
     DeviceId, UserId,
 };
+use tracing::info;
 
+use self::refresh::{is_expired_access_token, TokensRefreshedFn};
 use crate::{
-    add_user_id_to_query, send_customized_request, Error, HttpClient, ResponseError, ResponseResult,
+    add_user_id_to_query, deserialize_response, send_customized_http_request, Error, HttpClient,
+    ResponseError, ResponseResult,
 };
 
 mod builder;
+mod discovery;
+mod refresh;
+mod retry;
+#[cfg(feature = "unstable-msc4186")]
+mod sliding_sync;
+#[cfg(test)]
+mod test_utils;
+mod uiaa;
 
-pub use self::builder::ClientBuilder;
+#[cfg(feature = "unstable-msc4186")]
+use self::sliding_sync::MAX_CONSECUTIVE_RESETS;
+#[cfg(feature = "unstable-msc4186")]
+pub use self::sliding_sync::{SlidingSync, SlidingSyncRoomUpdate, SlidingSyncUpdate};
+pub use self::{builder::ClientBuilder, retry::RetryPolicy, uiaa::UiaaHandler};
 
 /// A client for the Matrix client-server API.
 #[derive(Clone, Debug)]
 pub struct Client<C>(Arc<ClientData<C>>);
 
 /// Data contained in Client's Rc
-#[derive(Debug)]
 struct ClientData<C> {
     /// The URL of the homeserver to connect to.
     homeserver_url: String,
@@ -42,8 +60,41 @@ struct ClientData<C> {
     /// The access token, if logged in.
     access_token: Mutex<Option<String>>,
 
+    /// The refresh token, if the homeserver supports refreshing the access token.
+    refresh_token: Mutex<Option<String>>,
+
+    /// Whether to ask for a refresh token when logging in or registering.
+    handle_refresh_tokens: bool,
+
+    /// The function to call when the access token was refreshed.
+    on_tokens_refreshed: Option<Box<TokensRefreshedFn>>,
+
+    /// The lock held while the access token is refreshed, so it is only refreshed once at a time.
+    refresh_lock: AsyncMutex<()>,
+
     /// The (known) Matrix versions the homeserver supports.
     supported_matrix_versions: Vec<MatrixVersion>,
+
+    /// The unstable features the homeserver advertises.
+    unstable_features: BTreeMap<String, bool>,
+
+    /// The capabilities of the homeserver, if they were fetched.
+    capabilities: Mutex<Option<Capabilities>>,
+
+    /// The policy for retrying failed requests, if any.
+    retry_policy: Option<RetryPolicy>,
+}
+
+impl<C: fmt::Debug> fmt::Debug for ClientData<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientData")
+            .field("homeserver_url", &self.homeserver_url)
+            .field("http_client", &self.http_client)
+            .field("supported_matrix_versions", &self.supported_matrix_versions)
+            .field("unstable_features", &self.unstable_features)
+            .field("retry_policy", &self.retry_policy)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Client<()> {
@@ -69,6 +120,9 @@ impl<C: HttpClient> Client<C> {
     }
 
     /// Makes a request to a Matrix API endpoint including additional URL parameters.
+    ///
+    /// If the request is sent again, because the access token was refreshed or because of the
+    /// [`RetryPolicy`], `customize` is called again for every attempt.
     pub async fn send_customized_request<R, F>(
         &self,
         request: R,
@@ -76,15 +130,69 @@ impl<C: HttpClient> Client<C> {
     ) -> ResponseResult<C, R>
     where
         R: OutgoingRequest,
-        F: FnOnce(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>>,
+        F: Fn(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>>,
     {
         let access_token = self.access_token();
-        let send_access_token = match access_token.as_deref() {
+
+        if self.0.retry_policy.is_none() && self.refresh_token().is_none() {
+            // The request will only be sent once, no need to clone it.
+            let http_res = self
+                .send_customized_http_request(access_token.as_deref(), request, customize)
+                .await?;
+            return deserialize_response::<C, R>(http_res);
+        }
+
+        let mut access_token = access_token;
+        let mut attempt = 0;
+        let mut refreshed_access_token = false;
+        loop {
+            let result = self
+                .send_customized_http_request(access_token.as_deref(), request.clone(), &customize)
+                .await;
+
+            if !refreshed_access_token && is_expired_access_token(&result) {
+                refreshed_access_token = true;
+
+                if self.refresh_expired_access_token(access_token.as_deref()).await {
+                    access_token = self.access_token();
+                    continue;
+                }
+            }
+
+            let retry_delay = self
+                .0
+                .retry_policy
+                .as_ref()
+                .and_then(|policy| policy.retry_delay(attempt, &result));
+            match (&self.0.retry_policy, retry_delay) {
+                (Some(retry_policy), Some(delay)) => {
+                    info!(attempt, ?delay, "Retrying request");
+                    retry_policy.sleep(delay).await;
+                    attempt += 1;
+                }
+                _ => return deserialize_response::<C, R>(result?),
+            }
+        }
+    }
+
+    /// Sends a single HTTP request for the given Matrix request with the given access token,
+    /// without deserializing the response.
+    async fn send_customized_http_request<R, F>(
+        &self,
+        access_token: Option<&str>,
+        request: R,
+        customize: F,
+    ) -> Result<http::Response<C::ResponseBody>, ResponseError<C, R>>
+    where
+        R: OutgoingRequest,
+        F: FnOnce(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>>,
+    {
+        let send_access_token = match access_token {
             Some(at) => SendAccessToken::IfRequired(at),
             None => SendAccessToken::None,
         };
 
-        send_customized_request(
+        send_customized_http_request(
             &self.0.http_client,
             &self.0.homeserver_url,
             send_access_token,
@@ -110,7 +218,7 @@ impl<C: HttpClient> Client<C> {
     /// Log in with a username and password.
     ///
     /// In contrast to [`send_request`][Self::send_request], this method stores the access token
-    /// returned by the endpoint in this client, in addition to returning it.
+    /// and refresh token returned by the endpoint in this client, in addition to returning them.
     pub async fn log_in(
         &self,
         user: &str,
@@ -126,10 +234,14 @@ impl<C: HttpClient> Client<C> {
             .send_request(assign!(login::v3::Request::new(login_info), {
                 device_id: device_id.map(ToOwned::to_owned),
                 initial_device_display_name: initial_device_display_name.map(ToOwned::to_owned),
+                refresh_token: self.0.handle_refresh_tokens,
             }))
             .await?;
 
-        *self.0.access_token.lock().unwrap() = Some(response.access_token.clone());
+        self.set_session_tokens(
+            Some(response.access_token.clone()),
+            response.refresh_token.clone(),
+        );
 
         Ok(response)
     }
@@ -137,15 +249,18 @@ impl<C: HttpClient> Client<C> {
     /// Register as a guest.
     ///
     /// In contrast to [`send_request`][Self::send_request], this method stores the access token
-    /// returned by the endpoint in this client, in addition to returning it.
+    /// and refresh token returned by the endpoint in this client, in addition to returning them.
     pub async fn register_guest(
         &self,
     ) -> Result<register::v3::Response, Error<C::Error, ruma_client_api::uiaa::UiaaResponse>> {
         let response = self
-            .send_request(assign!(register::v3::Request::new(), { kind: RegistrationKind::Guest }))
+            .send_request(assign!(register::v3::Request::new(), {
+                kind: RegistrationKind::Guest,
+                refresh_token: self.0.handle_refresh_tokens,
+            }))
             .await?;
 
-        self.0.access_token.lock().unwrap().clone_from(&response.access_token);
+        self.set_session_tokens(response.access_token.clone(), response.refresh_token.clone());
 
         Ok(response)
     }
@@ -153,7 +268,7 @@ impl<C: HttpClient> Client<C> {
     /// Register as a new user on this server.
     ///
     /// In contrast to [`send_request`][Self::send_request], this method stores the access token
-    /// returned by the endpoint in this client, in addition to returning it.
+    /// and refresh token returned by the endpoint in this client, in addition to returning them.
     ///
     /// The username is the local part of the returned user_id. If it is omitted from this request,
     /// the server will generate one.
@@ -165,11 +280,12 @@ impl<C: HttpClient> Client<C> {
         let response = self
             .send_request(assign!(register::v3::Request::new(), {
                 username: username.map(ToOwned::to_owned),
-                password: Some(password.to_owned())
+                password: Some(password.to_owned()),
+                refresh_token: self.0.handle_refresh_tokens,
             }))
             .await?;
 
-        self.0.access_token.lock().unwrap().clone_from(&response.access_token);
+        self.set_session_tokens(response.access_token.clone(), response.refresh_token.clone());
 
         Ok(response)
     }
@@ -226,4 +342,71 @@ impl<C: HttpClient> Client<C> {
             }
         }
     }
+
+    /// Convenience method that represents repeated calls to the sliding sync endpoint
+    /// ([MSC4186]) as a stream.
+    ///
+    /// The `pos` of the connection and the lists and room subscriptions to request are kept in
+    /// the given [`SlidingSync`], which can be modified while the stream is running. If the
+    /// connection expired on the server (`M_UNKNOWN_POS`), it is started again automatically and
+    /// the next update has its [`reset`][SlidingSyncUpdate::reset] field set to `true`. If the
+    /// connection cannot be started again after a few attempts, the error is returned.
+    ///
+    /// # Example:
+    ///
+    /// ```no_run
+    /// use js_int::uint;
+    /// use ruma_client::SlidingSync;
+    /// use ruma_client_api::sync::sync_events::v5::request;
+    ///
+    /// # use tokio_stream::{StreamExt as _};
+    /// # let homeserver_url = "https://example.com".to_owned();
+    /// # async {
+    /// # let client = ruma_client::Client::builder()
+    /// #     .homeserver_url(homeserver_url)
+    /// #     .build::<ruma_client::http_client::Dummy>()
+    /// #     .await?;
+    /// let sliding_sync = SlidingSync::new();
+    /// let mut list = request::List::default();
+    /// list.ranges = vec![(uint!(0), uint!(19))];
+    /// sliding_sync.set_list("all_rooms".to_owned(), list);
+    ///
+    /// let mut sync_stream = Box::pin(client.sliding_sync(&sliding_sync));
+    /// while let Some(update) = sync_stream.try_next().await? {
+    ///     // Do something with the rooms in the update...
+    /// }
+    /// # Result::<(), ruma_client::Error<_, _>>::Ok(())
+    /// # };
+    /// ```
+    ///
+    /// [MSC4186]: https://github.com/matrix-org/matrix-spec-proposals/pull/4186
+    #[cfg(feature = "unstable-msc4186")]
+    pub fn sliding_sync(
+        &self,
+        sliding_sync: &SlidingSync,
+    ) -> impl Stream<Item = Result<SlidingSyncUpdate, Error<C::Error, ruma_client_api::Error>>> + '_
+    {
+        let sliding_sync = sliding_sync.clone();
+
+        try_stream! {
+            let mut consecutive_resets = 0;
+
+            loop {
+                let response = match self.send_request(sliding_sync.next_request()).await {
+                    Ok(response) => response,
+                    Err(error)
+                        if consecutive_resets < MAX_CONSECUTIVE_RESETS
+                            && sliding_sync.handle_error_kind(error.error_kind()) =>
+                    {
+                        consecutive_resets += 1;
+                        continue;
+                    }
+                    Err(error) => Err(error)?,
+                };
+
+                consecutive_resets = 0;
+                yield sliding_sync.handle_response(response);
+            }
+        }
+    }
 }
@@ -1,9 +1,16 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
 
-use ruma_client_api::discovery::get_supported_versions;
+use futures_util::lock::Mutex as AsyncMutex;
+use ruma_client_api::{
+    discovery::{get_capabilities::Capabilities, get_supported_versions},
+    session::refresh_token,
+};
 use ruma_common::api::{MatrixVersion, SendAccessToken};
 
-use super::{Client, ClientData};
+use super::{refresh::TokensRefreshedFn, Client, ClientData, RetryPolicy};
 use crate::{DefaultConstructibleHttpClient, Error, HttpClient, HttpClientExt};
 
 /// A [`Client`] builder.
@@ -12,12 +19,28 @@ use crate::{DefaultConstructibleHttpClient, Error, HttpClient, HttpClientExt};
 pub struct ClientBuilder {
     homeserver_url: Option<String>,
     access_token: Option<String>,
+    refresh_token: Option<String>,
+    handle_refresh_tokens: bool,
+    on_tokens_refreshed: Option<Box<TokensRefreshedFn>>,
     supported_matrix_versions: Option<Vec<MatrixVersion>>,
+    unstable_features: Option<BTreeMap<String, bool>>,
+    capabilities: Option<Capabilities>,
+    retry_policy: Option<RetryPolicy>,
 }
 
 impl ClientBuilder {
     pub(super) fn new() -> Self {
-        Self { homeserver_url: None, access_token: None, supported_matrix_versions: None }
+        Self {
+            homeserver_url: None,
+            access_token: None,
+            refresh_token: None,
+            handle_refresh_tokens: false,
+            on_tokens_refreshed: None,
+            supported_matrix_versions: None,
+            unstable_features: None,
+            capabilities: None,
+            retry_policy: None,
+        }
     }
 
     /// Set the homeserver URL.
@@ -33,6 +56,31 @@ impl ClientBuilder {
         Self { access_token, ..self }
     }
 
+    /// Set the refresh token.
+    ///
+    /// When a request fails because the access token expired, the client uses the refresh token
+    /// to get a new access token and sends the request again.
+    pub fn refresh_token(self, refresh_token: Option<String>) -> Self {
+        Self { refresh_token, ..self }
+    }
+
+    /// Ask the homeserver for a refresh token when logging in or registering.
+    ///
+    /// Without this, the homeserver issues access tokens that never expire.
+    pub fn handle_refresh_tokens(self) -> Self {
+        Self { handle_refresh_tokens: true, ..self }
+    }
+
+    /// Set a function to call with the new tokens every time the access token is refreshed.
+    ///
+    /// This can be used to persist the new tokens, to be able to restore the session later.
+    pub fn on_tokens_refreshed<F>(self, on_tokens_refreshed: F) -> Self
+    where
+        F: Fn(&refresh_token::v3::Response) + Send + Sync + 'static,
+    {
+        Self { on_tokens_refreshed: Some(Box::new(on_tokens_refreshed)), ..self }
+    }
+
     /// Set the supported Matrix versions.
     ///
     /// This method generally *shouldn't* be called. The [`build()`][Self::build] or
@@ -42,12 +90,41 @@ impl ClientBuilder {
         Self { supported_matrix_versions: Some(versions), ..self }
     }
 
+    /// Set the unstable features advertised by the homeserver.
+    ///
+    /// Like [`supported_matrix_versions()`][Self::supported_matrix_versions], this method
+    /// generally *shouldn't* be called. If the supported Matrix versions are not set, the
+    /// unstable features are taken from the response to the [`get_supported_versions`] request.
+    /// Otherwise, they default to an empty map.
+    pub fn unstable_features(self, unstable_features: BTreeMap<String, bool>) -> Self {
+        Self { unstable_features: Some(unstable_features), ..self }
+    }
+
+    /// Set the capabilities of the homeserver.
+    ///
+    /// This method generally *shouldn't* be called. The capabilities are fetched with a
+    /// [`get_capabilities`] request the first time they are needed.
+    ///
+    /// [`get_capabilities`]: ruma_client_api::discovery::get_capabilities
+    pub fn capabilities(self, capabilities: Capabilities) -> Self {
+        Self { capabilities: Some(capabilities), ..self }
+    }
+
+    /// Set the policy for retrying requests that were rate-limited or failed with a transport
+    /// error.
+    ///
+    /// By default, requests are not retried.
+    pub fn retry_policy(self, retry_policy: RetryPolicy) -> Self {
+        Self { retry_policy: Some(retry_policy), ..self }
+    }
+
     /// Finish building the [`Client`].
     ///
     /// Uses [`DefaultConstructibleHttpClient::default()`] to create an HTTP client instance.
     /// Unless the supported Matrix versions were manually set via
     /// [`supported_matrix_versions`][Self::supported_matrix_versions], this will do a
-    /// [`get_supported_versions`] request to find out about the supported versions.
+    /// [`get_supported_versions`] request to find out about the supported versions and unstable
+    /// features.
     pub async fn build<C>(self) -> Result<Client<C>, Error<C::Error, ruma_client_api::Error>>
     where
         C: DefaultConstructibleHttpClient,
@@ -59,7 +136,8 @@ impl ClientBuilder {
     ///
     /// Unless the supported Matrix versions were manually set via
     /// [`supported_matrix_versions`][Self::supported_matrix_versions], this will do a
-    /// [`get_supported_versions`] request to find out about the supported versions.
+    /// [`get_supported_versions`] request to find out about the supported versions and unstable
+    /// features.
     pub async fn http_client<C>(
         self,
         http_client: C,
@@ -71,25 +149,35 @@ impl ClientBuilder {
             .homeserver_url
             .expect("homeserver URL has to be set prior to calling .build() or .http_client()");
 
-        let supported_matrix_versions = match self.supported_matrix_versions {
-            Some(versions) => versions,
-            None => http_client
-                .send_matrix_request(
-                    &homeserver_url,
-                    SendAccessToken::None,
-                    &[MatrixVersion::V1_0],
-                    get_supported_versions::Request::new(),
-                )
-                .await?
-                .known_versions()
-                .collect(),
+        let (supported_matrix_versions, unstable_features) = match self.supported_matrix_versions {
+            Some(versions) => (versions, self.unstable_features.unwrap_or_default()),
+            None => {
+                let response = http_client
+                    .send_matrix_request(
+                        &homeserver_url,
+                        SendAccessToken::None,
+                        &[MatrixVersion::V1_0],
+                        get_supported_versions::Request::new(),
+                    )
+                    .await?;
+
+                let versions = response.known_versions().collect();
+                (versions, self.unstable_features.unwrap_or(response.unstable_features))
+            }
         };
 
         Ok(Client(Arc::new(ClientData {
             homeserver_url,
             http_client,
             access_token: Mutex::new(self.access_token),
+            refresh_token: Mutex::new(self.refresh_token),
+            handle_refresh_tokens: self.handle_refresh_tokens,
+            on_tokens_refreshed: self.on_tokens_refreshed,
+            refresh_lock: AsyncMutex::new(()),
             supported_matrix_versions,
+            unstable_features,
+            capabilities: Mutex::new(self.capabilities),
+            retry_policy: self.retry_policy,
         })))
     }
 }
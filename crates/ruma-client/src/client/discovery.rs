@@ -0,0 +1,180 @@
+use std::collections::BTreeMap;
+
+use ruma_client_api::discovery::get_capabilities::{self, Capabilities};
+use ruma_common::{api::MatrixVersion, RoomVersionId};
+
+use super::Client;
+use crate::{Error, HttpClient};
+
+impl<C> Client<C> {
+    /// The (known) Matrix versions the homeserver supports.
+    pub fn supported_matrix_versions(&self) -> &[MatrixVersion] {
+        &self.0.supported_matrix_versions
+    }
+
+    /// The unstable features advertised by the homeserver, with whether they are enabled.
+    pub fn unstable_features(&self) -> &BTreeMap<String, bool> {
+        &self.0.unstable_features
+    }
+
+    /// Whether the homeserver supports the given Matrix version.
+    pub fn supports_matrix_version(&self, version: MatrixVersion) -> bool {
+        self.0.supported_matrix_versions.contains(&version)
+    }
+
+    /// Whether the homeserver advertises the given unstable feature as enabled.
+    ///
+    /// Unstable features are usually named after the MSC they implement, like
+    /// `org.matrix.msc3575` or `org.matrix.simplified_msc3575` for sliding sync.
+    pub fn supports_unstable_feature(&self, feature: &str) -> bool {
+        self.0.unstable_features.get(feature).copied().unwrap_or(false)
+    }
+
+    /// Whether the homeserver supports authenticated media, according to MSC3916 / Matrix 1.11.
+    pub fn supports_authenticated_media(&self) -> bool {
+        self.supports_matrix_version(MatrixVersion::V1_11)
+            || self.supports_unstable_feature("org.matrix.msc3916.stable")
+    }
+
+    /// The capabilities of the homeserver, if they were already fetched.
+    ///
+    /// Use [`capabilities`][Self::capabilities] to fetch them if necessary.
+    pub fn cached_capabilities(&self) -> Option<Capabilities> {
+        self.0.capabilities.lock().expect("capabilities mutex was poisoned").clone()
+    }
+}
+
+impl<C: HttpClient> Client<C> {
+    /// Get the capabilities of the homeserver.
+    ///
+    /// The capabilities are only fetched with a [`get_capabilities`] request the first time, then
+    /// they are cached in the client. Use [`refresh_capabilities`][Self::refresh_capabilities] to
+    /// fetch them again.
+    ///
+    /// This endpoint requires authentication, so this fails if the client is not logged in and the
+    /// capabilities were not fetched yet.
+    pub async fn capabilities(
+        &self,
+    ) -> Result<Capabilities, Error<C::Error, ruma_client_api::Error>> {
+        match self.cached_capabilities() {
+            Some(capabilities) => Ok(capabilities),
+            None => self.refresh_capabilities().await,
+        }
+    }
+
+    /// Fetch the capabilities of the homeserver with a [`get_capabilities`] request and cache them
+    /// in the client.
+    pub async fn refresh_capabilities(
+        &self,
+    ) -> Result<Capabilities, Error<C::Error, ruma_client_api::Error>> {
+        let capabilities =
+            self.send_request(get_capabilities::v3::Request::new()).await?.capabilities;
+        *self.0.capabilities.lock().expect("capabilities mutex was poisoned") =
+            Some(capabilities.clone());
+
+        Ok(capabilities)
+    }
+
+    /// Whether the homeserver supports the given room version.
+    ///
+    /// This uses the capabilities of the homeserver, which are fetched if necessary.
+    pub async fn supports_room_version(
+        &self,
+        room_version: &RoomVersionId,
+    ) -> Result<bool, Error<C::Error, ruma_client_api::Error>> {
+        Ok(self.capabilities().await?.room_versions.available.contains_key(room_version))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma_common::{api::MatrixVersion, RoomVersionId};
+
+    use crate::{
+        client::test_utils::{response, MockClient},
+        Client,
+    };
+
+    const VERSIONS: &str = r#"{
+        "versions": ["r0.6.1", "v1.1", "v1.10"],
+        "unstable_features": {
+            "org.matrix.msc3575": true,
+            "org.matrix.msc3916.stable": false
+        }
+    }"#;
+
+    const CAPABILITIES: &str = r#"{
+        "capabilities": {
+            "m.room_versions": {
+                "default": "10",
+                "available": { "10": "stable", "11": "stable", "org.example.custom": "unstable" }
+            }
+        }
+    }"#;
+
+    #[tokio::test]
+    async fn discover_with_access_token() {
+        let client = Client::builder()
+            .homeserver_url("https://example.com".to_owned())
+            .access_token(Some("token".to_owned()))
+            .http_client(MockClient::new([response(200, VERSIONS), response(200, CAPABILITIES)]))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            client.supported_matrix_versions(),
+            [MatrixVersion::V1_0, MatrixVersion::V1_1, MatrixVersion::V1_10]
+        );
+        assert!(client.supports_matrix_version(MatrixVersion::V1_1));
+        assert!(!client.supports_matrix_version(MatrixVersion::V1_11));
+        assert!(client.supports_unstable_feature("org.matrix.msc3575"));
+        assert!(!client.supports_unstable_feature("org.matrix.msc3916.stable"));
+        assert!(!client.supports_unstable_feature("org.matrix.msc4186"));
+        assert!(!client.supports_authenticated_media());
+
+        // The capabilities are only fetched when they are needed.
+        assert!(client.cached_capabilities().is_none());
+        assert_eq!(client.0.http_client.request_count(), 1);
+
+        assert!(client.supports_room_version(&RoomVersionId::V11).await.unwrap());
+        assert!(client
+            .supports_room_version(&RoomVersionId::try_from("org.example.custom").unwrap())
+            .await
+            .unwrap());
+        assert!(!client.supports_room_version(&RoomVersionId::V1).await.unwrap());
+        assert_eq!(client.0.http_client.request_count(), 2);
+
+        // The capabilities are fetched with the access token.
+        let requests = client.0.http_client.requests();
+        assert!(requests[1].uri().path().ends_with("/capabilities"));
+        assert_eq!(requests[1].headers()[http::header::AUTHORIZATION], "Bearer token");
+    }
+
+    #[tokio::test]
+    async fn fetch_capabilities_lazily() {
+        let client = Client::builder()
+            .homeserver_url("https://example.com".to_owned())
+            .supported_matrix_versions(vec![MatrixVersion::V1_11])
+            .http_client(MockClient::new([
+                response(
+                    200,
+                    r#"{ "user_id": "@alice:example.com", "access_token": "token", "device_id": "ABCDEFG" }"#,
+                ),
+                response(200, CAPABILITIES),
+            ]))
+            .await
+            .unwrap();
+
+        assert!(client.supports_authenticated_media());
+        assert!(client.unstable_features().is_empty());
+        assert!(client.cached_capabilities().is_none());
+        assert_eq!(client.0.http_client.request_count(), 0);
+
+        client.log_in("alice", "secret", None, None).await.unwrap();
+
+        let capabilities = client.capabilities().await.unwrap();
+        assert_eq!(capabilities.room_versions.default, RoomVersionId::V10);
+        assert!(client.supports_room_version(&RoomVersionId::V10).await.unwrap());
+        assert_eq!(client.0.http_client.request_count(), 2);
+    }
+}
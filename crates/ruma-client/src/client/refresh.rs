@@ -0,0 +1,310 @@
+use ruma_client_api::{error::ErrorKind, session::refresh_token};
+use ruma_common::api::{EndpointError, SendAccessToken};
+use tracing::warn;
+
+use super::Client;
+use crate::{send_customized_request, Error, HttpClient};
+
+/// A function called with the new tokens every time the access token is refreshed.
+pub(super) type TokensRefreshedFn = dyn Fn(&refresh_token::v3::Response) + Send + Sync;
+
+impl<C> Client<C> {
+    /// Get a copy of the current `refresh_token`, if any.
+    ///
+    /// Useful for serializing and persisting the session to be restored later.
+    pub fn refresh_token(&self) -> Option<String> {
+        self.0.refresh_token.lock().expect("session mutex was poisoned").clone()
+    }
+
+    /// Store the tokens returned by a login or registration endpoint.
+    pub(super) fn set_session_tokens(
+        &self,
+        access_token: Option<String>,
+        refresh_token: Option<String>,
+    ) {
+        *self.0.access_token.lock().expect("session mutex was poisoned") = access_token;
+        *self.0.refresh_token.lock().expect("session mutex was poisoned") = refresh_token;
+    }
+}
+
+impl<C: HttpClient> Client<C> {
+    /// Refresh the access token with the [`refresh_token`] endpoint.
+    ///
+    /// The new tokens are stored in this client, passed to the function set with
+    /// [`ClientBuilder::on_tokens_refreshed`][super::ClientBuilder::on_tokens_refreshed], and
+    /// returned.
+    ///
+    /// This is done automatically when a request fails because the access token expired, so this
+    /// method generally doesn't need to be called.
+    ///
+    /// Returns an [`Error::AuthenticationRequired`] if this client doesn't have a refresh token.
+    pub async fn refresh_access_token(
+        &self,
+    ) -> Result<refresh_token::v3::Response, Error<C::Error, ruma_client_api::Error>> {
+        let _refresh_guard = self.0.refresh_lock.lock().await;
+        self.refresh_access_token_locked().await
+    }
+
+    /// Refresh the access token, while holding the refresh lock.
+    async fn refresh_access_token_locked(
+        &self,
+    ) -> Result<refresh_token::v3::Response, Error<C::Error, ruma_client_api::Error>> {
+        let refresh_token = self.refresh_token().ok_or(Error::AuthenticationRequired)?;
+
+        let response = send_customized_request(
+            &self.0.http_client,
+            &self.0.homeserver_url,
+            SendAccessToken::None,
+            &self.0.supported_matrix_versions,
+            refresh_token::v3::Request::new(refresh_token),
+            |_| Ok(()),
+        )
+        .await?;
+
+        *self.0.access_token.lock().expect("session mutex was poisoned") =
+            Some(response.access_token.clone());
+        if let Some(refresh_token) = &response.refresh_token {
+            *self.0.refresh_token.lock().expect("session mutex was poisoned") =
+                Some(refresh_token.clone());
+        }
+
+        if let Some(on_tokens_refreshed) = &self.0.on_tokens_refreshed {
+            on_tokens_refreshed(&response);
+        }
+
+        Ok(response)
+    }
+
+    /// Try to get a new access token after a request sent with `expired_access_token` failed
+    /// because the access token expired.
+    ///
+    /// Returns `true` if the request can be sent again.
+    pub(super) async fn refresh_expired_access_token(
+        &self,
+        expired_access_token: Option<&str>,
+    ) -> bool {
+        // The refresh token can only be used once, so wait for the refresh started by another
+        // request, if any.
+        let _refresh_guard = self.0.refresh_lock.lock().await;
+
+        // The access token was already refreshed by another request.
+        if self.access_token().as_deref() != expired_access_token {
+            return true;
+        }
+
+        match self.refresh_access_token_locked().await {
+            Ok(_) => true,
+            Err(error) => {
+                warn!(error_kind = ?error.error_kind(), "Failed to refresh the access token");
+                false
+            }
+        }
+    }
+}
+
+/// Whether the given result means that the access token expired and should be refreshed.
+pub(super) fn is_expired_access_token<B, E, F>(
+    result: &Result<http::Response<B>, Error<E, F>>,
+) -> bool
+where
+    B: AsRef<[u8]>,
+{
+    let Ok(response) = result else {
+        return false;
+    };
+
+    if response.status() != http::StatusCode::UNAUTHORIZED {
+        return false;
+    }
+
+    let mut borrowed_response = http::Response::new(response.body().as_ref());
+    *borrowed_response.status_mut() = response.status();
+
+    let error = ruma_client_api::Error::from_http_response(borrowed_response);
+    matches!(error.error_kind(), Some(ErrorKind::UnknownToken { soft_logout: true }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use ruma_client_api::{account::whoami, error::ErrorKind};
+    use ruma_common::api::MatrixVersion;
+    use serde_json::{from_slice as from_json_slice, Value as JsonValue};
+
+    use crate::{
+        client::test_utils::{response, MockClient, MockResponse},
+        Client,
+    };
+
+    fn expired_token() -> MockResponse {
+        response(
+            401,
+            r#"{ "errcode": "M_UNKNOWN_TOKEN", "error": "Token expired", "soft_logout": true }"#,
+        )
+    }
+
+    fn whoami_ok() -> MockResponse {
+        response(200, r#"{ "user_id": "@alice:example.com" }"#)
+    }
+
+    /// The `Authorization` headers of the requests sent by the client.
+    fn sent_authorization(client: &Client<MockClient>) -> Vec<Option<String>> {
+        client
+            .0
+            .http_client
+            .requests()
+            .iter()
+            .map(|req| {
+                req.headers()
+                    .get(http::header::AUTHORIZATION)
+                    .map(|value| value.to_str().unwrap().to_owned())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn refresh_expired_token_and_retry() {
+        let refreshed = Arc::new(Mutex::new(Vec::new()));
+        let refreshed_clone = refreshed.clone();
+
+        let client = Client::builder()
+            .homeserver_url("https://example.com".to_owned())
+            .access_token(Some("old_access".to_owned()))
+            .refresh_token(Some("old_refresh".to_owned()))
+            .supported_matrix_versions(vec![MatrixVersion::V1_3])
+            .on_tokens_refreshed(move |response| {
+                refreshed_clone.lock().unwrap().push(response.access_token.clone());
+            })
+            .http_client(MockClient::new([
+                expired_token(),
+                response(
+                    200,
+                    r#"{ "access_token": "new_access", "refresh_token": "new_refresh" }"#,
+                ),
+                whoami_ok(),
+            ]))
+            .await
+            .unwrap();
+
+        client.send_request(whoami::v3::Request::new()).await.unwrap();
+
+        assert_eq!(client.access_token().as_deref(), Some("new_access"));
+        assert_eq!(client.refresh_token().as_deref(), Some("new_refresh"));
+        assert_eq!(*refreshed.lock().unwrap(), ["new_access"]);
+
+        assert_eq!(
+            sent_authorization(&client),
+            [Some("Bearer old_access".to_owned()), None, Some("Bearer new_access".to_owned())]
+        );
+        let refresh_body: JsonValue =
+            from_json_slice(client.0.http_client.requests()[1].body()).unwrap();
+        assert_eq!(refresh_body["refresh_token"], "old_refresh");
+    }
+
+    #[tokio::test]
+    async fn refresh_only_once() {
+        let client = Client::builder()
+            .homeserver_url("https://example.com".to_owned())
+            .access_token(Some("old_access".to_owned()))
+            .refresh_token(Some("old_refresh".to_owned()))
+            .supported_matrix_versions(vec![MatrixVersion::V1_3])
+            .http_client(MockClient::new([
+                expired_token(),
+                response(200, r#"{ "access_token": "new_access" }"#),
+                expired_token(),
+            ]))
+            .await
+            .unwrap();
+
+        let error = client.send_request(whoami::v3::Request::new()).await.unwrap_err();
+        assert_eq!(error.error_kind(), Some(&ErrorKind::UnknownToken { soft_logout: true }));
+        assert_eq!(client.0.http_client.request_count(), 3);
+        // The refresh token can be reused.
+        assert_eq!(client.refresh_token().as_deref(), Some("old_refresh"));
+    }
+
+    #[tokio::test]
+    async fn refresh_once_for_concurrent_requests() {
+        let client = Client::builder()
+            .homeserver_url("https://example.com".to_owned())
+            .access_token(Some("old_access".to_owned()))
+            .refresh_token(Some("old_refresh".to_owned()))
+            .supported_matrix_versions(vec![MatrixVersion::V1_3])
+            .http_client(MockClient::new([
+                expired_token(),
+                expired_token(),
+                response(
+                    200,
+                    r#"{ "access_token": "new_access", "refresh_token": "new_refresh" }"#,
+                ),
+                whoami_ok(),
+                whoami_ok(),
+            ]))
+            .await
+            .unwrap();
+
+        let (first, second) = tokio::join!(
+            client.send_request(whoami::v3::Request::new()),
+            client.send_request(whoami::v3::Request::new()),
+        );
+        first.unwrap();
+        second.unwrap();
+
+        // Both requests failed with the old access token, but only one refreshed it.
+        assert_eq!(
+            sent_authorization(&client),
+            [
+                Some("Bearer old_access".to_owned()),
+                Some("Bearer old_access".to_owned()),
+                None,
+                Some("Bearer new_access".to_owned()),
+                Some("Bearer new_access".to_owned()),
+            ]
+        );
+        assert_eq!(client.refresh_token().as_deref(), Some("new_refresh"));
+    }
+
+    #[tokio::test]
+    async fn no_refresh_token() {
+        let client = Client::builder()
+            .homeserver_url("https://example.com".to_owned())
+            .access_token(Some("old_access".to_owned()))
+            .supported_matrix_versions(vec![MatrixVersion::V1_3])
+            .http_client(MockClient::new([expired_token()]))
+            .await
+            .unwrap();
+
+        let error = client.send_request(whoami::v3::Request::new()).await.unwrap_err();
+        assert_eq!(error.error_kind(), Some(&ErrorKind::UnknownToken { soft_logout: true }));
+        assert_eq!(client.0.http_client.request_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn log_in_with_refresh_token() {
+        let client = Client::builder()
+            .homeserver_url("https://example.com".to_owned())
+            .supported_matrix_versions(vec![MatrixVersion::V1_3])
+            .handle_refresh_tokens()
+            .http_client(MockClient::new([response(
+                200,
+                r#"{
+                    "user_id": "@alice:example.com",
+                    "access_token": "access",
+                    "refresh_token": "refresh",
+                    "device_id": "ABCDEFG",
+                    "expires_in_ms": 60000
+                }"#,
+            )]))
+            .await
+            .unwrap();
+
+        client.log_in("alice", "secret", None, None).await.unwrap();
+
+        let login_body: JsonValue =
+            from_json_slice(client.0.http_client.requests()[0].body()).unwrap();
+        assert_eq!(login_body["refresh_token"], true);
+        assert_eq!(client.access_token().as_deref(), Some("access"));
+        assert_eq!(client.refresh_token().as_deref(), Some("refresh"));
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    fmt::{self, Debug, Formatter},
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+};
+
+use ruma_client_api::error::{ErrorKind, RetryAfter};
+use ruma_common::api::EndpointError;
+use web_time::{Duration, SystemTime};
+
+use crate::Error;
+
+type SleepFn = dyn Fn(Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync;
+
+/// A policy for retrying requests that failed with a rate-limit or a transient transport error.
+///
+/// A request is retried when:
+///
+/// * The server responded with `429 Too Many Requests`, e.g. with an `M_LIMIT_EXCEEDED` error. If
+///   the server specified how long to wait, either through the `Retry-After` header or the
+///   `retry_after_ms` field of the response body, that delay is used. Otherwise the exponential
+///   backoff delay is used.
+/// * The HTTP client failed to obtain a response (e.g. due to network or DNS issues). The
+///   exponential backoff delay is used.
+///
+/// Since `ruma-client` is not tied to a specific async runtime, the function used to wait between
+/// attempts has to be provided when constructing the policy.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    sleep: Arc<SleepFn>,
+}
+
+impl RetryPolicy {
+    /// Creates a new `RetryPolicy` using the given function to wait between attempts.
+    ///
+    /// By default, a request is retried at most 3 times, the first backoff delay is 500
+    /// milliseconds and the backoff delay is capped at 30 seconds.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// # // HACK: "ignore" the doctest here because it needs tokio.
+    /// let retry_policy = ruma_client::RetryPolicy::new(tokio::time::sleep).max_retries(5);
+    /// ```
+    pub fn new<S, Fut>(sleep: S) -> Self
+    where
+        S: Fn(Duration) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            sleep: Arc::new(move |delay| Box::pin(sleep(delay))),
+        }
+    }
+
+    /// Set the maximum number of times a request is retried.
+    pub fn max_retries(self, max_retries: u32) -> Self {
+        Self { max_retries, ..self }
+    }
+
+    /// Set the delay before the first retry.
+    ///
+    /// The delay is doubled for each subsequent retry, up to the
+    /// [maximum backoff delay][Self::max_backoff].
+    pub fn initial_backoff(self, initial_backoff: Duration) -> Self {
+        Self { initial_backoff, ..self }
+    }
+
+    /// Set the maximum delay between two attempts computed by the exponential backoff.
+    ///
+    /// This does not limit the delay requested by the server in a rate-limited response.
+    pub fn max_backoff(self, max_backoff: Duration) -> Self {
+        Self { max_backoff, ..self }
+    }
+
+    /// The exponential backoff delay before the given retry attempt, starting at 0.
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .checked_mul(2_u32.saturating_pow(attempt))
+            .map_or(self.max_backoff, |delay| delay.min(self.max_backoff))
+    }
+
+    /// Returns how long to wait before retrying a request that got the given result, or `None` if
+    /// the request should not be retried.
+    pub(super) fn retry_delay<B, E, F>(
+        &self,
+        attempt: u32,
+        result: &Result<http::Response<B>, Error<E, F>>,
+    ) -> Option<Duration>
+    where
+        B: AsRef<[u8]>,
+    {
+        if attempt >= self.max_retries {
+            return None;
+        }
+
+        match result {
+            Ok(response) if response.status() == http::StatusCode::TOO_MANY_REQUESTS => {
+                Some(server_retry_after(response).unwrap_or_else(|| self.backoff(attempt)))
+            }
+            Err(Error::Response(_)) => Some(self.backoff(attempt)),
+            _ => None,
+        }
+    }
+
+    /// Wait for the given delay.
+    pub(super) fn sleep(&self, delay: Duration) -> impl Future<Output = ()> + Send {
+        (self.sleep)(delay)
+    }
+}
+
+impl Debug for RetryPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_retries", &self.max_retries)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Get the delay requested by the server in a rate-limited response.
+fn server_retry_after<B: AsRef<[u8]>>(response: &http::Response<B>) -> Option<Duration> {
+    // Reuse the error parsing of ruma-client-api, which also takes care of giving precedence to
+    // the `Retry-After` header over the `retry_after_ms` field in the body.
+    let mut borrowed_response = http::Response::new(response.body().as_ref());
+    *borrowed_response.status_mut() = response.status();
+    borrowed_response.headers_mut().clone_from(response.headers());
+
+    let error = ruma_client_api::Error::from_http_response(borrowed_response);
+    let ErrorKind::LimitExceeded { retry_after: Some(retry_after) } = error.error_kind()? else {
+        return None;
+    };
+
+    match *retry_after {
+        RetryAfter::Delay(delay) => Some(delay),
+        RetryAfter::DateTime(time) => {
+            Some(time.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use assert_matches2::assert_matches;
+    use ruma_client_api::{alias::get_alias, error::ErrorKind};
+    use ruma_common::{api::MatrixVersion, owned_room_alias_id, room_id};
+    use web_time::Duration;
+
+    use super::RetryPolicy;
+    use crate::{
+        client::test_utils::{response, MockClient, MockResponse},
+        Client, Error,
+    };
+
+    fn get_alias_ok() -> MockResponse {
+        response(200, r#"{ "room_id": "!roomid:example.com", "servers": ["example.com"] }"#)
+    }
+
+    fn rate_limited(retry_after_ms: Option<u64>) -> MockResponse {
+        let body = match retry_after_ms {
+            Some(ms) => format!(
+                r#"{{ "errcode": "M_LIMIT_EXCEEDED", "error": "Too many requests", "retry_after_ms": {ms} }}"#
+            ),
+            None => r#"{ "errcode": "M_LIMIT_EXCEEDED", "error": "Too many requests" }"#.to_owned(),
+        };
+        response(429, &body)
+    }
+
+    /// Build a client with a retry policy that records the delays instead of waiting.
+    async fn client(
+        http_client: MockClient,
+        max_retries: u32,
+    ) -> (Client<MockClient>, Arc<Mutex<Vec<Duration>>>) {
+        let delays = Arc::new(Mutex::new(Vec::new()));
+        let recorded_delays = delays.clone();
+        let retry_policy = RetryPolicy::new(move |delay| {
+            recorded_delays.lock().unwrap().push(delay);
+            std::future::ready(())
+        })
+        .max_retries(max_retries)
+        .initial_backoff(Duration::from_secs(1))
+        .max_backoff(Duration::from_secs(3));
+
+        let client = Client::builder()
+            .homeserver_url("https://example.com".to_owned())
+            .supported_matrix_versions(vec![MatrixVersion::V1_0])
+            .retry_policy(retry_policy)
+            .http_client(http_client)
+            .await
+            .unwrap();
+
+        (client, delays)
+    }
+
+    fn get_alias_request() -> get_alias::v3::Request {
+        get_alias::v3::Request::new(owned_room_alias_id!("#alias:example.com"))
+    }
+
+    #[tokio::test]
+    async fn retry_rate_limited_with_server_delay() {
+        let (client, delays) =
+            client(MockClient::new([rate_limited(Some(2500)), get_alias_ok()]), 3).await;
+
+        let response = client.send_request(get_alias_request()).await.unwrap();
+        assert_eq!(response.room_id, room_id!("!roomid:example.com"));
+        assert_eq!(client.0.http_client.request_count(), 2);
+        assert_eq!(*delays.lock().unwrap(), [Duration::from_millis(2500)]);
+    }
+
+    #[tokio::test]
+    async fn retry_after_header_takes_precedence() {
+        let mut limited = rate_limited(Some(2500)).unwrap();
+        limited.headers_mut().insert(http::header::RETRY_AFTER, http::HeaderValue::from(7));
+        let (client, delays) = client(MockClient::new([Ok(limited), get_alias_ok()]), 3).await;
+
+        client.send_request(get_alias_request()).await.unwrap();
+        assert_eq!(*delays.lock().unwrap(), [Duration::from_secs(7)]);
+    }
+
+    #[tokio::test]
+    async fn retry_transport_errors_with_backoff() {
+        let (client, delays) = client(
+            MockClient::new([
+                Err("connection reset"),
+                rate_limited(None),
+                Err("connection reset"),
+                get_alias_ok(),
+            ]),
+            3,
+        )
+        .await;
+
+        client.send_request(get_alias_request()).await.unwrap();
+        assert_eq!(client.0.http_client.request_count(), 4);
+        assert_eq!(
+            *delays.lock().unwrap(),
+            [Duration::from_secs(1), Duration::from_secs(2), Duration::from_secs(3)]
+        );
+    }
+
+    #[tokio::test]
+    async fn give_up_after_max_retries() {
+        let (client, delays) = client(
+            MockClient::new([rate_limited(Some(10)), rate_limited(Some(10)), get_alias_ok()]),
+            1,
+        )
+        .await;
+
+        let error = client.send_request(get_alias_request()).await.unwrap_err();
+        assert_eq!(client.0.http_client.request_count(), 2);
+        assert_eq!(delays.lock().unwrap().len(), 1);
+        assert_matches!(error.error_kind(), Some(ErrorKind::LimitExceeded { .. }));
+    }
+
+    #[tokio::test]
+    async fn no_retry_for_other_errors() {
+        let (client, delays) = client(
+            MockClient::new([response(404, r#"{ "errcode": "M_NOT_FOUND", "error": "" }"#)]),
+            3,
+        )
+        .await;
+
+        let error = client.send_request(get_alias_request()).await.unwrap_err();
+        assert_eq!(client.0.http_client.request_count(), 1);
+        assert!(delays.lock().unwrap().is_empty());
+        assert_eq!(error.error_kind(), Some(&ErrorKind::NotFound));
+        assert_matches!(error, Error::FromHttpResponse(_));
+    }
+}
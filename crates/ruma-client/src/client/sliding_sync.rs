@@ -0,0 +1,487 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use js_int::UInt;
+use ruma_client_api::{
+    error::ErrorKind,
+    sync::sync_events::v5::{self, request, response},
+};
+use ruma_common::{OwnedRoomId, RoomId};
+
+/// The maximum number of times a connection is reset in a row after `M_UNKNOWN_POS` errors, before
+/// the error is returned.
+pub(super) const MAX_CONSECUTIVE_RESETS: usize = 3;
+
+/// The configuration and state of a [sliding sync] connection.
+///
+/// This is a cheaply cloneable handle: the lists, room subscriptions and extensions can be changed
+/// through any clone while [`Client::sliding_sync`][super::Client::sliding_sync] is running, and
+/// the changes are taken into account for the next request.
+///
+/// [sliding sync]: https://github.com/matrix-org/matrix-spec-proposals/pull/4186
+#[derive(Clone, Debug, Default)]
+pub struct SlidingSync(Arc<Mutex<SlidingSyncState>>);
+
+#[derive(Debug, Default)]
+struct SlidingSyncState {
+    /// The identifier of this connection.
+    conn_id: Option<String>,
+
+    /// The maximum time to poll before the server responds.
+    timeout: Option<Duration>,
+
+    /// The `pos` of the last response.
+    pos: Option<String>,
+
+    /// The requested lists.
+    lists: BTreeMap<String, request::List>,
+
+    /// The total number of rooms in each list, as returned by the server.
+    list_counts: BTreeMap<String, UInt>,
+
+    /// The explicit room subscriptions.
+    room_subscriptions: BTreeMap<OwnedRoomId, request::RoomSubscription>,
+
+    /// The configuration of the extensions.
+    extensions: request::Extensions,
+
+    /// Whether the extensions need to be sent with the next request.
+    ///
+    /// The extensions are sticky: they are only sent until the server acknowledges them by
+    /// echoing the `txn_id` of the request that contained them, or after the connection was
+    /// reset.
+    extensions_changed: bool,
+
+    /// The `txn_id` of the last request that contained the extensions, until it is acknowledged.
+    pending_txn_id: Option<String>,
+
+    /// The counter used to generate `txn_id`s.
+    txn_counter: u64,
+
+    /// Whether the connection was reset since the last response.
+    reset: bool,
+}
+
+impl SlidingSync {
+    /// Creates a new `SlidingSync` without any lists or room subscriptions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the identifier of this connection.
+    ///
+    /// This is only necessary if the client needs several concurrent sliding sync connections.
+    pub fn conn_id(self, conn_id: String) -> Self {
+        self.lock().conn_id = Some(conn_id);
+        self
+    }
+
+    /// Set the maximum time to poll before the server responds.
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.lock().timeout = Some(timeout);
+        self
+    }
+
+    /// The `pos` of the last response, if any.
+    ///
+    /// It can be persisted with the configuration to resume the connection later with
+    /// [`set_pos`][Self::set_pos].
+    pub fn pos(&self) -> Option<String> {
+        self.lock().pos.clone()
+    }
+
+    /// Set the `pos` to continue the connection from.
+    pub fn set_pos(&self, pos: Option<String>) {
+        self.lock().pos = pos;
+    }
+
+    /// Add a list with the given name, or replace the existing list with that name.
+    pub fn set_list(&self, name: String, list: request::List) {
+        self.lock().lists.insert(name, list);
+    }
+
+    /// Set the ranges of the list with the given name.
+    ///
+    /// Returns `false` if there is no list with that name.
+    pub fn set_list_ranges(&self, name: &str, ranges: Vec<(UInt, UInt)>) -> bool {
+        match self.lock().lists.get_mut(name) {
+            Some(list) => {
+                list.ranges = ranges;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The ranges of the list with the given name, if there is one.
+    pub fn list_ranges(&self, name: &str) -> Option<Vec<(UInt, UInt)>> {
+        self.lock().lists.get(name).map(|list| list.ranges.clone())
+    }
+
+    /// The total number of rooms in the list with the given name, as returned by the server.
+    pub fn list_count(&self, name: &str) -> Option<UInt> {
+        self.lock().list_counts.get(name).copied()
+    }
+
+    /// Remove the list with the given name.
+    ///
+    /// Returns the removed list, if there was one.
+    pub fn remove_list(&self, name: &str) -> Option<request::List> {
+        let mut state = self.lock();
+        state.list_counts.remove(name);
+        state.lists.remove(name)
+    }
+
+    /// Subscribe to the room with the given ID, or replace the existing subscription.
+    pub fn subscribe_to_room(&self, room_id: OwnedRoomId, subscription: request::RoomSubscription) {
+        self.lock().room_subscriptions.insert(room_id, subscription);
+    }
+
+    /// Unsubscribe from the room with the given ID.
+    ///
+    /// Returns the removed subscription, if there was one.
+    pub fn unsubscribe_from_room(&self, room_id: &RoomId) -> Option<request::RoomSubscription> {
+        self.lock().room_subscriptions.remove(room_id)
+    }
+
+    /// Set the configuration of the extensions.
+    pub fn set_extensions(&self, extensions: request::Extensions) {
+        let mut state = self.lock();
+        state.extensions = extensions;
+        state.extensions_changed = true;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SlidingSyncState> {
+        self.0.lock().expect("sliding sync mutex was poisoned")
+    }
+
+    /// Build the next request from the current state.
+    pub(super) fn next_request(&self) -> v5::Request {
+        let mut state = self.lock();
+
+        let mut request = v5::Request::new();
+        request.pos.clone_from(&state.pos);
+        request.conn_id.clone_from(&state.conn_id);
+        request.timeout = state.timeout;
+        request.lists.clone_from(&state.lists);
+        request.room_subscriptions.clone_from(&state.room_subscriptions);
+
+        if state.extensions_changed || state.pending_txn_id.is_some() {
+            state.txn_counter += 1;
+            let txn_id = state.txn_counter.to_string();
+
+            request.txn_id = Some(txn_id.clone());
+            request.extensions.clone_from(&state.extensions);
+            state.extensions_changed = false;
+            state.pending_txn_id = Some(txn_id);
+        }
+
+        request
+    }
+
+    /// Update the state with the given response and convert it to an update.
+    pub(super) fn handle_response(&self, response: v5::Response) -> SlidingSyncUpdate {
+        let mut state = self.lock();
+
+        if state.pending_txn_id.is_some() && response.txn_id == state.pending_txn_id {
+            state.pending_txn_id = None;
+        }
+
+        if let Some(to_device) = &response.extensions.to_device {
+            state.extensions.to_device.since = Some(to_device.next_batch.clone());
+            state.extensions_changed = true;
+        }
+
+        state.pos = Some(response.pos);
+
+        let mut lists = BTreeMap::new();
+        for (name, list) in response.lists {
+            // Ignore lists that were removed while the request was in flight.
+            if state.lists.contains_key(&name) {
+                state.list_counts.insert(name.clone(), list.count);
+                lists.insert(name, list.count);
+            }
+        }
+
+        let rooms = response
+            .rooms
+            .into_iter()
+            .map(|(room_id, data)| SlidingSyncRoomUpdate {
+                subscribed: state.room_subscriptions.contains_key(&room_id),
+                initial: data.initial.unwrap_or(false),
+                room_id,
+                data,
+            })
+            .collect();
+
+        SlidingSyncUpdate {
+            reset: std::mem::take(&mut state.reset),
+            lists,
+            rooms,
+            extensions: response.extensions,
+        }
+    }
+
+    /// Handle an error returned by the server.
+    ///
+    /// Returns `true` if the error means that the connection expired and was reset, in which case
+    /// the request should be sent again.
+    pub(super) fn handle_error_kind(&self, error_kind: Option<&ErrorKind>) -> bool {
+        if !matches!(error_kind, Some(ErrorKind::UnknownPos)) {
+            return false;
+        }
+
+        let mut state = self.lock();
+        state.pos = None;
+        state.list_counts.clear();
+        state.extensions_changed = true;
+        state.reset = true;
+
+        true
+    }
+}
+
+/// The changes contained in a sliding sync response.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct SlidingSyncUpdate {
+    /// Whether the connection expired and was started again since the previous update.
+    ///
+    /// In this case, the server sends the rooms again as if the connection was new, so any state
+    /// that was computed from the previous updates should be considered as stale.
+    pub reset: bool,
+
+    /// The new total number of rooms for each list that was returned by the server.
+    pub lists: BTreeMap<String, UInt>,
+
+    /// The updated rooms.
+    pub rooms: Vec<SlidingSyncRoomUpdate>,
+
+    /// The responses of the extensions.
+    pub extensions: response::Extensions,
+}
+
+/// The changes to a single room in a sliding sync response.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct SlidingSyncRoomUpdate {
+    /// The ID of the room.
+    pub room_id: OwnedRoomId,
+
+    /// Whether the room is part of the explicit room subscriptions.
+    ///
+    /// If this is `false`, the room was returned because it is in the range of one of the lists.
+    pub subscribed: bool,
+
+    /// Whether this is the first time the room is returned in this connection.
+    ///
+    /// If this is `true`, `data` contains the full requested state of the room rather than the
+    /// changes since the previous update.
+    pub initial: bool,
+
+    /// The data of the room returned by the server.
+    pub data: response::Room,
+}
+
+impl SlidingSyncRoomUpdate {
+    /// Whether this room is a pending invite.
+    pub fn is_invite(&self) -> bool {
+        self.data.invite_state.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches2::assert_matches;
+    use js_int::uint;
+    use ruma_client_api::{error::ErrorKind, sync::sync_events::v5::request};
+    use ruma_common::{api::MatrixVersion, owned_room_id};
+    use serde_json::{from_slice as from_json_slice, Value as JsonValue};
+
+    use super::{SlidingSync, MAX_CONSECUTIVE_RESETS};
+    use crate::{
+        client::test_utils::{response, MockClient},
+        Client,
+    };
+
+    async fn client(http_client: MockClient) -> Client<MockClient> {
+        Client::builder()
+            .homeserver_url("https://example.com".to_owned())
+            .access_token(Some("token".to_owned()))
+            .supported_matrix_versions(vec![MatrixVersion::V1_0])
+            .http_client(http_client)
+            .await
+            .unwrap()
+    }
+
+    fn sliding_sync() -> SlidingSync {
+        let sliding_sync = SlidingSync::new().conn_id("main".to_owned());
+
+        let mut list = request::List::default();
+        list.ranges = vec![(uint!(0), uint!(9))];
+        sliding_sync.set_list("all".to_owned(), list);
+
+        let mut extensions = request::Extensions::default();
+        extensions.to_device.enabled = Some(true);
+        sliding_sync.set_extensions(extensions);
+
+        sliding_sync
+    }
+
+    /// The query string and the JSON body of the requests sent by the client.
+    fn sent_requests(client: &Client<MockClient>) -> Vec<(String, JsonValue)> {
+        client
+            .0
+            .http_client
+            .requests()
+            .iter()
+            .map(|req| {
+                (
+                    req.uri().query().unwrap_or_default().to_owned(),
+                    from_json_slice(req.body()).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn keep_pos_and_acknowledge_extensions() {
+        use tokio_stream::StreamExt as _;
+
+        let client = client(MockClient::new([
+            response(
+                200,
+                r#"{
+                    "pos": "1",
+                    "txn_id": "1",
+                    "lists": { "all": { "count": 2 } },
+                    "rooms": {
+                        "!a:example.com": { "initial": true, "name": "A" },
+                        "!b:example.com": { "name": "B" }
+                    }
+                }"#,
+            ),
+            response(200, r#"{ "pos": "2", "lists": { "all": { "count": 3 } } }"#),
+        ]))
+        .await;
+        let sliding_sync = sliding_sync();
+        sliding_sync.subscribe_to_room(owned_room_id!("!b:example.com"), Default::default());
+
+        let mut stream = Box::pin(client.sliding_sync(&sliding_sync));
+
+        let update = stream.try_next().await.unwrap().unwrap();
+        assert!(!update.reset);
+        assert_eq!(update.lists["all"], uint!(2));
+        assert_eq!(update.rooms.len(), 2);
+        assert_eq!(update.rooms[0].room_id, "!a:example.com");
+        assert!(update.rooms[0].initial);
+        assert!(!update.rooms[0].subscribed);
+        assert!(!update.rooms[1].initial);
+        assert!(update.rooms[1].subscribed);
+
+        let update = stream.try_next().await.unwrap().unwrap();
+        assert_eq!(update.lists["all"], uint!(3));
+        assert_eq!(sliding_sync.pos().as_deref(), Some("2"));
+        assert_eq!(sliding_sync.list_count("all"), Some(uint!(3)));
+
+        let requests = sent_requests(&client);
+        assert_eq!(requests[0].0, "");
+        assert_eq!(requests[0].1["conn_id"], "main");
+        assert_eq!(requests[0].1["txn_id"], "1");
+        assert_eq!(requests[0].1["extensions"]["to_device"]["enabled"], true);
+        assert_eq!(requests[0].1["lists"]["all"]["ranges"][0][1], 9);
+
+        // The extensions were acknowledged, they are not sent anymore, but the lists and
+        // subscriptions are.
+        assert_eq!(requests[1].0, "pos=1");
+        assert!(requests[1].1.get("txn_id").is_none());
+        assert!(requests[1].1.get("extensions").is_none());
+        assert_eq!(requests[1].1["lists"]["all"]["ranges"][0][1], 9);
+        assert!(requests[1].1["room_subscriptions"].get("!b:example.com").is_some());
+    }
+
+    #[tokio::test]
+    async fn resend_sticky_parameters_after_reset() {
+        use tokio_stream::StreamExt as _;
+
+        let client = client(MockClient::new([
+            response(200, r#"{ "pos": "1", "txn_id": "1" }"#),
+            response(400, r#"{ "errcode": "M_UNKNOWN_POS", "error": "Unknown pos" }"#),
+            response(
+                200,
+                r#"{
+                    "pos": "a",
+                    "txn_id": "2",
+                    "lists": { "all": { "count": 1 } },
+                    "rooms": { "!a:example.com": { "initial": true } }
+                }"#,
+            ),
+        ]))
+        .await;
+        let sliding_sync = sliding_sync();
+
+        let mut stream = Box::pin(client.sliding_sync(&sliding_sync));
+
+        let update = stream.try_next().await.unwrap().unwrap();
+        assert!(!update.reset);
+
+        let update = stream.try_next().await.unwrap().unwrap();
+        assert!(update.reset);
+        assert!(update.rooms[0].initial);
+        assert_eq!(sliding_sync.pos().as_deref(), Some("a"));
+
+        let requests = sent_requests(&client);
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[1].0, "pos=1");
+        assert!(requests[1].1.get("extensions").is_none());
+
+        // The connection is started again with all the sticky parameters.
+        assert_eq!(requests[2].0, "");
+        assert_eq!(requests[2].1["txn_id"], "2");
+        assert_eq!(requests[2].1["extensions"]["to_device"]["enabled"], true);
+    }
+
+    #[tokio::test]
+    async fn stop_after_consecutive_resets() {
+        use tokio_stream::StreamExt as _;
+
+        let unknown_pos =
+            || response(400, r#"{ "errcode": "M_UNKNOWN_POS", "error": "Unknown pos" }"#);
+        let client =
+            client(MockClient::new([unknown_pos(), unknown_pos(), unknown_pos(), unknown_pos()]))
+                .await;
+        let sliding_sync = sliding_sync();
+
+        let mut stream = Box::pin(client.sliding_sync(&sliding_sync));
+
+        let error = stream.try_next().await.unwrap_err();
+        assert_matches!(error.error_kind(), Some(ErrorKind::UnknownPos));
+        assert_eq!(client.0.http_client.request_count(), MAX_CONSECUTIVE_RESETS + 1);
+    }
+
+    #[tokio::test]
+    async fn track_to_device_since() {
+        use tokio_stream::StreamExt as _;
+
+        let client = client(MockClient::new([
+            response(
+                200,
+                r#"{ "pos": "1", "txn_id": "1", "extensions": { "to_device": { "next_batch": "td1" } } }"#,
+            ),
+            response(200, r#"{ "pos": "2" }"#),
+        ]))
+        .await;
+        let sliding_sync = sliding_sync();
+
+        let mut stream = Box::pin(client.sliding_sync(&sliding_sync));
+        stream.try_next().await.unwrap().unwrap();
+        stream.try_next().await.unwrap().unwrap();
+
+        let requests = sent_requests(&client);
+        assert_eq!(requests[1].1["txn_id"], "2");
+        assert_eq!(requests[1].1["extensions"]["to_device"]["since"], "td1");
+    }
+}
@@ -0,0 +1,52 @@
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, MutexGuard},
+};
+
+use crate::HttpClient;
+
+/// A canned result of [`MockClient::send_http_request`].
+pub(super) type MockResponse = Result<http::Response<Vec<u8>>, &'static str>;
+
+/// An HTTP client that replays canned responses and records the requests it gets.
+#[derive(Default)]
+pub(super) struct MockClient {
+    responses: Mutex<VecDeque<MockResponse>>,
+    requests: Mutex<Vec<http::Request<Vec<u8>>>>,
+}
+
+impl MockClient {
+    pub(super) fn new(responses: impl IntoIterator<Item = MockResponse>) -> Self {
+        Self { responses: Mutex::new(responses.into_iter().collect()), ..Default::default() }
+    }
+
+    pub(super) fn request_count(&self) -> usize {
+        self.requests.lock().unwrap().len()
+    }
+
+    pub(super) fn requests(&self) -> MutexGuard<'_, Vec<http::Request<Vec<u8>>>> {
+        self.requests.lock().unwrap()
+    }
+}
+
+impl HttpClient for MockClient {
+    type RequestBody = Vec<u8>;
+    type ResponseBody = Vec<u8>;
+    type Error = &'static str;
+
+    async fn send_http_request(
+        &self,
+        req: http::Request<Self::RequestBody>,
+    ) -> Result<http::Response<Self::ResponseBody>, Self::Error> {
+        // Let concurrent requests make progress, like a real HTTP client would.
+        tokio::task::yield_now().await;
+
+        self.requests.lock().unwrap().push(req);
+        self.responses.lock().unwrap().pop_front().expect("no more canned responses")
+    }
+}
+
+/// A canned response with the given status code and body.
+pub(super) fn response(status: u16, body: &str) -> MockResponse {
+    Ok(http::Response::builder().status(status).body(body.as_bytes().to_vec()).unwrap())
+}
@@ -0,0 +1,432 @@
+use std::future::Future;
+
+use ruma_client_api::{
+    error::ErrorKind,
+    uiaa::{AuthData, AuthFlow, AuthType, UiaaInfo, UiaaResponse},
+};
+use ruma_common::api::{error::FromHttpResponseError, OutgoingRequest};
+
+use super::Client;
+use crate::{Error, HttpClient, ResponseResult};
+
+/// A handler for the stages of the [User-Interactive Authentication API].
+///
+/// It is used by [`Client::send_request_with_uiaa`] to get the authentication data for each stage
+/// of the chosen authentication flow.
+///
+/// This trait is implemented for closures with the same signature as
+/// [`auth_data`][Self::auth_data], that support all the stages.
+///
+/// [User-Interactive Authentication API]: https://spec.matrix.org/latest/client-server-api/#user-interactive-authentication-api
+pub trait UiaaHandler: Send {
+    /// Whether this handler can complete the stages with the given authentication type.
+    ///
+    /// This is used to choose the flow to follow: flows with a stage that is not supported are
+    /// ignored.
+    ///
+    /// Returns `true` by default.
+    fn supports(&self, auth_type: &AuthType) -> bool {
+        let _ = auth_type;
+        true
+    }
+
+    /// Get the authentication data for the given stage.
+    ///
+    /// `info` contains the parameters of the stages and the error returned by the homeserver if a
+    /// previous attempt of this stage failed.
+    ///
+    /// The `session` of the returned data doesn't need to be set, it is filled in automatically.
+    /// Returns `None` to abort the authentication.
+    fn auth_data(
+        &mut self,
+        stage: &AuthType,
+        info: &UiaaInfo,
+    ) -> impl Future<Output = Option<AuthData>> + Send;
+}
+
+impl<F, Fut> UiaaHandler for F
+where
+    F: FnMut(&AuthType, &UiaaInfo) -> Fut + Send,
+    Fut: Future<Output = Option<AuthData>> + Send,
+{
+    fn auth_data(
+        &mut self,
+        stage: &AuthType,
+        info: &UiaaInfo,
+    ) -> impl Future<Output = Option<AuthData>> + Send {
+        self(stage, info)
+    }
+}
+
+impl<C: HttpClient> Client<C> {
+    /// Makes a request to a Matrix API endpoint that uses the User-Interactive Authentication API,
+    /// completing the authentication stages with the given handler.
+    ///
+    /// `make_request` is called to build the request for each attempt, with the authentication
+    /// data to send, if any. The first attempt is made without authentication data. Every time the
+    /// homeserver responds that more authentication is needed, the first flow that is compatible
+    /// with the completed stages and only contains stages [supported][UiaaHandler::supports] by
+    /// `handler` is chosen, and `handler` is asked for the data of the next stage of that flow.
+    ///
+    /// The `session` returned by the homeserver is kept across attempts.
+    ///
+    /// If no flow can be completed, the handler aborts the authentication or a stage fails again
+    /// with the same error, the last error returned by the homeserver is returned.
+    ///
+    /// # Example:
+    ///
+    /// ```no_run
+    /// use ruma_client_api::{
+    ///     device::delete_device,
+    ///     uiaa::{AuthData, AuthType, Password, UiaaInfo, UserIdentifier},
+    /// };
+    /// use ruma_common::owned_device_id;
+    ///
+    /// # let homeserver_url = "https://example.com".to_owned();
+    /// # async {
+    /// # let client = ruma_client::Client::builder()
+    /// #     .homeserver_url(homeserver_url)
+    /// #     .build::<ruma_client::http_client::Dummy>()
+    /// #     .await
+    /// #     .unwrap();
+    /// let device_id = owned_device_id!("ABCDEFG");
+    /// client
+    ///     .send_request_with_uiaa(
+    ///         |auth| {
+    ///             let mut request = delete_device::v3::Request::new(device_id.clone());
+    ///             request.auth = auth;
+    ///             request
+    ///         },
+    ///         |stage: &AuthType, info: &UiaaInfo| {
+    ///             let auth_data = match stage {
+    ///                 // Give up if the password was rejected.
+    ///                 AuthType::Password if info.auth_error.is_none() => {
+    ///                     Some(AuthData::Password(Password::new(
+    ///                         UserIdentifier::UserIdOrLocalpart("alice".to_owned()),
+    ///                         "secret".to_owned(),
+    ///                     )))
+    ///                 }
+    ///                 _ => None,
+    ///             };
+    ///             std::future::ready(auth_data)
+    ///         },
+    ///     )
+    ///     .await?;
+    /// # Result::<(), ruma_client::Error<_, _>>::Ok(())
+    /// # };
+    /// ```
+    pub async fn send_request_with_uiaa<R, F, H>(
+        &self,
+        make_request: F,
+        mut handler: H,
+    ) -> ResponseResult<C, R>
+    where
+        R: OutgoingRequest<EndpointError = UiaaResponse>,
+        F: Fn(Option<AuthData>) -> R,
+        H: UiaaHandler,
+    {
+        let mut auth = None;
+        let mut session: Option<String> = None;
+        // The stages that failed and the errors they failed with, to not retry them forever.
+        let mut failures: Vec<(AuthType, ErrorKind, String)> = Vec::new();
+
+        loop {
+            let error = match self.send_request(make_request(auth.take())).await {
+                Ok(response) => return Ok(response),
+                Err(error) => error,
+            };
+
+            let Error::FromHttpResponse(FromHttpResponseError::Server(UiaaResponse::AuthResponse(
+                info,
+            ))) = &error
+            else {
+                return Err(error);
+            };
+
+            if info.session.is_some() {
+                session.clone_from(&info.session);
+            }
+
+            let Some(stage) = next_stage(info, &handler) else {
+                return Err(error);
+            };
+
+            if let Some(auth_error) = &info.auth_error {
+                let failure = (stage.clone(), auth_error.kind.clone(), auth_error.message.clone());
+                if failures.contains(&failure) {
+                    return Err(error);
+                }
+                failures.push(failure);
+            }
+
+            let Some(mut auth_data) = handler.auth_data(stage, info).await else {
+                return Err(error);
+            };
+
+            if let Some(session) = &session {
+                auth_data.set_session(session.clone());
+            }
+            auth = Some(auth_data);
+        }
+    }
+}
+
+/// The next stage to complete, in the first flow that can be completed by the handler.
+fn next_stage<'a>(info: &'a UiaaInfo, handler: &impl UiaaHandler) -> Option<&'a AuthType> {
+    info.flows.iter().find_map(|AuthFlow { stages, .. }| {
+        let remaining_stages = stages.strip_prefix(info.completed.as_slice())?;
+        remaining_stages
+            .iter()
+            .all(|stage| handler.supports(stage))
+            .then(|| remaining_stages.first())
+            .flatten()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::{ready, Future},
+        sync::{Arc, Mutex},
+    };
+
+    use assert_matches2::assert_matches;
+    use ruma_client_api::{
+        device::delete_device,
+        uiaa::{AuthData, AuthType, Dummy, Password, RegistrationToken, UiaaInfo, UserIdentifier},
+    };
+    use ruma_common::{api::MatrixVersion, owned_device_id};
+    use serde_json::{from_slice as from_json_slice, json, Value as JsonValue};
+
+    use super::UiaaHandler;
+    use crate::{
+        client::test_utils::{response, MockClient},
+        Client,
+    };
+
+    async fn client(http_client: MockClient) -> Client<MockClient> {
+        Client::builder()
+            .homeserver_url("https://example.com".to_owned())
+            .access_token(Some("token".to_owned()))
+            .supported_matrix_versions(vec![MatrixVersion::V1_1])
+            .http_client(http_client)
+            .await
+            .unwrap()
+    }
+
+    fn delete_device_request(auth: Option<AuthData>) -> delete_device::v3::Request {
+        let mut request = delete_device::v3::Request::new(owned_device_id!("ABCDEFG"));
+        request.auth = auth;
+        request
+    }
+
+    /// The `auth` field of the bodies of the requests sent by the client.
+    fn sent_auth(client: &Client<MockClient>) -> Vec<Option<JsonValue>> {
+        client
+            .0
+            .http_client
+            .requests()
+            .iter()
+            .map(|req| {
+                from_json_slice::<JsonValue>(req.body()).unwrap().get("auth").map(ToOwned::to_owned)
+            })
+            .collect()
+    }
+
+    /// A handler that supports the password, registration token and dummy stages and records the
+    /// stages it was asked for.
+    #[derive(Clone, Default)]
+    struct Handler {
+        stages: Arc<Mutex<Vec<AuthType>>>,
+    }
+
+    impl UiaaHandler for Handler {
+        fn supports(&self, auth_type: &AuthType) -> bool {
+            matches!(auth_type, AuthType::Password | AuthType::RegistrationToken | AuthType::Dummy)
+        }
+
+        fn auth_data(
+            &mut self,
+            stage: &AuthType,
+            _info: &UiaaInfo,
+        ) -> impl Future<Output = Option<AuthData>> + Send {
+            self.stages.lock().unwrap().push(stage.clone());
+
+            ready(match stage {
+                AuthType::Password => Some(AuthData::Password(Password::new(
+                    UserIdentifier::UserIdOrLocalpart("alice".to_owned()),
+                    "secret".to_owned(),
+                ))),
+                AuthType::RegistrationToken => {
+                    Some(AuthData::RegistrationToken(RegistrationToken::new("token".to_owned())))
+                }
+                AuthType::Dummy => Some(AuthData::Dummy(Dummy::new())),
+                _ => None,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn single_stage() {
+        let client = client(MockClient::new([
+            response(
+                401,
+                r#"{ "flows": [{ "stages": ["m.login.password"] }], "params": {}, "session": "abc" }"#,
+            ),
+            response(200, "{}"),
+        ]))
+        .await;
+
+        let handler = Handler::default();
+        client.send_request_with_uiaa(delete_device_request, handler.clone()).await.unwrap();
+
+        assert_eq!(*handler.stages.lock().unwrap(), [AuthType::Password]);
+        assert_eq!(
+            sent_auth(&client),
+            [
+                None,
+                Some(json!({
+                    "type": "m.login.password",
+                    "identifier": { "type": "m.id.user", "user": "alice" },
+                    "password": "secret",
+                    "session": "abc",
+                })),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn multiple_stages_in_supported_flow() {
+        let client = client(MockClient::new([
+            response(
+                401,
+                r#"{
+                    "flows": [
+                        { "stages": ["m.login.recaptcha"] },
+                        { "stages": ["m.login.registration_token", "m.login.dummy"] }
+                    ],
+                    "params": {},
+                    "session": "abc"
+                }"#,
+            ),
+            response(
+                401,
+                r#"{
+                    "flows": [
+                        { "stages": ["m.login.recaptcha"] },
+                        { "stages": ["m.login.registration_token", "m.login.dummy"] }
+                    ],
+                    "completed": ["m.login.registration_token"],
+                    "params": {}
+                }"#,
+            ),
+            response(200, "{}"),
+        ]))
+        .await;
+
+        let handler = Handler::default();
+        client.send_request_with_uiaa(delete_device_request, handler.clone()).await.unwrap();
+
+        assert_eq!(*handler.stages.lock().unwrap(), [AuthType::RegistrationToken, AuthType::Dummy]);
+        assert_eq!(
+            sent_auth(&client),
+            [
+                None,
+                Some(json!({
+                    "type": "m.login.registration_token",
+                    "token": "token",
+                    "session": "abc",
+                })),
+                Some(json!({ "type": "m.login.dummy", "session": "abc" })),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn no_supported_flow() {
+        let client = client(MockClient::new([response(
+            401,
+            r#"{ "flows": [{ "stages": ["m.login.recaptcha"] }], "params": {}, "session": "abc" }"#,
+        )]))
+        .await;
+
+        let handler = Handler::default();
+        let error = client
+            .send_request_with_uiaa(delete_device_request, handler.clone())
+            .await
+            .unwrap_err();
+
+        assert!(handler.stages.lock().unwrap().is_empty());
+        assert_eq!(client.0.http_client.request_count(), 1);
+        assert_matches!(error, crate::Error::FromHttpResponse(_));
+    }
+
+    #[tokio::test]
+    async fn closure_aborts() {
+        let client = client(MockClient::new([
+            response(
+                401,
+                r#"{ "flows": [{ "stages": ["m.login.password"] }], "params": {}, "session": "abc" }"#,
+            ),
+            response(
+                401,
+                r#"{
+                    "flows": [{ "stages": ["m.login.password"] }],
+                    "params": {},
+                    "session": "abc",
+                    "errcode": "M_FORBIDDEN",
+                    "error": "Invalid password"
+                }"#,
+            ),
+        ]))
+        .await;
+
+        let mut attempts = 0;
+        let handler = |_stage: &AuthType, info: &UiaaInfo| {
+            attempts += 1;
+            // Give up once the password was rejected.
+            let auth_data = info.auth_error.is_none().then(|| {
+                AuthData::Password(Password::new(
+                    UserIdentifier::UserIdOrLocalpart("alice".to_owned()),
+                    "wrong".to_owned(),
+                ))
+            });
+            ready(auth_data)
+        };
+
+        client.send_request_with_uiaa(delete_device_request, handler).await.unwrap_err();
+        assert_eq!(attempts, 2);
+        assert_eq!(client.0.http_client.request_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn same_stage_fails_again() {
+        let rejected = r#"{
+            "flows": [{ "stages": ["m.login.password"] }],
+            "params": {},
+            "session": "abc",
+            "errcode": "M_FORBIDDEN",
+            "error": "Invalid password"
+        }"#;
+        let client = client(MockClient::new([
+            response(
+                401,
+                r#"{ "flows": [{ "stages": ["m.login.password"] }], "params": {}, "session": "abc" }"#,
+            ),
+            response(401, rejected),
+            response(401, rejected),
+        ]))
+        .await;
+
+        // The handler always sends the same password.
+        let handler = Handler::default();
+        let error = client
+            .send_request_with_uiaa(delete_device_request, handler.clone())
+            .await
+            .unwrap_err();
+
+        assert_eq!(*handler.stages.lock().unwrap(), [AuthType::Password, AuthType::Password]);
+        assert_eq!(client.0.http_client.request_count(), 3);
+        assert_matches!(error, crate::Error::FromHttpResponse(_));
+    }
+}
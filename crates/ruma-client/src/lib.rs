@@ -112,7 +112,9 @@ mod error;
 pub mod http_client;
 
 #[cfg(feature = "client-api")]
-pub use self::client::{Client, ClientBuilder};
+pub use self::client::{Client, ClientBuilder, RetryPolicy, UiaaHandler};
+#[cfg(feature = "unstable-msc4186")]
+pub use self::client::{SlidingSync, SlidingSyncRoomUpdate, SlidingSyncUpdate};
 pub use self::{
     error::Error,
     http_client::{DefaultConstructibleHttpClient, HttpClient, HttpClientExt},
@@ -134,6 +136,31 @@ fn send_customized_request<'a, C, R, F>(
     request: R,
     customize: F,
 ) -> impl Future<Output = ResponseResult<C, R>> + Send + 'a
+where
+    C: HttpClient + ?Sized,
+    R: OutgoingRequest,
+    F: FnOnce(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>>,
+{
+    let send_fut = send_customized_http_request(
+        http_client,
+        homeserver_url,
+        send_access_token,
+        for_versions,
+        request,
+        customize,
+    );
+
+    async move { deserialize_response::<C, R>(send_fut.await?) }
+}
+
+fn send_customized_http_request<'a, C, R, F>(
+    http_client: &'a C,
+    homeserver_url: &str,
+    send_access_token: SendAccessToken<'_>,
+    for_versions: &[MatrixVersion],
+    request: R,
+    customize: F,
+) -> impl Future<Output = Result<http::Response<C::ResponseBody>, ResponseError<C, R>>> + Send + 'a
 where
     C: HttpClient + ?Sized,
     R: OutgoingRequest,
@@ -158,25 +185,31 @@ where
     );
 
     async move {
-        let http_res = http_client
+        http_client
             .send_http_request(http_req?)
             .instrument(send_span)
             .await
-            .map_err(Error::Response)?;
+            .map_err(Error::Response)
+    }
+}
 
-        let res =
-            info_span!("deserialize_response", response_type = type_name::<R::IncomingResponse>())
-                .in_scope(move || {
-                    ruma_common::api::IncomingResponse::try_from_http_response(http_res)
-                })?;
+fn deserialize_response<C, R>(http_res: http::Response<C::ResponseBody>) -> ResponseResult<C, R>
+where
+    C: HttpClient + ?Sized,
+    R: OutgoingRequest,
+{
+    let res =
+        info_span!("deserialize_response", response_type = type_name::<R::IncomingResponse>())
+            .in_scope(move || {
+                ruma_common::api::IncomingResponse::try_from_http_response(http_res)
+            })?;
 
-        Ok(res)
-    }
+    Ok(res)
 }
 
 fn add_user_id_to_query<C: HttpClient + ?Sized, R: OutgoingRequest>(
     user_id: &UserId,
-) -> impl FnOnce(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>> + '_ {
+) -> impl Fn(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>> + '_ {
     use assign::assign;
     use http::uri::Uri;
 
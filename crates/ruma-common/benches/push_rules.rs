@@ -0,0 +1,187 @@
+// `cargo bench` works, but if you use `cargo bench -- --save-baseline <name>`
+// or pass any other args to it, it fails with the error
+// `cargo bench unknown option --save-baseline`.
+// To pass args to criterion, use this form
+// `cargo bench --features criterion --bench <name of the bench> -- --save-baseline <name>`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use js_int::{int, uint};
+use ruma_common::{
+    owned_room_id, owned_user_id,
+    power_levels::NotificationPowerLevels,
+    push::{
+        Action, FlattenedJson, PatternedPushRuleInit, PushConditionPowerLevelsCtx,
+        PushConditionRoomCtx, Ruleset, Tweak,
+    },
+    serde::Raw,
+    user_id,
+};
+use serde_json::{json, Value as JsonValue};
+
+/// The number of users that receive each event.
+const RECIPIENTS_COUNT: usize = 100;
+
+/// The server default ruleset, with a few keywords.
+fn ruleset() -> Ruleset {
+    let mut ruleset = Ruleset::server_default(user_id!("@jolly_jumper:server.name"));
+
+    for keyword in ["ruma", "matrix", "rust*", "push?rule", "lucky luke"] {
+        ruleset.content.insert(
+            PatternedPushRuleInit {
+                actions: vec![Action::Notify, Action::SetTweak(Tweak::Sound("default".into()))],
+                default: false,
+                enabled: true,
+                rule_id: keyword.to_owned(),
+                pattern: keyword.to_owned(),
+            }
+            .into(),
+        );
+    }
+
+    ruleset
+}
+
+fn context() -> PushConditionRoomCtx {
+    let mut context = PushConditionRoomCtx::new(
+        owned_room_id!("!far_west:server.name"),
+        uint!(100),
+        owned_user_id!("@jolly_jumper:server.name"),
+        "Jolly Jumper".to_owned(),
+    );
+    context.power_levels = Some(PushConditionPowerLevelsCtx {
+        users: [(owned_user_id!("@lucky_luke:server.name"), int!(100))].into(),
+        users_default: int!(0),
+        notifications: NotificationPowerLevels::new(),
+    });
+    context
+}
+
+fn events() -> Vec<Raw<JsonValue>> {
+    [
+        json!({
+            "content": {
+                "body": "Where is the saloon?",
+                "msgtype": "m.text",
+            },
+            "event_id": "$message:server.name",
+            "origin_server_ts": 1_000_000,
+            "room_id": "!far_west:server.name",
+            "sender": "@rantanplan:server.name",
+            "type": "m.room.message",
+        }),
+        json!({
+            "content": {
+                "body": "Jolly Jumper, are you there?",
+                "format": "org.matrix.custom.html",
+                "formatted_body": "<b>Jolly Jumper</b>, are you there?",
+                "m.mentions": {
+                    "user_ids": ["@jolly_jumper:server.name"],
+                },
+                "msgtype": "m.text",
+            },
+            "event_id": "$mention:server.name",
+            "origin_server_ts": 1_000_000,
+            "room_id": "!far_west:server.name",
+            "sender": "@lucky_luke:server.name",
+            "type": "m.room.message",
+        }),
+        json!({
+            "content": {
+                "body": "The push rules are written in Rust",
+                "msgtype": "m.notice",
+            },
+            "event_id": "$notice:server.name",
+            "origin_server_ts": 1_000_000,
+            "room_id": "!far_west:server.name",
+            "sender": "@bot:server.name",
+            "type": "m.room.message",
+        }),
+        json!({
+            "content": {
+                "algorithm": "m.megolm.v1.aes-sha2",
+                "ciphertext": "AwgAEnACgAkLmt6qF84IK++J7UDH2Za1YVchHyprqTqsg...",
+                "device_id": "RJYKSTBOIE",
+                "sender_key": "IlRMeOPX2e0MurIyfWEucYBRVOEEUMrOHqn/8mLqMjA",
+                "session_id": "X3lUlvLELLYxeTx4yOVu6UDpasGEVO0Jbu+QFnm0cKQ",
+            },
+            "event_id": "$encrypted:server.name",
+            "origin_server_ts": 1_000_000,
+            "room_id": "!far_west:server.name",
+            "sender": "@rantanplan:server.name",
+            "type": "m.room.encrypted",
+        }),
+    ]
+    .into_iter()
+    .map(|event| Raw::new(&event).unwrap())
+    .collect()
+}
+
+fn ruleset_get_actions(c: &mut Criterion) {
+    let ruleset = ruleset();
+    let context = context();
+    let events = events();
+
+    c.bench_function("`Ruleset::get_actions()` for each recipient", |b| {
+        b.iter(|| {
+            for event in &events {
+                for _ in 0..RECIPIENTS_COUNT {
+                    let _ = ruleset.get_actions(event, &context);
+                }
+            }
+        });
+    });
+}
+
+fn compiled_ruleset_get_actions(c: &mut Criterion) {
+    let ruleset = ruleset().compile();
+    let context = context();
+    let events = events();
+
+    c.bench_function("`CompiledRuleset::get_actions()` for each recipient", |b| {
+        b.iter(|| {
+            for event in &events {
+                for _ in 0..RECIPIENTS_COUNT {
+                    let _ = ruleset.get_actions(event, &context);
+                }
+            }
+        });
+    });
+}
+
+fn compiled_ruleset_get_actions_flattened(c: &mut Criterion) {
+    let ruleset = ruleset().compile();
+    let context = context();
+    let events = events();
+
+    c.bench_function("`CompiledRuleset::get_actions_flattened()` for each recipient", |b| {
+        b.iter(|| {
+            for event in &events {
+                let event = FlattenedJson::from_raw(event);
+
+                for _ in 0..RECIPIENTS_COUNT {
+                    let _ = ruleset.get_actions_flattened(&event, &context);
+                }
+            }
+        });
+    });
+}
+
+fn compile_ruleset(c: &mut Criterion) {
+    let ruleset = ruleset();
+
+    c.bench_function("`Ruleset::compile()`", |b| {
+        b.iter(|| {
+            let _ = ruleset.compile();
+        });
+    });
+}
+
+criterion_group!(
+    benches,
+    ruleset_get_actions,
+    compiled_ruleset_get_actions,
+    compiled_ruleset_get_actions_flattened,
+    compile_ruleset
+);
+
+criterion_main!(benches);
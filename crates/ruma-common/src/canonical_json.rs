@@ -8,7 +8,7 @@ use serde_json::Value as JsonValue;
 mod value;
 
 pub use self::value::{CanonicalJsonObject, CanonicalJsonValue};
-use crate::{serde::Raw, RoomVersionId};
+use crate::{room_version_rules::RedactionRules, serde::Raw, RoomVersionId};
 
 /// The set of possible errors when serializing to canonical JSON.
 #[cfg(feature = "canonical-json")]
@@ -177,11 +177,13 @@ pub fn redact_in_place(
     version: &RoomVersionId,
     redacted_because: Option<RedactedBecause>,
 ) -> Result<(), RedactionError> {
+    let rules = redaction_rules_for(version);
+
     // Get the content keys here even if they're only needed inside the branch below, because we
     // can't teach rust that this is a disjoint borrow with `get_mut("content")`.
     let allowed_content_keys = match event.get("type") {
         Some(CanonicalJsonValue::String(event_type)) => {
-            allowed_content_keys_for(event_type, version)
+            allowed_content_keys_for(event_type, &rules)
         }
         Some(_) => return Err(RedactionError::not_of_type("type", JsonType::String)),
         None => return Err(RedactionError::field_missing_from_object("type")),
@@ -198,7 +200,7 @@ pub fn redact_in_place(
 
     let mut old_event = mem::take(event);
 
-    for &key in allowed_event_keys_for(version) {
+    for &key in allowed_event_keys_for(&rules) {
         if let Some(value) = old_event.remove(key) {
             event.insert(key.to_owned(), value);
         }
@@ -223,7 +225,18 @@ pub fn redact_content_in_place(
     version: &RoomVersionId,
     event_type: impl AsRef<str>,
 ) -> Result<(), RedactionError> {
-    object_retain_keys(object, allowed_content_keys_for(event_type.as_ref(), version))
+    object_retain_keys(
+        object,
+        allowed_content_keys_for(event_type.as_ref(), &redaction_rules_for(version)),
+    )
+}
+
+/// The redaction rules of the given room version.
+///
+/// Custom room versions whose rules were not registered use the rules of the latest room version.
+fn redaction_rules_for(version: &RoomVersionId) -> RedactionRules {
+    // TODO: Should we return an error for unknown versions instead?
+    version.rules().map_or(RedactionRules::V11, |rules| rules.redaction)
 }
 
 fn object_retain_keys(
@@ -278,18 +291,9 @@ fn object_retain_some_keys(
 
 /// The fields that are allowed to remain in an event during redaction depending on the room
 /// version.
-fn allowed_event_keys_for(version: &RoomVersionId) -> &'static [&'static str] {
-    match version {
-        RoomVersionId::V1
-        | RoomVersionId::V2
-        | RoomVersionId::V3
-        | RoomVersionId::V4
-        | RoomVersionId::V5
-        | RoomVersionId::V6
-        | RoomVersionId::V7
-        | RoomVersionId::V8
-        | RoomVersionId::V9
-        | RoomVersionId::V10 => &[
+fn allowed_event_keys_for(rules: &RedactionRules) -> &'static [&'static str] {
+    if rules.keep_origin_membership_prev_state {
+        &[
             "event_id",
             "type",
             "room_id",
@@ -305,8 +309,9 @@ fn allowed_event_keys_for(version: &RoomVersionId) -> &'static [&'static str] {
             "origin",
             "origin_server_ts",
             "membership",
-        ],
-        _ => &[
+        ]
+    } else {
+        &[
             "event_id",
             "type",
             "room_id",
@@ -319,7 +324,7 @@ fn allowed_event_keys_for(version: &RoomVersionId) -> &'static [&'static str] {
             "prev_events",
             "auth_events",
             "origin_server_ts",
-        ],
+        ]
     }
 }
 
@@ -417,82 +422,57 @@ static ROOM_HISTORY_VISIBILITY_V1: AllowedKeys = AllowedKeys::some(&["history_vi
 /// Allowed keys in `m.room.redaction`'s content according to room version 11.
 static ROOM_REDACTION_V11: AllowedKeys = AllowedKeys::some(&["redacts"]);
 
-fn allowed_content_keys_for(event_type: &str, version: &RoomVersionId) -> &'static AllowedKeys {
+fn allowed_content_keys_for(event_type: &str, rules: &RedactionRules) -> &'static AllowedKeys {
     match event_type {
-        "m.room.member" => match version {
-            RoomVersionId::V1
-            | RoomVersionId::V2
-            | RoomVersionId::V3
-            | RoomVersionId::V4
-            | RoomVersionId::V5
-            | RoomVersionId::V6
-            | RoomVersionId::V7
-            | RoomVersionId::V8 => &ROOM_MEMBER_V1,
-            RoomVersionId::V9 | RoomVersionId::V10 => &ROOM_MEMBER_V9,
-            _ => &ROOM_MEMBER_V11,
-        },
-        "m.room.create" => match version {
-            RoomVersionId::V1
-            | RoomVersionId::V2
-            | RoomVersionId::V3
-            | RoomVersionId::V4
-            | RoomVersionId::V5
-            | RoomVersionId::V6
-            | RoomVersionId::V7
-            | RoomVersionId::V8
-            | RoomVersionId::V9
-            | RoomVersionId::V10 => &ROOM_CREATE_V1,
-            _ => &AllowedKeys::All,
-        },
-        "m.room.join_rules" => match version {
-            RoomVersionId::V1
-            | RoomVersionId::V2
-            | RoomVersionId::V3
-            | RoomVersionId::V4
-            | RoomVersionId::V5
-            | RoomVersionId::V6
-            | RoomVersionId::V7 => &ROOM_JOIN_RULES_V1,
-            _ => &ROOM_JOIN_RULES_V8,
-        },
-        "m.room.power_levels" => match version {
-            RoomVersionId::V1
-            | RoomVersionId::V2
-            | RoomVersionId::V3
-            | RoomVersionId::V4
-            | RoomVersionId::V5
-            | RoomVersionId::V6
-            | RoomVersionId::V7
-            | RoomVersionId::V8
-            | RoomVersionId::V9
-            | RoomVersionId::V10 => &ROOM_POWER_LEVELS_V1,
-            _ => &ROOM_POWER_LEVELS_V11,
-        },
-        "m.room.aliases" => match version {
-            RoomVersionId::V1
-            | RoomVersionId::V2
-            | RoomVersionId::V3
-            | RoomVersionId::V4
-            | RoomVersionId::V5 => &ROOM_ALIASES_V1,
-            // All other room versions, including custom ones, are treated by version 6 rules.
-            // TODO: Should we return an error for unknown versions instead?
-            _ => &AllowedKeys::None,
-        },
+        "m.room.member" => {
+            if rules.keep_room_member_third_party_invite_signed {
+                &ROOM_MEMBER_V11
+            } else if rules.keep_room_member_join_authorised_via_users_server {
+                &ROOM_MEMBER_V9
+            } else {
+                &ROOM_MEMBER_V1
+            }
+        }
+        "m.room.create" => {
+            if rules.keep_room_create_content {
+                &AllowedKeys::All
+            } else {
+                &ROOM_CREATE_V1
+            }
+        }
+        "m.room.join_rules" => {
+            if rules.keep_room_join_rules_allow {
+                &ROOM_JOIN_RULES_V8
+            } else {
+                &ROOM_JOIN_RULES_V1
+            }
+        }
+        "m.room.power_levels" => {
+            if rules.keep_room_power_levels_invite {
+                &ROOM_POWER_LEVELS_V11
+            } else {
+                &ROOM_POWER_LEVELS_V1
+            }
+        }
+        "m.room.aliases" => {
+            if rules.keep_room_aliases_aliases {
+                &ROOM_ALIASES_V1
+            } else {
+                &AllowedKeys::None
+            }
+        }
         #[cfg(feature = "unstable-msc2870")]
-        "m.room.server_acl" if version.as_str() == "org.matrix.msc2870" => &ROOM_SERVER_ACL_MSC2870,
+        "m.room.server_acl" if rules.keep_room_server_acl_allow_deny_allow_ip_literals => {
+            &ROOM_SERVER_ACL_MSC2870
+        }
         "m.room.history_visibility" => &ROOM_HISTORY_VISIBILITY_V1,
-        "m.room.redaction" => match version {
-            RoomVersionId::V1
-            | RoomVersionId::V2
-            | RoomVersionId::V3
-            | RoomVersionId::V4
-            | RoomVersionId::V5
-            | RoomVersionId::V6
-            | RoomVersionId::V7
-            | RoomVersionId::V8
-            | RoomVersionId::V9
-            | RoomVersionId::V10 => &AllowedKeys::None,
-            _ => &ROOM_REDACTION_V11,
-        },
+        "m.room.redaction" => {
+            if rules.keep_room_redaction_redacts {
+                &ROOM_REDACTION_V11
+            } else {
+                &AllowedKeys::None
+            }
+        }
         _ => &AllowedKeys::None,
     }
 }
@@ -29,7 +29,9 @@ use crate::{
 };
 
 mod action;
+mod compiled;
 mod condition;
+mod diff;
 mod iter;
 mod predefined;
 
@@ -37,11 +39,13 @@ mod predefined;
 pub use self::condition::RoomVersionFeature;
 pub use self::{
     action::{Action, Tweak},
+    compiled::CompiledRuleset,
     condition::{
         ComparisonOperator, FlattenedJson, FlattenedJsonValue, PushCondition,
         PushConditionPowerLevelsCtx, PushConditionRoomCtx, RoomMemberCountIs, ScalarJsonValue,
         _CustomPushCondition,
     },
+    diff::PushRuleChange,
     iter::{AnyPushRule, AnyPushRuleRef, RulesetIntoIter, RulesetIter},
     predefined::{
         PredefinedContentRuleId, PredefinedOverrideRuleId, PredefinedRuleId,
@@ -317,6 +321,11 @@ impl Ruleset {
         self.get_match(event, context).map(|rule| rule.actions()).unwrap_or(&[])
     }
 
+    /// Compile this ruleset, to evaluate it efficiently against many events.
+    pub fn compile(&self) -> CompiledRuleset {
+        CompiledRuleset::new(self)
+    }
+
     /// Removes a user-defined rule in the rule set.
     ///
     /// Returns an error if the parameters are invalid.
@@ -0,0 +1,607 @@
+use std::cell::OnceCell;
+
+#[cfg(feature = "unstable-msc3932")]
+use super::RoomVersionFeature;
+use super::{
+    condition::PatternMatcher, Action, AnyPushRuleRef, FlattenedJson, FlattenedJsonValue,
+    PredefinedContentRuleId, PredefinedOverrideRuleId, PushCondition, PushConditionRoomCtx,
+    Ruleset, ScalarJsonValue,
+};
+use crate::serde::Raw;
+
+/// A [`Ruleset`] prepared to be evaluated against many events.
+///
+/// The glob patterns of the push rules are compiled once, and the properties of the event that
+/// are used by several conditions are only looked up once per event. It gives the same results as
+/// [`Ruleset::get_match()`] and [`Ruleset::get_actions()`].
+///
+/// Changes to the `Ruleset` are not reflected in the `CompiledRuleset`, which must be compiled
+/// again.
+///
+/// To evaluate the push rules of several users for the same event, the event can be flattened
+/// once with [`FlattenedJson::from_raw()`] and evaluated with
+/// [`CompiledRuleset::get_match_flattened()`].
+#[derive(Clone, Debug)]
+pub struct CompiledRuleset {
+    /// The compiled ruleset.
+    ruleset: Ruleset,
+
+    /// The enabled rules, in order of priority.
+    rules: Vec<CompiledRule>,
+
+    /// The dot-separated paths of the properties used by the rules.
+    keys: Vec<String>,
+}
+
+impl CompiledRuleset {
+    /// Compile the given `Ruleset`.
+    pub fn new(ruleset: &Ruleset) -> Self {
+        let mut keys = Vec::new();
+        let mut rules = Vec::new();
+
+        for (index, rule) in ruleset.override_.iter().enumerate() {
+            if rule.enabled {
+                rules.push(CompiledRule::conditional(
+                    RuleIndex::Override(index),
+                    &rule.rule_id,
+                    &rule.conditions,
+                    &mut keys,
+                ));
+            }
+        }
+        for (index, rule) in ruleset.content.iter().enumerate() {
+            if rule.enabled {
+                #[allow(deprecated)]
+                let legacy_mention =
+                    rule.rule_id == PredefinedContentRuleId::ContainsUserName.as_ref();
+
+                rules.push(CompiledRule {
+                    index: RuleIndex::Content(index),
+                    conditions: vec![CompiledCondition::event_match(
+                        &mut keys,
+                        "content.body",
+                        &rule.pattern,
+                    )],
+                    legacy_mention,
+                    #[cfg(feature = "unstable-msc3932")]
+                    disabled_with_extensible_events: false,
+                });
+            }
+        }
+        for (index, rule) in ruleset.room.iter().enumerate() {
+            if rule.enabled {
+                rules.push(CompiledRule::simple(
+                    RuleIndex::Room(index),
+                    CompiledCondition::event_match(&mut keys, "room_id", rule.rule_id.as_str()),
+                ));
+            }
+        }
+        for (index, rule) in ruleset.sender.iter().enumerate() {
+            if rule.enabled {
+                rules.push(CompiledRule::simple(
+                    RuleIndex::Sender(index),
+                    CompiledCondition::event_match(&mut keys, "sender", rule.rule_id.as_str()),
+                ));
+            }
+        }
+        for (index, rule) in ruleset.underride.iter().enumerate() {
+            if rule.enabled {
+                rules.push(CompiledRule::conditional(
+                    RuleIndex::Underride(index),
+                    &rule.rule_id,
+                    &rule.conditions,
+                    &mut keys,
+                ));
+            }
+        }
+
+        Self { ruleset: ruleset.clone(), rules, keys }
+    }
+
+    /// The `Ruleset` that was compiled.
+    pub fn ruleset(&self) -> &Ruleset {
+        &self.ruleset
+    }
+
+    /// Get the first push rule that applies to this event, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The raw JSON of a room message event.
+    /// * `context` - The context of the message and room at the time of the event.
+    pub fn get_match<T>(
+        &self,
+        event: &Raw<T>,
+        context: &PushConditionRoomCtx,
+    ) -> Option<AnyPushRuleRef<'_>> {
+        self.get_match_flattened(&FlattenedJson::from_raw(event), context)
+    }
+
+    /// Get the push actions that apply to this event.
+    ///
+    /// Returns an empty slice if no push rule applies.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The raw JSON of a room message event.
+    /// * `context` - The context of the message and room at the time of the event.
+    pub fn get_actions<T>(&self, event: &Raw<T>, context: &PushConditionRoomCtx) -> &[Action] {
+        self.get_match(event, context).map(|rule| rule.actions()).unwrap_or(&[])
+    }
+
+    /// Get the first push rule that applies to this flattened event, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The flattened JSON representation of a room message event.
+    /// * `context` - The context of the message and room at the time of the event.
+    pub fn get_match_flattened(
+        &self,
+        event: &FlattenedJson,
+        context: &PushConditionRoomCtx,
+    ) -> Option<AnyPushRuleRef<'_>> {
+        if event.get_str("sender").is_some_and(|sender| sender == context.user_id) {
+            // no need to look at the rules if the event was by the user themselves
+            return None;
+        }
+
+        let values = EventValues::new(event, &self.keys);
+
+        self.rules
+            .iter()
+            .find(|rule| rule.applies(&values, context))
+            .and_then(|rule| rule.index.get(&self.ruleset))
+    }
+
+    /// Get the push actions that apply to this flattened event.
+    ///
+    /// Returns an empty slice if no push rule applies.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The flattened JSON representation of a room message event.
+    /// * `context` - The context of the message and room at the time of the event.
+    pub fn get_actions_flattened(
+        &self,
+        event: &FlattenedJson,
+        context: &PushConditionRoomCtx,
+    ) -> &[Action] {
+        self.get_match_flattened(event, context).map(|rule| rule.actions()).unwrap_or(&[])
+    }
+}
+
+impl From<&Ruleset> for CompiledRuleset {
+    fn from(ruleset: &Ruleset) -> Self {
+        Self::new(ruleset)
+    }
+}
+
+/// The index of a rule in a `Ruleset`.
+#[derive(Clone, Copy, Debug)]
+enum RuleIndex {
+    Override(usize),
+    Content(usize),
+    Room(usize),
+    Sender(usize),
+    Underride(usize),
+}
+
+impl RuleIndex {
+    fn get(self, ruleset: &Ruleset) -> Option<AnyPushRuleRef<'_>> {
+        match self {
+            Self::Override(index) => {
+                ruleset.override_.get_index(index).map(AnyPushRuleRef::Override)
+            }
+            Self::Content(index) => ruleset.content.get_index(index).map(AnyPushRuleRef::Content),
+            Self::Room(index) => ruleset.room.get_index(index).map(AnyPushRuleRef::Room),
+            Self::Sender(index) => ruleset.sender.get_index(index).map(AnyPushRuleRef::Sender),
+            Self::Underride(index) => {
+                ruleset.underride.get_index(index).map(AnyPushRuleRef::Underride)
+            }
+        }
+    }
+}
+
+/// A compiled enabled push rule.
+#[derive(Clone, Debug)]
+struct CompiledRule {
+    /// The index of the rule in the ruleset.
+    index: RuleIndex,
+
+    /// The conditions of the rule.
+    conditions: Vec<CompiledCondition>,
+
+    /// Whether this is a legacy mention rule, that is disabled when the event has an `m.mentions`
+    /// property.
+    legacy_mention: bool,
+
+    /// Whether the rule is disabled in rooms that support extensible events.
+    #[cfg(feature = "unstable-msc3932")]
+    disabled_with_extensible_events: bool,
+}
+
+impl CompiledRule {
+    fn conditional(
+        index: RuleIndex,
+        rule_id: &str,
+        conditions: &[PushCondition],
+        keys: &mut Vec<String>,
+    ) -> Self {
+        #[cfg(feature = "unstable-msc3932")]
+        #[allow(deprecated)]
+        let disabled_with_extensible_events = rule_id != PredefinedOverrideRuleId::Master.as_ref()
+            && rule_id != PredefinedOverrideRuleId::RoomNotif.as_ref()
+            && rule_id != PredefinedOverrideRuleId::ContainsDisplayName.as_ref()
+            && !conditions
+                .iter()
+                .any(|condition| matches!(condition, PushCondition::RoomVersionSupports { .. }));
+
+        #[allow(deprecated)]
+        let legacy_mention = rule_id == PredefinedOverrideRuleId::RoomNotif.as_ref()
+            || rule_id == PredefinedOverrideRuleId::ContainsDisplayName.as_ref();
+
+        let conditions =
+            conditions.iter().map(|condition| CompiledCondition::new(keys, condition)).collect();
+
+        Self {
+            index,
+            conditions,
+            legacy_mention,
+            #[cfg(feature = "unstable-msc3932")]
+            disabled_with_extensible_events,
+        }
+    }
+
+    fn simple(index: RuleIndex, condition: CompiledCondition) -> Self {
+        Self {
+            index,
+            conditions: vec![condition],
+            legacy_mention: false,
+            #[cfg(feature = "unstable-msc3932")]
+            disabled_with_extensible_events: false,
+        }
+    }
+
+    fn applies(&self, event: &EventValues<'_>, context: &PushConditionRoomCtx) -> bool {
+        #[cfg(feature = "unstable-msc3932")]
+        if self.disabled_with_extensible_events
+            && context.supported_features.contains(&RoomVersionFeature::ExtensibleEvents)
+        {
+            return false;
+        }
+
+        if self.legacy_mention && event.contains_mentions() {
+            return false;
+        }
+
+        self.conditions.iter().all(|condition| condition.applies(event, context))
+    }
+}
+
+/// A compiled push condition.
+#[derive(Clone, Debug)]
+enum CompiledCondition {
+    /// A glob pattern match on a property of the event.
+    EventMatch { key: MatchKey, matcher: PatternMatcher },
+
+    /// Exact value match on a property of the event.
+    EventPropertyIs { key: usize, value: ScalarJsonValue },
+
+    /// Exact value match on a value in an array property of the event.
+    EventPropertyContains { key: usize, value: ScalarJsonValue },
+
+    /// A condition that doesn't benefit from compilation.
+    Other(PushCondition),
+}
+
+impl CompiledCondition {
+    /// Compile the given condition, interning the keys it uses in `keys`.
+    fn new(keys: &mut Vec<String>, condition: &PushCondition) -> Self {
+        match condition {
+            PushCondition::EventMatch { key, pattern } => Self::event_match(keys, key, pattern),
+            PushCondition::EventPropertyIs { key, value } => {
+                Self::EventPropertyIs { key: intern_key(keys, key), value: value.clone() }
+            }
+            PushCondition::EventPropertyContains { key, value } => {
+                Self::EventPropertyContains { key: intern_key(keys, key), value: value.clone() }
+            }
+            _ => Self::Other(condition.clone()),
+        }
+    }
+
+    /// Compile an `event_match` condition, interning its key in `keys`.
+    fn event_match(keys: &mut Vec<String>, key: &str, pattern: &str) -> Self {
+        let matcher = PatternMatcher::new(pattern, key == "content.body");
+        let key = match key {
+            "room_id" => MatchKey::RoomId,
+            _ => MatchKey::Event(intern_key(keys, key)),
+        };
+
+        Self::EventMatch { key, matcher }
+    }
+
+    fn applies(&self, event: &EventValues<'_>, context: &PushConditionRoomCtx) -> bool {
+        match self {
+            Self::EventMatch { key: MatchKey::RoomId, matcher } => {
+                matcher.matches_lowercase(&context.room_id.as_str().to_lowercase())
+            }
+            Self::EventMatch { key: MatchKey::Event(key), matcher } => {
+                event.get_lowercase_str(*key).is_some_and(|value| matcher.matches_lowercase(value))
+            }
+            Self::EventPropertyIs { key, value } => event.get(*key).is_some_and(|v| value == v),
+            Self::EventPropertyContains { key, value } => event
+                .get(*key)
+                .and_then(FlattenedJsonValue::as_array)
+                .is_some_and(|a| a.contains(value)),
+            Self::Other(condition) => condition.applies(event.event, context),
+        }
+    }
+}
+
+/// Get the index of the given key in `keys`, adding it if necessary.
+fn intern_key(keys: &mut Vec<String>, key: &str) -> usize {
+    match keys.iter().position(|k| k == key) {
+        Some(index) => index,
+        None => {
+            keys.push(key.to_owned());
+            keys.len() - 1
+        }
+    }
+}
+
+/// The property matched by an `event_match` condition.
+#[derive(Clone, Copy, Debug)]
+enum MatchKey {
+    /// The ID of the room, from the context.
+    RoomId,
+
+    /// The property of the event with the given interned key.
+    Event(usize),
+}
+
+/// The properties of an event, looked up lazily by interned key.
+struct EventValues<'a> {
+    /// The flattened event.
+    event: &'a FlattenedJson,
+
+    /// The interned keys.
+    keys: &'a [String],
+
+    /// The values of the properties.
+    values: Vec<OnceCell<Option<&'a FlattenedJsonValue>>>,
+
+    /// The lowercase values of the string properties.
+    lowercase_strings: Vec<OnceCell<Option<String>>>,
+
+    /// Whether the event has an `m.mentions` property.
+    contains_mentions: OnceCell<bool>,
+}
+
+impl<'a> EventValues<'a> {
+    fn new(event: &'a FlattenedJson, keys: &'a [String]) -> Self {
+        Self {
+            event,
+            keys,
+            values: keys.iter().map(|_| OnceCell::new()).collect(),
+            lowercase_strings: keys.iter().map(|_| OnceCell::new()).collect(),
+            contains_mentions: OnceCell::new(),
+        }
+    }
+
+    fn get(&self, key: usize) -> Option<&'a FlattenedJsonValue> {
+        *self.values[key].get_or_init(|| self.event.get(&self.keys[key]))
+    }
+
+    fn get_lowercase_str(&self, key: usize) -> Option<&str> {
+        self.lowercase_strings[key]
+            .get_or_init(|| self.get(key).and_then(|v| v.as_str()).map(str::to_lowercase))
+            .as_deref()
+    }
+
+    fn contains_mentions(&self) -> bool {
+        *self.contains_mentions.get_or_init(|| self.event.contains_mentions())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use js_int::int;
+    use serde_json::{json, Value as JsonValue};
+
+    use super::CompiledRuleset;
+    use crate::{
+        owned_room_id, owned_user_id,
+        power_levels::NotificationPowerLevels,
+        push::{
+            Action, ConditionalPushRule, PatternedPushRule, PushCondition,
+            PushConditionPowerLevelsCtx, PushConditionRoomCtx, Ruleset, SimplePushRule, Tweak,
+        },
+        serde::Raw,
+        user_id,
+    };
+
+    fn ruleset() -> Ruleset {
+        let mut set = Ruleset::server_default(user_id!("@jj:server.name"));
+
+        set.content.insert(PatternedPushRule {
+            actions: vec![Action::Notify, Action::SetTweak(Tweak::Sound("joke".into()))],
+            default: false,
+            enabled: true,
+            rule_id: "joke".into(),
+            pattern: "jok*".into(),
+        });
+        set.content.insert(PatternedPushRule {
+            actions: vec![Action::Notify],
+            default: false,
+            enabled: false,
+            rule_id: "disabled".into(),
+            pattern: "great".into(),
+        });
+        set.override_.insert(ConditionalPushRule {
+            actions: vec![],
+            default: false,
+            enabled: true,
+            rule_id: "bots".into(),
+            conditions: vec![
+                PushCondition::EventPropertyContains {
+                    key: r"content.org\.example\.tags".into(),
+                    value: "bot".into(),
+                },
+                PushCondition::EventPropertyIs {
+                    key: "content.msgtype".into(),
+                    value: "m.text".into(),
+                },
+            ],
+        });
+        set.sender.insert(SimplePushRule {
+            actions: vec![Action::Notify, Action::SetTweak(Tweak::Highlight(true))],
+            default: false,
+            enabled: true,
+            rule_id: owned_user_id!("@lucky_luke:server.name"),
+        });
+        set.room.insert(SimplePushRule {
+            actions: vec![],
+            default: false,
+            enabled: true,
+            rule_id: owned_room_id!("!muted:server.name"),
+        });
+
+        set
+    }
+
+    fn context(room_id: &str, member_count: u32) -> PushConditionRoomCtx {
+        let mut context = PushConditionRoomCtx::new(
+            room_id.try_into().unwrap(),
+            member_count.into(),
+            owned_user_id!("@jj:server.name"),
+            "Jolly Jumper".to_owned(),
+        );
+        context.power_levels = Some(PushConditionPowerLevelsCtx {
+            users: BTreeMap::from([(owned_user_id!("@lucky_luke:server.name"), int!(100))]),
+            users_default: int!(0),
+            notifications: NotificationPowerLevels { room: int!(50) },
+        });
+        context
+    }
+
+    fn message(sender: &str, content: JsonValue) -> Raw<JsonValue> {
+        Raw::new(&json!({
+            "sender": sender,
+            "type": "m.room.message",
+            "content": content,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn same_results_as_ruleset() {
+        let set = ruleset();
+        let compiled = set.compile();
+
+        let contexts = [
+            context("!dm:server.name", 2),
+            context("!far_west:server.name", 100),
+            context("!muted:server.name", 10),
+        ];
+        let events = [
+            message("@rantanplan:server.name", json!({ "msgtype": "m.text", "body": "Hello" })),
+            message("@rantanplan:server.name", json!({ "msgtype": "m.text", "body": "A joke!" })),
+            message("@rantanplan:server.name", json!({ "msgtype": "m.text", "body": "Jokers" })),
+            message("@rantanplan:server.name", json!({ "msgtype": "m.text", "body": "jok" })),
+            message("@rantanplan:server.name", json!({ "msgtype": "m.text", "body": "Great" })),
+            message("@rantanplan:server.name", json!({ "msgtype": "m.notice", "body": "joke" })),
+            message(
+                "@rantanplan:server.name",
+                json!({ "msgtype": "m.text", "body": "Hey Jolly Jumper, a joke" }),
+            ),
+            message(
+                "@rantanplan:server.name",
+                json!({ "msgtype": "m.text", "body": "Hey Jolly Jumper", "m.mentions": {} }),
+            ),
+            message(
+                "@rantanplan:server.name",
+                json!({
+                    "msgtype": "m.text",
+                    "body": "Hey",
+                    "m.mentions": { "user_ids": ["@jj:server.name"] },
+                }),
+            ),
+            message("@rantanplan:server.name", json!({ "msgtype": "m.text", "body": "@room" })),
+            message("@lucky_luke:server.name", json!({ "msgtype": "m.text", "body": "@room" })),
+            message(
+                "@lucky_luke:server.name",
+                json!({ "msgtype": "m.text", "body": "Hi", "m.mentions": { "room": true } }),
+            ),
+            message(
+                "@rantanplan:server.name",
+                json!({ "msgtype": "m.text", "body": "Joke", "org.example.tags": ["bot"] }),
+            ),
+            message("@jj:server.name", json!({ "msgtype": "m.text", "body": "A joke" })),
+            Raw::new(&json!({
+                "sender": "@rantanplan:server.name",
+                "type": "m.room.member",
+                "state_key": "@jj:server.name",
+                "content": { "membership": "invite" },
+            }))
+            .unwrap(),
+            Raw::new(&json!({
+                "sender": "@rantanplan:server.name",
+                "type": "m.room.encrypted",
+                "content": {},
+            }))
+            .unwrap(),
+            message("@lucky_luke:server.name", json!({ "msgtype": "m.text", "body": "Howdy" })),
+        ];
+
+        for context in &contexts {
+            for event in &events {
+                let expected = set.get_match(event, context).map(|rule| rule.rule_id());
+                let compiled_match = compiled.get_match(event, context).map(|rule| rule.rule_id());
+                assert_eq!(compiled_match, expected, "{} in {}", event.json(), context.room_id);
+            }
+        }
+
+        // Check a few results to make sure that the rules are relevant.
+        let context = &contexts[1];
+        assert_eq!(compiled.get_match(&events[0], context).unwrap().rule_id(), ".m.rule.message");
+        assert_eq!(compiled.get_match(&events[1], context).unwrap().rule_id(), "joke");
+        assert_eq!(compiled.get_match(&events[3], context).unwrap().rule_id(), "joke");
+        assert_eq!(
+            compiled.get_match(&events[10], context).unwrap().rule_id(),
+            ".m.rule.roomnotif"
+        );
+        assert_eq!(compiled.get_match(&events[12], context).unwrap().rule_id(), "bots");
+        assert_eq!(
+            compiled.get_match(&events[16], context).unwrap().rule_id(),
+            "@lucky_luke:server.name"
+        );
+        assert!(compiled.get_match(&events[13], context).is_none());
+        assert_eq!(
+            compiled.get_match(&events[0], &contexts[2]).unwrap().rule_id(),
+            "!muted:server.name"
+        );
+    }
+
+    #[test]
+    fn ruleset_changes_need_compilation() {
+        let mut set = ruleset();
+        let compiled = CompiledRuleset::new(&set);
+        let context = &context("!far_west:server.name", 100);
+        let event =
+            message("@rantanplan:server.name", json!({ "msgtype": "m.text", "body": "Great" }));
+
+        set.content.shift_remove("disabled");
+        set.content.insert(PatternedPushRule {
+            actions: vec![Action::Notify],
+            default: false,
+            enabled: true,
+            rule_id: "disabled".into(),
+            pattern: "great".into(),
+        });
+
+        assert_eq!(compiled.get_match(&event, context).unwrap().rule_id(), ".m.rule.message");
+        assert_eq!(set.compile().get_match(&event, context).unwrap().rule_id(), "disabled");
+    }
+}
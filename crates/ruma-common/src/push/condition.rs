@@ -165,6 +165,11 @@ impl PushCondition {
         match self {
             Self::EventMatch { key, pattern } => check_event_match(event, key, pattern, context),
             Self::ContainsDisplayName => {
+                // A user without a display name is never mentioned by it.
+                if context.user_display_name.is_empty() {
+                    return false;
+                }
+
                 let value = match event.get_str("content.body") {
                     Some(v) => v,
                     None => return false,
@@ -250,6 +255,29 @@ pub struct PushConditionRoomCtx {
     pub supported_features: Vec<RoomVersionFeature>,
 }
 
+impl PushConditionRoomCtx {
+    /// Creates a new `PushConditionRoomCtx` with the given room ID, member count, user ID and
+    /// display name.
+    ///
+    /// The other fields are empty.
+    pub fn new(
+        room_id: OwnedRoomId,
+        member_count: UInt,
+        user_id: OwnedUserId,
+        user_display_name: String,
+    ) -> Self {
+        Self {
+            room_id,
+            member_count,
+            user_id,
+            user_display_name,
+            power_levels: None,
+            #[cfg(feature = "unstable-msc3931")]
+            supported_features: Vec::new(),
+        }
+    }
+}
+
 /// The room power levels context to be able to test the corresponding push conditions.
 #[derive(Clone, Debug)]
 #[allow(clippy::exhaustive_structs)]
@@ -346,14 +374,7 @@ impl StrExt for str {
     }
 
     fn matches_pattern(&self, pattern: &str, match_words: bool) -> bool {
-        let value = &self.to_lowercase();
-        let pattern = &pattern.to_lowercase();
-
-        if match_words {
-            value.matches_word(pattern)
-        } else {
-            WildMatch::new(pattern).matches(value)
-        }
+        PatternMatcher::new(pattern, match_words).matches_lowercase(&self.to_lowercase())
     }
 
     fn matches_word(&self, pattern: &str) -> bool {
@@ -364,43 +385,8 @@ impl StrExt for str {
             return false;
         }
 
-        let has_wildcards = pattern.contains(['?', '*']);
-
-        if has_wildcards {
-            let mut chunks: Vec<String> = vec![];
-            let mut prev_wildcard = false;
-            let mut chunk_start = 0;
-
-            for (i, c) in pattern.char_indices() {
-                if matches!(c, '?' | '*') && !prev_wildcard {
-                    if i != 0 {
-                        chunks.push(regex::escape(&pattern[chunk_start..i]));
-                        chunk_start = i;
-                    }
-
-                    prev_wildcard = true;
-                } else if prev_wildcard {
-                    let chunk = &pattern[chunk_start..i];
-                    chunks.push(chunk.wildcards_to_regex());
-
-                    chunk_start = i;
-                    prev_wildcard = false;
-                }
-            }
-
-            let len = pattern.len();
-            if !prev_wildcard {
-                chunks.push(regex::escape(&pattern[chunk_start..len]));
-            } else if prev_wildcard {
-                let chunk = &pattern[chunk_start..len];
-                chunks.push(chunk.wildcards_to_regex());
-            }
-
-            // The word characters in ASCII compatible mode (with the `-u` flag) match the
-            // definition in the spec: any character not in the set `[A-Za-z0-9_]`.
-            let regex = format!(r"(?-u:^|\W|\b){}(?-u:\b|\W|$)", chunks.concat());
-            let re = Regex::new(&regex).expect("regex construction should succeed");
-            re.is_match(self.as_bytes())
+        if pattern.contains(['?', '*']) {
+            word_regex(pattern).is_match(self.as_bytes())
         } else {
             match self.find(pattern) {
                 Some(start) => {
@@ -454,6 +440,98 @@ impl StrExt for str {
     }
 }
 
+/// Build the regex that matches `pattern` with word boundaries.
+///
+/// The pattern can be a glob with wildcards `*` and `?`.
+fn word_regex(pattern: &str) -> Regex {
+    let mut chunks: Vec<String> = vec![];
+    let mut prev_wildcard = false;
+    let mut chunk_start = 0;
+
+    for (i, c) in pattern.char_indices() {
+        if matches!(c, '?' | '*') && !prev_wildcard {
+            if i != 0 {
+                chunks.push(regex::escape(&pattern[chunk_start..i]));
+                chunk_start = i;
+            }
+
+            prev_wildcard = true;
+        } else if prev_wildcard {
+            let chunk = &pattern[chunk_start..i];
+            chunks.push(chunk.wildcards_to_regex());
+
+            chunk_start = i;
+            prev_wildcard = false;
+        }
+    }
+
+    let len = pattern.len();
+    if !prev_wildcard {
+        chunks.push(regex::escape(&pattern[chunk_start..len]));
+    } else if prev_wildcard {
+        let chunk = &pattern[chunk_start..len];
+        chunks.push(chunk.wildcards_to_regex());
+    }
+
+    // The word characters in ASCII compatible mode (with the `-u` flag) match the
+    // definition in the spec: any character not in the set `[A-Za-z0-9_]`.
+    let regex = format!(r"(?-u:^|\W|\b){}(?-u:\b|\W|$)", chunks.concat());
+    Regex::new(&regex).expect("regex construction should succeed")
+}
+
+/// A glob pattern prepared to be matched against many strings.
+///
+/// The match is case insensitive, like [`StrExt::matches_pattern()`].
+#[derive(Clone, Debug)]
+pub(super) enum PatternMatcher {
+    /// A glob that must match the whole value.
+    Glob(WildMatch),
+
+    /// A glob that must match a word of the value.
+    Word {
+        /// The lowercase pattern.
+        pattern: String,
+
+        /// The regex of the pattern, if it contains wildcards.
+        regex: Option<Regex>,
+    },
+}
+
+impl PatternMatcher {
+    /// Prepare the given pattern.
+    ///
+    /// If `match_words` is `true`, the pattern must be separated from other words in the value.
+    pub(super) fn new(pattern: &str, match_words: bool) -> Self {
+        let pattern = pattern.to_lowercase();
+
+        if match_words {
+            let regex =
+                (!pattern.is_empty() && pattern.contains(['?', '*'])).then(|| word_regex(&pattern));
+            Self::Word { pattern, regex }
+        } else {
+            Self::Glob(WildMatch::new(&pattern))
+        }
+    }
+
+    /// Whether the given value matches this pattern.
+    ///
+    /// The value must already be lowercase.
+    pub(super) fn matches_lowercase(&self, value: &str) -> bool {
+        match self {
+            Self::Glob(wildmatch) => wildmatch.matches(value),
+            Self::Word { pattern, regex } => {
+                if value == pattern {
+                    true
+                } else if let Some(regex) = regex {
+                    regex.is_match(value.as_bytes())
+                } else {
+                    value.matches_word(pattern)
+                }
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::BTreeMap;
@@ -0,0 +1,558 @@
+use serde::Serialize;
+use serde_json::to_value as to_json_value;
+
+use super::{
+    Action, AnyPushRuleRef, NewConditionalPushRule, NewPatternedPushRule, NewPushRule,
+    NewSimplePushRule, RuleKind, Ruleset,
+};
+
+/// A change to apply to a push rule, to update a [`Ruleset`] to another one.
+///
+/// The changes between two rulesets can be computed with [`Ruleset::diff()`]. They match the
+/// operations that are available with the client-server API.
+///
+/// This enum is exhaustive, so a variant can only be added along with the operation of the API
+/// that applies it.
+#[derive(Clone, Debug)]
+#[allow(clippy::large_enum_variant, clippy::exhaustive_enums)]
+pub enum PushRuleChange {
+    /// A user-defined rule was added.
+    ///
+    /// New rules are enabled. If the added rule is disabled, this change is followed by a
+    /// [`PushRuleChange::EnabledChanged`] change.
+    Added {
+        /// The new rule.
+        rule: NewPushRule,
+
+        /// The ID of the user-defined rule that comes before the new rule, if any.
+        after: Option<String>,
+
+        /// The ID of the user-defined rule that comes after the new rule, if any.
+        ///
+        /// This is only set if `after` is not set.
+        before: Option<String>,
+    },
+
+    /// A user-defined rule was removed.
+    Removed {
+        /// The kind of the rule.
+        kind: RuleKind,
+
+        /// The ID of the rule.
+        rule_id: String,
+    },
+
+    /// A user-defined rule was moved relative to the other user-defined rules of its kind.
+    ///
+    /// The rule contains the new conditions, pattern and actions of the rule.
+    Moved {
+        /// The moved rule.
+        rule: NewPushRule,
+
+        /// The ID of the user-defined rule that comes before the moved rule, if any.
+        after: Option<String>,
+
+        /// The ID of the user-defined rule that comes after the moved rule, if any.
+        ///
+        /// This is only set if `after` is not set.
+        before: Option<String>,
+    },
+
+    /// The conditions or the pattern of a user-defined rule changed.
+    ///
+    /// The rule contains the new conditions, pattern and actions of the rule.
+    Modified {
+        /// The modified rule.
+        rule: NewPushRule,
+    },
+
+    /// A rule was enabled or disabled.
+    EnabledChanged {
+        /// The kind of the rule.
+        kind: RuleKind,
+
+        /// The ID of the rule.
+        rule_id: String,
+
+        /// Whether the rule is enabled.
+        enabled: bool,
+    },
+
+    /// The actions of a rule changed.
+    ///
+    /// This change is not used for rules that are [`Added`](Self::Added),
+    /// [`Moved`](Self::Moved) or [`Modified`](Self::Modified), since these changes already contain
+    /// the new actions.
+    ActionsChanged {
+        /// The kind of the rule.
+        kind: RuleKind,
+
+        /// The ID of the rule.
+        rule_id: String,
+
+        /// The new actions of the rule.
+        actions: Vec<Action>,
+    },
+}
+
+impl PushRuleChange {
+    /// The kind of the rule that changed.
+    pub fn kind(&self) -> RuleKind {
+        match self {
+            Self::Added { rule, .. } | Self::Moved { rule, .. } | Self::Modified { rule } => {
+                rule.kind()
+            }
+            Self::Removed { kind, .. }
+            | Self::EnabledChanged { kind, .. }
+            | Self::ActionsChanged { kind, .. } => kind.clone(),
+        }
+    }
+
+    /// The ID of the rule that changed.
+    pub fn rule_id(&self) -> &str {
+        match self {
+            Self::Added { rule, .. } | Self::Moved { rule, .. } | Self::Modified { rule } => {
+                rule.rule_id()
+            }
+            Self::Removed { rule_id, .. }
+            | Self::EnabledChanged { rule_id, .. }
+            | Self::ActionsChanged { rule_id, .. } => rule_id,
+        }
+    }
+}
+
+impl Ruleset {
+    /// Compute the changes to apply to this ruleset to get the `new` ruleset.
+    ///
+    /// The changes are returned in the order in which they must be applied: first the removed
+    /// rules, then the added, moved and modified rules, and finally the enabled and actions
+    /// changes.
+    ///
+    /// Only user-defined rules can be added, removed, moved or modified, so server-default rules
+    /// are only compared for their `enabled` flag and their actions. The position of user-defined
+    /// rules is only compared relative to the other user-defined rules of the same kind, and the
+    /// number of moved rules is kept to a minimum.
+    pub fn diff(&self, new: &Ruleset) -> Vec<PushRuleChange> {
+        let mut removed = Vec::new();
+        let mut set = Vec::new();
+        let mut state = Vec::new();
+
+        for kind in [
+            RuleKind::Override,
+            RuleKind::Content,
+            RuleKind::Room,
+            RuleKind::Sender,
+            RuleKind::Underride,
+        ] {
+            let old_rules = self.rules_of_kind(&kind);
+            let new_rules = new.rules_of_kind(&kind);
+
+            // The IDs of the user-defined rules, in the order of the old ruleset, as they are
+            // updated by the changes.
+            let mut order: Vec<&str> = old_rules
+                .iter()
+                .filter(|rule| !rule.is_server_default())
+                .map(|rule| rule.rule_id())
+                .collect();
+            let new_order: Vec<&str> = new_rules
+                .iter()
+                .filter(|rule| !rule.is_server_default())
+                .map(|rule| rule.rule_id())
+                .collect();
+
+            order.retain(|rule_id| {
+                let keep = new_order.contains(rule_id);
+                if !keep {
+                    removed.push(PushRuleChange::Removed {
+                        kind: kind.clone(),
+                        rule_id: (*rule_id).to_owned(),
+                    });
+                }
+                keep
+            });
+
+            // The rules whose relative position doesn't change.
+            let positions: Vec<Option<usize>> =
+                new_order.iter().map(|rule_id| order.iter().position(|id| id == rule_id)).collect();
+            let stable = longest_increasing_subsequence(&positions);
+
+            let mut prev: Option<&str> = None;
+            for (index, rule_id) in new_order.iter().copied().enumerate() {
+                let new_rule = find_rule(&new_rules, rule_id);
+                let old_rule = old_rules.iter().copied().find(|rule| rule.rule_id() == rule_id);
+
+                let is_new = positions[index].is_none();
+                if is_new || !stable[index] {
+                    order.retain(|&id| id != rule_id);
+
+                    let (after, before) = match prev {
+                        Some(prev) => (Some(prev.to_owned()), None),
+                        None => (None, order.first().map(|&id| id.to_owned())),
+                    };
+                    let to = prev.map_or(0, |prev| {
+                        order.iter().position(|&id| id == prev).map_or(0, |pos| pos + 1)
+                    });
+                    order.insert(to, rule_id);
+
+                    let rule = to_new_push_rule(new_rule);
+                    if is_new {
+                        set.push(PushRuleChange::Added { rule, after, before });
+                        if !new_rule.enabled() {
+                            set.push(PushRuleChange::EnabledChanged {
+                                kind: kind.clone(),
+                                rule_id: rule_id.to_owned(),
+                                enabled: false,
+                            });
+                        }
+                    } else {
+                        set.push(PushRuleChange::Moved { rule, after, before });
+                    }
+                } else if let Some(old_rule) = old_rule {
+                    if !same_definition(old_rule, new_rule) {
+                        set.push(PushRuleChange::Modified { rule: to_new_push_rule(new_rule) });
+                    }
+                }
+
+                prev = Some(rule_id);
+            }
+
+            // Changes of the state of the rules.
+            for new_rule in &new_rules {
+                let rule_id = new_rule.rule_id();
+                let Some(old_rule) = old_rules.iter().find(|rule| rule.rule_id() == rule_id) else {
+                    continue;
+                };
+
+                if old_rule.enabled() != new_rule.enabled() {
+                    state.push(PushRuleChange::EnabledChanged {
+                        kind: kind.clone(),
+                        rule_id: rule_id.to_owned(),
+                        enabled: new_rule.enabled(),
+                    });
+                }
+
+                let is_set = set.iter().any(|change| {
+                    matches!(change, PushRuleChange::Moved { .. } | PushRuleChange::Modified { .. })
+                        && change.kind() == kind
+                        && change.rule_id() == rule_id
+                });
+                if !is_set && !json_eq(old_rule.actions(), new_rule.actions()) {
+                    state.push(PushRuleChange::ActionsChanged {
+                        kind: kind.clone(),
+                        rule_id: rule_id.to_owned(),
+                        actions: new_rule.actions().to_owned(),
+                    });
+                }
+            }
+        }
+
+        removed.into_iter().chain(set).chain(state).collect()
+    }
+
+    /// Get the rules of the given kind, in order of priority.
+    fn rules_of_kind(&self, kind: &RuleKind) -> Vec<AnyPushRuleRef<'_>> {
+        match kind {
+            RuleKind::Override => self.override_.iter().map(AnyPushRuleRef::Override).collect(),
+            RuleKind::Underride => self.underride.iter().map(AnyPushRuleRef::Underride).collect(),
+            RuleKind::Sender => self.sender.iter().map(AnyPushRuleRef::Sender).collect(),
+            RuleKind::Room => self.room.iter().map(AnyPushRuleRef::Room).collect(),
+            RuleKind::Content => self.content.iter().map(AnyPushRuleRef::Content).collect(),
+            RuleKind::_Custom(_) => Vec::new(),
+        }
+    }
+}
+
+/// Find the rule with the given ID in the given list.
+fn find_rule<'a>(rules: &[AnyPushRuleRef<'a>], rule_id: &str) -> AnyPushRuleRef<'a> {
+    rules
+        .iter()
+        .copied()
+        .find(|rule| rule.rule_id() == rule_id)
+        .expect("rule ID should come from the same list")
+}
+
+/// Convert the given rule to a rule that can be set with the client-server API.
+fn to_new_push_rule(rule: AnyPushRuleRef<'_>) -> NewPushRule {
+    match rule {
+        AnyPushRuleRef::Override(rule) => NewPushRule::Override(NewConditionalPushRule::new(
+            rule.rule_id.clone(),
+            rule.conditions.clone(),
+            rule.actions.clone(),
+        )),
+        AnyPushRuleRef::Content(rule) => NewPushRule::Content(NewPatternedPushRule::new(
+            rule.rule_id.clone(),
+            rule.pattern.clone(),
+            rule.actions.clone(),
+        )),
+        AnyPushRuleRef::Room(rule) => {
+            NewPushRule::Room(NewSimplePushRule::new(rule.rule_id.clone(), rule.actions.clone()))
+        }
+        AnyPushRuleRef::Sender(rule) => {
+            NewPushRule::Sender(NewSimplePushRule::new(rule.rule_id.clone(), rule.actions.clone()))
+        }
+        AnyPushRuleRef::Underride(rule) => NewPushRule::Underride(NewConditionalPushRule::new(
+            rule.rule_id.clone(),
+            rule.conditions.clone(),
+            rule.actions.clone(),
+        )),
+    }
+}
+
+/// Whether the given rules have the same conditions or pattern.
+fn same_definition(old: AnyPushRuleRef<'_>, new: AnyPushRuleRef<'_>) -> bool {
+    match (old, new) {
+        (AnyPushRuleRef::Override(old), AnyPushRuleRef::Override(new))
+        | (AnyPushRuleRef::Underride(old), AnyPushRuleRef::Underride(new)) => {
+            json_eq(&old.conditions, &new.conditions)
+        }
+        (AnyPushRuleRef::Content(old), AnyPushRuleRef::Content(new)) => old.pattern == new.pattern,
+        _ => true,
+    }
+}
+
+/// Whether the given values have the same JSON representation.
+fn json_eq<T: Serialize + ?Sized>(a: &T, b: &T) -> bool {
+    match (to_json_value(a), to_json_value(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Find the longest increasing subsequence of the given positions, ignoring `None`s.
+///
+/// Returns whether each element is part of the subsequence.
+fn longest_increasing_subsequence(positions: &[Option<usize>]) -> Vec<bool> {
+    // The length of the longest subsequence ending at each element, and the previous element in
+    // that subsequence.
+    let mut lengths = vec![0; positions.len()];
+    let mut prev = vec![None; positions.len()];
+
+    for (i, position) in positions.iter().enumerate() {
+        let Some(position) = position else {
+            continue;
+        };
+
+        lengths[i] = 1;
+        for j in 0..i {
+            if positions[j].is_some_and(|p| p < *position) && lengths[j] + 1 > lengths[i] {
+                lengths[i] = lengths[j] + 1;
+                prev[i] = Some(j);
+            }
+        }
+    }
+
+    let mut in_subsequence = vec![false; positions.len()];
+    let mut current = lengths
+        .iter()
+        .enumerate()
+        .filter(|(_, length)| **length > 0)
+        .max_by_key(|(_, length)| **length)
+        .map(|(i, _)| i);
+
+    while let Some(i) = current {
+        in_subsequence[i] = true;
+        current = prev[i];
+    }
+
+    in_subsequence
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::to_value as to_json_value;
+
+    use super::{longest_increasing_subsequence, PushRuleChange};
+    use crate::{
+        owned_room_id, owned_user_id,
+        push::{
+            Action, NewConditionalPushRule, NewPatternedPushRule, NewPushRule, NewSimplePushRule,
+            PredefinedOverrideRuleId, PushCondition, RuleKind, Ruleset, Tweak,
+        },
+        user_id,
+    };
+
+    fn keyword(rule_id: &str) -> NewPushRule {
+        NewPushRule::Content(NewPatternedPushRule::new(
+            rule_id.to_owned(),
+            rule_id.to_owned(),
+            vec![Action::Notify],
+        ))
+    }
+
+    fn user_ids(ruleset: &Ruleset, kind: RuleKind) -> Vec<&str> {
+        ruleset
+            .rules_of_kind(&kind)
+            .into_iter()
+            .filter(|rule| !rule.is_server_default())
+            .map(|rule| rule.rule_id())
+            .collect()
+    }
+
+    /// Apply the changes like a homeserver would.
+    fn apply(ruleset: &mut Ruleset, changes: Vec<PushRuleChange>) {
+        for change in changes {
+            match change {
+                PushRuleChange::Added { rule, after, before }
+                | PushRuleChange::Moved { rule, after, before } => {
+                    ruleset.insert(rule, after.as_deref(), before.as_deref()).unwrap();
+                }
+                PushRuleChange::Modified { rule } => ruleset.insert(rule, None, None).unwrap(),
+                PushRuleChange::Removed { kind, rule_id } => ruleset.remove(kind, rule_id).unwrap(),
+                PushRuleChange::EnabledChanged { kind, rule_id, enabled } => {
+                    ruleset.set_enabled(kind, rule_id, enabled).unwrap();
+                }
+                PushRuleChange::ActionsChanged { kind, rule_id, actions } => {
+                    ruleset.set_actions(kind, rule_id, actions).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lis() {
+        assert_eq!(longest_increasing_subsequence(&[]), Vec::<bool>::new());
+        assert_eq!(
+            longest_increasing_subsequence(&[Some(2), Some(0), None, Some(1), Some(3)]),
+            [false, true, false, true, true]
+        );
+        assert_eq!(longest_increasing_subsequence(&[None, None]), [false, false]);
+    }
+
+    #[test]
+    fn no_changes() {
+        let mut ruleset = Ruleset::server_default(user_id!("@jj:server.name"));
+        ruleset.insert(keyword("joke"), None, None).unwrap();
+
+        assert!(ruleset.diff(&ruleset.clone()).is_empty());
+    }
+
+    #[test]
+    fn diff_and_apply() {
+        let mut old = Ruleset::server_default(user_id!("@jj:server.name"));
+        for rule_id in ["d", "c", "b", "a"] {
+            old.insert(keyword(rule_id), None, None).unwrap();
+        }
+        old.insert(
+            NewPushRule::Room(NewSimplePushRule::new(
+                owned_room_id!("!far_west:server.name"),
+                vec![],
+            )),
+            None,
+            None,
+        )
+        .unwrap();
+        old.insert(
+            NewPushRule::Override(NewConditionalPushRule::new(
+                "bots".to_owned(),
+                vec![PushCondition::EventMatch {
+                    key: "sender".to_owned(),
+                    pattern: "@bot*".to_owned(),
+                }],
+                vec![],
+            )),
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(user_ids(&old, RuleKind::Content), ["a", "b", "c", "d"]);
+
+        let mut new = old.clone();
+        // Move `d` before `b` and `a` after `c`.
+        new.insert(keyword("d"), Some("a"), None).unwrap();
+        new.insert(keyword("a"), Some("c"), None).unwrap();
+        // Add `e` at the top and `f` after `b`, disabled.
+        new.insert(keyword("e"), None, None).unwrap();
+        new.insert(keyword("f"), Some("b"), None).unwrap();
+        new.set_enabled(RuleKind::Content, "f", false).unwrap();
+        // Change the actions of `c` and a server-default rule.
+        new.set_actions(RuleKind::Content, "c", vec![]).unwrap();
+        new.set_enabled(RuleKind::Override, PredefinedOverrideRuleId::Master, true).unwrap();
+        new.set_actions(
+            RuleKind::Override,
+            PredefinedOverrideRuleId::SuppressNotices,
+            vec![Action::Notify],
+        )
+        .unwrap();
+        // Remove the room rule and add a sender rule.
+        new.remove(RuleKind::Room, "!far_west:server.name").unwrap();
+        new.insert(
+            NewPushRule::Sender(NewSimplePushRule::new(
+                owned_user_id!("@rantanplan:server.name"),
+                vec![Action::SetTweak(Tweak::Highlight(true))],
+            )),
+            None,
+            None,
+        )
+        .unwrap();
+        // Change the conditions of an override rule.
+        new.insert(
+            NewPushRule::Override(NewConditionalPushRule::new(
+                "bots".to_owned(),
+                vec![PushCondition::EventMatch {
+                    key: "sender".to_owned(),
+                    pattern: "@*bot:server.name".to_owned(),
+                }],
+                vec![],
+            )),
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(user_ids(&new, RuleKind::Content), ["e", "d", "b", "f", "c", "a"]);
+
+        let changes = old.diff(&new);
+        let summary: Vec<_> = changes
+            .iter()
+            .map(|change| {
+                let change_kind = match change {
+                    PushRuleChange::Added { .. } => "added",
+                    PushRuleChange::Removed { .. } => "removed",
+                    PushRuleChange::Moved { .. } => "moved",
+                    PushRuleChange::Modified { .. } => "modified",
+                    PushRuleChange::EnabledChanged { .. } => "enabled",
+                    PushRuleChange::ActionsChanged { .. } => "actions",
+                };
+                (change_kind, change.rule_id())
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("removed", "!far_west:server.name"),
+                ("modified", "bots"),
+                ("added", "e"),
+                ("moved", "d"),
+                ("added", "f"),
+                ("enabled", "f"),
+                ("moved", "a"),
+                ("added", "@rantanplan:server.name"),
+                ("enabled", PredefinedOverrideRuleId::Master.as_str()),
+                ("actions", PredefinedOverrideRuleId::SuppressNotices.as_str()),
+                ("actions", "c"),
+            ]
+        );
+
+        apply(&mut old, changes);
+        assert_eq!(to_json_value(&old).unwrap(), to_json_value(&new).unwrap());
+    }
+
+    #[test]
+    fn reverse_order() {
+        let mut old = Ruleset::server_default(user_id!("@jj:server.name"));
+        for rule_id in ["a", "b", "c", "d", "e"] {
+            old.insert(keyword(rule_id), None, None).unwrap();
+        }
+
+        let mut new = Ruleset::server_default(user_id!("@jj:server.name"));
+        for rule_id in ["e", "d", "c", "b", "a"] {
+            new.insert(keyword(rule_id), None, None).unwrap();
+        }
+
+        let changes = old.diff(&new);
+        assert_eq!(changes.len(), 4);
+
+        apply(&mut old, changes);
+        assert_eq!(user_ids(&old, RuleKind::Content), ["a", "b", "c", "d", "e"]);
+        assert_eq!(to_json_value(&old).unwrap(), to_json_value(&new).unwrap());
+    }
+}
@@ -0,0 +1,558 @@
+//! The rules of Matrix room versions.
+//!
+//! The rules of the room versions defined in the specification are available as constants of
+//! [`RoomVersionRules`], and can be obtained from a [`RoomVersionId`] with
+//! [`RoomVersionId::rules()`].
+//!
+//! The rules of custom room versions can be registered with
+//! [`RoomVersionRules::register_custom()`]. They are then used by all the functions that take a
+//! `RoomVersionId`, like [`canonical_json::redact()`].
+//!
+//! [`canonical_json::redact()`]: crate::canonical_json::redact
+
+use std::{collections::BTreeMap, sync::RwLock};
+
+use crate::RoomVersionId;
+
+/// The rules of custom room versions, registered with [`RoomVersionRules::register_custom()`].
+static CUSTOM_ROOM_VERSIONS: RwLock<BTreeMap<RoomVersionId, RoomVersionRules>> =
+    RwLock::new(BTreeMap::new());
+
+/// The rules of a room version.
+///
+/// The fields of this type can be changed to create a custom room version based on one of the
+/// constants of this type.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::exhaustive_structs)]
+pub struct RoomVersionRules {
+    /// The stability of the room version.
+    pub disposition: RoomVersionDisposition,
+
+    /// The format of the event IDs.
+    pub event_id_format: EventIdFormatVersion,
+
+    /// The state resolution algorithm.
+    pub state_res: StateResolutionVersion,
+
+    /// Whether to enforce the validity period of signing keys.
+    pub enforce_key_validity: bool,
+
+    /// Whether to strictly enforce [canonical JSON].
+    ///
+    /// If this is `true`, integers outside the range of `[-(2**53)+1, (2**53)-1]`, floats, `NaN`,
+    /// `Infinity` and `-Infinity` are not allowed in events.
+    ///
+    /// [canonical JSON]: https://spec.matrix.org/latest/appendices/#canonical-json
+    pub strict_canonical_json: bool,
+
+    /// The rules of the redaction algorithm.
+    pub redaction: RedactionRules,
+
+    /// The rules to check the signatures of events.
+    pub signatures: SignaturesRules,
+
+    /// The rules of the authorization checks.
+    pub authorization: AuthorizationRules,
+}
+
+impl RoomVersionRules {
+    /// Rules for [room version 1].
+    ///
+    /// [room version 1]: https://spec.matrix.org/latest/rooms/v1/
+    pub const V1: Self = Self {
+        disposition: RoomVersionDisposition::Stable,
+        event_id_format: EventIdFormatVersion::V1,
+        state_res: StateResolutionVersion::V1,
+        enforce_key_validity: false,
+        strict_canonical_json: false,
+        redaction: RedactionRules::V1,
+        signatures: SignaturesRules::V1,
+        authorization: AuthorizationRules::V1,
+    };
+
+    /// Rules for [room version 2].
+    ///
+    /// [room version 2]: https://spec.matrix.org/latest/rooms/v2/
+    pub const V2: Self = Self { state_res: StateResolutionVersion::V2, ..Self::V1 };
+
+    /// Rules for [room version 3].
+    ///
+    /// [room version 3]: https://spec.matrix.org/latest/rooms/v3/
+    pub const V3: Self = Self {
+        event_id_format: EventIdFormatVersion::V2,
+        signatures: SignaturesRules::V3,
+        authorization: AuthorizationRules::V3,
+        ..Self::V2
+    };
+
+    /// Rules for [room version 4].
+    ///
+    /// [room version 4]: https://spec.matrix.org/latest/rooms/v4/
+    pub const V4: Self = Self { event_id_format: EventIdFormatVersion::V3, ..Self::V3 };
+
+    /// Rules for [room version 5].
+    ///
+    /// [room version 5]: https://spec.matrix.org/latest/rooms/v5/
+    pub const V5: Self = Self { enforce_key_validity: true, ..Self::V4 };
+
+    /// Rules for [room version 6].
+    ///
+    /// [room version 6]: https://spec.matrix.org/latest/rooms/v6/
+    pub const V6: Self = Self {
+        strict_canonical_json: true,
+        redaction: RedactionRules::V6,
+        authorization: AuthorizationRules::V6,
+        ..Self::V5
+    };
+
+    /// Rules for [room version 7].
+    ///
+    /// [room version 7]: https://spec.matrix.org/latest/rooms/v7/
+    pub const V7: Self = Self { authorization: AuthorizationRules::V7, ..Self::V6 };
+
+    /// Rules for [room version 8].
+    ///
+    /// [room version 8]: https://spec.matrix.org/latest/rooms/v8/
+    pub const V8: Self = Self {
+        redaction: RedactionRules::V8,
+        signatures: SignaturesRules::V8,
+        authorization: AuthorizationRules::V8,
+        ..Self::V7
+    };
+
+    /// Rules for [room version 9].
+    ///
+    /// [room version 9]: https://spec.matrix.org/latest/rooms/v9/
+    pub const V9: Self = Self { redaction: RedactionRules::V9, ..Self::V8 };
+
+    /// Rules for [room version 10].
+    ///
+    /// [room version 10]: https://spec.matrix.org/latest/rooms/v10/
+    pub const V10: Self = Self { authorization: AuthorizationRules::V10, ..Self::V9 };
+
+    /// Rules for [room version 11].
+    ///
+    /// [room version 11]: https://spec.matrix.org/latest/rooms/v11/
+    pub const V11: Self = Self {
+        redaction: RedactionRules::V11,
+        authorization: AuthorizationRules::V11,
+        ..Self::V10
+    };
+
+    /// Rules for the room version `org.matrix.msc2870`, based on room version 11.
+    ///
+    /// See [MSC2870](https://github.com/matrix-org/matrix-spec-proposals/pull/2870).
+    #[cfg(feature = "unstable-msc2870")]
+    pub const MSC2870: Self = Self {
+        disposition: RoomVersionDisposition::Unstable,
+        redaction: RedactionRules::MSC2870,
+        ..Self::V11
+    };
+
+    /// Register the rules of a custom room version.
+    ///
+    /// After this call, [`RoomVersionId::rules()`] returns these rules for `room_version`, so they
+    /// are used by all the functions that take a `RoomVersionId`.
+    ///
+    /// Returns the rules that were previously registered for this room version, if any.
+    ///
+    /// Returns an error if `room_version` already has rules defined in this crate, like a room
+    /// version of the specification.
+    pub fn register_custom(
+        room_version: RoomVersionId,
+        rules: Self,
+    ) -> Result<Option<Self>, KnownRoomVersionError> {
+        if Self::for_known_version(&room_version).is_some() {
+            return Err(KnownRoomVersionError(room_version));
+        }
+
+        Ok(CUSTOM_ROOM_VERSIONS
+            .write()
+            .expect("custom room versions lock was poisoned")
+            .insert(room_version, rules))
+    }
+
+    /// Unregister the rules of a custom room version.
+    ///
+    /// Returns the rules that were registered for this room version, if any.
+    pub fn unregister_custom(room_version: &RoomVersionId) -> Option<Self> {
+        CUSTOM_ROOM_VERSIONS
+            .write()
+            .expect("custom room versions lock was poisoned")
+            .remove(room_version)
+    }
+
+    /// The rules of the given room version, if they are defined in this crate.
+    fn for_known_version(room_version: &RoomVersionId) -> Option<Self> {
+        Some(match room_version {
+            RoomVersionId::V1 => Self::V1,
+            RoomVersionId::V2 => Self::V2,
+            RoomVersionId::V3 => Self::V3,
+            RoomVersionId::V4 => Self::V4,
+            RoomVersionId::V5 => Self::V5,
+            RoomVersionId::V6 => Self::V6,
+            RoomVersionId::V7 => Self::V7,
+            RoomVersionId::V8 => Self::V8,
+            RoomVersionId::V9 => Self::V9,
+            RoomVersionId::V10 => Self::V10,
+            RoomVersionId::V11 => Self::V11,
+            #[cfg(feature = "unstable-msc2870")]
+            version if version.as_str() == "org.matrix.msc2870" => Self::MSC2870,
+            _ => return None,
+        })
+    }
+}
+
+impl RoomVersionId {
+    /// The rules of this room version.
+    ///
+    /// Returns `None` if this is a custom room version whose rules were not registered with
+    /// [`RoomVersionRules::register_custom()`].
+    pub fn rules(&self) -> Option<RoomVersionRules> {
+        RoomVersionRules::for_known_version(self).or_else(|| {
+            CUSTOM_ROOM_VERSIONS
+                .read()
+                .expect("custom room versions lock was poisoned")
+                .get(self)
+                .cloned()
+        })
+    }
+}
+
+/// An error encountered when trying to register the rules of a room version that are defined in
+/// this crate.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("cannot override the rules of room version {0}")]
+pub struct KnownRoomVersionError(RoomVersionId);
+
+impl KnownRoomVersionError {
+    /// The room version that could not be registered.
+    pub fn room_version(&self) -> &RoomVersionId {
+        &self.0
+    }
+}
+
+/// The rules of a custom room version that are registered until this guard is dropped.
+///
+/// This is only meant to be used in tests, to not leak the rules of a custom room version into
+/// other tests, and is not considered part of this crate's public API.
+#[doc(hidden)]
+#[derive(Debug)]
+pub struct ScopedCustomRoomVersion(RoomVersionId);
+
+impl ScopedCustomRoomVersion {
+    /// Register the rules of the given custom room version until the returned guard is dropped.
+    ///
+    /// Returns an error if the room version is defined in this crate.
+    pub fn register(
+        room_version: RoomVersionId,
+        rules: RoomVersionRules,
+    ) -> Result<Self, KnownRoomVersionError> {
+        RoomVersionRules::register_custom(room_version.clone(), rules)?;
+        Ok(Self(room_version))
+    }
+}
+
+impl Drop for ScopedCustomRoomVersion {
+    fn drop(&mut self) {
+        RoomVersionRules::unregister_custom(&self.0);
+    }
+}
+
+/// The stability of a room version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(clippy::exhaustive_enums)]
+pub enum RoomVersionDisposition {
+    /// A room version that has a stable specification.
+    Stable,
+
+    /// A room version that is not yet fully specified.
+    Unstable,
+}
+
+/// The format of event IDs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub enum EventIdFormatVersion {
+    /// `$id:server` format, introduced in room version 1.
+    V1,
+
+    /// `$hash` format using the standard base64 alphabet, introduced in room version 3.
+    V2,
+
+    /// `$hash` format using the URL-safe base64 alphabet, introduced in room version 4.
+    V3,
+}
+
+/// The state resolution algorithm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub enum StateResolutionVersion {
+    /// State resolution of room version 1.
+    V1,
+
+    /// State resolution introduced in room version 2.
+    V2,
+}
+
+/// The rules of the [redaction algorithm].
+///
+/// Each field is a key of the events that is kept after redaction, in addition to the ones that
+/// are kept in all room versions.
+///
+/// [redaction algorithm]: https://spec.matrix.org/latest/client-server-api/#redactions
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::exhaustive_structs)]
+pub struct RedactionRules {
+    /// Keep the `origin`, `membership` and `prev_state` top-level keys.
+    ///
+    /// Removed in room version 11.
+    pub keep_origin_membership_prev_state: bool,
+
+    /// Keep the `aliases` key of `m.room.aliases` events.
+    ///
+    /// Removed in room version 6.
+    pub keep_room_aliases_aliases: bool,
+
+    /// Keep the `allow` key of `m.room.join_rules` events.
+    ///
+    /// Added in room version 8.
+    pub keep_room_join_rules_allow: bool,
+
+    /// Keep the `join_authorised_via_users_server` key of `m.room.member` events.
+    ///
+    /// Added in room version 9.
+    pub keep_room_member_join_authorised_via_users_server: bool,
+
+    /// Keep the `signed` key of the `third_party_invite` of `m.room.member` events.
+    ///
+    /// Added in room version 11.
+    pub keep_room_member_third_party_invite_signed: bool,
+
+    /// Keep all the keys of `m.room.create` events, instead of only the `creator`.
+    ///
+    /// Added in room version 11.
+    pub keep_room_create_content: bool,
+
+    /// Keep the `invite` key of `m.room.power_levels` events.
+    ///
+    /// Added in room version 11.
+    pub keep_room_power_levels_invite: bool,
+
+    /// Keep the `redacts` key of `m.room.redaction` events, which was moved to the content by
+    /// [MSC2174].
+    ///
+    /// Added in room version 11.
+    ///
+    /// [MSC2174]: https://github.com/matrix-org/matrix-spec-proposals/pull/2174
+    pub keep_room_redaction_redacts: bool,
+
+    /// Keep the `allow`, `deny` and `allow_ip_literals` keys of `m.room.server_acl` events.
+    ///
+    /// See [MSC2870](https://github.com/matrix-org/matrix-spec-proposals/pull/2870).
+    #[cfg(feature = "unstable-msc2870")]
+    pub keep_room_server_acl_allow_deny_allow_ip_literals: bool,
+}
+
+impl RedactionRules {
+    /// Redaction rules of room version 1.
+    pub const V1: Self = Self {
+        keep_origin_membership_prev_state: true,
+        keep_room_aliases_aliases: true,
+        keep_room_join_rules_allow: false,
+        keep_room_member_join_authorised_via_users_server: false,
+        keep_room_member_third_party_invite_signed: false,
+        keep_room_create_content: false,
+        keep_room_power_levels_invite: false,
+        keep_room_redaction_redacts: false,
+        #[cfg(feature = "unstable-msc2870")]
+        keep_room_server_acl_allow_deny_allow_ip_literals: false,
+    };
+
+    /// Redaction rules of room version 6.
+    pub const V6: Self = Self { keep_room_aliases_aliases: false, ..Self::V1 };
+
+    /// Redaction rules of room version 8.
+    pub const V8: Self = Self { keep_room_join_rules_allow: true, ..Self::V6 };
+
+    /// Redaction rules of room version 9.
+    pub const V9: Self =
+        Self { keep_room_member_join_authorised_via_users_server: true, ..Self::V8 };
+
+    /// Redaction rules of room version 11.
+    pub const V11: Self = Self {
+        keep_origin_membership_prev_state: false,
+        keep_room_member_third_party_invite_signed: true,
+        keep_room_create_content: true,
+        keep_room_power_levels_invite: true,
+        keep_room_redaction_redacts: true,
+        ..Self::V9
+    };
+
+    /// Redaction rules of room version 11 with [MSC2870].
+    ///
+    /// [MSC2870]: https://github.com/matrix-org/matrix-spec-proposals/pull/2870
+    #[cfg(feature = "unstable-msc2870")]
+    pub const MSC2870: Self =
+        Self { keep_room_server_acl_allow_deny_allow_ip_literals: true, ..Self::V11 };
+}
+
+/// The rules to check the signatures of events.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::exhaustive_structs)]
+pub struct SignaturesRules {
+    /// Check the signature of the server of the event ID.
+    ///
+    /// Only valid with [`EventIdFormatVersion::V1`].
+    pub check_event_id_server: bool,
+
+    /// Check the signature of the server of the user in the `join_authorised_via_users_server`
+    /// key of `m.room.member` events.
+    ///
+    /// Added in room version 8.
+    pub check_join_authorised_via_users_server: bool,
+}
+
+impl SignaturesRules {
+    /// Signatures rules of room version 1.
+    pub const V1: Self =
+        Self { check_event_id_server: true, check_join_authorised_via_users_server: false };
+
+    /// Signatures rules of room version 3.
+    pub const V3: Self = Self { check_event_id_server: false, ..Self::V1 };
+
+    /// Signatures rules of room version 8.
+    pub const V8: Self = Self { check_join_authorised_via_users_server: true, ..Self::V3 };
+}
+
+/// The rules of the [authorization checks].
+///
+/// [authorization checks]: https://spec.matrix.org/latest/server-server-api/#authorization-rules
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::exhaustive_structs)]
+pub struct AuthorizationRules {
+    /// Apply the special authorization rules of `m.room.aliases` events.
+    ///
+    /// Removed in room version 6.
+    pub special_case_aliases_auth: bool,
+
+    /// Check the `notifications` key of `m.room.power_levels` events.
+    ///
+    /// Added in room version 6.
+    pub limit_notifications_power_levels: bool,
+
+    /// Apply the extra authorization rules of `m.room.redaction` events.
+    ///
+    /// Removed in room version 3.
+    pub extra_redaction_checks: bool,
+
+    /// Allow the `knock` membership and join rule.
+    ///
+    /// Added in room version 7.
+    pub allow_knocking: bool,
+
+    /// Allow the `restricted` join rule.
+    ///
+    /// Added in room version 8.
+    pub restricted_join_rules: bool,
+
+    /// Allow the `knock_restricted` join rule.
+    ///
+    /// Added in room version 10.
+    pub knock_restricted_join_rule: bool,
+
+    /// Only allow integers in the values of `m.room.power_levels` events.
+    ///
+    /// Added in room version 10.
+    pub integer_power_levels: bool,
+
+    /// Use the `sender` of the `m.room.create` event as the creator of the room, instead of the
+    /// `creator` key of its content.
+    ///
+    /// Added in room version 11.
+    pub use_room_create_sender: bool,
+}
+
+impl AuthorizationRules {
+    /// Authorization rules of room version 1.
+    pub const V1: Self = Self {
+        special_case_aliases_auth: true,
+        limit_notifications_power_levels: false,
+        extra_redaction_checks: true,
+        allow_knocking: false,
+        restricted_join_rules: false,
+        knock_restricted_join_rule: false,
+        integer_power_levels: false,
+        use_room_create_sender: false,
+    };
+
+    /// Authorization rules of room version 3.
+    pub const V3: Self = Self { extra_redaction_checks: false, ..Self::V1 };
+
+    /// Authorization rules of room version 6.
+    pub const V6: Self = Self {
+        special_case_aliases_auth: false,
+        limit_notifications_power_levels: true,
+        ..Self::V3
+    };
+
+    /// Authorization rules of room version 7.
+    pub const V7: Self = Self { allow_knocking: true, ..Self::V6 };
+
+    /// Authorization rules of room version 8.
+    pub const V8: Self = Self { restricted_join_rules: true, ..Self::V7 };
+
+    /// Authorization rules of room version 10.
+    pub const V10: Self =
+        Self { knock_restricted_join_rule: true, integer_power_levels: true, ..Self::V8 };
+
+    /// Authorization rules of room version 11.
+    pub const V11: Self = Self { use_room_create_sender: true, ..Self::V10 };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        EventIdFormatVersion, RoomVersionDisposition, RoomVersionRules, ScopedCustomRoomVersion,
+    };
+    use crate::RoomVersionId;
+
+    #[test]
+    fn known_room_versions() {
+        assert_eq!(RoomVersionId::V1.rules(), Some(RoomVersionRules::V1));
+        assert_eq!(RoomVersionId::V11.rules(), Some(RoomVersionRules::V11));
+        assert_eq!(RoomVersionId::try_from("org.example.unknown").unwrap().rules(), None);
+    }
+
+    #[test]
+    fn register_custom_room_version() {
+        let room_version = RoomVersionId::try_from("org.example.custom").unwrap();
+        let rules = RoomVersionRules {
+            disposition: RoomVersionDisposition::Unstable,
+            event_id_format: EventIdFormatVersion::V2,
+            ..RoomVersionRules::V11
+        };
+
+        let guard = ScopedCustomRoomVersion::register(room_version.clone(), rules.clone()).unwrap();
+        assert_eq!(room_version.rules(), Some(rules.clone()));
+
+        // Registering the room version again replaces its rules.
+        assert_eq!(
+            RoomVersionRules::register_custom(room_version.clone(), RoomVersionRules::V11).unwrap(),
+            Some(rules)
+        );
+        assert_eq!(room_version.rules(), Some(RoomVersionRules::V11));
+
+        drop(guard);
+        assert_eq!(room_version.rules(), None);
+        assert_eq!(RoomVersionRules::unregister_custom(&room_version), None);
+    }
+
+    #[test]
+    fn cannot_override_known_room_version() {
+        let err =
+            RoomVersionRules::register_custom(RoomVersionId::V6, RoomVersionRules::V1).unwrap_err();
+        assert_eq!(*err.room_version(), RoomVersionId::V6);
+        assert_eq!(RoomVersionId::V6.rules(), Some(RoomVersionRules::V6));
+    }
+}
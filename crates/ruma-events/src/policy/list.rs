@@ -0,0 +1,404 @@
+//! Evaluation of [moderation policy lists].
+//!
+//! [moderation policy lists]: https://spec.matrix.org/latest/client-server-api/#moderation-policy-lists
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use ruma_common::{EventId, OwnedEventId, OwnedRoomId, RoomId, RoomOrAliasId, ServerName, UserId};
+use wildmatch::WildMatch;
+
+use super::rule::Recommendation;
+use crate::{AnySyncStateEvent, SyncStateEvent};
+
+/// The kind of entity affected by a policy rule.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[allow(clippy::exhaustive_enums)]
+pub enum PolicyRuleKind {
+    /// An `m.policy.rule.user` rule, affecting users.
+    User,
+
+    /// An `m.policy.rule.room` rule, affecting rooms.
+    Room,
+
+    /// An `m.policy.rule.server` rule, affecting servers.
+    Server,
+}
+
+/// A policy rule of a [`PolicyLists`].
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct PolicyRule {
+    /// The policy room containing the rule.
+    pub policy_room: OwnedRoomId,
+
+    /// The kind of entity affected by the rule.
+    pub kind: PolicyRuleKind,
+
+    /// The state key of the rule.
+    pub state_key: String,
+
+    /// The ID of the state event of the rule.
+    pub event_id: OwnedEventId,
+
+    /// The entity affected by the rule, that can contain glob characters.
+    pub entity: String,
+
+    /// The suggested action to take.
+    pub recommendation: Recommendation,
+
+    /// The human-readable description for the recommendation.
+    pub reason: String,
+}
+
+/// The rules of a set of moderation policy lists.
+///
+/// The rules are indexed by entity when they don't contain glob characters, and their glob
+/// patterns are parsed only once, so checking an entity doesn't require to go through all the
+/// rules.
+///
+/// The rules must be updated with [`PolicyLists::apply_event()`] when a policy rule state event is
+/// received, and with [`PolicyLists::redact()`] when a policy rule event is redacted.
+#[derive(Clone, Debug, Default)]
+pub struct PolicyLists {
+    /// The rules, by key.
+    rules: BTreeMap<RuleKey, PolicyRule>,
+
+    /// The keys of the rules, by event ID.
+    event_ids: BTreeMap<OwnedEventId, RuleKey>,
+
+    /// The keys of the rules without glob characters, by kind and entity.
+    literals: BTreeMap<(PolicyRuleKind, String), BTreeSet<RuleKey>>,
+
+    /// The parsed patterns of the rules with glob characters, by kind.
+    globs: BTreeMap<PolicyRuleKind, BTreeMap<RuleKey, WildMatch>>,
+}
+
+impl PolicyLists {
+    /// Creates an empty `PolicyLists`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update the rules with the given state event of the given policy room.
+    ///
+    /// Events that are not policy rules are ignored. A redacted policy rule event, or one without
+    /// an entity, removes the previous rule with the same type and state key.
+    ///
+    /// Returns `true` if the rules changed.
+    pub fn apply_event(&mut self, policy_room: &RoomId, event: &AnySyncStateEvent) -> bool {
+        use PolicyRuleKind::*;
+
+        let (kind, event_id, state_key, content) = match event {
+            AnySyncStateEvent::PolicyRuleUser(SyncStateEvent::Original(ev)) => {
+                (User, &ev.event_id, &ev.state_key, Some(&ev.content.0))
+            }
+            AnySyncStateEvent::PolicyRuleUser(SyncStateEvent::Redacted(ev)) => {
+                (User, &ev.event_id, &ev.state_key, None)
+            }
+            AnySyncStateEvent::PolicyRuleRoom(SyncStateEvent::Original(ev)) => {
+                (Room, &ev.event_id, &ev.state_key, Some(&ev.content.0))
+            }
+            AnySyncStateEvent::PolicyRuleRoom(SyncStateEvent::Redacted(ev)) => {
+                (Room, &ev.event_id, &ev.state_key, None)
+            }
+            AnySyncStateEvent::PolicyRuleServer(SyncStateEvent::Original(ev)) => {
+                (Server, &ev.event_id, &ev.state_key, Some(&ev.content.0))
+            }
+            AnySyncStateEvent::PolicyRuleServer(SyncStateEvent::Redacted(ev)) => {
+                (Server, &ev.event_id, &ev.state_key, None)
+            }
+            _ => return false,
+        };
+
+        let key =
+            RuleKey { kind, policy_room: policy_room.to_owned(), state_key: state_key.clone() };
+        let removed = self.remove(&key).is_some();
+
+        match content.filter(|content| !content.entity.is_empty()) {
+            Some(content) => {
+                self.insert(
+                    key,
+                    PolicyRule {
+                        policy_room: policy_room.to_owned(),
+                        kind,
+                        state_key: state_key.clone(),
+                        event_id: event_id.clone(),
+                        entity: content.entity.clone(),
+                        recommendation: content.recommendation.clone(),
+                        reason: content.reason.clone(),
+                    },
+                );
+                true
+            }
+            None => removed,
+        }
+    }
+
+    /// Remove the rule of the given policy rule event, if it is the current rule for its type and
+    /// state key.
+    ///
+    /// Returns `true` if a rule was removed.
+    pub fn redact(&mut self, event_id: &EventId) -> bool {
+        match self.event_ids.get(event_id).cloned() {
+            Some(key) => self.remove(&key).is_some(),
+            None => false,
+        }
+    }
+
+    /// Remove all the rules of the given policy room.
+    pub fn remove_policy_room(&mut self, policy_room: &RoomId) {
+        let keys: Vec<_> =
+            self.rules.keys().filter(|key| key.policy_room == policy_room).cloned().collect();
+
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+
+    /// All the rules.
+    pub fn rules(&self) -> impl Iterator<Item = &PolicyRule> {
+        self.rules.values()
+    }
+
+    /// The rules of the given kind whose entity matches the given entity.
+    ///
+    /// The rules without glob characters are returned first.
+    pub fn matching_rules<'a, 'b>(
+        &'a self,
+        kind: PolicyRuleKind,
+        entity: &'b str,
+    ) -> impl Iterator<Item = &'a PolicyRule> + 'b
+    where
+        'a: 'b,
+    {
+        let literals = self.literals.get(&(kind, entity.to_owned())).into_iter().flatten();
+        let globs = self
+            .globs
+            .get(&kind)
+            .into_iter()
+            .flatten()
+            .filter(move |(_, glob)| glob.matches(entity))
+            .map(|(key, _)| key);
+
+        literals.chain(globs).map(|key| &self.rules[key])
+    }
+
+    /// The first rule of the given kind that bans the given entity, if any.
+    pub fn ban(&self, kind: PolicyRuleKind, entity: &str) -> Option<&PolicyRule> {
+        self.matching_rules(kind, entity).find(|rule| rule.recommendation == Recommendation::Ban)
+    }
+
+    /// The first rule that bans the given user, if any.
+    ///
+    /// A user is banned by the user rules matching their user ID, and by the server rules
+    /// matching their server.
+    pub fn user_ban(&self, user_id: &UserId) -> Option<&PolicyRule> {
+        self.ban(PolicyRuleKind::User, user_id.as_str())
+            .or_else(|| self.server_ban(user_id.server_name()))
+    }
+
+    /// The first rule that bans the given server, if any.
+    ///
+    /// Like for server ACLs, the rules are matched against the host of the server name, without
+    /// the port.
+    pub fn server_ban(&self, server_name: &ServerName) -> Option<&PolicyRule> {
+        self.ban(PolicyRuleKind::Server, server_name.host())
+    }
+
+    /// The first rule that bans the given room ID or alias, if any.
+    pub fn room_ban(&self, room: &RoomOrAliasId) -> Option<&PolicyRule> {
+        self.ban(PolicyRuleKind::Room, room.as_str())
+    }
+
+    fn insert(&mut self, key: RuleKey, rule: PolicyRule) {
+        if rule.entity.contains(['*', '?']) {
+            let glob = WildMatch::new(&rule.entity);
+            self.globs.entry(key.kind).or_default().insert(key.clone(), glob);
+        } else {
+            self.literals.entry((key.kind, rule.entity.clone())).or_default().insert(key.clone());
+        }
+
+        self.event_ids.insert(rule.event_id.clone(), key.clone());
+        self.rules.insert(key, rule);
+    }
+
+    fn remove(&mut self, key: &RuleKey) -> Option<PolicyRule> {
+        let rule = self.rules.remove(key)?;
+
+        self.event_ids.remove(&rule.event_id);
+
+        if let Some(globs) = self.globs.get_mut(&key.kind) {
+            globs.remove(key);
+        }
+
+        let literal_key = (key.kind, rule.entity.clone());
+        if let Some(keys) = self.literals.get_mut(&literal_key) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.literals.remove(&literal_key);
+            }
+        }
+
+        Some(rule)
+    }
+}
+
+/// The key of a rule: its kind, policy room and state key.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct RuleKey {
+    kind: PolicyRuleKind,
+    policy_room: OwnedRoomId,
+    state_key: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma_common::{event_id, room_alias_id, room_id, server_name, user_id};
+    use serde_json::{from_value as from_json_value, json};
+
+    use super::{PolicyLists, PolicyRuleKind};
+    use crate::AnySyncStateEvent;
+
+    fn rule_event(
+        event_type: &str,
+        state_key: &str,
+        event_id: &str,
+        entity: &str,
+        recommendation: &str,
+    ) -> AnySyncStateEvent {
+        from_json_value(json!({
+            "content": {
+                "entity": entity,
+                "recommendation": recommendation,
+                "reason": format!("{entity} is spamming"),
+            },
+            "event_id": event_id,
+            "origin_server_ts": 1,
+            "sender": "@moderator:example.org",
+            "state_key": state_key,
+            "type": event_type,
+        }))
+        .unwrap()
+    }
+
+    fn policy_lists() -> PolicyLists {
+        let mut lists = PolicyLists::new();
+        let ban_list = room_id!("!ban_list:example.org");
+        let other_list = room_id!("!other_list:example.org");
+
+        let events = [
+            (
+                ban_list,
+                rule_event("m.policy.rule.user", "rule:1", "$1", "@spam:example.org", "m.ban"),
+            ),
+            (
+                ban_list,
+                rule_event("m.policy.rule.user", "rule:2", "$2", "@bot*:example.org", "m.ban"),
+            ),
+            (ban_list, rule_event("m.policy.rule.server", "rule:3", "$3", "*.evil.com", "m.ban")),
+            (
+                ban_list,
+                rule_event("m.policy.rule.room", "rule:4", "$4", "#spam:example.org", "m.ban"),
+            ),
+            (
+                other_list,
+                rule_event(
+                    "m.policy.rule.user",
+                    "rule:1",
+                    "$5",
+                    "@spam:example.org",
+                    "org.example.warn",
+                ),
+            ),
+            (
+                other_list,
+                rule_event("m.policy.rule.room", "rule:2", "$6", "!spam:example.org", "m.ban"),
+            ),
+        ];
+
+        for (room_id, event) in &events {
+            assert!(lists.apply_event(room_id, event));
+        }
+
+        lists
+    }
+
+    #[test]
+    fn bans() {
+        let lists = policy_lists();
+        assert_eq!(lists.rules().count(), 6);
+
+        let rule = lists.user_ban(user_id!("@spam:example.org")).unwrap();
+        assert_eq!(rule.policy_room, "!ban_list:example.org");
+        assert_eq!(rule.event_id, "$1");
+        assert_eq!(rule.reason, "@spam:example.org is spamming");
+        assert_eq!(lists.matching_rules(PolicyRuleKind::User, "@spam:example.org").count(), 2);
+
+        assert_eq!(lists.user_ban(user_id!("@bot42:example.org")).unwrap().event_id, "$2");
+        assert!(lists.user_ban(user_id!("@alice:example.org")).is_none());
+
+        // Users are banned by the rules of their server.
+        assert_eq!(lists.user_ban(user_id!("@alice:matrix.evil.com")).unwrap().event_id, "$3");
+        assert_eq!(lists.server_ban(server_name!("matrix.evil.com:8448")).unwrap().event_id, "$3");
+        assert!(lists.server_ban(server_name!("evil.com")).is_none());
+
+        assert_eq!(
+            lists.room_ban(room_alias_id!("#spam:example.org").into()).unwrap().event_id,
+            "$4"
+        );
+        assert_eq!(lists.room_ban(room_id!("!spam:example.org").into()).unwrap().event_id, "$6");
+        assert!(lists.room_ban(room_id!("!room:example.org").into()).is_none());
+
+        // Only the rules of the right kind are used.
+        assert!(lists.ban(PolicyRuleKind::Server, "@spam:example.org").is_none());
+    }
+
+    #[test]
+    fn update_rules() {
+        let mut lists = policy_lists();
+        let ban_list = room_id!("!ban_list:example.org");
+
+        // Replace a rule.
+        let event = rule_event("m.policy.rule.user", "rule:1", "$7", "@other:example.org", "m.ban");
+        assert!(lists.apply_event(ban_list, &event));
+        assert!(lists.user_ban(user_id!("@spam:example.org")).is_none());
+        assert_eq!(lists.user_ban(user_id!("@other:example.org")).unwrap().event_id, "$7");
+
+        // Redacting a replaced event doesn't change anything.
+        assert!(!lists.redact(event_id!("$1")));
+        assert!(lists.redact(event_id!("$7")));
+        assert!(lists.user_ban(user_id!("@other:example.org")).is_none());
+
+        // A rule without entity removes the rule.
+        let event = rule_event("m.policy.rule.user", "rule:2", "$8", "", "m.ban");
+        assert!(lists.apply_event(ban_list, &event));
+        assert!(lists.user_ban(user_id!("@bot42:example.org")).is_none());
+
+        // A redacted event removes the rule.
+        let event: AnySyncStateEvent = from_json_value(json!({
+            "content": {},
+            "event_id": "$9",
+            "origin_server_ts": 1,
+            "sender": "@moderator:example.org",
+            "state_key": "rule:3",
+            "type": "m.policy.rule.server",
+            "unsigned": {
+                "redacted_because": {
+                    "content": {},
+                    "event_id": "$10",
+                    "origin_server_ts": 1,
+                    "redacts": "$3",
+                    "sender": "@moderator:example.org",
+                    "type": "m.room.redaction",
+                },
+            },
+        }))
+        .unwrap();
+        assert!(lists.apply_event(ban_list, &event));
+        assert!(lists.server_ban(server_name!("matrix.evil.com")).is_none());
+
+        lists.remove_policy_room(room_id!("!other_list:example.org"));
+        assert!(lists.room_ban(room_id!("!spam:example.org").into()).is_none());
+        assert_eq!(lists.rules().count(), 1);
+    }
+}
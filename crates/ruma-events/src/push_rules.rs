@@ -6,6 +6,8 @@ use ruma_common::push::Ruleset;
 use ruma_macros::EventContent;
 use serde::{Deserialize, Serialize};
 
+pub mod evaluator;
+
 /// The content of an `m.push_rules` event.
 ///
 /// Describes all push rules for a user.
@@ -0,0 +1,537 @@
+//! Evaluation of push rules on the timeline of a room, with notification counts.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use js_int::UInt;
+use ruma_common::{
+    push::{Action, PushConditionRoomCtx, Ruleset},
+    serde::Raw,
+    EventId, OwnedEventId, OwnedRoomId, OwnedUserId,
+};
+use serde::Deserialize;
+
+use crate::{
+    receipt::{ReceiptEventContent, ReceiptThread, ReceiptType},
+    room::{member::MembershipState, power_levels::RoomPowerLevels},
+    AnySyncStateEvent, AnySyncTimelineEvent, SyncStateEvent,
+};
+
+/// The notification counts of a room or a thread.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[allow(clippy::exhaustive_structs)]
+pub struct NotificationCounts {
+    /// The number of unread events that notify the user.
+    pub notification_count: u64,
+
+    /// The number of unread events that notify the user with a highlight.
+    pub highlight_count: u64,
+}
+
+/// An evaluator of the push rules of a user on the timeline of a room.
+///
+/// It keeps track of the state of the room needed to evaluate the push rules: the number of
+/// joined members, the display name of the user and the power levels. This state must be
+/// initialized with [`apply_state_event()`] and is then updated with the state events of the
+/// timeline.
+///
+/// The unread notifications are counted in the main timeline and per thread, and the read
+/// receipts of the user and the events sent by the user mark the previous events as read.
+///
+/// [`apply_state_event()`]: Self::apply_state_event
+#[derive(Clone, Debug)]
+pub struct RoomPushEvaluator {
+    /// The push rules of the user.
+    ruleset: Ruleset,
+
+    /// The context of the room, at the end of the timeline.
+    context: PushConditionRoomCtx,
+
+    /// The joined members of the room.
+    joined_members: BTreeSet<OwnedUserId>,
+
+    /// The positions of the events in the timeline.
+    positions: BTreeMap<OwnedEventId, usize>,
+
+    /// The events that notify the user, in the order of the timeline.
+    notifications: Vec<Notification>,
+
+    /// The position of the last unthreaded read receipt.
+    unthreaded_read: Option<usize>,
+
+    /// The position of the last read receipt in the main timeline.
+    main_read: Option<usize>,
+
+    /// The positions of the last read receipts in threads, by thread root.
+    thread_read: BTreeMap<OwnedEventId, usize>,
+}
+
+impl RoomPushEvaluator {
+    /// Creates a new `RoomPushEvaluator` for the given push rules, room and user.
+    pub fn new(ruleset: Ruleset, room_id: OwnedRoomId, user_id: OwnedUserId) -> Self {
+        Self {
+            ruleset,
+            context: PushConditionRoomCtx::new(room_id, UInt::MIN, user_id, String::new()),
+            joined_members: BTreeSet::new(),
+            positions: BTreeMap::new(),
+            notifications: Vec::new(),
+            unthreaded_read: None,
+            main_read: None,
+            thread_read: BTreeMap::new(),
+        }
+    }
+
+    /// Replace the push rules of the user.
+    ///
+    /// This doesn't change the actions of the events that were already evaluated.
+    pub fn set_ruleset(&mut self, ruleset: Ruleset) {
+        self.ruleset = ruleset;
+    }
+
+    /// The context of the room used to evaluate the push rules of the next event.
+    pub fn context(&self) -> &PushConditionRoomCtx {
+        &self.context
+    }
+
+    /// Update the state of the room with the given state event.
+    ///
+    /// `m.room.member` events update the number of joined members and the display name of the
+    /// user, and `m.room.power_levels` events update the power levels. Other events are ignored.
+    pub fn apply_state_event(&mut self, event: &AnySyncStateEvent) {
+        match event {
+            AnySyncStateEvent::RoomMember(event) => {
+                let (user_id, membership, display_name) = match event {
+                    SyncStateEvent::Original(ev) => {
+                        (&ev.state_key, &ev.content.membership, ev.content.displayname.as_deref())
+                    }
+                    SyncStateEvent::Redacted(ev) => (&ev.state_key, &ev.content.membership, None),
+                };
+
+                if *membership == MembershipState::Join {
+                    self.joined_members.insert(user_id.clone());
+                } else {
+                    self.joined_members.remove(user_id);
+                }
+                self.context.member_count =
+                    UInt::try_from(self.joined_members.len()).unwrap_or(UInt::MAX);
+
+                // Without a display name, the user cannot be mentioned by it.
+                if *user_id == self.context.user_id {
+                    self.context.user_display_name = display_name.unwrap_or_default().to_owned();
+                }
+            }
+            AnySyncStateEvent::RoomPowerLevels(event) => {
+                let power_levels = match event {
+                    SyncStateEvent::Original(ev) => RoomPowerLevels::from(ev.content.clone()),
+                    SyncStateEvent::Redacted(ev) => RoomPowerLevels::from(ev.content.clone()),
+                };
+                self.context.power_levels = Some(power_levels.into());
+            }
+            _ => {}
+        }
+    }
+
+    /// Evaluate the push rules on the given event of the timeline, and update the state and the
+    /// notification counts of the room.
+    ///
+    /// The event is evaluated with the state of the room before the event. Events that were
+    /// already evaluated and events sent by the user don't notify. An event sent by the user
+    /// marks the previous events of its thread as read.
+    ///
+    /// Returns the actions of the push rule that applies to the event.
+    pub fn handle_timeline_event(&mut self, event: &Raw<AnySyncTimelineEvent>) -> Vec<Action> {
+        let Ok(info) = event.deserialize_as::<TimelineEventInfo>() else {
+            return Vec::new();
+        };
+
+        if self.positions.contains_key(&info.event_id) {
+            return Vec::new();
+        }
+
+        let position = self.positions.len();
+        self.positions.insert(info.event_id.clone(), position);
+
+        let thread_root = info.content.relates_to.and_then(|relation| {
+            (relation.rel_type.as_deref() == Some("m.thread"))
+                .then_some(relation.event_id)
+                .flatten()
+        });
+
+        let actions = if info.sender == self.context.user_id {
+            let thread = match &thread_root {
+                Some(thread_root) => ReceiptThread::Thread(thread_root.clone()),
+                None => ReceiptThread::Main,
+            };
+            self.handle_read_receipt(&info.event_id, &thread);
+
+            Vec::new()
+        } else {
+            self.ruleset.get_actions(event, &self.context).to_vec()
+        };
+
+        if actions.iter().any(Action::should_notify) {
+            self.notifications.push(Notification {
+                position,
+                thread_root,
+                highlight: actions.iter().any(Action::is_highlight),
+            });
+        }
+
+        if info.state_key.is_some() {
+            if let Ok(state_event) = event.deserialize_as::<AnySyncStateEvent>() {
+                self.apply_state_event(&state_event);
+            }
+        }
+
+        actions
+    }
+
+    /// Mark the events up to the given event in the given thread as read.
+    ///
+    /// An unthreaded receipt marks the events of the main timeline and of all the threads as
+    /// read. Receipts for unknown events are ignored.
+    pub fn handle_read_receipt(&mut self, event_id: &EventId, thread: &ReceiptThread) {
+        let Some(&position) = self.positions.get(event_id) else {
+            return;
+        };
+
+        let read = match thread {
+            ReceiptThread::Unthreaded => &mut self.unthreaded_read,
+            ReceiptThread::Main => &mut self.main_read,
+            ReceiptThread::Thread(thread_root) => {
+                let read = self.thread_read.entry(thread_root.clone()).or_insert(position);
+                *read = (*read).max(position);
+                return;
+            }
+            _ => return,
+        };
+
+        *read = Some(read.map_or(position, |read| read.max(position)));
+    }
+
+    /// Apply the public and private read receipts of the user in the given receipt event.
+    pub fn handle_receipt_event(&mut self, content: &ReceiptEventContent) {
+        for (event_id, receipts) in &content.0 {
+            for receipt_type in [ReceiptType::Read, ReceiptType::ReadPrivate] {
+                if let Some(receipt) =
+                    receipts.get(&receipt_type).and_then(|users| users.get(&self.context.user_id))
+                {
+                    self.handle_read_receipt(event_id, &receipt.thread);
+                }
+            }
+        }
+    }
+
+    /// The notification counts of the main timeline of the room.
+    pub fn main_counts(&self) -> NotificationCounts {
+        self.counts(|notification| notification.thread_root.is_none())
+    }
+
+    /// The notification counts of the threads of the room, by thread root.
+    ///
+    /// Threads without unread notifications are not included.
+    pub fn thread_counts(&self) -> BTreeMap<OwnedEventId, NotificationCounts> {
+        let mut thread_counts = BTreeMap::<_, NotificationCounts>::new();
+
+        for notification in self.unread_notifications() {
+            if let Some(thread_root) = &notification.thread_root {
+                let counts = thread_counts.entry(thread_root.clone()).or_default();
+                counts.notification_count += 1;
+                counts.highlight_count += u64::from(notification.highlight);
+            }
+        }
+
+        thread_counts
+    }
+
+    /// The notification counts of the room, including the threads.
+    pub fn total_counts(&self) -> NotificationCounts {
+        self.counts(|_| true)
+    }
+
+    fn counts(&self, filter: impl Fn(&Notification) -> bool) -> NotificationCounts {
+        let mut counts = NotificationCounts::default();
+
+        for notification in self.unread_notifications().filter(|notification| filter(notification))
+        {
+            counts.notification_count += 1;
+            counts.highlight_count += u64::from(notification.highlight);
+        }
+
+        counts
+    }
+
+    fn unread_notifications(&self) -> impl Iterator<Item = &Notification> {
+        self.notifications.iter().filter(|notification| {
+            let thread_read = match &notification.thread_root {
+                Some(thread_root) => self.thread_read.get(thread_root).copied(),
+                None => self.main_read,
+            };
+
+            thread_read.max(self.unthreaded_read).map_or(true, |read| notification.position > read)
+        })
+    }
+}
+
+/// An event that notifies the user.
+#[derive(Clone, Debug)]
+struct Notification {
+    /// The position of the event in the timeline.
+    position: usize,
+
+    /// The root of the thread of the event, if it is in a thread.
+    thread_root: Option<OwnedEventId>,
+
+    /// Whether the event notifies with a highlight.
+    highlight: bool,
+}
+
+/// The fields of a timeline event needed by the evaluator.
+#[derive(Deserialize)]
+struct TimelineEventInfo {
+    event_id: OwnedEventId,
+    sender: OwnedUserId,
+    state_key: Option<String>,
+    #[serde(default)]
+    content: ContentInfo,
+}
+
+#[derive(Default, Deserialize)]
+struct ContentInfo {
+    #[serde(rename = "m.relates_to")]
+    relates_to: Option<RelationInfo>,
+}
+
+#[derive(Deserialize)]
+struct RelationInfo {
+    rel_type: Option<String>,
+    event_id: Option<OwnedEventId>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use js_int::uint;
+    use ruma_common::{
+        owned_event_id, owned_room_id, owned_user_id,
+        push::{Action, RuleKind, Ruleset, Tweak},
+        serde::Raw,
+        user_id,
+    };
+    use serde_json::{from_value as from_json_value, json, Value as JsonValue};
+
+    use super::{NotificationCounts, RoomPushEvaluator};
+    use crate::receipt::ReceiptThread;
+
+    fn evaluator() -> RoomPushEvaluator {
+        let mut evaluator = RoomPushEvaluator::new(
+            Ruleset::server_default(user_id!("@alice:example.org")),
+            owned_room_id!("!room:example.org"),
+            owned_user_id!("@alice:example.org"),
+        );
+
+        for user in ["@alice:example.org", "@bob:example.org", "@carl:example.org"] {
+            evaluator.apply_state_event(&from_json_value(member(user, "Margatroid", 0)).unwrap());
+        }
+        evaluator
+    }
+
+    fn member(user_id: &str, display_name: &str, n: u32) -> JsonValue {
+        json!({
+            "content": { "membership": "join", "displayname": display_name },
+            "event_id": format!("$member{n}"),
+            "origin_server_ts": 1,
+            "sender": user_id,
+            "state_key": user_id,
+            "type": "m.room.member",
+        })
+    }
+
+    fn message(event_id: &str, sender: &str, body: &str, thread_root: Option<&str>) -> JsonValue {
+        let mut content = json!({ "msgtype": "m.text", "body": body });
+        if let Some(thread_root) = thread_root {
+            content["m.relates_to"] = json!({ "rel_type": "m.thread", "event_id": thread_root });
+        }
+
+        json!({
+            "content": content,
+            "event_id": event_id,
+            "origin_server_ts": 1,
+            "sender": sender,
+            "type": "m.room.message",
+        })
+    }
+
+    fn handle(evaluator: &mut RoomPushEvaluator, event: JsonValue) -> Vec<Action> {
+        evaluator.handle_timeline_event(&Raw::new(&event).unwrap().cast())
+    }
+
+    fn is_sound(action: &Action) -> bool {
+        matches!(action, Action::SetTweak(Tweak::Sound(_)))
+    }
+
+    fn counts(notification_count: u64, highlight_count: u64) -> NotificationCounts {
+        NotificationCounts { notification_count, highlight_count }
+    }
+
+    #[test]
+    fn actions_and_counts() {
+        let mut evaluator = evaluator();
+        assert_eq!(evaluator.context().member_count, uint!(3));
+
+        // In a room with more than 2 members, messages notify without sound.
+        let actions = handle(&mut evaluator, message("$1", "@bob:example.org", "Hello", None));
+        assert!(actions.iter().any(Action::should_notify));
+        assert!(!actions.iter().any(is_sound));
+
+        let actions =
+            handle(&mut evaluator, message("$2", "@bob:example.org", "Hello Margatroid", None));
+        assert!(actions.iter().any(Action::should_notify));
+        assert!(actions.iter().any(Action::is_highlight));
+
+        // Once carl leaves, the room is a 1:1 room.
+        let leave = json!({
+            "content": { "membership": "leave" },
+            "event_id": "$3",
+            "origin_server_ts": 1,
+            "sender": "@carl:example.org",
+            "state_key": "@carl:example.org",
+            "type": "m.room.member",
+        });
+        handle(&mut evaluator, leave);
+        assert_eq!(evaluator.context().member_count, uint!(2));
+
+        let actions = handle(&mut evaluator, message("$4", "@bob:example.org", "Hi", Some("$1")));
+        assert!(actions.iter().any(Action::should_notify));
+        assert!(actions.iter().any(is_sound));
+
+        assert_eq!(evaluator.main_counts(), counts(2, 1));
+        assert_eq!(
+            evaluator.thread_counts(),
+            BTreeMap::from([(owned_event_id!("$1"), counts(1, 0))])
+        );
+        assert_eq!(evaluator.total_counts(), counts(3, 1));
+
+        // The same event is not counted twice.
+        assert!(
+            handle(&mut evaluator, message("$4", "@bob:example.org", "Hi", Some("$1"))).is_empty()
+        );
+        assert_eq!(evaluator.total_counts(), counts(3, 1));
+    }
+
+    #[test]
+    fn display_name_change() {
+        let mut evaluator = evaluator();
+
+        handle(&mut evaluator, member("@alice:example.org", "Marisa", 1));
+        assert_eq!(evaluator.context().user_display_name, "Marisa");
+
+        let actions = handle(&mut evaluator, message("$1", "@bob:example.org", "Margatroid", None));
+        assert!(!actions.iter().any(Action::is_highlight));
+        let actions =
+            handle(&mut evaluator, message("$2", "@bob:example.org", "Hey Marisa!", None));
+        assert!(actions.iter().any(Action::is_highlight));
+    }
+
+    #[test]
+    fn no_display_name() {
+        // Disable the legacy rule that matches the localpart of the user.
+        let mut ruleset = Ruleset::server_default(user_id!("@alice:example.org"));
+        ruleset.set_enabled(RuleKind::Content, ".m.rule.contains_user_name", false).unwrap();
+
+        let mut evaluator = RoomPushEvaluator::new(
+            ruleset,
+            owned_room_id!("!room:example.org"),
+            owned_user_id!("@alice:example.org"),
+        );
+        let mut member = member("@alice:example.org", "", 0);
+        member["content"].as_object_mut().unwrap().remove("displayname");
+        evaluator.apply_state_event(&from_json_value(member).unwrap());
+        assert_eq!(evaluator.context().user_display_name, "");
+
+        // The localpart is not used as a display name.
+        let actions =
+            handle(&mut evaluator, message("$1", "@bob:example.org", "Where is alice?", None));
+        assert!(!actions.iter().any(Action::is_highlight));
+    }
+
+    #[test]
+    fn power_levels() {
+        let mut evaluator = evaluator();
+        let room_mention = |event_id: &str, sender: &str| {
+            let mut event = message(event_id, sender, "@room wake up", None);
+            event["content"]["m.mentions"] = json!({ "room": true });
+            event
+        };
+
+        // Without power levels, room mentions don't highlight.
+        let actions = handle(&mut evaluator, room_mention("$1", "@bob:example.org"));
+        assert!(!actions.iter().any(Action::is_highlight));
+
+        let power_levels = json!({
+            "content": { "users": { "@bob:example.org": 50 } },
+            "event_id": "$2",
+            "origin_server_ts": 1,
+            "sender": "@bob:example.org",
+            "state_key": "",
+            "type": "m.room.power_levels",
+        });
+        handle(&mut evaluator, power_levels);
+        assert!(evaluator.context().power_levels.is_some());
+
+        let actions = handle(&mut evaluator, room_mention("$3", "@bob:example.org"));
+        assert!(actions.iter().any(Action::is_highlight));
+        let actions = handle(&mut evaluator, room_mention("$4", "@carl:example.org"));
+        assert!(!actions.iter().any(Action::is_highlight));
+    }
+
+    #[test]
+    fn read_receipts() {
+        let mut evaluator = evaluator();
+        let bob = "@bob:example.org";
+
+        handle(&mut evaluator, message("$1", bob, "Alice Margatroid", None));
+        handle(&mut evaluator, message("$2", bob, "Alice Margatroid?", Some("$1")));
+        handle(&mut evaluator, message("$3", bob, "Alice Margatroid!", None));
+        handle(&mut evaluator, message("$4", bob, "Alice Margatroid!!", Some("$1")));
+        assert_eq!(evaluator.main_counts(), counts(2, 2));
+        assert_eq!(evaluator.total_counts(), counts(4, 4));
+
+        // A receipt in the main timeline doesn't mark the threads as read.
+        evaluator.handle_read_receipt(&owned_event_id!("$3"), &ReceiptThread::Main);
+        assert_eq!(evaluator.main_counts(), counts(0, 0));
+        assert_eq!(evaluator.total_counts(), counts(2, 2));
+
+        evaluator.handle_read_receipt(
+            &owned_event_id!("$2"),
+            &ReceiptThread::Thread(owned_event_id!("$1")),
+        );
+        assert_eq!(evaluator.total_counts(), counts(1, 1));
+
+        // Sending a message in the thread marks it as read.
+        handle(&mut evaluator, message("$5", "@alice:example.org", "Yes?", Some("$1")));
+        assert_eq!(evaluator.total_counts(), counts(0, 0));
+
+        // An unthreaded receipt marks everything as read, from an `m.receipt` event.
+        handle(&mut evaluator, message("$6", bob, "Alice Margatroid", None));
+        handle(&mut evaluator, message("$7", bob, "Alice Margatroid", Some("$1")));
+        assert_eq!(evaluator.total_counts(), counts(2, 2));
+
+        let receipt = from_json_value(json!({
+            "$7": { "m.read.private": { "@alice:example.org": { "ts": 1 } } },
+        }))
+        .unwrap();
+        evaluator.handle_receipt_event(&receipt);
+        assert_eq!(evaluator.total_counts(), counts(0, 0));
+
+        // Receipts of other users are ignored, and receipts don't go backwards.
+        handle(&mut evaluator, message("$8", bob, "Alice Margatroid", None));
+        let receipt = from_json_value(json!({
+            "$8": { "m.read": { "@bob:example.org": { "ts": 1 } } },
+            "$1": { "m.read": { "@alice:example.org": { "ts": 1 } } },
+        }))
+        .unwrap();
+        evaluator.handle_receipt_event(&receipt);
+        assert_eq!(evaluator.total_counts(), counts(1, 1));
+    }
+}
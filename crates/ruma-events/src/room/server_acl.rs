@@ -2,6 +2,8 @@
 //!
 //! [`m.room.server_acl`]: https://spec.matrix.org/latest/client-server-api/#mroomserver_acl
 
+use std::collections::BTreeSet;
+
 use ruma_common::ServerName;
 use ruma_macros::EventContent;
 use serde::{Deserialize, Serialize};
@@ -54,6 +56,9 @@ impl RoomServerAclEventContent {
     }
 
     /// Returns true if and only if the server is allowed by the ACL rules.
+    ///
+    /// This compiles the patterns of the ACL on every call. To check many servers against the same
+    /// ACL, use [`RoomServerAclEventContent::compile()`] instead.
     pub fn is_allowed(&self, server_name: &ServerName) -> bool {
         if !self.allow_ip_literals && server_name.is_ip_literal() {
             return false;
@@ -64,6 +69,87 @@ impl RoomServerAclEventContent {
         self.deny.iter().all(|d| !WildMatch::new(d).matches(host))
             && self.allow.iter().any(|a| WildMatch::new(a).matches(host))
     }
+
+    /// Compile the patterns of this ACL, to check servers against it efficiently.
+    pub fn compile(&self) -> CompiledServerAcl {
+        CompiledServerAcl {
+            allow_ip_literals: self.allow_ip_literals,
+            allow: ServerNamePatterns::new(&self.allow),
+            deny: ServerNamePatterns::new(&self.deny),
+        }
+    }
+}
+
+/// The compiled form of a [`RoomServerAclEventContent`].
+///
+/// The patterns without wildcards are matched with a lookup in a set, and the patterns with
+/// wildcards are only parsed once.
+#[derive(Clone, Debug)]
+pub struct CompiledServerAcl {
+    /// Whether to allow server names that are IP address literals.
+    allow_ip_literals: bool,
+
+    /// The patterns of the servers to allow.
+    allow: ServerNamePatterns,
+
+    /// The patterns of the servers to deny.
+    deny: ServerNamePatterns,
+}
+
+impl CompiledServerAcl {
+    /// Returns true if and only if the server is allowed by the ACL rules.
+    pub fn is_allowed(&self, server_name: &ServerName) -> bool {
+        if !self.allow_ip_literals && server_name.is_ip_literal() {
+            return false;
+        }
+
+        let host = server_name.host();
+
+        !self.deny.matches(host) && self.allow.matches(host)
+    }
+}
+
+impl From<&RoomServerAclEventContent> for CompiledServerAcl {
+    fn from(content: &RoomServerAclEventContent) -> Self {
+        content.compile()
+    }
+}
+
+/// A compiled list of server name patterns.
+#[derive(Clone, Debug)]
+struct ServerNamePatterns {
+    /// Whether the list contains a pattern that matches any server name.
+    any: bool,
+
+    /// The patterns without wildcards.
+    literals: BTreeSet<String>,
+
+    /// The patterns with wildcards.
+    globs: Vec<WildMatch>,
+}
+
+impl ServerNamePatterns {
+    fn new(patterns: &[String]) -> Self {
+        let mut any = false;
+        let mut literals = BTreeSet::new();
+        let mut globs = Vec::new();
+
+        for pattern in patterns {
+            if !pattern.is_empty() && pattern.chars().all(|c| c == '*') {
+                any = true;
+            } else if pattern.contains(['*', '?']) {
+                globs.push(WildMatch::new(pattern));
+            } else {
+                literals.insert(pattern.clone());
+            }
+        }
+
+        Self { any, literals, globs }
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        self.any || self.literals.contains(host) || self.globs.iter().any(|glob| glob.matches(host))
+    }
 }
 
 #[cfg(test)]
@@ -71,7 +157,7 @@ mod tests {
     use ruma_common::server_name;
     use serde_json::{from_value as from_json_value, json};
 
-    use super::RoomServerAclEventContent;
+    use super::{CompiledServerAcl, RoomServerAclEventContent};
     use crate::OriginalStateEvent;
 
     #[test]
@@ -178,4 +264,70 @@ mod tests {
         assert!(!acl_event.is_allowed(server_name!("[2001:db8:1234::2]")));
         assert!(acl_event.is_allowed(server_name!("[2001:db8:1234::1]")));
     }
+
+    #[test]
+    fn compiled_acl_spec_example() {
+        let acl_event = RoomServerAclEventContent {
+            allow_ip_literals: false,
+            allow: vec!["*".to_owned()],
+            deny: vec!["*.evil.com".to_owned(), "evil.com".to_owned()],
+        };
+        let acl = CompiledServerAcl::from(&acl_event);
+
+        for server_name in [server_name!("good.com"), server_name!("good.com:8448")] {
+            assert!(acl.is_allowed(server_name), "{server_name}");
+            assert!(acl_event.is_allowed(server_name), "{server_name}");
+        }
+
+        for server_name in [
+            server_name!("evil.com"),
+            server_name!("evil.com:8448"),
+            server_name!("sub.evil.com"),
+            server_name!("1.1.1.1"),
+            server_name!("[2001:db8:1234::1]:8448"),
+        ] {
+            assert!(!acl.is_allowed(server_name), "{server_name}");
+            assert!(!acl_event.is_allowed(server_name), "{server_name}");
+        }
+    }
+
+    #[test]
+    fn compiled_acl_matches_uncompiled() {
+        let acl_event = RoomServerAclEventContent {
+            allow_ip_literals: true,
+            allow: vec![
+                "conduit.rs".to_owned(),
+                "*.matrix.org".to_owned(),
+                "matrix??.org".to_owned(),
+                "[2001:db8:1234::1]".to_owned(),
+            ],
+            deny: vec!["bad.matrix.org".to_owned()],
+        };
+        let acl = acl_event.compile();
+
+        for server_name in [
+            server_name!("conduit.rs"),
+            server_name!("conduit.rs:443"),
+            server_name!("matrix.org"),
+            server_name!("server.matrix.org"),
+            server_name!("bad.matrix.org"),
+            server_name!("matrix1.org"),
+            server_name!("matrix02.org"),
+            server_name!("[2001:db8:1234::1]"),
+            server_name!("[2001:db8:1234::2]"),
+            server_name!("1.1.1.1"),
+        ] {
+            assert_eq!(
+                acl.is_allowed(server_name),
+                acl_event.is_allowed(server_name),
+                "{server_name}"
+            );
+        }
+    }
+
+    #[test]
+    fn compiled_acl_empty_allow() {
+        let acl = RoomServerAclEventContent::new(true, Vec::new(), Vec::new()).compile();
+        assert!(!acl.is_allowed(server_name!("matrix.org")));
+    }
 }
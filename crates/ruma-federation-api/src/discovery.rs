@@ -4,6 +4,7 @@ use std::collections::BTreeMap;
 
 use ruma_common::{
     serde::Base64, MilliSecondsSinceUnixEpoch, OwnedServerName, OwnedServerSigningKeyId,
+    ServerSigningKeyId,
 };
 use serde::{Deserialize, Serialize};
 
@@ -88,4 +89,95 @@ impl ServerSigningKeys {
             valid_until_ts,
         }
     }
+
+    /// Moves the verify key with the given ID to `old_verify_keys`, with the given expiry time.
+    ///
+    /// Returns the new entry of `old_verify_keys`, or `None` if there is no verify key with this
+    /// ID.
+    ///
+    /// The `signatures` are cleared if the key was found, since they are not valid anymore. The
+    /// keys must be signed again before they are published.
+    pub fn expire_verify_key(
+        &mut self,
+        key_id: &ServerSigningKeyId,
+        expired_ts: MilliSecondsSinceUnixEpoch,
+    ) -> Option<OldVerifyKey> {
+        let (key_id, verify_key) = self.verify_keys.remove_entry(key_id)?;
+        let old_verify_key = OldVerifyKey::new(expired_ts, verify_key.key);
+
+        self.old_verify_keys.insert(key_id, old_verify_key.clone());
+        self.signatures.clear();
+
+        Some(old_verify_key)
+    }
+
+    /// Replaces the verify keys with the given new key.
+    ///
+    /// The current verify keys are moved to `old_verify_keys`, with the given expiry time, so
+    /// other servers can still verify what was signed with them.
+    ///
+    /// The `signatures` are cleared, since they are not valid anymore. The keys must be signed
+    /// again with the new key before they are published.
+    pub fn rotate_verify_key(
+        &mut self,
+        key_id: OwnedServerSigningKeyId,
+        key: VerifyKey,
+        expired_ts: MilliSecondsSinceUnixEpoch,
+    ) {
+        let old_verify_keys = std::mem::take(&mut self.verify_keys)
+            .into_iter()
+            .map(|(key_id, verify_key)| (key_id, OldVerifyKey::new(expired_ts, verify_key.key)));
+
+        self.old_verify_keys.extend(old_verify_keys);
+        self.old_verify_keys.remove(&key_id);
+        self.verify_keys.insert(key_id, key);
+        self.signatures.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use js_int::uint;
+    use ruma_common::{serde::Base64, server_name, MilliSecondsSinceUnixEpoch, ServerSigningKeyId};
+
+    use super::{ServerSigningKeys, VerifyKey};
+
+    #[test]
+    fn rotate_verify_key() {
+        let mut keys = ServerSigningKeys::new(
+            server_name!("example.org").to_owned(),
+            MilliSecondsSinceUnixEpoch(uint!(1_000)),
+        );
+        keys.verify_keys
+            .insert("ed25519:1".try_into().unwrap(), VerifyKey::new(Base64::new(b"key1".to_vec())));
+        keys.signatures
+            .entry(server_name!("example.org").to_owned())
+            .or_default()
+            .insert("ed25519:1".try_into().unwrap(), "signature".to_owned());
+
+        keys.rotate_verify_key(
+            "ed25519:2".try_into().unwrap(),
+            VerifyKey::new(Base64::new(b"key2".to_vec())),
+            MilliSecondsSinceUnixEpoch(uint!(500)),
+        );
+
+        let key_id_1 = <&ServerSigningKeyId>::try_from("ed25519:1").unwrap();
+        let key_id_2 = <&ServerSigningKeyId>::try_from("ed25519:2").unwrap();
+
+        assert_eq!(keys.verify_keys.len(), 1);
+        assert_eq!(keys.verify_keys[key_id_2].key.as_bytes(), b"key2");
+        assert_eq!(keys.old_verify_keys.len(), 1);
+        let old_key = &keys.old_verify_keys[key_id_1];
+        assert_eq!(old_key.key.as_bytes(), b"key1");
+        assert_eq!(old_key.expired_ts, MilliSecondsSinceUnixEpoch(uint!(500)));
+        assert!(keys.signatures.is_empty());
+
+        let old_key =
+            keys.expire_verify_key(key_id_2, MilliSecondsSinceUnixEpoch(uint!(800))).unwrap();
+        assert_eq!(old_key.key.as_bytes(), b"key2");
+        assert!(keys.verify_keys.is_empty());
+        assert_eq!(keys.old_verify_keys.len(), 2);
+
+        assert!(keys.expire_verify_key(key_id_2, MilliSecondsSinceUnixEpoch(uint!(800))).is_none());
+    }
 }
@@ -18,6 +18,8 @@ use tracing::debug;
 
 #[cfg(feature = "matrix")]
 pub mod matrix;
+#[cfg(feature = "matrix")]
+mod text;
 
 use crate::SanitizerConfig;
 
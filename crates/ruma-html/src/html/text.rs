@@ -0,0 +1,612 @@
+//! Conversion of HTML to plain text and Markdown.
+
+use as_variant::as_variant;
+use ruma_common::matrix_uri::MatrixId;
+
+use super::{
+    matrix::{AnchorUri, MatrixElement},
+    Html, NodeData, NodeRef,
+};
+
+impl Html {
+    /// Convert this HTML to plain text.
+    ///
+    /// This can be used to generate the plain text fallback of a formatted message, or to display
+    /// it where HTML is not supported.
+    ///
+    /// Blocks are separated by blank lines, list items are prefixed by `-` or by their number,
+    /// and blockquotes are prefixed by `>`. Links are followed by their URL, unless they are
+    /// mentions or their text is already the URL. The content of [spoilers] is hidden, and the
+    /// [rich reply fallback] is removed.
+    ///
+    /// [spoilers]: https://spec.matrix.org/latest/client-server-api/#spoiler-messages
+    /// [rich reply fallback]: https://spec.matrix.org/latest/client-server-api/#fallbacks-for-rich-replies
+    pub fn to_plain_text(&self) -> String {
+        TextWriter::new(TextFormat::PlainText).render(self)
+    }
+
+    /// Convert this HTML to [CommonMark].
+    ///
+    /// The elements that don't have an equivalent in CommonMark, like strikethrough, underline
+    /// or [spoilers], are kept as inline HTML. The special characters of the text are escaped,
+    /// and the [rich reply fallback] is removed.
+    ///
+    /// [CommonMark]: https://commonmark.org/
+    /// [spoilers]: https://spec.matrix.org/latest/client-server-api/#spoiler-messages
+    /// [rich reply fallback]: https://spec.matrix.org/latest/client-server-api/#fallbacks-for-rich-replies
+    pub fn to_markdown(&self) -> String {
+        TextWriter::new(TextFormat::Markdown).render(self)
+    }
+}
+
+/// The format of the text generated by a [`TextWriter`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TextFormat {
+    /// Plain text.
+    PlainText,
+
+    /// CommonMark.
+    Markdown,
+}
+
+/// A writer of text from an HTML tree.
+///
+/// The whitespace of the HTML is collapsed, and the line breaks and the blank lines between
+/// blocks are only written before the next content, so there is no leading or trailing
+/// whitespace.
+struct TextWriter {
+    /// The format of the text.
+    format: TextFormat,
+
+    /// The text written so far.
+    out: String,
+
+    /// The prefixes of the lines of the current blocks.
+    prefixes: Vec<LinePrefix>,
+
+    /// The line break to write before the next content.
+    pending_break: Option<PendingBreak>,
+
+    /// The number of line breaks inside the current block to write before the next content.
+    ///
+    /// They are dropped if the block ends before the next content.
+    pending_line_breaks: usize,
+
+    /// Whether the next content is at the start of a line.
+    at_line_start: bool,
+
+    /// Whether a space should be written before the next content.
+    pending_space: bool,
+
+    /// The opening markup of the current inline elements, to write before the next content.
+    ///
+    /// The markup is only written with the content so it is not separated from it by whitespace,
+    /// and so that elements without content are omitted.
+    pending_open: String,
+
+    /// The number of lists that contain the current node.
+    list_depth: usize,
+
+    /// Whether the current node is inside a Markdown heading, which cannot contain line breaks.
+    in_heading: bool,
+}
+
+/// The prefix of the lines of a block.
+struct LinePrefix {
+    /// The prefix of the first line, if it was not written yet.
+    marker: Option<String>,
+
+    /// The prefix of the other lines.
+    indent: String,
+}
+
+/// A line break between blocks.
+#[derive(Clone, Copy)]
+struct PendingBreak {
+    /// The number of newlines to write.
+    newlines: usize,
+
+    /// The number of line prefixes to write on blank lines.
+    depth: usize,
+}
+
+impl TextWriter {
+    /// Construct a new `TextWriter` for the given format.
+    fn new(format: TextFormat) -> Self {
+        Self {
+            format,
+            out: String::new(),
+            prefixes: Vec::new(),
+            pending_break: None,
+            pending_line_breaks: 0,
+            at_line_start: true,
+            pending_space: false,
+            pending_open: String::new(),
+            list_depth: 0,
+            in_heading: false,
+        }
+    }
+
+    /// Render the given HTML and return the text.
+    fn render(mut self, html: &Html) -> String {
+        for child in html.children() {
+            self.node(&child);
+        }
+
+        self.out
+    }
+
+    /// Render the children of the given node.
+    fn children(&mut self, node: &NodeRef) {
+        for child in node.children() {
+            self.node(&child);
+        }
+    }
+
+    /// Render the given node.
+    fn node(&mut self, node: &NodeRef) {
+        match node.data() {
+            NodeData::Text(text) => self.text(&text.borrow()),
+            NodeData::Element(data) => self.element(node, data.to_matrix().element),
+            _ => {}
+        }
+    }
+
+    /// Render the given element node.
+    fn element(&mut self, node: &NodeRef, element: MatrixElement) {
+        let is_markdown = self.format == TextFormat::Markdown;
+
+        match element {
+            MatrixElement::MatrixReply => {}
+            MatrixElement::P | MatrixElement::Table | MatrixElement::Details => {
+                self.block(node, 2);
+            }
+            MatrixElement::Div(_)
+            | MatrixElement::Tr
+            | MatrixElement::Caption
+            | MatrixElement::Summary => self.block(node, 1),
+            MatrixElement::H(data) => {
+                self.request_break(2);
+
+                if is_markdown {
+                    let marker = format!("{} ", "#".repeat(data.level.value().into()));
+                    self.in_heading = true;
+                    self.inline(node, &marker, "");
+                    self.in_heading = false;
+                } else {
+                    self.children(node);
+                }
+
+                self.request_break(2);
+            }
+            MatrixElement::Blockquote => {
+                self.request_break(2);
+                self.prefixes.push(LinePrefix { marker: None, indent: "> ".to_owned() });
+                self.children(node);
+                self.prefixes.pop();
+                self.request_break(2);
+            }
+            MatrixElement::Ul => self.list(node, None),
+            MatrixElement::Ol(data) => self.list(node, Some(data.start.unwrap_or(1))),
+            MatrixElement::Li => self.list_item(node, "- ".to_owned()),
+            MatrixElement::Pre => self.code_block(node),
+            MatrixElement::Hr => {
+                self.request_break(2);
+                self.write_content("---");
+                self.request_break(2);
+            }
+            MatrixElement::Br => self.line_break(),
+            MatrixElement::Code(_) if is_markdown => self.inline_code(node),
+            MatrixElement::B | MatrixElement::Strong if is_markdown => {
+                self.inline(node, "**", "**");
+            }
+            MatrixElement::I | MatrixElement::Em if is_markdown => self.inline(node, "*", "*"),
+            MatrixElement::Del | MatrixElement::S if is_markdown => {
+                self.inline(node, "<del>", "</del>");
+            }
+            MatrixElement::U if is_markdown => self.inline(node, "<u>", "</u>"),
+            MatrixElement::Sup if is_markdown => self.inline(node, "<sup>", "</sup>"),
+            MatrixElement::Sub if is_markdown => self.inline(node, "<sub>", "</sub>"),
+            MatrixElement::A(data) => self.link(node, data.href),
+            MatrixElement::Span(data) => match data.spoiler {
+                Some(reason) => self.spoiler(node, &reason),
+                None => self.children(node),
+            },
+            MatrixElement::Img(data) => {
+                let alt = data.alt.or(data.title);
+
+                match (data.src, alt) {
+                    (Some(src), alt) if is_markdown => {
+                        let alt = alt.map(|alt| escape_markdown(&alt, false)).unwrap_or_default();
+                        self.write_content(&format!(
+                            "![{alt}]({})",
+                            link_destination(src.as_str())
+                        ));
+                    }
+                    (_, Some(alt)) => self.text(&alt),
+                    _ => {}
+                }
+            }
+            MatrixElement::Td | MatrixElement::Th => {
+                let is_first_cell =
+                    std::iter::successors(node.prev_sibling(), NodeRef::prev_sibling)
+                        .all(|sibling| sibling.as_element().is_none());
+
+                if !is_first_cell {
+                    self.pending_space = true;
+                    self.write_content("|");
+                    self.pending_space = true;
+                }
+
+                self.children(node);
+            }
+            _ => self.children(node),
+        }
+    }
+
+    /// Render the given block node, separated from the surrounding blocks by the given number of
+    /// newlines.
+    fn block(&mut self, node: &NodeRef, newlines: usize) {
+        self.request_break(newlines);
+        self.children(node);
+        self.request_break(newlines);
+    }
+
+    /// Render the given list node.
+    ///
+    /// If `start` is set, this is an ordered list whose items are numbered from this value.
+    fn list(&mut self, node: &NodeRef, mut start: Option<i64>) {
+        // Nested lists are not separated by blank lines.
+        let newlines = if self.list_depth > 0 { 1 } else { 2 };
+
+        self.request_break(newlines);
+        self.list_depth += 1;
+
+        for child in node.children() {
+            let is_item = child
+                .as_element()
+                .is_some_and(|element| matches!(element.to_matrix().element, MatrixElement::Li));
+
+            if !is_item {
+                self.node(&child);
+                continue;
+            }
+
+            let marker = match &mut start {
+                Some(number) => {
+                    let marker = format!("{number}. ");
+                    *number = number.saturating_add(1);
+                    marker
+                }
+                None => "- ".to_owned(),
+            };
+
+            self.list_item(&child, marker);
+        }
+
+        self.list_depth -= 1;
+        self.request_break(newlines);
+    }
+
+    /// Render the given list item node with the given marker.
+    fn list_item(&mut self, node: &NodeRef, marker: String) {
+        self.request_break(1);
+
+        let indent = " ".repeat(marker.len());
+        self.prefixes.push(LinePrefix { marker: Some(marker), indent });
+        self.children(node);
+        self.prefixes.pop();
+
+        self.request_break(1);
+    }
+
+    /// Render the given preformatted text node.
+    ///
+    /// In Markdown, it is rendered as a fenced code block with the language of its `<code>`
+    /// child, if any.
+    fn code_block(&mut self, node: &NodeRef) {
+        let mut code = String::new();
+        collect_text(node, &mut code);
+        let code = code.strip_suffix('\n').unwrap_or(&code);
+
+        self.request_break(2);
+
+        if self.format == TextFormat::Markdown {
+            let language = node
+                .children()
+                .find_map(|child| {
+                    as_variant!(child.as_element()?.to_matrix().element, MatrixElement::Code)?
+                        .language
+                })
+                .unwrap_or_default();
+            let fence = "`".repeat(longest_run(code, '`').max(2) + 1);
+
+            self.write_content(&format!("{fence}{language}"));
+
+            for line in code.split('\n') {
+                self.newline();
+                self.write_content(line);
+            }
+
+            self.newline();
+            self.write_content(&fence);
+        } else {
+            for (i, line) in code.split('\n').enumerate() {
+                if i > 0 {
+                    self.newline();
+                }
+
+                self.write_content(line);
+            }
+        }
+
+        self.request_break(2);
+    }
+
+    /// Render the given inline code node as a Markdown code span.
+    fn inline_code(&mut self, node: &NodeRef) {
+        let mut code = String::new();
+        collect_text(node, &mut code);
+
+        if code.is_empty() {
+            return;
+        }
+
+        let code = code.replace('\n', " ");
+        let fence = "`".repeat(longest_run(&code, '`') + 1);
+        let padding = if code.starts_with('`') || code.ends_with('`') { " " } else { "" };
+
+        self.write_content(&format!("{fence}{padding}{code}{padding}{fence}"));
+    }
+
+    /// Render the given inline node surrounded by the given markup.
+    fn inline(&mut self, node: &NodeRef, open: &str, close: &str) {
+        self.pending_open.push_str(open);
+        self.children(node);
+
+        // Omit the markup if the element has no content.
+        if let Some(len) = self.pending_open.strip_suffix(open).map(str::len) {
+            self.pending_open.truncate(len);
+        } else {
+            self.out.push_str(close);
+        }
+    }
+
+    /// Render the given link node with the given URI.
+    fn link(&mut self, node: &NodeRef, href: Option<AnchorUri>) {
+        let Some(href) = href else {
+            self.children(node);
+            return;
+        };
+
+        let (href, is_mention) = match href {
+            AnchorUri::Matrix(uri) => (uri.to_string(), is_mention(uri.id())),
+            AnchorUri::MatrixTo(uri) => (uri.to_string(), is_mention(uri.id())),
+            AnchorUri::Other(uri) => (uri.to_string(), false),
+        };
+
+        if self.format == TextFormat::Markdown {
+            self.pending_open.push('[');
+            self.children(node);
+
+            if self.pending_open.ends_with('[') {
+                // There is no text, use an autolink.
+                self.pending_open.pop();
+
+                if !href.contains(|c: char| c.is_whitespace() || c == '<' || c == '>') {
+                    self.write_content(&format!("<{href}>"));
+                }
+            } else {
+                self.out.push_str(&format!("]({})", link_destination(&href)));
+            }
+
+            return;
+        }
+
+        let start = self.out.len();
+        self.children(node);
+
+        if self.out.len() == start {
+            self.write_content(&href);
+        } else if !is_mention && !self.out.ends_with(&href) {
+            let pending_space = self.pending_space;
+            self.pending_space = true;
+            self.write_content(&format!("({href})"));
+            self.pending_space = pending_space;
+        }
+    }
+
+    /// Render the given spoiler node with the given reason.
+    fn spoiler(&mut self, node: &NodeRef, reason: &str) {
+        if self.format == TextFormat::Markdown {
+            let open = if reason.is_empty() {
+                "<span data-mx-spoiler>".to_owned()
+            } else {
+                let reason = reason.replace('&', "&amp;").replace('"', "&quot;");
+                format!("<span data-mx-spoiler=\"{reason}\">")
+            };
+
+            self.inline(node, &open, "</span>");
+        } else if reason.is_empty() {
+            self.write_content("[Spoiler]");
+        } else {
+            self.write_content(&format!("[Spoiler for {reason}]"));
+        }
+    }
+
+    /// Render the given text, collapsing its whitespace.
+    fn text(&mut self, text: &str) {
+        for (i, word) in text.split(|c: char| c.is_ascii_whitespace()).enumerate() {
+            if i > 0 {
+                self.pending_space = true;
+            }
+
+            if word.is_empty() {
+                continue;
+            }
+
+            if self.format == TextFormat::Markdown {
+                let at_line_start = (self.at_line_start
+                    || self.pending_break.is_some()
+                    || self.pending_line_breaks > 0)
+                    && self.pending_open.is_empty();
+                self.write_content(&escape_markdown(word, at_line_start));
+            } else {
+                self.write_content(word);
+            }
+        }
+    }
+
+    /// Request a line break inside a block before the next content.
+    fn line_break(&mut self) {
+        if self.out.is_empty() || self.pending_break.is_some() {
+            return;
+        }
+
+        if self.in_heading {
+            self.pending_space = true;
+        } else {
+            self.pending_line_breaks += 1;
+        }
+    }
+
+    /// Start a new line.
+    fn newline(&mut self) {
+        self.out.push('\n');
+        self.at_line_start = true;
+        self.pending_space = false;
+    }
+
+    /// Request the given number of newlines before the next content.
+    fn request_break(&mut self, newlines: usize) {
+        let depth = self.prefixes.len();
+
+        self.pending_break = Some(match self.pending_break {
+            Some(pending) => PendingBreak {
+                newlines: pending.newlines.max(newlines),
+                depth: pending.depth.min(depth),
+            },
+            None => PendingBreak { newlines, depth },
+        });
+        self.pending_line_breaks = 0;
+        self.pending_space = false;
+    }
+
+    /// Write the given content, preceded by the pending line breaks, line prefixes, space and
+    /// markup.
+    fn write_content(&mut self, content: &str) {
+        if let Some(pending) = self.pending_break.take() {
+            if !self.out.is_empty() {
+                if !self.at_line_start {
+                    self.out.push('\n');
+                }
+
+                for _ in 1..pending.newlines {
+                    let blank_prefix = self.prefixes[..pending.depth]
+                        .iter()
+                        .map(|prefix| prefix.indent.as_str())
+                        .collect::<String>();
+                    self.out.push_str(blank_prefix.trim_end());
+                    self.out.push('\n');
+                }
+
+                self.at_line_start = true;
+            }
+        }
+
+        for _ in 0..std::mem::take(&mut self.pending_line_breaks) {
+            if self.format == TextFormat::Markdown && !self.at_line_start {
+                // A hard line break.
+                self.out.push('\\');
+            }
+
+            self.out.push('\n');
+            self.at_line_start = true;
+        }
+
+        if self.at_line_start {
+            for prefix in &mut self.prefixes {
+                match prefix.marker.take() {
+                    Some(marker) => self.out.push_str(&marker),
+                    None => self.out.push_str(&prefix.indent),
+                }
+            }
+
+            self.at_line_start = false;
+        } else if self.pending_space {
+            self.out.push(' ');
+        }
+
+        self.pending_space = false;
+        self.out.push_str(&std::mem::take(&mut self.pending_open));
+        self.out.push_str(content);
+    }
+}
+
+/// Whether a link to the given ID is a mention.
+fn is_mention(id: &MatrixId) -> bool {
+    matches!(id, MatrixId::User(_) | MatrixId::Room(_) | MatrixId::RoomAlias(_))
+}
+
+/// Append the text of the given node and its descendants to the given string.
+///
+/// `<br>` elements are converted to newlines.
+fn collect_text(node: &NodeRef, text: &mut String) {
+    for child in node.children() {
+        match child.data() {
+            NodeData::Text(t) => text.push_str(&t.borrow()),
+            NodeData::Element(data) if &data.name.local == "br" => text.push('\n'),
+            NodeData::Element(_) => collect_text(&child, text),
+            _ => {}
+        }
+    }
+}
+
+/// The length of the longest run of the given character in the given string.
+fn longest_run(s: &str, c: char) -> usize {
+    s.split(|other| other != c).map(str::len).max().unwrap_or_default()
+}
+
+/// Escape the characters of the given word that have a meaning in Markdown.
+///
+/// If the word is at the start of a line, the characters that would start a block are escaped
+/// too.
+fn escape_markdown(word: &str, at_line_start: bool) -> String {
+    let mut block_char = None;
+
+    if at_line_start {
+        if word.starts_with(['#', '-', '+', '=', '>']) {
+            block_char = Some(0);
+        } else {
+            // An ordered list item marker.
+            let digits = word.find(|c: char| !c.is_ascii_digit()).unwrap_or(word.len());
+
+            if digits > 0 && digits + 1 == word.len() && word.ends_with(['.', ')']) {
+                block_char = Some(digits);
+            }
+        }
+    }
+
+    let mut escaped = String::with_capacity(word.len());
+
+    for (i, c) in word.char_indices() {
+        if block_char == Some(i) || matches!(c, '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '&') {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// The destination of a Markdown link to the given URI.
+fn link_destination(uri: &str) -> String {
+    let uri = uri.replace('<', "%3C").replace('>', "%3E");
+
+    if uri.contains([' ', '(', ')']) {
+        format!("<{uri}>")
+    } else {
+        uri
+    }
+}
@@ -10,7 +10,8 @@
 //! # Features
 //!
 //! * `matrix` - Allow to convert HTML elements data into enums with variants for elements and
-//!   attributes [suggested by the Matrix Specification][spec].
+//!   attributes [suggested by the Matrix Specification][spec], and to convert HTML to plain text or
+//!   Markdown.
 //!
 //! [spec]: https://spec.matrix.org/latest/client-server-api/#mroommessage-msgtypes
 
@@ -2,3 +2,5 @@
 mod matrix;
 mod navigate;
 mod sanitize;
+#[cfg(feature = "matrix")]
+mod text;
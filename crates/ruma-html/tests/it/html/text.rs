@@ -0,0 +1,246 @@
+use ruma_html::Html;
+
+#[test]
+fn paragraphs_and_inline_formatting() {
+    let html = Html::parse(
+        "\
+        <h2>The  <em>plan</em></h2>\n\
+        <p>First, we <strong>bold</strong> this,\n then <del>strike</del> that.<br>On a new line.</p>\
+        <p><code>let x = 1;</code> and <u>underline</u>.</p>\
+        <hr>\
+        <p>* not a list</p>\
+        ",
+    );
+
+    assert_eq!(
+        html.to_plain_text(),
+        "\
+        The plan\n\
+        \n\
+        First, we bold this, then strike that.\n\
+        On a new line.\n\
+        \n\
+        let x = 1; and underline.\n\
+        \n\
+        ---\n\
+        \n\
+        * not a list\
+        "
+    );
+    assert_eq!(
+        html.to_markdown(),
+        "\
+        ## The *plan*\n\
+        \n\
+        First, we **bold** this, then <del>strike</del> that.\\\n\
+        On a new line.\n\
+        \n\
+        `let x = 1;` and <u>underline</u>.\n\
+        \n\
+        ---\n\
+        \n\
+        \\* not a list\
+        "
+    );
+}
+
+#[test]
+fn line_breaks() {
+    // A line break at the end of a block is dropped.
+    let html = Html::parse("<p>foo<br></p><p>bar</p>");
+    assert_eq!(html.to_plain_text(), "foo\n\nbar");
+    assert_eq!(html.to_markdown(), "foo\n\nbar");
+
+    // A trailing line break is dropped.
+    let html = Html::parse("foo<br>");
+    assert_eq!(html.to_plain_text(), "foo");
+    assert_eq!(html.to_markdown(), "foo");
+
+    // A line break in a Markdown heading is a space.
+    let html = Html::parse("<h2>a<br>b</h2>");
+    assert_eq!(html.to_plain_text(), "a\nb");
+    assert_eq!(html.to_markdown(), "## a b");
+
+    // Line breaks followed by content are kept.
+    let html = Html::parse("foo<br><br><em>bar</em>");
+    assert_eq!(html.to_plain_text(), "foo\n\nbar");
+    assert_eq!(html.to_markdown(), "foo\\\n\n*bar*");
+}
+
+#[test]
+fn lists() {
+    let html = Html::parse(
+        "\
+        <p>Steps:</p>\
+        <ol start=\"3\">\
+            <li>Open the door</li>\
+            <li>Look for:\
+                <ul>\
+                    <li>cats</li>\
+                    <li>dogs</li>\
+                </ul>\
+            </li>\
+            <li>Close the door</li>\
+        </ol>\
+        ",
+    );
+
+    assert_eq!(
+        html.to_plain_text(),
+        "\
+        Steps:\n\
+        \n\
+        3. Open the door\n\
+        4. Look for:\n   \
+           - cats\n   \
+           - dogs\n\
+        5. Close the door\
+        "
+    );
+    assert_eq!(html.to_markdown(), html.to_plain_text());
+}
+
+#[test]
+fn code_blocks() {
+    let html = Html::parse(
+        "\
+        <p>Example:</p>\
+        <pre><code class=\"language-rust\">fn main() {\n    println!(\"```\");\n}\n</code></pre>\
+        <pre>plain &lt;text&gt;</pre>\
+        ",
+    );
+
+    assert_eq!(
+        html.to_plain_text(),
+        "\
+        Example:\n\
+        \n\
+        fn main() {\n    \
+            println!(\"```\");\n\
+        }\n\
+        \n\
+        plain <text>\
+        "
+    );
+    assert_eq!(
+        html.to_markdown(),
+        "\
+        Example:\n\
+        \n\
+        ````rust\n\
+        fn main() {\n    \
+            println!(\"```\");\n\
+        }\n\
+        ````\n\
+        \n\
+        ```\n\
+        plain <text>\n\
+        ```\
+        "
+    );
+}
+
+#[test]
+fn blockquotes() {
+    let html = Html::parse(
+        "\
+        <p>Someone said:</p>\
+        <blockquote>\
+            <p>To be</p>\
+            <p>or not to be</p>\
+        </blockquote>\
+        <p>Indeed.</p>\
+        ",
+    );
+
+    assert_eq!(
+        html.to_plain_text(),
+        "\
+        Someone said:\n\
+        \n\
+        > To be\n\
+        >\n\
+        > or not to be\n\
+        \n\
+        Indeed.\
+        "
+    );
+    assert_eq!(html.to_markdown(), html.to_plain_text());
+}
+
+#[test]
+fn links_and_mentions() {
+    let html = Html::parse(
+        "\
+        Hey <a href=\"https://matrix.to/#/@alice:example.org\">Alice</a>, \
+        look at <a href=\"https://example.org/page_(1)\">this page</a> \
+        or <a href=\"https://example.org\">https://example.org</a> \
+        in <a href=\"matrix:r/room:example.org\">#room:example.org</a>.\
+        ",
+    );
+
+    assert_eq!(
+        html.to_plain_text(),
+        "Hey Alice, look at this page (https://example.org/page_(1)) or https://example.org \
+         in #room:example.org."
+    );
+    assert_eq!(
+        html.to_markdown(),
+        "Hey [Alice](https://matrix.to/#/@alice:example.org), \
+         look at [this page](<https://example.org/page_(1)>) \
+         or [https://example.org](https://example.org) \
+         in [#room:example.org](matrix:r/room:example.org)."
+    );
+}
+
+#[test]
+fn spoilers() {
+    let html = Html::parse(
+        "\
+        The killer is <span data-mx-spoiler>the gardener</span>, \
+        and <span data-mx-spoiler=\"ending\">they get away</span>.\
+        ",
+    );
+
+    assert_eq!(html.to_plain_text(), "The killer is [Spoiler], and [Spoiler for ending].");
+    assert_eq!(
+        html.to_markdown(),
+        "The killer is <span data-mx-spoiler>the gardener</span>, \
+         and <span data-mx-spoiler=\"ending\">they get away</span>."
+    );
+}
+
+#[test]
+fn reply_fallback() {
+    let html = Html::parse(
+        "\
+        <mx-reply>\
+            <blockquote>\
+                <a href=\"https://matrix.to/#/!room:example.org/$event\">In reply to</a> \
+                <a href=\"https://matrix.to/#/@alice:example.org\">@alice:example.org</a>\
+                <br>Original message\
+            </blockquote>\
+        </mx-reply>\
+        This is the reply\
+        ",
+    );
+
+    assert_eq!(html.to_plain_text(), "This is the reply");
+    assert_eq!(html.to_markdown(), "This is the reply");
+}
+
+#[test]
+fn escape_markdown() {
+    let html = Html::parse(
+        "<p>Tom &amp;amp; Jerry &lt;3 [links] and *stars*</p><p># 1. Not a heading</p>",
+    );
+
+    assert_eq!(
+        html.to_plain_text(),
+        "Tom &amp; Jerry <3 [links] and *stars*\n\n# 1. Not a heading"
+    );
+    assert_eq!(
+        html.to_markdown(),
+        "Tom \\&amp; Jerry \\<3 \\[links\\] and \\*stars\\*\n\n\\# 1. Not a heading"
+    );
+}
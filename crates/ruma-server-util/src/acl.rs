@@ -0,0 +1,197 @@
+//! Enforcement of the [server access control lists] of rooms on incoming federation requests.
+//!
+//! [server access control lists]: https://spec.matrix.org/latest/server-server-api/#server-access-control-lists-acls
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+use ruma_common::{serde::Raw, OwnedRoomId, OwnedServerName, RoomId, ServerName};
+use ruma_events::room::server_acl::{CompiledServerAcl, RoomServerAclEventContent};
+use ruma_federation_api::transactions::edu::Edu;
+use thiserror::Error;
+use tracing::debug;
+
+/// The compiled server ACLs of rooms.
+///
+/// Rooms without a server ACL allow all servers. The ACLs must be updated with
+/// [`ServerAcls::set_acl()`] when the `m.room.server_acl` state event of a room changes.
+#[derive(Debug, Default)]
+pub struct ServerAcls {
+    /// The compiled ACLs, by room.
+    acls: RwLock<BTreeMap<OwnedRoomId, Arc<CompiledServerAcl>>>,
+}
+
+impl ServerAcls {
+    /// Creates an empty `ServerAcls`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the server ACL of the given room, or remove it if `content` is `None`.
+    pub fn set_acl(&self, room_id: &RoomId, content: Option<&RoomServerAclEventContent>) {
+        let mut acls = self.acls.write().expect("server ACLs lock was poisoned");
+
+        match content {
+            Some(content) => {
+                acls.insert(room_id.to_owned(), Arc::new(content.compile()));
+            }
+            None => {
+                acls.remove(room_id);
+            }
+        }
+    }
+
+    /// Get the compiled server ACL of the given room, if any.
+    pub fn acl(&self, room_id: &RoomId) -> Option<Arc<CompiledServerAcl>> {
+        self.acls.read().expect("server ACLs lock was poisoned").get(room_id).cloned()
+    }
+
+    /// Whether the given server is allowed to participate in the given room.
+    pub fn is_allowed(&self, room_id: &RoomId, server_name: &ServerName) -> bool {
+        self.acls
+            .read()
+            .expect("server ACLs lock was poisoned")
+            .get(room_id)
+            .map_or(true, |acl| acl.is_allowed(server_name))
+    }
+
+    /// Check that the origin of a federation request is allowed to participate in the given room.
+    ///
+    /// The origin is the server name in the `X-Matrix` authorization header of the request, or the
+    /// `origin` of a transaction. The server ACL must be checked for the endpoints listed in the
+    /// specification, and requests that are denied must be rejected with a `403 M_FORBIDDEN`
+    /// error.
+    pub fn check(&self, room_id: &RoomId, origin: &ServerName) -> Result<(), ServerAclDenied> {
+        if self.is_allowed(room_id, origin) {
+            Ok(())
+        } else {
+            Err(ServerAclDenied { room_id: room_id.to_owned(), origin: origin.to_owned() })
+        }
+    }
+
+    /// Remove the data of the rooms where `origin` is denied from the given EDUs of a transaction.
+    ///
+    /// Typing notifications in rooms where `origin` is denied are removed, as well as the receipts
+    /// of these rooms. EDUs that are not related to a room, or that fail to deserialize, are kept.
+    pub fn filter_edus(&self, origin: &ServerName, edus: Vec<Raw<Edu>>) -> Vec<Raw<Edu>> {
+        edus.into_iter()
+            .filter_map(|raw_edu| match raw_edu.deserialize() {
+                Ok(Edu::Typing(typing)) => {
+                    if self.is_allowed(&typing.room_id, origin) {
+                        Some(raw_edu)
+                    } else {
+                        debug!(%origin, room_id = %typing.room_id, "Dropping denied typing EDU");
+                        None
+                    }
+                }
+                Ok(Edu::Receipt(mut receipt)) => {
+                    let rooms_count = receipt.receipts.len();
+                    receipt.receipts.retain(|room_id, _| self.is_allowed(room_id, origin));
+
+                    if receipt.receipts.len() == rooms_count {
+                        Some(raw_edu)
+                    } else if receipt.receipts.is_empty() {
+                        debug!(%origin, "Dropping denied receipt EDU");
+                        None
+                    } else {
+                        Raw::new(&Edu::Receipt(receipt)).ok()
+                    }
+                }
+                _ => Some(raw_edu),
+            })
+            .collect()
+    }
+}
+
+/// An error when a server is denied by the server ACL of a room.
+#[derive(Debug, Error)]
+#[error("server {origin} is denied by the server ACL of room {room_id}")]
+#[non_exhaustive]
+pub struct ServerAclDenied {
+    /// The room.
+    pub room_id: OwnedRoomId,
+
+    /// The denied server.
+    pub origin: OwnedServerName,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use assert_matches2::assert_matches;
+    use ruma_common::{owned_room_id, owned_user_id, room_id, serde::Raw, server_name};
+    use ruma_events::room::server_acl::RoomServerAclEventContent;
+    use ruma_federation_api::transactions::edu::{Edu, ReceiptContent, ReceiptMap, TypingContent};
+
+    use super::ServerAcls;
+
+    /// The ACL of the example of the spec, in `!denied:example.org`.
+    fn server_acls() -> ServerAcls {
+        let acls = ServerAcls::new();
+        let content = RoomServerAclEventContent::new(
+            false,
+            vec!["*".to_owned()],
+            vec!["*.evil.com".to_owned(), "evil.com".to_owned()],
+        );
+        acls.set_acl(room_id!("!denied:example.org"), Some(&content));
+        acls
+    }
+
+    #[test]
+    fn check_origin() {
+        let acls = server_acls();
+        let room_id = room_id!("!denied:example.org");
+
+        assert!(acls.check(room_id, server_name!("good.example.org")).is_ok());
+        assert!(acls.check(room_id, server_name!("good.example.org:8448")).is_ok());
+        assert_matches!(acls.check(room_id, server_name!("evil.com")), Err(error));
+        assert_eq!(error.origin, "evil.com");
+        assert!(acls.check(room_id, server_name!("sub.evil.com:8448")).is_err());
+        assert!(acls.check(room_id, server_name!("1.2.3.4")).is_err());
+        assert!(acls.check(room_id, server_name!("[1234:5678::abcd]")).is_err());
+
+        // Rooms without ACL allow all servers.
+        assert!(acls.check(room_id!("!other:example.org"), server_name!("evil.com")).is_ok());
+
+        acls.set_acl(room_id, None);
+        assert!(acls.check(room_id, server_name!("evil.com")).is_ok());
+    }
+
+    #[test]
+    fn filter_edus() {
+        let acls = server_acls();
+        let typing = |room_id| {
+            Raw::new(&Edu::Typing(TypingContent::new(
+                room_id,
+                owned_user_id!("@user:evil.com"),
+                true,
+            )))
+            .unwrap()
+        };
+        let receipt = Raw::new(&Edu::Receipt(ReceiptContent::new(BTreeMap::from([
+            (owned_room_id!("!denied:example.org"), ReceiptMap::new(BTreeMap::new())),
+            (owned_room_id!("!other:example.org"), ReceiptMap::new(BTreeMap::new())),
+        ]))))
+        .unwrap();
+
+        let edus = vec![
+            typing(owned_room_id!("!denied:example.org")),
+            typing(owned_room_id!("!other:example.org")),
+            receipt.clone(),
+        ];
+
+        // Nothing is removed for an allowed server.
+        let filtered = acls.filter_edus(server_name!("good.example.org"), edus.clone());
+        assert_eq!(filtered.len(), 3);
+
+        let filtered = acls.filter_edus(server_name!("evil.com"), edus);
+        assert_eq!(filtered.len(), 2);
+        assert_matches!(filtered[0].deserialize().unwrap(), Edu::Typing(typing));
+        assert_eq!(typing.room_id, "!other:example.org");
+        assert_matches!(filtered[1].deserialize().unwrap(), Edu::Receipt(receipt));
+        assert_eq!(receipt.receipts.keys().collect::<Vec<_>>(), ["!other:example.org"]);
+    }
+}
@@ -1,15 +1,17 @@
 //! Common types for implementing federation authorization.
 
-use std::{fmt, str::FromStr};
+use std::{collections::BTreeMap, fmt, str::FromStr};
 
 use headers::authorization::Credentials;
-use http::HeaderValue;
+use http::{header::AUTHORIZATION, HeaderValue, Request};
 use http_auth::ChallengeParser;
 use ruma_common::{
     http_headers::quote_ascii_string_if_required,
     serde::{Base64, Base64DecodeError},
-    IdParseError, OwnedServerName, OwnedServerSigningKeyId,
+    CanonicalJsonObject, CanonicalJsonValue, IdParseError, OwnedServerName,
+    OwnedServerSigningKeyId, ServerName,
 };
+use ruma_signatures::{canonical_json, verify_json, KeyPair, PublicKeyMap};
 use thiserror::Error;
 use tracing::debug;
 
@@ -174,6 +176,147 @@ impl Credentials for XMatrix {
     }
 }
 
+/// Get the JSON object that is signed to authenticate the given federation request.
+///
+/// The object contains the `method`, `uri`, `origin` and `destination` of the request, and the
+/// body of the request as `content`, if it is not empty.
+///
+/// Returns an error if the body of the request is not empty and not valid JSON.
+pub fn request_json<T: AsRef<[u8]>>(
+    request: &Request<T>,
+    origin: &ServerName,
+    destination: &ServerName,
+) -> Result<CanonicalJsonObject, serde_json::Error> {
+    let uri = request.uri().path_and_query().map_or("/", |path_and_query| path_and_query.as_str());
+
+    let mut object = BTreeMap::from([
+        ("method".to_owned(), CanonicalJsonValue::String(request.method().to_string())),
+        ("uri".to_owned(), CanonicalJsonValue::String(uri.to_owned())),
+        ("origin".to_owned(), CanonicalJsonValue::String(origin.to_string())),
+        ("destination".to_owned(), CanonicalJsonValue::String(destination.to_string())),
+    ]);
+
+    let body = request.body().as_ref();
+    if !body.is_empty() {
+        object.insert("content".to_owned(), serde_json::from_slice(body)?);
+    }
+
+    Ok(object)
+}
+
+/// Sign the given federation request with the given key pair, and add the resulting X-Matrix
+/// `Authorization` header to it.
+///
+/// The destination is always included in the header, and it is the destination that is signed,
+/// so it must be the server name of the receiving server, not the hostname the request is sent
+/// to.
+///
+/// Calling this several times with different key pairs adds one header per key. Returns an error
+/// if the request already has an X-Matrix header with a different origin or destination.
+pub fn sign_request<T, K>(
+    request: &mut Request<T>,
+    origin: &ServerName,
+    destination: &ServerName,
+    key_pair: &K,
+) -> Result<(), XMatrixSigningError>
+where
+    T: AsRef<[u8]>,
+    K: KeyPair + ?Sized,
+{
+    for credentials in request_credentials(request) {
+        let credentials = credentials?;
+
+        if credentials.origin != origin {
+            return Err(XMatrixSigningError::OriginMismatch(credentials.origin));
+        }
+        if credentials.destination.as_deref().is_some_and(|d| d != destination) {
+            return Err(XMatrixSigningError::DestinationMismatch(credentials.destination));
+        }
+    }
+
+    let object = request_json(request, origin, destination)?;
+    let signature = key_pair.sign(canonical_json(&object)?.as_bytes());
+
+    let credentials = XMatrix::new(
+        origin.to_owned(),
+        destination.to_owned(),
+        signature.id().try_into()?,
+        Base64::new(signature.as_bytes().to_vec()),
+    );
+    request.headers_mut().append(AUTHORIZATION, (&credentials).into());
+
+    Ok(())
+}
+
+/// Verify the X-Matrix `Authorization` headers of the given federation request.
+///
+/// `server_name` is the name of the receiving server. Headers with a destination that doesn't
+/// match it are rejected. Headers without a destination are accepted for compatibility with
+/// older servers, and verified as if they had this destination.
+///
+/// The `public_key_map` must contain the keys of the origin server that were used to sign the
+/// request. They can be found in the `key` field of the [`XMatrix`] headers.
+///
+/// All the headers must have the same origin, and all their signatures must be valid. Returns the
+/// authenticated origin of the request.
+pub fn verify_request<T: AsRef<[u8]>>(
+    request: &Request<T>,
+    server_name: &ServerName,
+    public_key_map: &PublicKeyMap,
+) -> Result<OwnedServerName, XMatrixVerificationError> {
+    let mut origin = None;
+    let mut signature_set = BTreeMap::new();
+
+    for credentials in request_credentials(request) {
+        let credentials = credentials?;
+
+        if let Some(destination) = credentials.destination {
+            if destination != server_name {
+                return Err(XMatrixVerificationError::DestinationMismatch(destination));
+            }
+        }
+
+        match &origin {
+            None => origin = Some(credentials.origin),
+            Some(origin) if *origin != credentials.origin => {
+                return Err(XMatrixVerificationError::OriginMismatch(credentials.origin));
+            }
+            Some(_) => {}
+        }
+
+        signature_set.insert(
+            credentials.key.to_string(),
+            CanonicalJsonValue::String(credentials.sig.encode()),
+        );
+    }
+
+    let origin = origin.ok_or(XMatrixVerificationError::MissingCredentials)?;
+
+    let mut object = request_json(request, &origin, server_name)?;
+    object.insert(
+        "signatures".to_owned(),
+        CanonicalJsonValue::Object(BTreeMap::from([(
+            origin.to_string(),
+            CanonicalJsonValue::Object(signature_set),
+        )])),
+    );
+    verify_json(public_key_map, &object)?;
+
+    Ok(origin)
+}
+
+/// Parse the `Authorization` headers of the given request that use the X-Matrix scheme.
+fn request_credentials<T>(
+    request: &Request<T>,
+) -> impl Iterator<Item = Result<XMatrix, XMatrixParseError>> + '_ {
+    request.headers().get_all(AUTHORIZATION).iter().filter_map(|value| {
+        match XMatrix::try_from(value) {
+            Err(XMatrixParseError::NotFound) => None,
+            result => Some(result),
+        }
+    })
+}
+
 /// An error when trying to parse an X-Matrix Authorization header.
 #[derive(Debug, Error)]
 #[non_exhaustive]
@@ -207,6 +350,64 @@ pub enum XMatrixParseError {
     DuplicateParameter(String),
 }
 
+/// An error when trying to sign a federation request.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum XMatrixSigningError {
+    /// The body of the request is not valid JSON.
+    #[error("invalid JSON body: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// An existing X-Matrix Authorization header could not be parsed.
+    #[error(transparent)]
+    Parse(#[from] XMatrixParseError),
+
+    /// An existing X-Matrix Authorization header has a different origin.
+    #[error("the request is already signed by {0}")]
+    OriginMismatch(OwnedServerName),
+
+    /// An existing X-Matrix Authorization header has a different destination.
+    #[error("the request is already signed for destination {0:?}")]
+    DestinationMismatch(Option<OwnedServerName>),
+
+    /// The ID of the key pair is not a valid signing key ID.
+    #[error(transparent)]
+    ParseKeyId(#[from] IdParseError),
+
+    /// The request could not be signed.
+    #[error(transparent)]
+    Signatures(#[from] ruma_signatures::Error),
+}
+
+/// An error when trying to verify the signatures of a federation request.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum XMatrixVerificationError {
+    /// The request doesn't have an X-Matrix Authorization header.
+    #[error("missing X-Matrix credentials")]
+    MissingCredentials,
+
+    /// An X-Matrix Authorization header could not be parsed.
+    #[error(transparent)]
+    Parse(#[from] XMatrixParseError),
+
+    /// The X-Matrix Authorization headers have different origins.
+    #[error("the request is signed by several origins, including {0}")]
+    OriginMismatch(OwnedServerName),
+
+    /// An X-Matrix Authorization header has a destination that is not the receiving server.
+    #[error("the request is destined to {0}")]
+    DestinationMismatch(OwnedServerName),
+
+    /// The body of the request is not valid JSON.
+    #[error("invalid JSON body: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// The signatures of the request are invalid.
+    #[error(transparent)]
+    Signatures(#[from] ruma_signatures::Error),
+}
+
 impl<'a> From<http_auth::parser::Error<'a>> for XMatrixParseError {
     fn from(value: http_auth::parser::Error<'a>) -> Self {
         Self::ParseStr(value.to_string())
@@ -215,10 +416,41 @@ impl<'a> From<http_auth::parser::Error<'a>> for XMatrixParseError {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
+
+    use assert_matches2::assert_matches;
     use headers::{authorization::Credentials, HeaderValue};
-    use ruma_common::{serde::Base64, OwnedServerName};
+    use http::{header::AUTHORIZATION, Request};
+    use ruma_common::{serde::Base64, server_name, OwnedServerName};
+    use ruma_signatures::{Ed25519KeyPair, PublicKeyMap};
+
+    use super::{
+        sign_request, verify_request, XMatrix, XMatrixSigningError, XMatrixVerificationError,
+    };
 
-    use super::XMatrix;
+    fn generate_key_pair(version: &str) -> Ed25519KeyPair {
+        let document = Ed25519KeyPair::generate().unwrap();
+        Ed25519KeyPair::from_der(&document, version.to_owned()).unwrap()
+    }
+
+    fn public_key_map(key_pairs: &[&Ed25519KeyPair]) -> PublicKeyMap {
+        let public_keys = key_pairs
+            .iter()
+            .map(|key_pair| {
+                (
+                    format!("ed25519:{}", key_pair.version()),
+                    Base64::new(key_pair.public_key().to_vec()),
+                )
+            })
+            .collect();
+        BTreeMap::from([("origin.local".to_owned(), public_keys)])
+    }
+
+    fn request(body: &str) -> Request<Vec<u8>> {
+        Request::put("https://destination.local:8448/_matrix/federation/v1/send/1?foo=bar")
+            .body(body.as_bytes().to_vec())
+            .unwrap()
+    }
 
     #[test]
     fn xmatrix_auth_pre_1_3() {
@@ -294,4 +526,109 @@ mod tests {
         assert_eq!(credentials.key, "ed25519:key1");
         assert_eq!(credentials.sig, sig);
     }
+
+    #[test]
+    fn sign_and_verify_request() {
+        let origin = server_name!("origin.local");
+        let destination = server_name!("destination.local");
+        let key_pair = generate_key_pair("1");
+        let other_key_pair = generate_key_pair("2");
+
+        let mut request = request(r#"{ "pdus": [] }"#);
+        sign_request(&mut request, origin, destination, &key_pair).unwrap();
+        sign_request(&mut request, origin, destination, &other_key_pair).unwrap();
+
+        let headers: Vec<_> = request.headers().get_all(AUTHORIZATION).iter().collect();
+        assert_eq!(headers.len(), 2);
+        let credentials = XMatrix::try_from(headers[0]).unwrap();
+        assert_eq!(credentials.origin, origin);
+        assert_eq!(credentials.destination.as_deref(), Some(destination));
+        assert_eq!(credentials.key, "ed25519:1");
+
+        let public_key_map = public_key_map(&[&key_pair, &other_key_pair]);
+        assert_eq!(verify_request(&request, destination, &public_key_map).unwrap(), origin);
+
+        // All the signatures must be valid.
+        assert_matches!(
+            verify_request(&request, destination, &self::public_key_map(&[&key_pair])),
+            Err(XMatrixVerificationError::Signatures(_))
+        );
+
+        // The body is signed.
+        let (parts, _) = request.into_parts();
+        let request = Request::from_parts(parts, br#"{ "pdus": [{}] }"#.to_vec());
+        assert_matches!(
+            verify_request(&request, destination, &public_key_map),
+            Err(XMatrixVerificationError::Signatures(_))
+        );
+    }
+
+    #[test]
+    fn sign_request_already_signed() {
+        let key_pair = generate_key_pair("1");
+
+        let mut request = request("");
+        sign_request(
+            &mut request,
+            server_name!("origin.local"),
+            server_name!("destination.local"),
+            &key_pair,
+        )
+        .unwrap();
+
+        assert_matches!(
+            sign_request(
+                &mut request,
+                server_name!("other.local"),
+                server_name!("destination.local"),
+                &key_pair,
+            ),
+            Err(XMatrixSigningError::OriginMismatch(_))
+        );
+        assert_matches!(
+            sign_request(
+                &mut request,
+                server_name!("origin.local"),
+                server_name!("other.local"),
+                &key_pair,
+            ),
+            Err(XMatrixSigningError::DestinationMismatch(_))
+        );
+    }
+
+    #[test]
+    fn verify_request_destination() {
+        let origin = server_name!("origin.local");
+        let destination = server_name!("destination.local");
+        let key_pair = generate_key_pair("1");
+        let public_key_map = public_key_map(&[&key_pair]);
+
+        let mut request = request("");
+        sign_request(&mut request, origin, destination, &key_pair).unwrap();
+
+        // The request was sent to another server.
+        assert_matches!(
+            verify_request(&request, server_name!("other.local"), &public_key_map),
+            Err(XMatrixVerificationError::DestinationMismatch(other))
+        );
+        assert_eq!(other, destination);
+
+        // Headers without destination are verified against the receiving server.
+        let mut credentials =
+            XMatrix::try_from(request.headers().get(AUTHORIZATION).unwrap()).unwrap();
+        credentials.destination = None;
+        request.headers_mut().insert(AUTHORIZATION, (&credentials).into());
+        assert_eq!(verify_request(&request, destination, &public_key_map).unwrap(), origin);
+        assert_matches!(
+            verify_request(&request, server_name!("other.local"), &public_key_map),
+            Err(XMatrixVerificationError::Signatures(_))
+        );
+
+        // Credentials are required.
+        request.headers_mut().remove(AUTHORIZATION);
+        assert_matches!(
+            verify_request(&request, destination, &public_key_map),
+            Err(XMatrixVerificationError::MissingCredentials)
+        );
+    }
 }
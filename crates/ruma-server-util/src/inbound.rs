@@ -0,0 +1,982 @@
+//! Processing of the PDUs received in federation transactions.
+//!
+//! [`TransactionProcessor`] performs the [checks on receipt of a PDU] that only depend on the PDU
+//! and its auth events, and stores the accepted and rejected PDUs with a pluggable [`PduStore`].
+//!
+//! [checks on receipt of a PDU]: https://spec.matrix.org/latest/server-server-api/#checks-performed-on-receipt-of-a-pdu
+
+use std::{collections::BTreeMap, fmt, future::Future, sync::Arc};
+
+use js_int::UInt;
+use ruma_common::{
+    canonical_json::redact, room_version_rules::EventIdFormatVersion, CanonicalJsonObject,
+    CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId,
+    OwnedUserId, RoomId, RoomVersionId, ServerName, UserId,
+};
+use ruma_events::{
+    pdu::{RoomV1Pdu, RoomV3Pdu},
+    StateEventType, TimelineEventType,
+};
+use ruma_federation_api::transactions::send_transaction_message;
+use ruma_signatures::{reference_hash, verify_event, Verified};
+use ruma_state_res::{auth_check, auth_types_for_event, Event, RoomVersion};
+use serde::Deserialize;
+use serde_json::value::RawValue as RawJsonValue;
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use crate::{
+    acl::{ServerAclDenied, ServerAcls},
+    keys::{KeyStore, KeyTransport},
+};
+
+/// The storage used by a [`TransactionProcessor`].
+pub trait PduStore: Send + Sync {
+    /// The error returned when the storage fails.
+    type Error: fmt::Display;
+
+    /// Get the version of the given room, or `None` if the room is unknown.
+    ///
+    /// The version of an unknown room is taken from its `m.room.create` event.
+    fn room_version(
+        &self,
+        room_id: &RoomId,
+    ) -> impl Future<Output = Result<Option<RoomVersionId>, Self::Error>> + Send;
+
+    /// Get an accepted PDU, or `None` if it is unknown or was rejected.
+    ///
+    /// This is used to get the auth events of the PDUs. It can fetch the missing auth events from
+    /// other servers, as long as they go through the same checks before being returned.
+    fn get_pdu(
+        &self,
+        event_id: &EventId,
+    ) -> impl Future<Output = Result<Option<PduEvent>, Self::Error>> + Send;
+
+    /// Whether the given PDU was rejected, with [`PduStore::store_rejected_pdu()`].
+    fn is_rejected(
+        &self,
+        event_id: &EventId,
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send;
+
+    /// Store a PDU that passed the checks.
+    fn store_pdu(&self, pdu: PduEvent) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Store a PDU that has valid signatures but failed the checks of its auth events, with the
+    /// reason of the rejection.
+    ///
+    /// A rejected PDU must not be returned by [`PduStore::get_pdu()`], so it can't be used as an
+    /// auth event, but it must be kept so it is not processed again. It can still be referenced by
+    /// the `prev_events` of other PDUs.
+    fn store_rejected_pdu(
+        &self,
+        pdu: PduEvent,
+        reason: String,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+/// A PDU that passed the signature checks.
+///
+/// It implements [`Event`], so it can be used with the state resolution and authorization
+/// algorithms of ruma-state-res.
+#[derive(Clone, Debug)]
+pub struct PduEvent {
+    event_id: OwnedEventId,
+    room_id: OwnedRoomId,
+    sender: OwnedUserId,
+    origin_server_ts: MilliSecondsSinceUnixEpoch,
+    event_type: TimelineEventType,
+    content: Box<RawJsonValue>,
+    state_key: Option<String>,
+    prev_events: Vec<OwnedEventId>,
+    auth_events: Vec<OwnedEventId>,
+    redacts: Option<OwnedEventId>,
+    depth: UInt,
+    json: CanonicalJsonObject,
+}
+
+impl PduEvent {
+    /// Construct a `PduEvent` from the given event ID and canonical JSON of a PDU, using the
+    /// format of the given room version.
+    ///
+    /// Returns an error if the PDU has an invalid format or if the room version is not supported.
+    pub fn from_canonical_json(
+        event_id: OwnedEventId,
+        json: CanonicalJsonObject,
+        room_version: &RoomVersionId,
+    ) -> Result<Self, serde_json::Error> {
+        let rules = room_version.rules().ok_or_else(|| {
+            serde::de::Error::custom(format!("unsupported room version {room_version}"))
+        })?;
+        let serialized = serde_json::to_string(&json)?;
+
+        Ok(match rules.event_id_format {
+            EventIdFormatVersion::V1 => {
+                let pdu: RoomV1Pdu = serde_json::from_str(&serialized)?;
+                Self {
+                    event_id,
+                    room_id: pdu.room_id,
+                    sender: pdu.sender,
+                    origin_server_ts: pdu.origin_server_ts,
+                    event_type: pdu.kind,
+                    content: pdu.content,
+                    state_key: pdu.state_key,
+                    prev_events: pdu.prev_events.into_iter().map(|(id, _)| id).collect(),
+                    auth_events: pdu.auth_events.into_iter().map(|(id, _)| id).collect(),
+                    redacts: pdu.redacts,
+                    depth: pdu.depth,
+                    json,
+                }
+            }
+            _ => {
+                let pdu: RoomV3Pdu = serde_json::from_str(&serialized)?;
+                Self {
+                    event_id,
+                    room_id: pdu.room_id,
+                    sender: pdu.sender,
+                    origin_server_ts: pdu.origin_server_ts,
+                    event_type: pdu.kind,
+                    content: pdu.content,
+                    state_key: pdu.state_key,
+                    prev_events: pdu.prev_events,
+                    auth_events: pdu.auth_events,
+                    redacts: pdu.redacts,
+                    depth: pdu.depth,
+                    json,
+                }
+            }
+        })
+    }
+
+    /// The canonical JSON of the PDU.
+    ///
+    /// If the content hash of the PDU didn't match, this is the redacted PDU.
+    pub fn json(&self) -> &CanonicalJsonObject {
+        &self.json
+    }
+
+    /// Convert this `PduEvent` into the canonical JSON of the PDU.
+    pub fn into_json(self) -> CanonicalJsonObject {
+        self.json
+    }
+}
+
+impl Event for PduEvent {
+    type Id = OwnedEventId;
+
+    fn event_id(&self) -> &Self::Id {
+        &self.event_id
+    }
+
+    fn room_id(&self) -> &RoomId {
+        &self.room_id
+    }
+
+    fn sender(&self) -> &UserId {
+        &self.sender
+    }
+
+    fn origin_server_ts(&self) -> MilliSecondsSinceUnixEpoch {
+        self.origin_server_ts
+    }
+
+    fn event_type(&self) -> &TimelineEventType {
+        &self.event_type
+    }
+
+    fn content(&self) -> &RawJsonValue {
+        &self.content
+    }
+
+    fn state_key(&self) -> Option<&str> {
+        self.state_key.as_deref()
+    }
+
+    fn prev_events(&self) -> impl DoubleEndedIterator<Item = &Self::Id> + Send + '_ {
+        self.prev_events.iter()
+    }
+
+    fn auth_events(&self) -> impl DoubleEndedIterator<Item = &Self::Id> + Send + '_ {
+        self.auth_events.iter()
+    }
+
+    fn depth(&self) -> UInt {
+        self.depth
+    }
+
+    fn redacts(&self) -> Option<&Self::Id> {
+        self.redacts.as_ref()
+    }
+}
+
+/// A processor of the PDUs received in federation transactions.
+///
+/// For each PDU, it:
+///
+/// * checks that the PDU is valid for the version of its room,
+/// * checks that the origin of the transaction is allowed by the server ACL of the room, if
+///   [`ServerAcls`] are set,
+/// * verifies its signatures, with the keys of the [`KeyStore`],
+/// * redacts it if its content hash doesn't match,
+/// * checks that its auth events are the expected ones and that it passes the authorization rules
+///   based on them.
+///
+/// The PDUs that fail the last check are stored as rejected.
+///
+/// The checks based on the state of the room before the PDU and on the current state of the room
+/// are left to the homeserver, as well as fetching missing `prev_events`.
+#[derive(Debug)]
+pub struct TransactionProcessor<S, T> {
+    /// The storage of the PDUs.
+    store: S,
+
+    /// The store of the signing keys of the servers.
+    key_store: KeyStore<T>,
+
+    /// The server ACLs of the rooms.
+    server_acls: Option<Arc<ServerAcls>>,
+}
+
+impl<S, T> TransactionProcessor<S, T> {
+    /// Creates a new `TransactionProcessor` with the given storage and key store.
+    pub fn new(store: S, key_store: KeyStore<T>) -> Self {
+        Self { store, key_store, server_acls: None }
+    }
+
+    /// Reject the PDUs from origins that are denied by the server ACL of their room.
+    pub fn server_acls(self, server_acls: Arc<ServerAcls>) -> Self {
+        Self { server_acls: Some(server_acls), ..self }
+    }
+
+    /// The storage of the PDUs.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// The store of the signing keys of the servers.
+    pub fn key_store(&self) -> &KeyStore<T> {
+        &self.key_store
+    }
+}
+
+impl<S: PduStore, T: KeyTransport> TransactionProcessor<S, T> {
+    /// Process the PDUs of the given transaction.
+    ///
+    /// The PDUs are processed in order, so a PDU can use the PDUs before it in the same
+    /// transaction as auth events. PDUs that were already accepted or rejected are not processed
+    /// again.
+    ///
+    /// Returns the response to the transaction, with the errors of the PDUs that were rejected.
+    /// PDUs whose event ID can't be computed are ignored. The EDUs of the transaction are left to
+    /// the caller.
+    pub async fn process_transaction(
+        &self,
+        request: &send_transaction_message::v1::Request,
+    ) -> send_transaction_message::v1::Response {
+        let mut pdus = BTreeMap::new();
+
+        for pdu in &request.pdus {
+            let (event_id, room_id, room_version, object) = match self.prepare_pdu(pdu).await {
+                Ok(prepared) => prepared,
+                Err(error) => {
+                    warn!(origin = %request.origin, %error, "Ignoring invalid PDU");
+                    continue;
+                }
+            };
+
+            let result = match self.check_server_acl(&room_id, &request.origin) {
+                Ok(()) => self.process_pdu(event_id.clone(), &room_version, object).await,
+                Err(error) => Err(error),
+            };
+            if let Err(error) = &result {
+                debug!(origin = %request.origin, %event_id, %error, "Rejected PDU");
+            }
+
+            pdus.insert(event_id, result.map_err(|error| error.to_string()));
+        }
+
+        send_transaction_message::v1::Response::new(pdus)
+    }
+
+    /// Get the event ID, the room ID, the room version and the canonical JSON of the given PDU.
+    async fn prepare_pdu(
+        &self,
+        pdu: &RawJsonValue,
+    ) -> Result<(OwnedEventId, OwnedRoomId, RoomVersionId, CanonicalJsonObject), PduError> {
+        let object: CanonicalJsonObject = serde_json::from_str(pdu.get())?;
+
+        let room_id = match object.get("room_id") {
+            Some(CanonicalJsonValue::String(room_id)) => <&RoomId>::try_from(room_id.as_str())
+                .map_err(|_| PduError::InvalidPdu("invalid room_id"))?,
+            _ => return Err(PduError::InvalidPdu("missing room_id")),
+        };
+
+        // The create event is only trusted for the version of rooms that are not known yet.
+        let room_version = match self
+            .store
+            .room_version(room_id)
+            .await
+            .map_err(|error| PduError::Store(error.to_string()))?
+        {
+            Some(room_version) => room_version,
+            None => room_version_of_create_event(&object)?
+                .ok_or_else(|| PduError::UnknownRoom(room_id.to_owned()))?,
+        };
+
+        let rules = room_version
+            .rules()
+            .ok_or_else(|| PduError::UnsupportedRoomVersion(room_version.clone()))?;
+
+        let event_id = match rules.event_id_format {
+            EventIdFormatVersion::V1 => match object.get("event_id") {
+                Some(CanonicalJsonValue::String(event_id)) => EventId::parse(event_id)
+                    .map_err(|_| PduError::InvalidPdu("invalid event_id"))?,
+                _ => return Err(PduError::InvalidPdu("missing event_id")),
+            },
+            _ => EventId::parse(format!("${}", reference_hash(&object, &room_version)?))
+                .map_err(|_| PduError::InvalidPdu("invalid reference hash"))?,
+        };
+
+        Ok((event_id, room_id.to_owned(), room_version, object))
+    }
+
+    /// Check that the given origin is allowed by the server ACL of the given room.
+    fn check_server_acl(&self, room_id: &RoomId, origin: &ServerName) -> Result<(), PduError> {
+        match &self.server_acls {
+            Some(server_acls) => Ok(server_acls.check(room_id, origin)?),
+            None => Ok(()),
+        }
+    }
+
+    /// Check the given PDU and store it as accepted or rejected.
+    async fn process_pdu(
+        &self,
+        event_id: OwnedEventId,
+        room_version: &RoomVersionId,
+        mut object: CanonicalJsonObject,
+    ) -> Result<(), PduError> {
+        if self.get_pdu(&event_id).await?.is_some() {
+            return Ok(());
+        }
+        if self.is_rejected(&event_id).await? {
+            return Err(PduError::Rejected(event_id));
+        }
+
+        let public_key_map = self.key_store.public_key_map_for_event(&object, room_version).await?;
+        if verify_event(&public_key_map, &object, room_version)? == Verified::Signatures {
+            debug!(%event_id, "Content hash mismatch, redacting PDU");
+            object = redact(object, room_version, None)
+                .map_err(|_| PduError::InvalidPdu("PDU cannot be redacted"))?;
+        }
+
+        let event = PduEvent::from_canonical_json(event_id, object, room_version)?;
+
+        match self.check_auth_events(&event, room_version).await {
+            Ok(()) => self
+                .store
+                .store_pdu(event)
+                .await
+                .map_err(|error| PduError::Store(error.to_string())),
+            Err(error @ PduError::Store(_)) => Err(error),
+            Err(error) => {
+                self.store
+                    .store_rejected_pdu(event, error.to_string())
+                    .await
+                    .map_err(|error| PduError::Store(error.to_string()))?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Check that the auth events of the given PDU are valid and that it passes the authorization
+    /// rules based on them.
+    async fn check_auth_events(
+        &self,
+        event: &PduEvent,
+        room_version: &RoomVersionId,
+    ) -> Result<(), PduError> {
+        let auth_events = self.auth_events(event).await?;
+
+        let third_party_invite = third_party_invite_token(event).and_then(|token| {
+            auth_events.get(&(StateEventType::RoomThirdPartyInvite, token.to_owned()))
+        });
+        let rules = room_version
+            .rules()
+            .ok_or_else(|| PduError::UnsupportedRoomVersion(room_version.clone()))?;
+
+        auth_check(
+            &RoomVersion::from_rules(&rules)?,
+            event,
+            third_party_invite,
+            |event_type, state_key| {
+                std::future::ready(auth_events.get(&(event_type.clone(), state_key.to_owned())))
+            },
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Get the auth events of the given PDU, by type and state key.
+    ///
+    /// Checks that there are no duplicate entries and that they are the ones selected by the
+    /// [auth events selection] algorithm.
+    ///
+    /// [auth events selection]: https://spec.matrix.org/latest/server-server-api/#auth-events-selection
+    async fn auth_events(
+        &self,
+        event: &PduEvent,
+    ) -> Result<BTreeMap<(StateEventType, String), PduEvent>, PduError> {
+        let expected_auth_types = auth_types_for_event(
+            event.event_type(),
+            event.sender(),
+            event.state_key(),
+            event.content(),
+        )?;
+
+        let mut auth_events = BTreeMap::new();
+
+        for auth_event_id in event.auth_events() {
+            let Some(auth_event) = self.get_pdu(auth_event_id).await? else {
+                if self.is_rejected(auth_event_id).await? {
+                    return Err(PduError::RejectedAuthEvent(auth_event_id.clone()));
+                }
+
+                return Err(PduError::MissingAuthEvent(auth_event_id.clone()));
+            };
+
+            if auth_event.room_id() != event.room_id() {
+                return Err(PduError::InvalidAuthEvent(auth_event_id.clone()));
+            }
+
+            let key = (
+                StateEventType::from(auth_event.event_type().to_string()),
+                auth_event.state_key().unwrap_or_default().to_owned(),
+            );
+            if auth_event.state_key().is_none() || !expected_auth_types.contains(&key) {
+                return Err(PduError::InvalidAuthEvent(auth_event_id.clone()));
+            }
+
+            if auth_events.insert(key, auth_event).is_some() {
+                return Err(PduError::DuplicateAuthEvent(auth_event_id.clone()));
+            }
+        }
+
+        Ok(auth_events)
+    }
+
+    async fn get_pdu(&self, event_id: &EventId) -> Result<Option<PduEvent>, PduError> {
+        self.store.get_pdu(event_id).await.map_err(|error| PduError::Store(error.to_string()))
+    }
+
+    async fn is_rejected(&self, event_id: &EventId) -> Result<bool, PduError> {
+        self.store.is_rejected(event_id).await.map_err(|error| PduError::Store(error.to_string()))
+    }
+}
+
+/// Get the room version from the given PDU, if it is an `m.room.create` event.
+fn room_version_of_create_event(
+    object: &CanonicalJsonObject,
+) -> Result<Option<RoomVersionId>, PduError> {
+    let is_create_event = matches!(object.get("type"), Some(CanonicalJsonValue::String(t)) if t == "m.room.create")
+        && matches!(object.get("state_key"), Some(CanonicalJsonValue::String(s)) if s.is_empty());
+    if !is_create_event {
+        return Ok(None);
+    }
+
+    match object.get("content") {
+        Some(CanonicalJsonValue::Object(content)) => match content.get("room_version") {
+            Some(CanonicalJsonValue::String(room_version)) => {
+                RoomVersionId::try_from(room_version.as_str())
+                    .map(Some)
+                    .map_err(|_| PduError::InvalidPdu("invalid room_version"))
+            }
+            // The default room version of `m.room.create` events.
+            None => Ok(Some(RoomVersionId::V1)),
+            Some(_) => Err(PduError::InvalidPdu("invalid room_version")),
+        },
+        _ => Err(PduError::InvalidPdu("missing content")),
+    }
+}
+
+/// Get the token of the third-party invite of the given `m.room.member` event, if any.
+fn third_party_invite_token(event: &PduEvent) -> Option<String> {
+    #[derive(Deserialize)]
+    struct MemberContent {
+        third_party_invite: ThirdPartyInvite,
+    }
+
+    #[derive(Deserialize)]
+    struct ThirdPartyInvite {
+        signed: Signed,
+    }
+
+    #[derive(Deserialize)]
+    struct Signed {
+        token: String,
+    }
+
+    if *event.event_type() != TimelineEventType::RoomMember {
+        return None;
+    }
+
+    serde_json::from_str::<MemberContent>(event.content().get())
+        .ok()
+        .map(|content| content.third_party_invite.signed.token)
+}
+
+/// An error when processing a PDU.
+#[derive(Debug, Error)]
+enum PduError {
+    /// The PDU has an invalid format.
+    #[error("invalid PDU: {0}")]
+    InvalidPdu(&'static str),
+
+    /// The PDU could not be deserialized.
+    #[error("invalid PDU: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// The room of the PDU is unknown.
+    #[error("unknown room {0}")]
+    UnknownRoom(OwnedRoomId),
+
+    /// The version of the room is not supported.
+    #[error("unsupported room version {0}")]
+    UnsupportedRoomVersion(RoomVersionId),
+
+    /// The signatures of the PDU are invalid.
+    #[error("failed to verify signatures: {0}")]
+    Signatures(#[from] ruma_signatures::Error),
+
+    /// The PDU was already rejected.
+    #[error("PDU {0} was already rejected")]
+    Rejected(OwnedEventId),
+
+    /// An auth event of the PDU is unknown.
+    #[error("missing auth event {0}")]
+    MissingAuthEvent(OwnedEventId),
+
+    /// An auth event of the PDU was rejected.
+    #[error("rejected auth event {0}")]
+    RejectedAuthEvent(OwnedEventId),
+
+    /// An auth event of the PDU is not in the same room, or is not expected for this PDU.
+    #[error("invalid auth event {0}")]
+    InvalidAuthEvent(OwnedEventId),
+
+    /// The PDU has several auth events with the same type and state key.
+    #[error("duplicate auth event {0}")]
+    DuplicateAuthEvent(OwnedEventId),
+
+    /// The PDU failed the authorization rules.
+    #[error(transparent)]
+    AuthCheck(#[from] ruma_state_res::Error),
+
+    /// The origin of the transaction is denied by the server ACL of the room.
+    #[error(transparent)]
+    ServerAcl(#[from] ServerAclDenied),
+
+    /// The storage failed.
+    #[error("storage error: {0}")]
+    Store(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        future::{ready, Future},
+        sync::{Arc, Mutex},
+    };
+
+    use js_int::{uint, UInt};
+    use ruma_common::{
+        owned_event_id, owned_room_id, owned_user_id, room_id,
+        serde::{base64::Standard, Base64, Raw},
+        server_name, CanonicalJsonObject, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch,
+        OwnedEventId, RoomId, RoomVersionId, ServerName,
+    };
+    use ruma_events::{
+        pdu::Pdu,
+        room::{
+            create::RoomCreateEventContent,
+            member::{MembershipState, RoomMemberEventContent},
+            message::RoomMessageEventContent,
+            server_acl::RoomServerAclEventContent,
+        },
+        AnyMessageLikeEventContent, AnyStateEventContent,
+    };
+    use ruma_federation_api::{
+        discovery::{
+            get_remote_server_keys_batch::v2 as get_remote_server_keys_batch,
+            get_server_keys::v2 as get_server_keys,
+        },
+        transactions::send_transaction_message,
+    };
+    use ruma_signatures::{reference_hash, sign_json, Ed25519KeyPair};
+    use serde_json::{json, value::to_raw_value as to_raw_json_value};
+
+    use super::{PduEvent, PduStore, TransactionProcessor};
+    use crate::{
+        acl::ServerAcls,
+        keys::{KeyStore, KeyTransport},
+        pdu::PduBuilder,
+    };
+
+    /// A transport for servers that are all unreachable.
+    struct OfflineTransport;
+
+    impl KeyTransport for OfflineTransport {
+        type Error = String;
+
+        fn get_server_keys(
+            &self,
+            server: &ServerName,
+        ) -> impl Future<Output = Result<get_server_keys::Response, Self::Error>> + Send {
+            ready(Err(format!("{server} is unreachable")))
+        }
+
+        fn get_remote_server_keys_batch(
+            &self,
+            notary: &ServerName,
+            _request: get_remote_server_keys_batch::Request,
+        ) -> impl Future<Output = Result<get_remote_server_keys_batch::Response, Self::Error>> + Send
+        {
+            ready(Err(format!("{notary} is unreachable")))
+        }
+    }
+
+    #[derive(Default)]
+    struct MemoryStore {
+        pdus: Mutex<BTreeMap<OwnedEventId, PduEvent>>,
+        rejected_pdus: Mutex<BTreeMap<OwnedEventId, String>>,
+    }
+
+    impl PduStore for MemoryStore {
+        type Error = String;
+
+        fn room_version(
+            &self,
+            room_id: &RoomId,
+        ) -> impl Future<Output = Result<Option<RoomVersionId>, Self::Error>> + Send {
+            ready(Ok((room_id == "!room:origin.local").then_some(RoomVersionId::V11)))
+        }
+
+        fn get_pdu(
+            &self,
+            event_id: &EventId,
+        ) -> impl Future<Output = Result<Option<PduEvent>, Self::Error>> + Send {
+            ready(Ok(self.pdus.lock().unwrap().get(event_id).cloned()))
+        }
+
+        fn is_rejected(
+            &self,
+            event_id: &EventId,
+        ) -> impl Future<Output = Result<bool, Self::Error>> + Send {
+            ready(Ok(self.rejected_pdus.lock().unwrap().contains_key(event_id)))
+        }
+
+        fn store_pdu(&self, pdu: PduEvent) -> impl Future<Output = Result<(), Self::Error>> + Send {
+            self.pdus.lock().unwrap().insert(pdu.event_id.clone(), pdu);
+            ready(Ok(()))
+        }
+
+        fn store_rejected_pdu(
+            &self,
+            pdu: PduEvent,
+            reason: String,
+        ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+            self.rejected_pdus.lock().unwrap().insert(pdu.event_id, reason);
+            ready(Ok(()))
+        }
+    }
+
+    struct Room {
+        processor: TransactionProcessor<MemoryStore, OfflineTransport>,
+        key_pair: Ed25519KeyPair,
+        create: OwnedEventId,
+        member: OwnedEventId,
+    }
+
+    impl Room {
+        /// A room with an `m.room.create` event and the membership of `@alice:origin.local`.
+        async fn new() -> (Self, send_transaction_message::v1::Response) {
+            let document = Ed25519KeyPair::generate().unwrap();
+            let key_pair = Ed25519KeyPair::from_der(&document, "1".to_owned()).unwrap();
+
+            let mut server_keys: CanonicalJsonObject = serde_json::from_value(json!({
+                "server_name": "origin.local",
+                "verify_keys": {
+                    "ed25519:1": {
+                        "key": Base64::<Standard>::new(key_pair.public_key().to_vec()).encode(),
+                    },
+                },
+                "old_verify_keys": {},
+                "valid_until_ts": u64::from(MilliSecondsSinceUnixEpoch::now().get()) + 3_600_000,
+            }))
+            .unwrap();
+            sign_json("origin.local", &key_pair, &mut server_keys).unwrap();
+
+            let key_store = KeyStore::new(OfflineTransport);
+            key_store
+                .add_server_keys(&Raw::from_json(to_raw_json_value(&server_keys).unwrap()))
+                .unwrap();
+
+            let mut room = Self {
+                processor: TransactionProcessor::new(MemoryStore::default(), key_store),
+                key_pair,
+                create: owned_event_id!("$create"),
+                member: owned_event_id!("$member"),
+            };
+
+            let create_content = RoomCreateEventContent::new_v11();
+            let create = room.state(AnyStateEventContent::RoomCreate(create_content), "", vec![]);
+            room.create = create.0.clone();
+
+            let member_content = RoomMemberEventContent::new(MembershipState::Join);
+            let member = room.state(
+                AnyStateEventContent::RoomMember(member_content),
+                "@alice:origin.local",
+                vec![room.create.clone()],
+            );
+            room.member = member.0.clone();
+
+            let response = room.send(vec![create.1, member.1]).await;
+            (room, response)
+        }
+
+        fn state(
+            &self,
+            content: AnyStateEventContent,
+            state_key: &str,
+            auth_events: Vec<OwnedEventId>,
+        ) -> (OwnedEventId, Pdu) {
+            PduBuilder::state(
+                owned_room_id!("!room:origin.local"),
+                owned_user_id!("@alice:origin.local"),
+                &content,
+                state_key.to_owned(),
+                UInt::from(auth_events.len() as u32 + 1),
+            )
+            .unwrap()
+            .prev_events(auth_events.clone())
+            .auth_events(auth_events)
+            .build(server_name!("origin.local"), &self.key_pair, &RoomVersionId::V11)
+            .unwrap()
+        }
+
+        fn message(&self, sender: &str) -> (OwnedEventId, Pdu) {
+            self.message_with_auth_events(sender, vec![self.create.clone(), self.member.clone()])
+        }
+
+        fn message_with_auth_events(
+            &self,
+            sender: &str,
+            auth_events: Vec<OwnedEventId>,
+        ) -> (OwnedEventId, Pdu) {
+            let content = RoomMessageEventContent::text_plain("Hello");
+            PduBuilder::message_like(
+                owned_room_id!("!room:origin.local"),
+                sender.try_into().unwrap(),
+                &AnyMessageLikeEventContent::RoomMessage(content),
+                uint!(4),
+            )
+            .unwrap()
+            .auth_events(auth_events)
+            .origin_server_ts(MilliSecondsSinceUnixEpoch(uint!(1_000)))
+            .build(server_name!("origin.local"), &self.key_pair, &RoomVersionId::V11)
+            .unwrap()
+        }
+
+        async fn send(&self, pdus: Vec<Pdu>) -> send_transaction_message::v1::Response {
+            let mut request = send_transaction_message::v1::Request::new(
+                "txn".into(),
+                server_name!("origin.local").to_owned(),
+                MilliSecondsSinceUnixEpoch::now(),
+            );
+            request.pdus = pdus.iter().map(|pdu| to_raw_json_value(pdu).unwrap()).collect();
+            self.processor.process_transaction(&request).await
+        }
+    }
+
+    fn reference_event_id(pdu: &Pdu) -> OwnedEventId {
+        let object: CanonicalJsonObject =
+            serde_json::from_value(serde_json::to_value(pdu).unwrap()).unwrap();
+        let hash = reference_hash(&object, &RoomVersionId::V11).unwrap();
+        OwnedEventId::try_from(format!("${hash}")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn accept_valid_pdus() {
+        let (room, response) = Room::new().await;
+        assert_eq!(response.pdus.len(), 2);
+        assert_eq!(response.pdus[&room.create], Ok(()));
+        assert_eq!(response.pdus[&room.member], Ok(()));
+
+        let (event_id, message) = room.message("@alice:origin.local");
+        let response = room.send(vec![message]).await;
+        assert_eq!(response.pdus[&event_id], Ok(()));
+        assert_eq!(room.processor.store().pdus.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn reject_unauthorized_pdu() {
+        let (room, _) = Room::new().await;
+
+        // Bob is not in the room, and his membership is not an expected auth event.
+        let (event_id, message) = room.message("@bob:origin.local");
+        let response = room.send(vec![message]).await;
+        let error = response.pdus[&event_id].as_ref().unwrap_err();
+        assert!(error.starts_with("invalid auth event"), "{error}");
+
+        let (event_id, message) =
+            room.message_with_auth_events("@bob:origin.local", vec![room.create.clone()]);
+        let response = room.send(vec![message]).await;
+        let error = response.pdus[&event_id].as_ref().unwrap_err();
+        assert!(error.contains("sender is not joined to the room"), "{error}");
+
+        assert_eq!(room.processor.store().pdus.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_signatures_and_missing_auth_events() {
+        let (room, _) = Room::new().await;
+
+        // Changing a field that is not redacted invalidates the signature.
+        let (_, mut message) = room.message("@alice:origin.local");
+        let Pdu::RoomV3Pdu(pdu) = &mut message else { panic!("unexpected PDU format") };
+        pdu.origin_server_ts = MilliSecondsSinceUnixEpoch(uint!(2_000));
+        let event_id = reference_event_id(&message);
+
+        let auth_events =
+            vec![room.create.clone(), room.member.clone(), owned_event_id!("$unknown")];
+        let (missing_event_id, missing_auth) =
+            room.message_with_auth_events("@alice:origin.local", auth_events);
+
+        let response = room.send(vec![message, missing_auth]).await;
+        let error = response.pdus[&event_id].as_ref().unwrap_err();
+        assert!(error.starts_with("failed to verify signatures"), "{error}");
+        let error = response.pdus[&missing_event_id].as_ref().unwrap_err();
+        assert_eq!(error, "missing auth event $unknown");
+    }
+
+    #[tokio::test]
+    async fn redact_pdu_with_content_hash_mismatch() {
+        let (room, _) = Room::new().await;
+
+        let (event_id, mut message) = room.message("@alice:origin.local");
+        let Pdu::RoomV3Pdu(pdu) = &mut message else { panic!("unexpected PDU format") };
+        pdu.content = to_raw_json_value(&json!({ "msgtype": "m.text", "body": "Bye" })).unwrap();
+
+        // The content is not part of the reference hash, so the event ID doesn't change.
+        let response = room.send(vec![message]).await;
+        assert_eq!(response.pdus[&event_id], Ok(()));
+
+        let pdus = room.processor.store().pdus.lock().unwrap();
+        assert_eq!(
+            pdus[&event_id].json()["content"],
+            CanonicalJsonValue::Object(Default::default())
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_pdu_denied_by_server_acl() {
+        let (mut room, _) = Room::new().await;
+
+        let server_acls = Arc::new(ServerAcls::new());
+        let content = RoomServerAclEventContent::new(
+            false,
+            vec!["*".to_owned()],
+            vec!["origin.local".to_owned()],
+        );
+        server_acls.set_acl(room_id!("!room:origin.local"), Some(&content));
+        room.processor = room.processor.server_acls(server_acls);
+
+        let (event_id, message) = room.message("@alice:origin.local");
+        let response = room.send(vec![message]).await;
+        let error = response.pdus[&event_id].as_ref().unwrap_err();
+        assert_eq!(
+            error,
+            "server origin.local is denied by the server ACL of room !room:origin.local"
+        );
+        assert_eq!(room.processor.store().pdus.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn store_rejected_pdus() {
+        let (room, _) = Room::new().await;
+
+        let (rejected_event_id, message) =
+            room.message_with_auth_events("@bob:origin.local", vec![room.create.clone()]);
+        let response = room.send(vec![message.clone()]).await;
+        let error = response.pdus[&rejected_event_id].as_ref().unwrap_err();
+        assert!(error.contains("sender is not joined to the room"), "{error}");
+        assert_eq!(
+            room.processor.store().rejected_pdus.lock().unwrap()[&rejected_event_id],
+            *error
+        );
+
+        // The PDU is not processed again.
+        let response = room.send(vec![message]).await;
+        let error = response.pdus[&rejected_event_id].as_ref().unwrap_err();
+        assert!(error.ends_with("was already rejected"), "{error}");
+
+        // A rejected PDU can't be used as an auth event.
+        let auth_events = vec![room.create.clone(), room.member.clone(), rejected_event_id.clone()];
+        let (event_id, message) = room.message_with_auth_events("@alice:origin.local", auth_events);
+        let response = room.send(vec![message]).await;
+        let error = response.pdus[&event_id].as_ref().unwrap_err();
+        assert_eq!(*error, format!("rejected auth event {rejected_event_id}"));
+
+        // PDUs with invalid signatures are not stored.
+        let (_, mut message) = room.message("@alice:origin.local");
+        let Pdu::RoomV3Pdu(pdu) = &mut message else { panic!("unexpected PDU format") };
+        pdu.origin_server_ts = MilliSecondsSinceUnixEpoch(uint!(2_000));
+        let invalid_event_id = reference_event_id(&message);
+        room.send(vec![message]).await;
+
+        let rejected_pdus = room.processor.store().rejected_pdus.lock().unwrap();
+        assert_eq!(rejected_pdus.len(), 2);
+        assert!(rejected_pdus.contains_key(&event_id));
+        assert!(!rejected_pdus.contains_key(&invalid_event_id));
+        assert_eq!(room.processor.store().pdus.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn room_version_of_known_and_new_rooms() {
+        let (room, _) = Room::new().await;
+
+        let create_in_room = |room_id: &str, room_version: RoomVersionId| {
+            let mut content = RoomCreateEventContent::new_v11();
+            content.room_version = room_version;
+            PduBuilder::state(
+                room_id.try_into().unwrap(),
+                owned_user_id!("@alice:origin.local"),
+                &AnyStateEventContent::RoomCreate(content),
+                "",
+                uint!(1),
+            )
+            .unwrap()
+            .build(server_name!("origin.local"), &room.key_pair, &RoomVersionId::V11)
+            .unwrap()
+        };
+
+        // The version of a known room is used, even if the create event claims another one.
+        let (event_id, create) = create_in_room("!room:origin.local", RoomVersionId::V1);
+        let response = room.send(vec![create]).await;
+        assert_eq!(response.pdus[&event_id], Ok(()));
+
+        // The version of a new room is the one of its create event.
+        let (event_id, create) = create_in_room("!new:origin.local", RoomVersionId::V11);
+        let response = room.send(vec![create]).await;
+        assert_eq!(response.pdus[&event_id], Ok(()));
+
+        // The event ID can't be computed without the rules of the room version, so the PDU is
+        // ignored.
+        let unknown_room_version = RoomVersionId::try_from("org.example.unknown").unwrap();
+        let (event_id, create) = create_in_room("!unknown:origin.local", unknown_room_version);
+        let response = room.send(vec![create]).await;
+        assert!(response.pdus.is_empty());
+        assert!(!room.processor.store().pdus.lock().unwrap().contains_key(&event_id));
+        assert!(room.processor.store().rejected_pdus.lock().unwrap().is_empty());
+    }
+}
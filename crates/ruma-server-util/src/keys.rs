@@ -0,0 +1,932 @@
+//! Fetching and caching of the signing keys of homeservers.
+//!
+//! [`verify_event`] and [`verify_json`] need the public keys of the servers that signed an object.
+//! A [`KeyStore`] gathers them from its cache, from the servers themselves with the
+//! [`get_server_keys`] endpoint, or from notary servers with the [`get_remote_server_keys_batch`]
+//! endpoint.
+//!
+//! [`verify_event`]: ruma_signatures::verify_event
+//! [`get_server_keys`]: ruma_federation_api::discovery::get_server_keys
+//! [`get_remote_server_keys_batch`]: ruma_federation_api::discovery::get_remote_server_keys_batch
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    future::Future,
+    sync::RwLock,
+};
+
+use futures_util::future::join_all;
+use js_int::UInt;
+use ruma_common::{
+    canonical_json::JsonType, serde::Raw, CanonicalJsonObject, CanonicalJsonValue,
+    MilliSecondsSinceUnixEpoch, OwnedServerName, OwnedServerSigningKeyId, RoomVersionId,
+    ServerName,
+};
+use ruma_federation_api::discovery::{
+    get_remote_server_keys_batch::v2 as get_remote_server_keys_batch,
+    get_server_keys::v2 as get_server_keys, ServerSigningKeys,
+};
+use ruma_signatures::{
+    verify_json, Error, JsonError, PublicKeyMap, PublicKeySet, VerificationError,
+};
+use tracing::warn;
+
+/// The maximum validity period of the keys returned by a server, 7 days.
+///
+/// Servers must use the lesser of `valid_until_ts` and 7 days into the future.
+const MAX_VALIDITY_PERIOD_MS: u32 = 604_800_000;
+
+/// A transport used by a [`KeyStore`] to send requests to other homeservers.
+pub trait KeyTransport: Send + Sync {
+    /// The error returned when a request fails.
+    type Error: fmt::Display;
+
+    /// Send a [`get_server_keys`] request to the given server.
+    ///
+    /// [`get_server_keys`]: ruma_federation_api::discovery::get_server_keys
+    fn get_server_keys(
+        &self,
+        server: &ServerName,
+    ) -> impl Future<Output = Result<get_server_keys::Response, Self::Error>> + Send;
+
+    /// Send a [`get_remote_server_keys_batch`] request to the given notary server.
+    ///
+    /// [`get_remote_server_keys_batch`]: ruma_federation_api::discovery::get_remote_server_keys_batch
+    fn get_remote_server_keys_batch(
+        &self,
+        notary: &ServerName,
+        request: get_remote_server_keys_batch::Request,
+    ) -> impl Future<Output = Result<get_remote_server_keys_batch::Response, Self::Error>> + Send;
+}
+
+/// A store of the signing keys of homeservers.
+///
+/// The keys are cached after they were fetched. Keys that are missing from the cache, or that are
+/// not valid anymore at the time they are needed, are fetched from their server first, then from
+/// the notary servers, in order.
+///
+/// Only keys that are signed by their server are stored. The keys fetched from a server must be the
+/// ones of that server, and the keys fetched from a notary server must also be signed by the
+/// notary server, with one of its keys that are already trusted. If they are not cached, the keys
+/// of the notary server are fetched from the notary server itself first. In both cases, only the
+/// keys that were requested are stored.
+#[derive(Debug)]
+pub struct KeyStore<T> {
+    /// The transport used to send requests.
+    transport: T,
+
+    /// The notary servers to query for keys that could not be fetched from their server.
+    notary_servers: Vec<OwnedServerName>,
+
+    /// The cached keys, by server name and key ID.
+    keys: RwLock<BTreeMap<OwnedServerName, BTreeMap<String, CachedKey>>>,
+}
+
+impl<T> KeyStore<T> {
+    /// Creates a new `KeyStore` using the given transport, without notary servers.
+    pub fn new(transport: T) -> Self {
+        Self { transport, notary_servers: Vec::new(), keys: RwLock::new(BTreeMap::new()) }
+    }
+
+    /// Set the notary servers to query for keys that could not be fetched from their server.
+    ///
+    /// The notary servers are queried in the given order, until all keys are found.
+    pub fn notary_servers(self, notary_servers: Vec<OwnedServerName>) -> Self {
+        Self { notary_servers, ..self }
+    }
+
+    /// Add the given keys to the cache.
+    ///
+    /// This can be used to add keys that were fetched by other means, like the ones persisted in a
+    /// database.
+    ///
+    /// Returns the name of the server of the keys, or an error if they are not signed by one of
+    /// the keys in `verify_keys`. The signatures of other servers, like notary servers, are
+    /// ignored.
+    pub fn add_server_keys(
+        &self,
+        server_keys: &Raw<ServerSigningKeys>,
+    ) -> Result<OwnedServerName, Error> {
+        let (server_keys, _) = verify_server_keys(server_keys)?;
+        let server_name = server_keys.server_name.clone();
+        self.cache_server_keys(server_keys, None);
+
+        Ok(server_name)
+    }
+
+    /// Add the given verified keys to the cache.
+    ///
+    /// If `key_ids` is set, only the keys with these IDs are added.
+    fn cache_server_keys(
+        &self,
+        server_keys: ServerSigningKeys,
+        key_ids: Option<&BTreeSet<String>>,
+    ) {
+        let max_valid_until_ts = MilliSecondsSinceUnixEpoch(
+            MilliSecondsSinceUnixEpoch::now().get().saturating_add(MAX_VALIDITY_PERIOD_MS.into()),
+        );
+        let valid_until_ts = server_keys.valid_until_ts.min(max_valid_until_ts);
+
+        let new_keys = server_keys
+            .verify_keys
+            .into_iter()
+            .map(|(key_id, verify_key)| (key_id, CachedKey { key: verify_key.key, valid_until_ts }))
+            .chain(server_keys.old_verify_keys.into_iter().map(|(key_id, old_verify_key)| {
+                (
+                    key_id,
+                    CachedKey {
+                        key: old_verify_key.key,
+                        valid_until_ts: old_verify_key.expired_ts,
+                    },
+                )
+            }))
+            .map(|(key_id, key)| (key_id.to_string(), key))
+            .filter(|(key_id, _)| key_ids.map_or(true, |key_ids| key_ids.contains(key_id)));
+
+        let mut keys = self.keys.write().expect("keys lock was poisoned");
+        let server_cache = keys.entry(server_keys.server_name).or_default();
+
+        for (key_id, new_key) in new_keys {
+            // Keep the key with the latest validity.
+            if server_cache
+                .get(&key_id)
+                .map_or(true, |key| key.valid_until_ts < new_key.valid_until_ts)
+            {
+                server_cache.insert(key_id, new_key);
+            }
+        }
+    }
+
+    /// Get the cached keys of the given server that are valid at the given time.
+    ///
+    /// If `valid_at` is `None`, all the cached keys of the server are returned.
+    pub fn cached_keys(
+        &self,
+        server: &ServerName,
+        valid_at: Option<MilliSecondsSinceUnixEpoch>,
+    ) -> PublicKeySet {
+        let keys = self.keys.read().expect("keys lock was poisoned");
+
+        keys.get(server)
+            .into_iter()
+            .flatten()
+            .filter(|(_, key)| key.is_valid_at(valid_at))
+            .map(|(key_id, key)| (key_id.clone(), key.key.clone()))
+            .collect()
+    }
+
+    /// Get the keys in `required_keys` that are not in the cache or not valid at the given time.
+    fn missing_keys(
+        &self,
+        required_keys: &BTreeMap<OwnedServerName, BTreeSet<String>>,
+        valid_at: Option<MilliSecondsSinceUnixEpoch>,
+    ) -> BTreeMap<OwnedServerName, BTreeSet<String>> {
+        let keys = self.keys.read().expect("keys lock was poisoned");
+
+        required_keys
+            .iter()
+            .filter_map(|(server, key_ids)| {
+                let server_cache = keys.get(server);
+                let missing_key_ids: BTreeSet<_> = key_ids
+                    .iter()
+                    .filter(|key_id| {
+                        !server_cache
+                            .and_then(|server_cache| server_cache.get(*key_id))
+                            .is_some_and(|key| key.is_valid_at(valid_at))
+                    })
+                    .cloned()
+                    .collect();
+
+                (!missing_key_ids.is_empty()).then(|| (server.clone(), missing_key_ids))
+            })
+            .collect()
+    }
+
+    /// Verify the keys returned by `origin` and add the requested ones to the cache.
+    ///
+    /// If `notary_keys` is `None`, `origin` was queried for its own keys. Otherwise `origin` is a
+    /// notary server, and the keys must also be signed by it with one of `notary_keys`.
+    ///
+    /// If `requested_keys` is set, the keys must be the ones of one of its servers, and only the
+    /// key IDs that were requested for that server are added. Nothing is added if the keys are
+    /// invalid.
+    fn add_fetched_server_keys(
+        &self,
+        origin: &ServerName,
+        notary_keys: Option<&PublicKeySet>,
+        server_keys: &Raw<ServerSigningKeys>,
+        requested_keys: Option<&BTreeMap<OwnedServerName, BTreeSet<String>>>,
+    ) -> Result<(), FetchedKeysError> {
+        let (server_keys, server_keys_object) = verify_server_keys(server_keys)?;
+        let server_name = &server_keys.server_name;
+
+        if notary_keys.is_none() && server_name != origin {
+            return Err(FetchedKeysError::UnexpectedServer(server_name.clone()));
+        }
+
+        let key_ids = requested_keys
+            .map(|requested_keys| {
+                requested_keys
+                    .get(server_name)
+                    .ok_or_else(|| FetchedKeysError::UnexpectedServer(server_name.clone()))
+            })
+            .transpose()?;
+
+        if let Some(notary_keys) = notary_keys {
+            verify_signature(origin, notary_keys.clone(), server_keys_object)?;
+        }
+
+        self.cache_server_keys(server_keys, key_ids);
+
+        Ok(())
+    }
+}
+
+impl<T: KeyTransport> KeyStore<T> {
+    /// Get the public keys needed to verify the signatures of the given object.
+    ///
+    /// The required keys are the ones in the `signatures` field of the object. The keys that are
+    /// not cached, or that are not valid at `valid_at`, are fetched. If `valid_at` is `None`, the
+    /// validity of the keys is not checked.
+    ///
+    /// The keys that could not be found are missing from the returned map, which results in an
+    /// error when verifying the signatures.
+    ///
+    /// Returns an error if the `signatures` field of the object is missing or invalid.
+    pub async fn public_key_map(
+        &self,
+        object: &CanonicalJsonObject,
+        valid_at: Option<MilliSecondsSinceUnixEpoch>,
+    ) -> Result<PublicKeyMap, Error> {
+        let required_keys = required_keys(object)?;
+        self.fetch_missing_keys(&required_keys, valid_at).await;
+
+        let public_key_map = required_keys
+            .into_iter()
+            .map(|(server, key_ids)| {
+                let mut public_keys = self.cached_keys(&server, valid_at);
+                public_keys.retain(|key_id, _| key_ids.contains(key_id));
+                (server.into(), public_keys)
+            })
+            .collect();
+
+        Ok(public_key_map)
+    }
+
+    /// Get the public keys needed to verify the signatures of the given PDU with
+    /// [`verify_event`].
+    ///
+    /// If the room version enforces the validity period of signing keys, the keys must be valid
+    /// at the `origin_server_ts` of the event.
+    ///
+    /// Returns an error if the `signatures` or `origin_server_ts` field of the event is missing or
+    /// invalid.
+    ///
+    /// [`verify_event`]: ruma_signatures::verify_event
+    pub async fn public_key_map_for_event(
+        &self,
+        event: &CanonicalJsonObject,
+        room_version: &RoomVersionId,
+    ) -> Result<PublicKeyMap, Error> {
+        let enforce_key_validity =
+            room_version.rules().map_or(true, |rules| rules.enforce_key_validity);
+
+        let valid_at = if enforce_key_validity {
+            let origin_server_ts = match event.get("origin_server_ts") {
+                Some(CanonicalJsonValue::Integer(ts)) => {
+                    UInt::try_from(i64::from(*ts)).map_err(|_| JsonError::NotOfType {
+                        target: "origin_server_ts".to_owned(),
+                        of_type: JsonType::Integer,
+                    })?
+                }
+                Some(_) => {
+                    return Err(JsonError::NotOfType {
+                        target: "origin_server_ts".to_owned(),
+                        of_type: JsonType::Integer,
+                    }
+                    .into())
+                }
+                None => {
+                    return Err(JsonError::JsonFieldMissingFromObject(
+                        "origin_server_ts".to_owned(),
+                    )
+                    .into())
+                }
+            };
+
+            Some(MilliSecondsSinceUnixEpoch(origin_server_ts))
+        } else {
+            None
+        };
+
+        self.public_key_map(event, valid_at).await
+    }
+
+    /// Fetch the keys in `required_keys` that are missing from the cache.
+    async fn fetch_missing_keys(
+        &self,
+        required_keys: &BTreeMap<OwnedServerName, BTreeSet<String>>,
+        valid_at: Option<MilliSecondsSinceUnixEpoch>,
+    ) {
+        let missing_keys = self.missing_keys(required_keys, valid_at);
+        if missing_keys.is_empty() {
+            return;
+        }
+
+        // Query the servers directly first.
+        let responses =
+            join_all(missing_keys.keys().map(|server| async move {
+                (server, self.transport.get_server_keys(server).await)
+            }))
+            .await;
+
+        for (server, response) in responses {
+            match response {
+                Ok(response) => {
+                    if let Err(error) = self.add_fetched_server_keys(
+                        server,
+                        None,
+                        &response.server_key,
+                        Some(&missing_keys),
+                    ) {
+                        warn!(%server, %error, "Received invalid server keys");
+                    }
+                }
+                Err(error) => {
+                    warn!(%server, %error, "Failed to fetch the keys of the server");
+                }
+            }
+        }
+
+        // Then ask the notary servers for the keys that are still missing.
+        for notary in &self.notary_servers {
+            let missing_keys = self.missing_keys(required_keys, valid_at);
+            if missing_keys.is_empty() {
+                return;
+            }
+
+            let notary_keys = self.notary_keys(notary).await;
+            if notary_keys.is_empty() {
+                warn!(%notary, "No trusted keys for the notary server");
+                continue;
+            }
+
+            let mut criteria = get_remote_server_keys_batch::QueryCriteria::new();
+            criteria.minimum_valid_until_ts = valid_at;
+
+            let server_keys = missing_keys
+                .iter()
+                .map(|(server, key_ids)| {
+                    let key_ids = key_ids
+                        .iter()
+                        .filter_map(|key_id| {
+                            OwnedServerSigningKeyId::try_from(key_id.as_str()).ok()
+                        })
+                        .map(|key_id| (key_id, criteria.clone()))
+                        .collect();
+                    (server.clone(), key_ids)
+                })
+                .collect();
+
+            match self
+                .transport
+                .get_remote_server_keys_batch(
+                    notary,
+                    get_remote_server_keys_batch::Request::new(server_keys),
+                )
+                .await
+            {
+                Ok(response) => {
+                    for server_keys in &response.server_keys {
+                        if let Err(error) = self.add_fetched_server_keys(
+                            notary,
+                            Some(&notary_keys),
+                            server_keys,
+                            Some(&missing_keys),
+                        ) {
+                            warn!(%notary, %error, "Received invalid server keys");
+                        }
+                    }
+                }
+                Err(error) => {
+                    warn!(%notary, %error, "Failed to fetch keys from the notary server");
+                }
+            }
+        }
+    }
+
+    /// Get the trusted keys of the given notary server that are valid now.
+    ///
+    /// If there are none in the cache, they are fetched from the notary server.
+    async fn notary_keys(&self, notary: &ServerName) -> PublicKeySet {
+        let now = Some(MilliSecondsSinceUnixEpoch::now());
+
+        let notary_keys = self.cached_keys(notary, now);
+        if !notary_keys.is_empty() {
+            return notary_keys;
+        }
+
+        match self.transport.get_server_keys(notary).await {
+            Ok(response) => {
+                if let Err(error) =
+                    self.add_fetched_server_keys(notary, None, &response.server_key, None)
+                {
+                    warn!(%notary, %error, "Received invalid keys of the notary server");
+                }
+            }
+            Err(error) => {
+                warn!(%notary, %error, "Failed to fetch the keys of the notary server");
+            }
+        }
+
+        self.cached_keys(notary, now)
+    }
+}
+
+/// Get the keys needed to verify the signatures of the given object.
+///
+/// Returns a map of server name to key IDs, from the `signatures` field of the object. Servers
+/// with an invalid name are ignored.
+///
+/// Returns an error if the `signatures` field is missing or invalid.
+pub fn required_keys(
+    object: &CanonicalJsonObject,
+) -> Result<BTreeMap<OwnedServerName, BTreeSet<String>>, Error> {
+    let signatures = match object.get("signatures") {
+        Some(CanonicalJsonValue::Object(signatures)) => signatures,
+        Some(_) => {
+            return Err(JsonError::NotOfType {
+                target: "signatures".to_owned(),
+                of_type: JsonType::Object,
+            }
+            .into())
+        }
+        None => return Err(JsonError::JsonFieldMissingFromObject("signatures".to_owned()).into()),
+    };
+
+    let mut required_keys = BTreeMap::new();
+
+    for (server, signature_set) in signatures {
+        let CanonicalJsonValue::Object(signature_set) = signature_set else {
+            return Err(JsonError::NotMultiplesOfType {
+                target: "signature sets".to_owned(),
+                of_type: JsonType::Object,
+            }
+            .into());
+        };
+
+        let Ok(server) = OwnedServerName::try_from(server.as_str()) else {
+            continue;
+        };
+
+        required_keys
+            .entry(server)
+            .or_insert_with(BTreeSet::new)
+            .extend(signature_set.keys().cloned());
+    }
+
+    Ok(required_keys)
+}
+
+/// Deserialize the given server keys and verify that they are signed by their server with one of
+/// their `verify_keys`.
+///
+/// Returns the keys and their JSON object.
+fn verify_server_keys(
+    server_keys: &Raw<ServerSigningKeys>,
+) -> Result<(ServerSigningKeys, CanonicalJsonObject), Error> {
+    let server_keys_object: CanonicalJsonObject =
+        serde_json::from_str(server_keys.json().get()).map_err(JsonError::from)?;
+    let server_keys = server_keys.deserialize().map_err(JsonError::from)?;
+
+    let verify_keys: PublicKeySet = server_keys
+        .verify_keys
+        .iter()
+        .map(|(key_id, verify_key)| (key_id.to_string(), verify_key.key.clone()))
+        .collect();
+    verify_signature(&server_keys.server_name, verify_keys, server_keys_object.clone())?;
+
+    Ok((server_keys, server_keys_object))
+}
+
+/// Verify that the given object is signed by the given server with one of the given keys.
+///
+/// The signatures of other servers are ignored.
+fn verify_signature(
+    server_name: &ServerName,
+    public_keys: PublicKeySet,
+    mut object: CanonicalJsonObject,
+) -> Result<(), Error> {
+    if let Some(CanonicalJsonValue::Object(signatures)) = object.get_mut("signatures") {
+        signatures.retain(|server, _| server == server_name.as_str());
+
+        if signatures.is_empty() {
+            return Err(VerificationError::SignatureNotFound(server_name.to_owned()).into());
+        }
+    }
+
+    let public_key_map = BTreeMap::from([(server_name.to_string(), public_keys)]);
+    verify_json(&public_key_map, &object)
+}
+
+/// An error encountered when adding keys fetched from a server or a notary server.
+#[derive(Debug, thiserror::Error)]
+enum FetchedKeysError {
+    /// The signatures of the keys could not be verified.
+    #[error(transparent)]
+    Signatures(#[from] Error),
+
+    /// The keys are not the ones of a server that was requested.
+    #[error("received the keys of {0}, which were not requested")]
+    UnexpectedServer(OwnedServerName),
+}
+
+/// A cached key.
+#[derive(Debug)]
+struct CachedKey {
+    /// The public key.
+    key: ruma_common::serde::Base64,
+
+    /// The time until which the key is valid.
+    valid_until_ts: MilliSecondsSinceUnixEpoch,
+}
+
+impl CachedKey {
+    /// Whether this key is valid at the given time.
+    fn is_valid_at(&self, ts: Option<MilliSecondsSinceUnixEpoch>) -> bool {
+        ts.map_or(true, |ts| self.valid_until_ts >= ts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        future::{ready, Future},
+        sync::Mutex,
+    };
+
+    use js_int::uint;
+    use ruma_common::{
+        serde::{base64::Standard, Base64, Raw},
+        server_name, CanonicalJsonObject, MilliSecondsSinceUnixEpoch, OwnedServerName,
+        RoomVersionId, ServerName,
+    };
+    use ruma_federation_api::discovery::{
+        get_remote_server_keys_batch::v2 as get_remote_server_keys_batch,
+        get_server_keys::v2 as get_server_keys, ServerSigningKeys,
+    };
+    use ruma_signatures::{sign_json, verify_event, Ed25519KeyPair};
+    use serde_json::{json, value::to_raw_value as to_raw_json_value};
+
+    use super::{KeyStore, KeyTransport};
+
+    /// A transport that returns the keys it contains and records the requests it receives.
+    #[derive(Default)]
+    struct MemoryTransport {
+        /// The keys returned by the servers.
+        server_keys: BTreeMap<OwnedServerName, Raw<ServerSigningKeys>>,
+
+        /// The keys returned by the notary servers.
+        notary_keys: BTreeMap<OwnedServerName, Vec<Raw<ServerSigningKeys>>>,
+
+        /// The servers that received a request, and the servers queried from notary servers.
+        requests: Mutex<Vec<(OwnedServerName, Vec<OwnedServerName>)>>,
+    }
+
+    impl KeyTransport for MemoryTransport {
+        type Error = String;
+
+        fn get_server_keys(
+            &self,
+            server: &ServerName,
+        ) -> impl Future<Output = Result<get_server_keys::Response, Self::Error>> + Send {
+            self.requests.lock().unwrap().push((server.to_owned(), Vec::new()));
+
+            ready(
+                self.server_keys
+                    .get(server)
+                    .cloned()
+                    .map(get_server_keys::Response::new)
+                    .ok_or_else(|| format!("{server} is unreachable")),
+            )
+        }
+
+        fn get_remote_server_keys_batch(
+            &self,
+            notary: &ServerName,
+            request: get_remote_server_keys_batch::Request,
+        ) -> impl Future<Output = Result<get_remote_server_keys_batch::Response, Self::Error>> + Send
+        {
+            self.requests
+                .lock()
+                .unwrap()
+                .push((notary.to_owned(), request.server_keys.into_keys().collect()));
+
+            ready(
+                self.notary_keys
+                    .get(notary)
+                    .cloned()
+                    .map(get_remote_server_keys_batch::Response::new)
+                    .ok_or_else(|| format!("{notary} is unreachable")),
+            )
+        }
+    }
+
+    fn generate_key_pair(version: &str) -> Ed25519KeyPair {
+        let document = Ed25519KeyPair::generate().unwrap();
+        Ed25519KeyPair::from_der(&document, version.to_owned()).unwrap()
+    }
+
+    fn public_key(key_pair: &Ed25519KeyPair) -> String {
+        Base64::<Standard>::new(key_pair.public_key().to_vec()).encode()
+    }
+
+    /// The keys of the given server, signed by the key pair and the additional signers.
+    fn server_keys(
+        server: &ServerName,
+        key_pair: &Ed25519KeyPair,
+        valid_until_ts: u64,
+        old_key_pair: Option<(&Ed25519KeyPair, u64)>,
+        additional_signers: &[(&ServerName, &Ed25519KeyPair)],
+    ) -> Raw<ServerSigningKeys> {
+        let mut old_verify_keys = serde_json::Map::new();
+        if let Some((old_key_pair, expired_ts)) = old_key_pair {
+            old_verify_keys.insert(
+                format!("ed25519:{}", old_key_pair.version()),
+                json!({ "key": public_key(old_key_pair), "expired_ts": expired_ts }),
+            );
+        }
+
+        let mut object: CanonicalJsonObject = serde_json::from_value(json!({
+            "server_name": server,
+            "verify_keys": {
+                format!("ed25519:{}", key_pair.version()): { "key": public_key(key_pair) },
+            },
+            "old_verify_keys": old_verify_keys,
+            "valid_until_ts": valid_until_ts,
+        }))
+        .unwrap();
+        sign_json(server.as_str(), key_pair, &mut object).unwrap();
+
+        for (signer, signer_key_pair) in additional_signers {
+            sign_json(signer.as_str(), *signer_key_pair, &mut object).unwrap();
+        }
+
+        Raw::from_json(to_raw_json_value(&object).unwrap())
+    }
+
+    /// An event sent by `@alice:origin.local`, signed by the given key pair.
+    fn signed_event(key_pair: &Ed25519KeyPair, origin_server_ts: u64) -> CanonicalJsonObject {
+        let mut event: CanonicalJsonObject = serde_json::from_value(json!({
+            "auth_events": [],
+            "content": {},
+            "depth": 3,
+            "hashes": {
+                "sha256": "5jM4wQpv6lnBo7CLIghJuHdW+s2CMBJPUOGOC89ncos"
+            },
+            "origin_server_ts": origin_server_ts,
+            "prev_events": [],
+            "room_id": "!x:origin.local",
+            "sender": "@alice:origin.local",
+            "type": "X",
+        }))
+        .unwrap();
+        sign_json("origin.local", key_pair, &mut event).unwrap();
+        event
+    }
+
+    fn now_plus_one_day() -> u64 {
+        u64::from(MilliSecondsSinceUnixEpoch::now().get()) + 86_400_000
+    }
+
+    #[tokio::test]
+    async fn fetch_keys_from_server_and_cache_them() {
+        let origin = server_name!("origin.local");
+        let key_pair = generate_key_pair("1");
+
+        let transport = MemoryTransport {
+            server_keys: BTreeMap::from([(
+                origin.to_owned(),
+                server_keys(origin, &key_pair, now_plus_one_day(), None, &[]),
+            )]),
+            ..Default::default()
+        };
+        let store = KeyStore::new(transport);
+
+        let event = signed_event(&key_pair, 1_000);
+        let public_key_map =
+            store.public_key_map_for_event(&event, &RoomVersionId::V10).await.unwrap();
+
+        assert_eq!(public_key_map.len(), 1);
+        assert_eq!(public_key_map["origin.local"]["ed25519:1"].encode(), public_key(&key_pair));
+        verify_event(&public_key_map, &event, &RoomVersionId::V10).unwrap();
+
+        // The keys are cached.
+        store.public_key_map_for_event(&event, &RoomVersionId::V10).await.unwrap();
+        assert_eq!(store.transport.requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn fetch_keys_from_notary_server() {
+        let origin = server_name!("origin.local");
+        let notary = server_name!("notary.local");
+        let unrelated = server_name!("unrelated.local");
+        let old_key_pair = generate_key_pair("old");
+        let key_pair = generate_key_pair("1");
+        let notary_key_pair = generate_key_pair("notary");
+        let unrelated_key_pair = generate_key_pair("1");
+
+        let transport = MemoryTransport {
+            server_keys: BTreeMap::from([(
+                notary.to_owned(),
+                server_keys(notary, &notary_key_pair, now_plus_one_day(), None, &[]),
+            )]),
+            notary_keys: BTreeMap::from([(
+                notary.to_owned(),
+                vec![
+                    server_keys(
+                        origin,
+                        &key_pair,
+                        now_plus_one_day(),
+                        Some((&old_key_pair, 2_000)),
+                        &[(notary, &notary_key_pair)],
+                    ),
+                    server_keys(
+                        unrelated,
+                        &unrelated_key_pair,
+                        now_plus_one_day(),
+                        None,
+                        &[(notary, &notary_key_pair)],
+                    ),
+                ],
+            )]),
+            ..Default::default()
+        };
+        let store = KeyStore::new(transport)
+            .notary_servers(vec![server_name!("down.local").to_owned(), notary.to_owned()]);
+
+        let event = signed_event(&key_pair, 1_000);
+        let public_key_map = store.public_key_map(&event, None).await.unwrap();
+        verify_event(&public_key_map, &event, &RoomVersionId::V10).unwrap();
+
+        // The keys of the notary server are fetched before querying it.
+        assert_eq!(
+            *store.transport.requests.lock().unwrap(),
+            [
+                (origin.to_owned(), vec![]),
+                (server_name!("down.local").to_owned(), vec![]),
+                (notary.to_owned(), vec![]),
+                (notary.to_owned(), vec![origin.to_owned()]),
+            ]
+        );
+
+        // Only the requested keys are stored.
+        assert_eq!(store.cached_keys(origin, None).into_keys().collect::<Vec<_>>(), ["ed25519:1"]);
+        assert!(store.cached_keys(unrelated, None).is_empty());
+    }
+
+    #[tokio::test]
+    async fn reject_keys_of_other_server() {
+        let origin = server_name!("origin.local");
+        let other = server_name!("other.local");
+        let key_pair = generate_key_pair("1");
+        let other_key_pair = generate_key_pair("1");
+        let forged_key_pair = generate_key_pair("1");
+
+        // The server answers with keys for another server, signed by themselves.
+        let transport = MemoryTransport {
+            server_keys: BTreeMap::from([(
+                origin.to_owned(),
+                server_keys(other, &forged_key_pair, now_plus_one_day() + 1_000, None, &[]),
+            )]),
+            ..Default::default()
+        };
+        let store = KeyStore::new(transport);
+        store
+            .add_server_keys(&server_keys(other, &other_key_pair, now_plus_one_day(), None, &[]))
+            .unwrap();
+
+        let event = signed_event(&key_pair, 1_000);
+        let public_key_map = store.public_key_map(&event, None).await.unwrap();
+        assert!(public_key_map["origin.local"].is_empty());
+
+        // The cached keys of the other server are untouched.
+        assert!(store.cached_keys(origin, None).is_empty());
+        assert_eq!(
+            store.cached_keys(other, None)["ed25519:1"].encode(),
+            public_key(&other_key_pair)
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_forged_notary_response() {
+        let origin = server_name!("origin.local");
+        let notary = server_name!("notary.local");
+        let key_pair = generate_key_pair("1");
+        let notary_key_pair = generate_key_pair("notary");
+        let forged_notary_key_pair = generate_key_pair("notary");
+
+        // The keys of the server are signed with a key that is not the one of the notary server.
+        let transport = MemoryTransport {
+            notary_keys: BTreeMap::from([(
+                notary.to_owned(),
+                vec![server_keys(
+                    origin,
+                    &key_pair,
+                    now_plus_one_day(),
+                    None,
+                    &[(notary, &forged_notary_key_pair)],
+                )],
+            )]),
+            ..Default::default()
+        };
+        let store = KeyStore::new(transport).notary_servers(vec![notary.to_owned()]);
+        store
+            .add_server_keys(&server_keys(notary, &notary_key_pair, now_plus_one_day(), None, &[]))
+            .unwrap();
+
+        let event = signed_event(&key_pair, 1_000);
+        let public_key_map = store.public_key_map(&event, None).await.unwrap();
+        assert!(public_key_map["origin.local"].is_empty());
+        verify_event(&public_key_map, &event, &RoomVersionId::V10).unwrap_err();
+        assert!(store.cached_keys(origin, None).is_empty());
+
+        // The trusted keys of the notary server were used.
+        assert_eq!(
+            *store.transport.requests.lock().unwrap(),
+            [(origin.to_owned(), vec![]), (notary.to_owned(), vec![origin.to_owned()])]
+        );
+    }
+
+    #[tokio::test]
+    async fn key_validity() {
+        let origin = server_name!("origin.local");
+        let old_key_pair = generate_key_pair("old");
+        let key_pair = generate_key_pair("1");
+
+        let transport = MemoryTransport {
+            server_keys: BTreeMap::from([(
+                origin.to_owned(),
+                server_keys(origin, &key_pair, 5_000, Some((&old_key_pair, 2_000)), &[]),
+            )]),
+            ..Default::default()
+        };
+        let store = KeyStore::new(transport);
+
+        // The old key was valid when the event was sent.
+        let event = signed_event(&old_key_pair, 1_000);
+        let public_key_map =
+            store.public_key_map_for_event(&event, &RoomVersionId::V10).await.unwrap();
+        verify_event(&public_key_map, &event, &RoomVersionId::V10).unwrap();
+
+        // The old key had expired when the event was sent.
+        let event = signed_event(&old_key_pair, 3_000);
+        let public_key_map =
+            store.public_key_map_for_event(&event, &RoomVersionId::V10).await.unwrap();
+        assert!(public_key_map["origin.local"].is_empty());
+        verify_event(&public_key_map, &event, &RoomVersionId::V10).unwrap_err();
+
+        // The validity of keys is ignored in room version 4.
+        let public_key_map =
+            store.public_key_map_for_event(&event, &RoomVersionId::V4).await.unwrap();
+        assert!(public_key_map["origin.local"].contains_key("ed25519:old"));
+
+        // Keys that are not valid anymore are fetched again.
+        assert_eq!(store.transport.requests.lock().unwrap().len(), 2);
+        // Only the requested key is cached.
+        assert_eq!(
+            store.cached_keys(origin, None).into_keys().collect::<Vec<_>>(),
+            ["ed25519:old"]
+        );
+        assert!(store
+            .cached_keys(origin, Some(MilliSecondsSinceUnixEpoch(uint!(4_000))))
+            .is_empty());
+    }
+
+    #[test]
+    fn reject_keys_not_signed_by_server() {
+        let origin = server_name!("origin.local");
+        let other = server_name!("other.local");
+        let key_pair = generate_key_pair("1");
+        let other_key_pair = generate_key_pair("notary");
+
+        let store = KeyStore::new(MemoryTransport::default());
+
+        // Signed by another server.
+        let mut object: CanonicalJsonObject = serde_json::from_str(
+            server_keys(origin, &key_pair, 5_000, None, &[(other, &other_key_pair)]).json().get(),
+        )
+        .unwrap();
+        object.get_mut("signatures").unwrap().as_object_mut().unwrap().remove("origin.local");
+        let keys = Raw::from_json(to_raw_json_value(&object).unwrap());
+        store.add_server_keys(&keys).unwrap_err();
+
+        // Signed with a key that is not in `verify_keys`.
+        let keys = server_keys(origin, &key_pair, 5_000, None, &[(origin, &other_key_pair)]);
+        store.add_server_keys(&keys).unwrap_err();
+
+        assert!(store.cached_keys(origin, None).is_empty());
+    }
+}
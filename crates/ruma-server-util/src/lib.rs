@@ -3,4 +3,13 @@
 //! Collection of helpers for implementing Matrix homeservers using Ruma.
 
 #![warn(missing_docs)]
+pub mod acl;
 pub mod authorization;
+#[cfg(feature = "unstable-pdu")]
+pub mod inbound;
+pub mod keys;
+#[cfg(feature = "unstable-pdu")]
+pub mod pdu;
+pub mod pusher;
+pub mod resolver;
+pub mod sender;
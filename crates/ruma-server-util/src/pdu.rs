@@ -0,0 +1,425 @@
+//! Creation of persistent data units (PDUs).
+
+use std::collections::BTreeMap;
+
+use js_int::UInt;
+use ruma_common::{
+    room_version_rules::EventIdFormatVersion, CanonicalJsonObject, CanonicalJsonValue, EventId,
+    MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, OwnedUserId, RoomVersionId, ServerName,
+};
+use ruma_events::{
+    pdu::{EventHash, Pdu},
+    AnyMessageLikeEventContent, AnyStateEventContent, EventContent, TimelineEventType,
+};
+use ruma_signatures::{hash_and_sign_event, reference_hash, KeyPair};
+use serde_json::{
+    json,
+    value::{to_raw_value as to_raw_json_value, RawValue as RawJsonValue},
+    Value as JsonValue,
+};
+use thiserror::Error;
+
+/// A builder for a PDU, an event in a room that is sent over federation.
+///
+/// The builder takes care of the differences between the formats of the room versions, computes
+/// the ID of the event, and hashes and signs it.
+#[derive(Debug)]
+pub struct PduBuilder {
+    room_id: OwnedRoomId,
+    sender: OwnedUserId,
+    event_type: TimelineEventType,
+    content: Box<RawJsonValue>,
+    state_key: Option<String>,
+    redacts: Option<OwnedEventId>,
+    prev_events: Vec<OwnedEventId>,
+    auth_events: Vec<OwnedEventId>,
+    event_hashes: BTreeMap<OwnedEventId, EventHash>,
+    depth: UInt,
+    origin_server_ts: MilliSecondsSinceUnixEpoch,
+    unsigned: BTreeMap<String, Box<RawJsonValue>>,
+}
+
+impl PduBuilder {
+    /// Creates a new `PduBuilder` for a state event with the given content, state key and depth.
+    ///
+    /// The depth of the event is the maximum depth of its `prev_events`, plus one. The depth of
+    /// the `m.room.create` event is `1`.
+    ///
+    /// Returns an error if the content fails to serialize.
+    pub fn state(
+        room_id: OwnedRoomId,
+        sender: OwnedUserId,
+        content: &AnyStateEventContent,
+        state_key: impl Into<String>,
+        depth: UInt,
+    ) -> serde_json::Result<Self> {
+        let mut builder = Self::new(
+            room_id,
+            sender,
+            content.event_type().into(),
+            to_raw_json_value(content)?,
+            depth,
+        );
+        builder.state_key = Some(state_key.into());
+
+        Ok(builder)
+    }
+
+    /// Creates a new `PduBuilder` for a message-like event with the given content and depth.
+    ///
+    /// The depth of the event is the maximum depth of its `prev_events`, plus one.
+    ///
+    /// For a redaction, the `redacts` field of the content is also used for the top-level
+    /// `redacts` field of the PDU.
+    ///
+    /// Returns an error if the content fails to serialize.
+    pub fn message_like(
+        room_id: OwnedRoomId,
+        sender: OwnedUserId,
+        content: &AnyMessageLikeEventContent,
+        depth: UInt,
+    ) -> serde_json::Result<Self> {
+        let mut builder = Self::new(
+            room_id,
+            sender,
+            content.event_type().into(),
+            to_raw_json_value(content)?,
+            depth,
+        );
+
+        if let AnyMessageLikeEventContent::RoomRedaction(content) = content {
+            builder.redacts = content.redacts.clone();
+        }
+
+        Ok(builder)
+    }
+
+    fn new(
+        room_id: OwnedRoomId,
+        sender: OwnedUserId,
+        event_type: TimelineEventType,
+        content: Box<RawJsonValue>,
+        depth: UInt,
+    ) -> Self {
+        Self {
+            room_id,
+            sender,
+            event_type,
+            content,
+            state_key: None,
+            redacts: None,
+            prev_events: Vec::new(),
+            auth_events: Vec::new(),
+            event_hashes: BTreeMap::new(),
+            depth,
+            origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
+            unsigned: BTreeMap::new(),
+        }
+    }
+
+    /// Set the IDs of the most recent events in the room that the server is aware of.
+    pub fn prev_events(self, prev_events: Vec<OwnedEventId>) -> Self {
+        Self { prev_events, ..self }
+    }
+
+    /// Set the IDs of the authorization events that allow this event to be in the room.
+    pub fn auth_events(self, auth_events: Vec<OwnedEventId>) -> Self {
+        Self { auth_events, ..self }
+    }
+
+    /// Set the content hashes of the `prev_events` and `auth_events`.
+    ///
+    /// PDUs of room versions 1 and 2 reference other events along with their hashes, so these
+    /// hashes are required for those room versions. They are ignored for other room versions.
+    pub fn event_hashes(self, event_hashes: BTreeMap<OwnedEventId, EventHash>) -> Self {
+        Self { event_hashes, ..self }
+    }
+
+    /// Set the timestamp of the creation of the event.
+    ///
+    /// Defaults to the time when the builder was created.
+    pub fn origin_server_ts(self, origin_server_ts: MilliSecondsSinceUnixEpoch) -> Self {
+        Self { origin_server_ts, ..self }
+    }
+
+    /// Set the ID of the event being redacted, for redaction events.
+    ///
+    /// This is only necessary for room versions before 11, where the `redacts` field is not in the
+    /// content.
+    pub fn redacts(self, redacts: OwnedEventId) -> Self {
+        Self { redacts: Some(redacts), ..self }
+    }
+
+    /// Set the additional data that is not covered by the hashes and signatures.
+    pub fn unsigned(self, unsigned: BTreeMap<String, Box<RawJsonValue>>) -> Self {
+        Self { unsigned, ..self }
+    }
+
+    /// Build the PDU for the given room version and sign it with the key pair of the origin
+    /// server.
+    ///
+    /// Returns the ID of the event and the PDU, which is a [`Pdu::RoomV1Pdu`] for room versions 1
+    /// and 2 and a [`Pdu::RoomV3Pdu`] for later room versions.
+    ///
+    /// Returns an error if the room version is not supported, if the hash of one of the referenced
+    /// events is missing for room versions 1 and 2, or if the event can't be hashed or signed.
+    pub fn build<K>(
+        self,
+        origin: &ServerName,
+        key_pair: &K,
+        room_version: &RoomVersionId,
+    ) -> Result<(OwnedEventId, Pdu), PduBuildError>
+    where
+        K: KeyPair + ?Sized,
+    {
+        let rules = room_version
+            .rules()
+            .ok_or_else(|| PduBuildError::UnsupportedRoomVersion(room_version.clone()))?;
+        let has_event_id = rules.event_id_format == EventIdFormatVersion::V1;
+
+        let Self {
+            room_id,
+            sender,
+            event_type,
+            content,
+            state_key,
+            redacts,
+            prev_events,
+            auth_events,
+            event_hashes,
+            depth,
+            origin_server_ts,
+            unsigned,
+        } = self;
+
+        let (prev_events, auth_events) = if has_event_id {
+            let with_hashes = |event_ids: Vec<OwnedEventId>| {
+                event_ids
+                    .into_iter()
+                    .map(|event_id| match event_hashes.get(&event_id) {
+                        Some(hash) => Ok(serde_json::to_value((&event_id, hash))?),
+                        None => Err(PduBuildError::MissingEventHash(event_id)),
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+                    .map(JsonValue::Array)
+            };
+
+            (with_hashes(prev_events)?, with_hashes(auth_events)?)
+        } else {
+            (serde_json::to_value(prev_events)?, serde_json::to_value(auth_events)?)
+        };
+
+        let mut object: CanonicalJsonObject = serde_json::from_value(json!({
+            "room_id": room_id,
+            "sender": sender,
+            "origin_server_ts": origin_server_ts,
+            "type": event_type,
+            "content": content,
+            "prev_events": prev_events,
+            "depth": depth,
+            "auth_events": auth_events,
+        }))?;
+
+        if let Some(state_key) = state_key {
+            object.insert("state_key".to_owned(), CanonicalJsonValue::String(state_key));
+        }
+        if let Some(redacts) = redacts {
+            object.insert("redacts".to_owned(), CanonicalJsonValue::String(redacts.into()));
+        }
+
+        let event_id = if has_event_id {
+            let event_id = EventId::new(origin);
+            object.insert("event_id".to_owned(), CanonicalJsonValue::String(event_id.to_string()));
+            Some(event_id)
+        } else {
+            None
+        };
+
+        hash_and_sign_event(origin.as_str(), key_pair, &mut object, room_version)?;
+
+        let event_id = match event_id {
+            Some(event_id) => event_id,
+            None => EventId::parse(format!("${}", reference_hash(&object, room_version)?))
+                .expect("reference hash is a valid event ID"),
+        };
+
+        if !unsigned.is_empty() {
+            object.insert(
+                "unsigned".to_owned(),
+                serde_json::from_value(serde_json::to_value(unsigned)?)?,
+            );
+        }
+
+        let pdu = serde_json::from_value(serde_json::to_value(object)?)?;
+
+        Ok((event_id, pdu))
+    }
+}
+
+/// An error when trying to build a PDU.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum PduBuildError {
+    /// The room version is not supported.
+    #[error("unsupported room version {0}")]
+    UnsupportedRoomVersion(RoomVersionId),
+
+    /// The hash of a referenced event is missing.
+    #[error("missing hash of event {0}")]
+    MissingEventHash(OwnedEventId),
+
+    /// The PDU could not be serialized or deserialized.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    /// The PDU could not be hashed or signed.
+    #[error(transparent)]
+    Signatures(#[from] ruma_signatures::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use assert_matches2::assert_matches;
+    use js_int::uint;
+    use ruma_common::{
+        event_id, owned_event_id, owned_room_id, owned_user_id, serde::Base64, server_name,
+        CanonicalJsonObject, MilliSecondsSinceUnixEpoch, RoomVersionId,
+    };
+    use ruma_events::{
+        pdu::{EventHash, Pdu},
+        room::{
+            message::RoomMessageEventContent, redaction::RoomRedactionEventContent,
+            topic::RoomTopicEventContent,
+        },
+        AnyMessageLikeEventContent, AnyStateEventContent, TimelineEventType,
+    };
+    use ruma_signatures::{reference_hash, verify_event, Ed25519KeyPair, PublicKeyMap, Verified};
+
+    use super::{PduBuildError, PduBuilder};
+
+    fn key_pair_and_public_key_map() -> (Ed25519KeyPair, PublicKeyMap) {
+        let document = Ed25519KeyPair::generate().unwrap();
+        let key_pair = Ed25519KeyPair::from_der(&document, "1".to_owned()).unwrap();
+        let public_key = Base64::new(key_pair.public_key().to_vec());
+        let public_key_map = BTreeMap::from([(
+            "origin.local".to_owned(),
+            BTreeMap::from([("ed25519:1".to_owned(), public_key)]),
+        )]);
+
+        (key_pair, public_key_map)
+    }
+
+    fn to_canonical_json(pdu: &Pdu) -> CanonicalJsonObject {
+        serde_json::from_str(&serde_json::to_string(pdu).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn build_message_like_pdu() {
+        let (key_pair, public_key_map) = key_pair_and_public_key_map();
+        let content =
+            AnyMessageLikeEventContent::RoomMessage(RoomMessageEventContent::text_plain("Hello"));
+
+        let (event_id, pdu) = PduBuilder::message_like(
+            owned_room_id!("!room:origin.local"),
+            owned_user_id!("@alice:origin.local"),
+            &content,
+            uint!(3),
+        )
+        .unwrap()
+        .prev_events(vec![owned_event_id!("$prev")])
+        .auth_events(vec![owned_event_id!("$create"), owned_event_id!("$member")])
+        .origin_server_ts(MilliSecondsSinceUnixEpoch(uint!(1_000)))
+        .build(server_name!("origin.local"), &key_pair, &RoomVersionId::V10)
+        .unwrap();
+
+        assert_matches!(&pdu, Pdu::RoomV3Pdu(v3_pdu));
+        assert_eq!(v3_pdu.kind, TimelineEventType::RoomMessage);
+        assert_eq!(v3_pdu.prev_events, [owned_event_id!("$prev")]);
+        assert_eq!(v3_pdu.auth_events.len(), 2);
+        assert_eq!(v3_pdu.depth, uint!(3));
+        assert_eq!(v3_pdu.state_key, None);
+
+        let object = to_canonical_json(&pdu);
+        assert_eq!(
+            event_id.as_str(),
+            format!("${}", reference_hash(&object, &RoomVersionId::V10).unwrap())
+        );
+        assert_eq!(
+            verify_event(&public_key_map, &object, &RoomVersionId::V10).unwrap(),
+            Verified::All
+        );
+    }
+
+    #[test]
+    fn build_redaction_pdu() {
+        let (key_pair, public_key_map) = key_pair_and_public_key_map();
+        let content = AnyMessageLikeEventContent::RoomRedaction(
+            RoomRedactionEventContent::new_v11(owned_event_id!("$redacted")),
+        );
+
+        let (_, pdu) = PduBuilder::message_like(
+            owned_room_id!("!room:origin.local"),
+            owned_user_id!("@alice:origin.local"),
+            &content,
+            uint!(4),
+        )
+        .unwrap()
+        .build(server_name!("origin.local"), &key_pair, &RoomVersionId::V11)
+        .unwrap();
+
+        assert_matches!(&pdu, Pdu::RoomV3Pdu(v3_pdu));
+        assert_eq!(v3_pdu.redacts.as_deref(), Some(event_id!("$redacted")));
+        assert_eq!(
+            verify_event(&public_key_map, &to_canonical_json(&pdu), &RoomVersionId::V11).unwrap(),
+            Verified::All
+        );
+    }
+
+    #[test]
+    fn build_v1_state_pdu() {
+        let (key_pair, public_key_map) = key_pair_and_public_key_map();
+        let content =
+            AnyStateEventContent::RoomTopic(RoomTopicEventContent::new("Matrix".to_owned()));
+        let builder = || {
+            PduBuilder::state(
+                owned_room_id!("!room:origin.local"),
+                owned_user_id!("@alice:origin.local"),
+                &content,
+                "",
+                uint!(2),
+            )
+            .unwrap()
+            .prev_events(vec![owned_event_id!("$prev:origin.local")])
+        };
+
+        // The hashes of the referenced events are needed.
+        assert_matches!(
+            builder().build(server_name!("origin.local"), &key_pair, &RoomVersionId::V1),
+            Err(PduBuildError::MissingEventHash(event_id))
+        );
+        assert_eq!(event_id, "$prev:origin.local");
+
+        let (event_id, pdu) = builder()
+            .event_hashes(BTreeMap::from([(
+                owned_event_id!("$prev:origin.local"),
+                EventHash::new("hash".to_owned()),
+            )]))
+            .build(server_name!("origin.local"), &key_pair, &RoomVersionId::V1)
+            .unwrap();
+
+        assert_matches!(&pdu, Pdu::RoomV1Pdu(v1_pdu));
+        assert_eq!(v1_pdu.event_id, event_id);
+        assert_eq!(event_id.server_name(), Some(server_name!("origin.local")));
+        assert_eq!(v1_pdu.kind, TimelineEventType::RoomTopic);
+        assert_eq!(v1_pdu.state_key.as_deref(), Some(""));
+        assert_eq!(v1_pdu.prev_events[0].0, "$prev:origin.local");
+        assert_eq!(v1_pdu.prev_events[0].1.sha256, "hash");
+
+        assert_eq!(
+            verify_event(&public_key_map, &to_canonical_json(&pdu), &RoomVersionId::V1).unwrap(),
+            Verified::All
+        );
+    }
+}
@@ -0,0 +1,370 @@
+//! Building the notifications that HTTP pushers send to [push gateways].
+//!
+//! [push gateways]: https://spec.matrix.org/latest/push-gateway-api/
+
+use ruma_common::{
+    push::{Action, HttpPusherData, PushFormat, Tweak},
+    serde::Raw,
+    OwnedEventId, OwnedRoomAliasId, OwnedRoomId, OwnedUserId, SecondsSinceUnixEpoch, UserId,
+};
+use ruma_events::TimelineEventType;
+use ruma_push_gateway_api::send_event_notification::v1::{
+    Device, Notification, NotificationCounts, NotificationPriority, Response,
+};
+use serde::Deserialize;
+use serde_json::value::RawValue as RawJsonValue;
+
+/// An HTTP pusher of a user.
+///
+/// The notifications built by this pusher must be sent to the push gateway at the `url` of its
+/// [`HttpPusherData`], with the [`send_event_notification`] endpoint.
+///
+/// [`send_event_notification`]: ruma_push_gateway_api::send_event_notification
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct HttpPusher {
+    /// The `app_id` given when the pusher was created.
+    pub app_id: String,
+
+    /// The `pushkey` given when the pusher was created.
+    pub pushkey: String,
+
+    /// The unix timestamp (in seconds) when the pushkey was last updated.
+    pub pushkey_ts: Option<SecondsSinceUnixEpoch>,
+
+    /// The data given when the pusher was created.
+    pub data: HttpPusherData,
+}
+
+impl HttpPusher {
+    /// Creates a new `HttpPusher` with the given app ID, pushkey and data.
+    pub fn new(app_id: String, pushkey: String, data: HttpPusherData) -> Self {
+        Self { app_id, pushkey, pushkey_ts: None, data }
+    }
+
+    /// Sets the unix timestamp when the pushkey was last updated.
+    pub fn pushkey_ts(self, pushkey_ts: SecondsSinceUnixEpoch) -> Self {
+        Self { pushkey_ts: Some(pushkey_ts), ..self }
+    }
+
+    /// Whether this pusher uses the `event_id_only` format.
+    pub fn is_event_id_only(&self) -> bool {
+        self.data.format == Some(PushFormat::EventIdOnly)
+    }
+
+    /// Builds the notification to send to the push gateway of this pusher for the given event.
+    ///
+    /// The event must be in the format of the Client-Server API, so it includes its `event_id`
+    /// and `room_id`. `user_id` is the owner of this pusher, and `actions` are the actions of the
+    /// push rule that matched the event for this user. This should only be called if these
+    /// actions [notify].
+    ///
+    /// The tweaks of the notification are the [`Action::SetTweak`]s of `actions`. The
+    /// notification has a high priority if it is highlighted, if it has a sound, or if the event
+    /// is encrypted, since its content cannot be known by the homeserver.
+    ///
+    /// If the pusher uses the `event_id_only` format, the notification only contains the event
+    /// ID, the room ID, the counts and the priority, and the device doesn't have tweaks.
+    ///
+    /// Returns an error if the event doesn't have the required fields.
+    ///
+    /// [notify]: Action::should_notify
+    pub fn event_notification<T>(
+        &self,
+        event: &Raw<T>,
+        user_id: &UserId,
+        actions: &[Action],
+        info: NotificationInfo,
+    ) -> Result<Notification, serde_json::Error> {
+        let event = event.deserialize_as::<EventFields<'_>>()?;
+
+        let tweaks: Vec<_> = actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::SetTweak(tweak) => Some(tweak.clone()),
+                _ => None,
+            })
+            .collect();
+        let is_highlight_or_sound =
+            tweaks.iter().any(|tweak| matches!(tweak, Tweak::Highlight(true) | Tweak::Sound(_)));
+        let prio = if is_highlight_or_sound || event.event_type == TimelineEventType::RoomEncrypted
+        {
+            NotificationPriority::High
+        } else {
+            NotificationPriority::Low
+        };
+
+        let mut device = Device::new(self.app_id.clone(), self.pushkey.clone());
+        device.pushkey_ts = self.pushkey_ts;
+        device.data = self.data.clone().into();
+
+        if !self.is_event_id_only() {
+            device.tweaks = tweaks;
+        }
+
+        let mut notification = Notification::new(vec![device]);
+        notification.event_id = Some(event.event_id);
+        notification.room_id = Some(event.room_id);
+        notification.prio = prio;
+        notification.counts = info.counts;
+
+        if !self.is_event_id_only() {
+            notification.user_is_target = event.event_type == TimelineEventType::RoomMember
+                && event.state_key.as_deref() == Some(user_id.as_str());
+            notification.event_type = Some(event.event_type);
+            notification.sender = Some(event.sender);
+            notification.sender_display_name = info.sender_display_name;
+            notification.room_name = info.room_name;
+            notification.room_alias = info.room_alias;
+            notification.content = event.content.map(ToOwned::to_owned);
+        }
+
+        Ok(notification)
+    }
+
+    /// Whether the pushkey of this pusher was rejected in the given response of the push gateway.
+    ///
+    /// If this returns `true`, the homeserver must stop sending notifications to this pusher and
+    /// remove it.
+    pub fn is_rejected(&self, response: &Response) -> bool {
+        response.rejected.contains(&self.pushkey)
+    }
+}
+
+/// Information about an event and its recipient, to include in a push notification.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct NotificationInfo {
+    /// The current display name of the sender in the room of the event.
+    pub sender_display_name: Option<String>,
+
+    /// The name of the room of the event.
+    pub room_name: Option<String>,
+
+    /// An alias to display for the room of the event.
+    pub room_alias: Option<OwnedRoomAliasId>,
+
+    /// The current number of unacknowledged communications of the recipient.
+    pub counts: NotificationCounts,
+}
+
+impl NotificationInfo {
+    /// Creates a new `NotificationInfo` with the given counts.
+    pub fn new(counts: NotificationCounts) -> Self {
+        Self { counts, ..Default::default() }
+    }
+
+    /// Sets the display name of the sender.
+    pub fn sender_display_name(self, sender_display_name: String) -> Self {
+        Self { sender_display_name: Some(sender_display_name), ..self }
+    }
+
+    /// Sets the name of the room.
+    pub fn room_name(self, room_name: String) -> Self {
+        Self { room_name: Some(room_name), ..self }
+    }
+
+    /// Sets the alias of the room.
+    pub fn room_alias(self, room_alias: OwnedRoomAliasId) -> Self {
+        Self { room_alias: Some(room_alias), ..self }
+    }
+}
+
+/// The fields of an event that are used in a push notification.
+#[derive(Deserialize)]
+struct EventFields<'a> {
+    event_id: OwnedEventId,
+    room_id: OwnedRoomId,
+    #[serde(rename = "type")]
+    event_type: TimelineEventType,
+    sender: OwnedUserId,
+    state_key: Option<String>,
+    #[serde(borrow)]
+    content: Option<&'a RawJsonValue>,
+}
+
+#[cfg(test)]
+mod tests {
+    use js_int::uint;
+    use ruma_common::{
+        owned_room_alias_id,
+        push::{Action, HttpPusherData, PushFormat, Tweak},
+        serde::Raw,
+        user_id,
+    };
+    use ruma_push_gateway_api::send_event_notification::v1::{NotificationCounts, Response};
+    use serde_json::{json, to_value as to_json_value, Value as JsonValue};
+
+    use super::{HttpPusher, NotificationInfo};
+
+    fn pusher(format: Option<PushFormat>) -> HttpPusher {
+        let mut data =
+            HttpPusherData::new("https://push.example.org/_matrix/push/v1/notify".into());
+        data.format = format;
+        HttpPusher::new("org.example.app".into(), "abcdef".into(), data)
+    }
+
+    fn message() -> Raw<JsonValue> {
+        Raw::new(&json!({
+            "content": {
+                "body": "Hello Bob!",
+                "msgtype": "m.text",
+            },
+            "event_id": "$message:example.org",
+            "origin_server_ts": 1_000_000,
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+            "type": "m.room.message",
+        }))
+        .unwrap()
+    }
+
+    fn actions() -> Vec<Action> {
+        vec![
+            Action::Notify,
+            Action::SetTweak(Tweak::Sound("default".into())),
+            Action::SetTweak(Tweak::Highlight(true)),
+        ]
+    }
+
+    #[test]
+    fn full_notification() {
+        let info = NotificationInfo::new(NotificationCounts::new(uint!(2), uint!(0)))
+            .sender_display_name("Alice".into())
+            .room_name("Wonderland".into())
+            .room_alias(owned_room_alias_id!("#wonderland:example.org"));
+
+        let notification = pusher(None)
+            .event_notification(&message(), user_id!("@bob:example.org"), &actions(), info)
+            .unwrap();
+
+        assert_eq!(
+            to_json_value(notification).unwrap(),
+            json!({
+                "event_id": "$message:example.org",
+                "room_id": "!room:example.org",
+                "type": "m.room.message",
+                "sender": "@alice:example.org",
+                "sender_display_name": "Alice",
+                "room_name": "Wonderland",
+                "room_alias": "#wonderland:example.org",
+                "content": {
+                    "body": "Hello Bob!",
+                    "msgtype": "m.text",
+                },
+                "counts": {
+                    "unread": 2,
+                },
+                "devices": [{
+                    "app_id": "org.example.app",
+                    "pushkey": "abcdef",
+                    "tweaks": {
+                        "sound": "default",
+                        "highlight": true,
+                    },
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn event_id_only_notification() {
+        let info = NotificationInfo::new(NotificationCounts::new(uint!(2), uint!(0)))
+            .sender_display_name("Alice".into());
+
+        let notification = pusher(Some(PushFormat::EventIdOnly))
+            .event_notification(&message(), user_id!("@bob:example.org"), &actions(), info)
+            .unwrap();
+
+        assert_eq!(
+            to_json_value(notification).unwrap(),
+            json!({
+                "event_id": "$message:example.org",
+                "room_id": "!room:example.org",
+                "counts": {
+                    "unread": 2,
+                },
+                "devices": [{
+                    "app_id": "org.example.app",
+                    "pushkey": "abcdef",
+                    "data": {
+                        "format": "event_id_only",
+                    },
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn priority_and_target() {
+        let pusher = pusher(None);
+        let user_id = user_id!("@bob:example.org");
+
+        // No highlight or sound.
+        let notification = pusher
+            .event_notification(&message(), user_id, &[Action::Notify], NotificationInfo::default())
+            .unwrap();
+        assert_eq!(to_json_value(&notification).unwrap()["prio"], "low");
+        assert!(!notification.user_is_target);
+
+        // Encrypted events always have a high priority.
+        let encrypted = Raw::new(&json!({
+            "content": {
+                "algorithm": "m.megolm.v1.aes-sha2",
+                "ciphertext": "AwgAEnACgAkLmt6qF84IK++J7UDH2Za1YVchHyprqTqsg",
+                "device_id": "RJYKSTBOIE",
+                "sender_key": "IlRMeOPX2e0MurIyfWEucYBRVOEEUMrOHqn/8mLqMjA",
+                "session_id": "X3lUlvLELLYxeTx4yOVu6UDpasGEVO0Jbu+QFnm0cKQ",
+            },
+            "event_id": "$encrypted:example.org",
+            "origin_server_ts": 1_000_000,
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+            "type": "m.room.encrypted",
+        }))
+        .unwrap();
+        let notification = pusher
+            .event_notification(&encrypted, user_id, &[Action::Notify], NotificationInfo::default())
+            .unwrap();
+        assert!(to_json_value(&notification).unwrap().get("prio").is_none());
+
+        // Invite of the user.
+        let invite = Raw::new(&json!({
+            "content": {
+                "membership": "invite",
+            },
+            "event_id": "$invite:example.org",
+            "origin_server_ts": 1_000_000,
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+            "state_key": "@bob:example.org",
+            "type": "m.room.member",
+        }))
+        .unwrap();
+        let notification = pusher
+            .event_notification(&invite, user_id, &actions(), NotificationInfo::default())
+            .unwrap();
+        assert!(notification.user_is_target);
+
+        // Missing room ID.
+        let event = Raw::new(&json!({
+            "content": {},
+            "event_id": "$event:example.org",
+            "sender": "@alice:example.org",
+            "type": "m.room.message",
+        }))
+        .unwrap();
+        pusher
+            .event_notification(&event, user_id, &actions(), NotificationInfo::default())
+            .unwrap_err();
+    }
+
+    #[test]
+    fn rejected_pushkey() {
+        let pusher = pusher(None);
+
+        assert!(!pusher.is_rejected(&Response::new(vec![])));
+        assert!(!pusher.is_rejected(&Response::new(vec!["ghijkl".into()])));
+        assert!(pusher.is_rejected(&Response::new(vec!["ghijkl".into(), "abcdef".into()])));
+    }
+}
@@ -0,0 +1,574 @@
+//! Resolution of server names to the address of their federation API.
+//!
+//! This implements the algorithm to [resolve server names] of the Matrix Server-Server API.
+//!
+//! [resolve server names]: https://spec.matrix.org/latest/server-server-api/#resolving-server-names
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    future::Future,
+    sync::RwLock,
+    time::{Duration, SystemTime},
+};
+
+use headers::{CacheControl, Expires, HeaderMapExt};
+use http::StatusCode;
+use ruma_common::{OwnedServerName, ServerName};
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+/// The default port of the federation API.
+const DEFAULT_PORT: u16 = 8448;
+
+/// The default duration of the cache of a `.well-known` response without cache headers.
+const DEFAULT_WELL_KNOWN_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The maximum duration of the cache of a `.well-known` response.
+const MAX_WELL_KNOWN_LIFETIME: Duration = Duration::from_secs(48 * 60 * 60);
+
+/// The duration of the cache of a failed `.well-known` request.
+const ERROR_WELL_KNOWN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// The default maximum number of cached `.well-known` delegations.
+const DEFAULT_WELL_KNOWN_CACHE_SIZE: usize = 10_000;
+
+/// A DNS resolver used by a [`ServerResolver`].
+pub trait DnsResolver: Send + Sync {
+    /// The error returned when a lookup fails.
+    type Error: fmt::Display;
+
+    /// Look up the SRV records of the given name, like `_matrix-fed._tcp.example.org`.
+    ///
+    /// If the name has no SRV records, this should return an empty list.
+    fn lookup_srv(
+        &self,
+        name: &str,
+    ) -> impl Future<Output = Result<Vec<SrvRecord>, Self::Error>> + Send;
+}
+
+/// A DNS SRV record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::exhaustive_structs)]
+pub struct SrvRecord {
+    /// The priority of the target host, lower values are preferred.
+    pub priority: u16,
+
+    /// The relative weight of records with the same priority, higher values are preferred.
+    pub weight: u16,
+
+    /// The port of the service on the target host.
+    pub port: u16,
+
+    /// The domain name of the target host.
+    pub target: String,
+}
+
+/// An HTTP client used by a [`ServerResolver`] to query the `.well-known` delegation of servers.
+pub trait WellKnownClient: Send + Sync {
+    /// The error returned when a request fails.
+    type Error: fmt::Display;
+
+    /// Send a `GET` request to `https://{hostname}/.well-known/matrix/server`.
+    ///
+    /// The request should follow redirects, and the certificate of the server must be valid for
+    /// `hostname`.
+    fn get_well_known(
+        &self,
+        hostname: &str,
+    ) -> impl Future<Output = Result<http::Response<Vec<u8>>, Self::Error>> + Send;
+}
+
+/// The address of the federation API of a server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ResolvedServer {
+    /// The host to connect to.
+    ///
+    /// This is either an IP address, with IPv6 addresses enclosed in square brackets, or a
+    /// hostname that must be resolved with its AAAA or A records.
+    pub host: String,
+
+    /// The port to connect to.
+    pub port: u16,
+
+    /// The value of the `Host` header of the requests.
+    pub host_header: String,
+
+    /// The name to use for TLS Server Name Indication.
+    ///
+    /// The certificate of the server must be valid for this name. This is `None` when the server
+    /// is reached through an IP literal, in which case the certificate must be valid for the IP
+    /// address.
+    pub tls_server_name: Option<String>,
+}
+
+impl ResolvedServer {
+    /// A server reached directly with the host and port of the given server name.
+    fn direct(server_name: &ServerName) -> Self {
+        let host = server_name.host().to_owned();
+        let tls_server_name = (!server_name.is_ip_literal()).then(|| host.clone());
+
+        Self {
+            host,
+            port: server_name.port().unwrap_or(DEFAULT_PORT),
+            host_header: server_name.to_string(),
+            tls_server_name,
+        }
+    }
+
+    /// A server reached with the target of an SRV record.
+    fn srv(server_name: &ServerName, (host, port): (String, u16)) -> Self {
+        Self {
+            host,
+            port,
+            host_header: server_name.to_string(),
+            tls_server_name: Some(server_name.host().to_owned()),
+        }
+    }
+}
+
+/// A resolver of server names to the address of their federation API.
+///
+/// The `.well-known` delegations of servers are cached according to the cache headers of the
+/// response, for 24 hours by default and at most 48 hours. Failed requests are cached for 1 hour.
+/// Expired entries are evicted when a new one is cached, and the size of the cache is limited, see
+/// [`ServerResolver::well_known_cache_size()`]. The caching of DNS records is left to the
+/// [`DnsResolver`].
+#[derive(Debug)]
+pub struct ServerResolver<D, H> {
+    /// The DNS resolver.
+    dns: D,
+
+    /// The HTTP client for `.well-known` requests.
+    http: H,
+
+    /// The cached `.well-known` delegations, by hostname.
+    well_known_cache: RwLock<BTreeMap<String, CachedWellKnown>>,
+
+    /// The maximum number of cached `.well-known` delegations.
+    well_known_cache_size: usize,
+}
+
+impl<D, H> ServerResolver<D, H> {
+    /// Creates a new `ServerResolver` using the given DNS resolver and HTTP client.
+    pub fn new(dns: D, http: H) -> Self {
+        Self {
+            dns,
+            http,
+            well_known_cache: RwLock::new(BTreeMap::new()),
+            well_known_cache_size: DEFAULT_WELL_KNOWN_CACHE_SIZE,
+        }
+    }
+
+    /// Set the maximum number of `.well-known` delegations to cache.
+    ///
+    /// When the cache is full, the entry that expires the soonest is evicted to make room for a
+    /// new one. Defaults to 10,000.
+    pub fn well_known_cache_size(self, well_known_cache_size: usize) -> Self {
+        Self { well_known_cache_size, ..self }
+    }
+
+    /// Remove the cached `.well-known` delegation of the given server.
+    pub fn forget(&self, server_name: &ServerName) {
+        self.well_known_cache
+            .write()
+            .expect("well-known cache lock was poisoned")
+            .remove(server_name.host());
+    }
+}
+
+impl<D: DnsResolver, H: WellKnownClient> ServerResolver<D, H> {
+    /// Resolve the given server name to the address of its federation API.
+    ///
+    /// Failures of the `.well-known` requests and of the DNS lookups are logged and make the
+    /// resolution fall back to the next step of the algorithm, so this always returns an address.
+    pub async fn resolve(&self, server_name: &ServerName) -> ResolvedServer {
+        // IP literals and explicit ports are used as-is.
+        if server_name.is_ip_literal() || server_name.port().is_some() {
+            return ResolvedServer::direct(server_name);
+        }
+
+        if let Some(delegated_server_name) = self.well_known(server_name.host()).await {
+            if delegated_server_name.is_ip_literal() || delegated_server_name.port().is_some() {
+                return ResolvedServer::direct(&delegated_server_name);
+            }
+
+            if let Some(target) = self.lookup_srv(delegated_server_name.host()).await {
+                return ResolvedServer::srv(&delegated_server_name, target);
+            }
+
+            return ResolvedServer::direct(&delegated_server_name);
+        }
+
+        if let Some(target) = self.lookup_srv(server_name.host()).await {
+            return ResolvedServer::srv(server_name, target);
+        }
+
+        ResolvedServer::direct(server_name)
+    }
+
+    /// Get the delegated server name of the given hostname, from the cache or with a
+    /// `.well-known` request.
+    async fn well_known(&self, hostname: &str) -> Option<OwnedServerName> {
+        let now = SystemTime::now();
+
+        if let Some(cached) = self
+            .well_known_cache
+            .read()
+            .expect("well-known cache lock was poisoned")
+            .get(hostname)
+            .filter(|cached| cached.expires_at > now)
+        {
+            return cached.delegated_server_name.clone();
+        }
+
+        let (delegated_server_name, lifetime) = match self.http.get_well_known(hostname).await {
+            Ok(response) => match parse_well_known(&response) {
+                Some(delegated_server_name) => {
+                    (Some(delegated_server_name), well_known_lifetime(response.headers(), now))
+                }
+                None => {
+                    debug!(%hostname, status = %response.status(), "Invalid .well-known response");
+                    (None, ERROR_WELL_KNOWN_LIFETIME)
+                }
+            },
+            Err(error) => {
+                debug!(%hostname, %error, "Failed to query the .well-known delegation");
+                (None, ERROR_WELL_KNOWN_LIFETIME)
+            }
+        };
+
+        if let Some(expires_at) = now.checked_add(lifetime).filter(|_| !lifetime.is_zero()) {
+            self.cache_well_known(
+                hostname,
+                CachedWellKnown {
+                    delegated_server_name: delegated_server_name.clone(),
+                    expires_at,
+                },
+                now,
+            );
+        }
+
+        delegated_server_name
+    }
+
+    /// Add the given `.well-known` delegation to the cache.
+    ///
+    /// The expired entries are evicted first. If the cache is still full, the entry that expires
+    /// the soonest is evicted.
+    fn cache_well_known(&self, hostname: &str, cached: CachedWellKnown, now: SystemTime) {
+        if self.well_known_cache_size == 0 {
+            return;
+        }
+
+        let mut cache = self.well_known_cache.write().expect("well-known cache lock was poisoned");
+        cache.remove(hostname);
+        cache.retain(|_, cached| cached.expires_at > now);
+
+        while cache.len() >= self.well_known_cache_size {
+            let Some(hostname) = cache
+                .iter()
+                .min_by_key(|(_, cached)| cached.expires_at)
+                .map(|(hostname, _)| hostname.clone())
+            else {
+                break;
+            };
+            cache.remove(&hostname);
+        }
+
+        cache.insert(hostname.to_owned(), cached);
+    }
+
+    /// Look up the `_matrix-fed._tcp` SRV records of the given hostname, then the deprecated
+    /// `_matrix._tcp` SRV records.
+    ///
+    /// Returns the host and port of the target with the lowest priority and the highest weight.
+    async fn lookup_srv(&self, hostname: &str) -> Option<(String, u16)> {
+        for service in ["_matrix-fed._tcp", "_matrix._tcp"] {
+            let name = format!("{service}.{hostname}");
+
+            let records = match self.dns.lookup_srv(&name).await {
+                Ok(records) => records,
+                Err(error) => {
+                    warn!(%name, %error, "Failed to look up SRV records");
+                    continue;
+                }
+            };
+
+            let target = records
+                .into_iter()
+                // A target of `.` means that the service is not available.
+                .filter(|record| record.target != ".")
+                .min_by(|a, b| a.priority.cmp(&b.priority).then(b.weight.cmp(&a.weight)));
+
+            if let Some(record) = target {
+                let host = record.target.strip_suffix('.').unwrap_or(&record.target).to_owned();
+                return Some((host, record.port));
+            }
+        }
+
+        None
+    }
+}
+
+/// A cached `.well-known` delegation.
+#[derive(Debug)]
+struct CachedWellKnown {
+    /// The delegated server name, or `None` if the server doesn't delegate.
+    delegated_server_name: Option<OwnedServerName>,
+
+    /// The time when this entry expires.
+    expires_at: SystemTime,
+}
+
+/// Get the delegated server name from the given `.well-known` response.
+fn parse_well_known(response: &http::Response<Vec<u8>>) -> Option<OwnedServerName> {
+    #[derive(Deserialize)]
+    struct WellKnown {
+        #[serde(rename = "m.server")]
+        server: OwnedServerName,
+    }
+
+    if response.status() != StatusCode::OK {
+        return None;
+    }
+
+    serde_json::from_slice::<WellKnown>(response.body()).ok().map(|well_known| well_known.server)
+}
+
+/// Get the duration of the cache of a `.well-known` response with the given headers.
+fn well_known_lifetime(headers: &http::HeaderMap, now: SystemTime) -> Duration {
+    let lifetime = if let Some(cache_control) = headers
+        .typed_get::<CacheControl>()
+        .filter(|cache_control| cache_control.no_store() || cache_control.no_cache())
+    {
+        debug!(?cache_control, "Not caching .well-known response");
+        Duration::ZERO
+    } else if let Some(max_age) =
+        headers.typed_get::<CacheControl>().and_then(|cache_control| cache_control.max_age())
+    {
+        max_age
+    } else if let Some(expires) = headers.typed_get::<Expires>() {
+        SystemTime::from(expires).duration_since(now).unwrap_or_default()
+    } else {
+        DEFAULT_WELL_KNOWN_LIFETIME
+    };
+
+    lifetime.min(MAX_WELL_KNOWN_LIFETIME)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        future::{ready, Future},
+        sync::Mutex,
+    };
+
+    use http::header::CACHE_CONTROL;
+    use ruma_common::server_name;
+
+    use super::{DnsResolver, ResolvedServer, ServerResolver, SrvRecord, WellKnownClient};
+
+    #[derive(Default)]
+    struct MemoryDns {
+        records: BTreeMap<&'static str, Vec<SrvRecord>>,
+    }
+
+    impl MemoryDns {
+        fn with_record(mut self, name: &'static str, target: &str, port: u16) -> Self {
+            self.records.entry(name).or_default().push(SrvRecord {
+                priority: 10,
+                weight: 0,
+                port,
+                target: target.to_owned(),
+            });
+            self
+        }
+    }
+
+    impl DnsResolver for MemoryDns {
+        type Error = String;
+
+        fn lookup_srv(
+            &self,
+            name: &str,
+        ) -> impl Future<Output = Result<Vec<SrvRecord>, Self::Error>> + Send {
+            ready(Ok(self.records.get(name).cloned().unwrap_or_default()))
+        }
+    }
+
+    /// The `m.server` and `Cache-Control` header of the `.well-known` responses, by hostname.
+    #[derive(Default)]
+    struct MemoryWellKnown {
+        responses: BTreeMap<&'static str, (&'static str, Option<&'static str>)>,
+        requests: Mutex<Vec<String>>,
+    }
+
+    impl MemoryWellKnown {
+        fn with_delegation(mut self, hostname: &'static str, delegated: &'static str) -> Self {
+            self.responses.insert(hostname, (delegated, None));
+            self
+        }
+    }
+
+    impl WellKnownClient for MemoryWellKnown {
+        type Error = String;
+
+        fn get_well_known(
+            &self,
+            hostname: &str,
+        ) -> impl Future<Output = Result<http::Response<Vec<u8>>, Self::Error>> + Send {
+            self.requests.lock().unwrap().push(hostname.to_owned());
+
+            ready(match self.responses.get(hostname) {
+                Some((delegated, cache_control)) => {
+                    let mut response = http::Response::builder();
+                    if let Some(cache_control) = cache_control {
+                        response = response.header(CACHE_CONTROL, *cache_control);
+                    }
+                    let body = serde_json::to_vec(&serde_json::json!({ "m.server": delegated }));
+                    Ok(response.body(body.unwrap()).unwrap())
+                }
+                None => Err("connection refused".to_owned()),
+            })
+        }
+    }
+
+    fn resolved(host: &str, port: u16, host_header: &str, sni: Option<&str>) -> ResolvedServer {
+        ResolvedServer {
+            host: host.to_owned(),
+            port,
+            host_header: host_header.to_owned(),
+            tls_server_name: sni.map(ToOwned::to_owned),
+        }
+    }
+
+    #[tokio::test]
+    async fn ip_literals_and_explicit_ports() {
+        let resolver = ServerResolver::new(MemoryDns::default(), MemoryWellKnown::default());
+
+        assert_eq!(
+            resolver.resolve(server_name!("1.2.3.4")).await,
+            resolved("1.2.3.4", 8448, "1.2.3.4", None)
+        );
+        assert_eq!(
+            resolver.resolve(server_name!("[1234:5678::abcd]:1234")).await,
+            resolved("[1234:5678::abcd]", 1234, "[1234:5678::abcd]:1234", None)
+        );
+        assert_eq!(
+            resolver.resolve(server_name!("example.org:1234")).await,
+            resolved("example.org", 1234, "example.org:1234", Some("example.org"))
+        );
+
+        // The well-known delegation is not queried.
+        assert!(resolver.http.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn well_known_delegation() {
+        let dns = MemoryDns::default()
+            .with_record("_matrix-fed._tcp.srv.example.org", "matrix.example.org.", 443)
+            .with_record("_matrix._tcp.legacy.example.org", "old.example.org", 8000);
+        let http = MemoryWellKnown::default()
+            .with_delegation("ip.example.org", "5.6.7.8")
+            .with_delegation("port.example.org", "matrix.example.org:443")
+            .with_delegation("srv.example.org", "srv.example.org")
+            .with_delegation("legacy.example.org", "legacy.example.org")
+            .with_delegation("default.example.org", "matrix.example.org");
+        let resolver = ServerResolver::new(dns, http);
+
+        assert_eq!(
+            resolver.resolve(server_name!("ip.example.org")).await,
+            resolved("5.6.7.8", 8448, "5.6.7.8", None)
+        );
+        assert_eq!(
+            resolver.resolve(server_name!("port.example.org")).await,
+            resolved(
+                "matrix.example.org",
+                443,
+                "matrix.example.org:443",
+                Some("matrix.example.org")
+            )
+        );
+        assert_eq!(
+            resolver.resolve(server_name!("srv.example.org")).await,
+            resolved("matrix.example.org", 443, "srv.example.org", Some("srv.example.org"))
+        );
+        assert_eq!(
+            resolver.resolve(server_name!("legacy.example.org")).await,
+            resolved("old.example.org", 8000, "legacy.example.org", Some("legacy.example.org"))
+        );
+        assert_eq!(
+            resolver.resolve(server_name!("default.example.org")).await,
+            resolved("matrix.example.org", 8448, "matrix.example.org", Some("matrix.example.org"))
+        );
+    }
+
+    #[tokio::test]
+    async fn srv_without_delegation() {
+        let mut dns = MemoryDns::default()
+            .with_record("_matrix-fed._tcp.example.org", "backup.example.org", 8000)
+            .with_record("_matrix-fed._tcp.example.org", "matrix.example.org", 443)
+            .with_record("_matrix._tcp.example.org", "legacy.example.org", 8000);
+        // Records with the same priority are sorted by weight.
+        dns.records.get_mut("_matrix-fed._tcp.example.org").unwrap()[1].weight = 10;
+        let resolver = ServerResolver::new(dns, MemoryWellKnown::default());
+
+        assert_eq!(
+            resolver.resolve(server_name!("example.org")).await,
+            resolved("matrix.example.org", 443, "example.org", Some("example.org"))
+        );
+        assert_eq!(
+            resolver.resolve(server_name!("other.example.org")).await,
+            resolved("other.example.org", 8448, "other.example.org", Some("other.example.org"))
+        );
+    }
+
+    #[tokio::test]
+    async fn well_known_cache() {
+        let mut http = MemoryWellKnown::default();
+        http.responses.insert("cached.example.org", ("matrix.example.org", Some("max-age=3600")));
+        http.responses.insert("uncached.example.org", ("matrix.example.org", Some("no-store")));
+        let resolver = ServerResolver::new(MemoryDns::default(), http);
+
+        for _ in 0..2 {
+            resolver.resolve(server_name!("cached.example.org")).await;
+            resolver.resolve(server_name!("uncached.example.org")).await;
+            resolver.resolve(server_name!("error.example.org")).await;
+        }
+        assert_eq!(
+            *resolver.http.requests.lock().unwrap(),
+            [
+                "cached.example.org",
+                "uncached.example.org",
+                "error.example.org",
+                "uncached.example.org"
+            ]
+        );
+
+        resolver.forget(server_name!("cached.example.org"));
+        resolver.resolve(server_name!("cached.example.org")).await;
+        assert_eq!(resolver.http.requests.lock().unwrap().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn well_known_cache_size() {
+        let mut http = MemoryWellKnown::default();
+        http.responses.insert("short.example.org", ("matrix.example.org", Some("max-age=3600")));
+        http.responses.insert("long.example.org", ("matrix.example.org", Some("max-age=7200")));
+        let resolver = ServerResolver::new(MemoryDns::default(), http).well_known_cache_size(2);
+
+        resolver.resolve(server_name!("short.example.org")).await;
+        resolver.resolve(server_name!("long.example.org")).await;
+        assert_eq!(resolver.well_known_cache.read().unwrap().len(), 2);
+
+        // The entry that expires the soonest is evicted.
+        resolver.resolve(server_name!("error.example.org")).await;
+        let cache = resolver.well_known_cache.read().unwrap();
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains_key("long.example.org"));
+        assert!(cache.contains_key("error.example.org"));
+    }
+}
@@ -0,0 +1,499 @@
+//! Sending of transactions to other homeservers.
+//!
+//! [`TransactionSender`] queues the PDUs and EDUs to send to each destination, and batches them
+//! in [transactions] sent with a pluggable [`TransactionTransport`].
+//!
+//! [transactions]: https://spec.matrix.org/latest/server-server-api/#transactions
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    future::Future,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use futures_util::future::join_all;
+use ruma_common::{
+    serde::Raw, MilliSecondsSinceUnixEpoch, OwnedServerName, ServerName, TransactionId,
+};
+use ruma_federation_api::transactions::{edu::Edu, send_transaction_message};
+use serde_json::value::RawValue as RawJsonValue;
+use thiserror::Error;
+use tracing::{debug, warn};
+
+/// The maximum number of PDUs in a transaction.
+const MAX_PDUS_PER_TRANSACTION: usize = 50;
+
+/// The maximum number of EDUs in a transaction.
+const MAX_EDUS_PER_TRANSACTION: usize = 100;
+
+/// The transport used by a [`TransactionSender`] to send transactions.
+pub trait TransactionTransport: Send + Sync {
+    /// The error returned when a transaction could not be delivered.
+    type Error: fmt::Display;
+
+    /// Send the given transaction to the given destination, with the
+    /// `PUT /_matrix/federation/v1/send/{txnId}` endpoint.
+    fn send_transaction(
+        &self,
+        destination: &ServerName,
+        request: send_transaction_message::v1::Request,
+    ) -> impl Future<Output = Result<send_transaction_message::v1::Response, Self::Error>> + Send;
+}
+
+/// A sender of transactions to other homeservers.
+///
+/// Each destination has its own queue of PDUs and EDUs, that are sent in transactions of at most
+/// 50 PDUs and 100 EDUs. A destination receives a single transaction at a time, in the order the
+/// data was queued.
+///
+/// When a transaction fails, the destination is considered down and is retried with an
+/// exponential backoff. The failed transaction is replayed with the same transaction ID, so the
+/// destination can deduplicate it if it was actually received.
+///
+/// This type doesn't spawn any task: the transactions are sent when calling [`flush()`] or
+/// [`send_pending()`], and [`next_retry()`] can be used to schedule the retries.
+///
+/// [`flush()`]: Self::flush
+/// [`send_pending()`]: Self::send_pending
+/// [`next_retry()`]: Self::next_retry
+#[derive(Debug)]
+pub struct TransactionSender<T> {
+    /// The server name of this homeserver.
+    origin: OwnedServerName,
+
+    /// The transport used to send transactions.
+    transport: T,
+
+    /// The delay before the first retry of a failed destination.
+    min_backoff: Duration,
+
+    /// The maximum delay between retries of a failed destination.
+    max_backoff: Duration,
+
+    /// The queues, by destination.
+    destinations: Mutex<BTreeMap<OwnedServerName, DestinationQueue>>,
+}
+
+impl<T> TransactionSender<T> {
+    /// Creates a new `TransactionSender` for the given origin, using the given transport.
+    ///
+    /// Failed destinations are retried after 10 seconds, doubling up to 1 hour.
+    pub fn new(origin: OwnedServerName, transport: T) -> Self {
+        Self {
+            origin,
+            transport,
+            min_backoff: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(60 * 60),
+            destinations: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Set the delay before the first retry of a failed destination, and the maximum delay
+    /// between retries.
+    pub fn backoff(self, min_backoff: Duration, max_backoff: Duration) -> Self {
+        Self { min_backoff, max_backoff, ..self }
+    }
+
+    /// Queue a PDU to send to the given destination.
+    pub fn queue_pdu(&self, destination: &ServerName, pdu: Box<RawJsonValue>) {
+        self.lock().entry(destination.to_owned()).or_default().pdus.push_back(pdu);
+    }
+
+    /// Queue an EDU to send to the given destination.
+    pub fn queue_edu(&self, destination: &ServerName, edu: Raw<Edu>) {
+        self.lock().entry(destination.to_owned()).or_default().edus.push_back(edu);
+    }
+
+    /// Whether the given destination has PDUs or EDUs waiting to be sent.
+    pub fn has_pending(&self, destination: &ServerName) -> bool {
+        self.lock().get(destination).is_some_and(DestinationQueue::has_pending)
+    }
+
+    /// Whether the last transaction sent to the given destination failed.
+    pub fn is_down(&self, destination: &ServerName) -> bool {
+        self.lock().get(destination).is_some_and(|queue| queue.backoff.is_some())
+    }
+
+    /// The destinations whose last transaction failed.
+    pub fn down_destinations(&self) -> Vec<OwnedServerName> {
+        self.lock()
+            .iter()
+            .filter(|(_, queue)| queue.backoff.is_some())
+            .map(|(destination, _)| destination.clone())
+            .collect()
+    }
+
+    /// The time when the given destination can be retried, if it is down.
+    pub fn retry_at(&self, destination: &ServerName) -> Option<Instant> {
+        self.lock().get(destination)?.backoff.as_ref().map(|backoff| backoff.retry_at)
+    }
+
+    /// The earliest time when a down destination with pending data can be retried.
+    pub fn next_retry(&self) -> Option<Instant> {
+        self.lock()
+            .values()
+            .filter(|queue| queue.has_pending())
+            .filter_map(|queue| queue.backoff.as_ref().map(|backoff| backoff.retry_at))
+            .min()
+    }
+
+    /// Mark the given destination as up, allowing to retry it immediately.
+    ///
+    /// This can be called when the destination is known to be reachable again, for example when
+    /// it sent a request to this homeserver.
+    pub fn mark_up(&self, destination: &ServerName) {
+        if let Some(queue) = self.lock().get_mut(destination) {
+            queue.backoff = None;
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<OwnedServerName, DestinationQueue>> {
+        self.destinations.lock().expect("transaction queues lock was poisoned")
+    }
+}
+
+impl<T: TransactionTransport> TransactionSender<T> {
+    /// Send the next transaction to the given destination.
+    ///
+    /// Returns `Ok(None)` if there is nothing to send, or if a transaction is already being sent
+    /// to the destination.
+    ///
+    /// If the destination didn't acknowledge the previous transaction, it is sent again.
+    /// Otherwise, a new transaction is created with the oldest queued PDUs and EDUs.
+    pub async fn send_pending(
+        &self,
+        destination: &ServerName,
+    ) -> Result<Option<send_transaction_message::v1::Response>, SendError<T::Error>> {
+        let request = {
+            let mut destinations = self.lock();
+            let Some(queue) = destinations.get_mut(destination) else {
+                return Ok(None);
+            };
+
+            if queue.sending || !queue.has_pending() {
+                return Ok(None);
+            }
+
+            if let Some(backoff) = queue.backoff.as_ref().filter(|b| b.retry_at > Instant::now()) {
+                return Err(SendError::Backoff(backoff.retry_at));
+            }
+
+            let request = queue.in_flight.get_or_insert_with(|| {
+                let mut request = send_transaction_message::v1::Request::new(
+                    TransactionId::new(),
+                    self.origin.clone(),
+                    MilliSecondsSinceUnixEpoch::now(),
+                );
+                let pdus_count = queue.pdus.len().min(MAX_PDUS_PER_TRANSACTION);
+                request.pdus = queue.pdus.drain(..pdus_count).collect();
+                let edus_count = queue.edus.len().min(MAX_EDUS_PER_TRANSACTION);
+                request.edus = queue.edus.drain(..edus_count).collect();
+                request
+            });
+
+            queue.sending = true;
+            request.clone()
+        };
+
+        // Reset the sending state even if this future is dropped.
+        let _guard = SendingGuard { sender: self, destination };
+        let transaction_id = request.transaction_id.clone();
+
+        let result = self.transport.send_transaction(destination, request).await;
+
+        let mut destinations = self.lock();
+        let queue = destinations.entry(destination.to_owned()).or_default();
+
+        match result {
+            Ok(response) => {
+                debug!(%destination, %transaction_id, "Transaction sent");
+                queue.in_flight = None;
+                queue.backoff = None;
+                Ok(Some(response))
+            }
+            Err(error) => {
+                let failures = queue.backoff.as_ref().map_or(0, |backoff| backoff.failures) + 1;
+                let delay = self
+                    .min_backoff
+                    .checked_mul(2_u32.saturating_pow(failures - 1))
+                    .map_or(self.max_backoff, |delay| delay.min(self.max_backoff));
+                let retry_at = Instant::now() + delay;
+
+                warn!(%destination, %transaction_id, %error, ?delay, "Failed to send transaction");
+                queue.backoff = Some(Backoff { failures, retry_at });
+                Err(SendError::Transport(error))
+            }
+        }
+    }
+
+    /// Send all the pending PDUs and EDUs to the destinations that are not down.
+    ///
+    /// Destinations are sent their transactions concurrently, until their queue is empty or a
+    /// transaction fails. Errors of PDUs reported by the destinations are logged.
+    ///
+    /// Returns the errors of the destinations that failed.
+    pub async fn flush(&self) -> BTreeMap<OwnedServerName, SendError<T::Error>> {
+        let now = Instant::now();
+        let destinations: Vec<_> = self
+            .lock()
+            .iter()
+            .filter(|(_, queue)| {
+                queue.has_pending() && queue.backoff.as_ref().map_or(true, |b| b.retry_at <= now)
+            })
+            .map(|(destination, _)| destination.clone())
+            .collect();
+
+        let results = join_all(destinations.into_iter().map(|destination| async move {
+            loop {
+                match self.send_pending(&destination).await {
+                    Ok(Some(response)) => {
+                        for (event_id, error) in response.pdus {
+                            if let Err(error) = error {
+                                warn!(%destination, %event_id, %error, "PDU was rejected");
+                            }
+                        }
+                    }
+                    Ok(None) => return None,
+                    Err(error) => return Some((destination, error)),
+                }
+            }
+        }))
+        .await;
+
+        results.into_iter().flatten().collect()
+    }
+}
+
+/// An error when sending a transaction.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum SendError<E> {
+    /// The destination is down and can't be retried before the given time.
+    #[error("destination is down until {0:?}")]
+    Backoff(Instant),
+
+    /// The transport failed to deliver the transaction.
+    #[error("failed to send transaction: {0}")]
+    Transport(E),
+}
+
+/// The queue of a destination.
+#[derive(Debug, Default)]
+struct DestinationQueue {
+    /// The PDUs waiting to be sent.
+    pdus: VecDeque<Box<RawJsonValue>>,
+
+    /// The EDUs waiting to be sent.
+    edus: VecDeque<Raw<Edu>>,
+
+    /// The transaction that was sent but not acknowledged yet.
+    in_flight: Option<send_transaction_message::v1::Request>,
+
+    /// Whether a transaction is currently being sent.
+    sending: bool,
+
+    /// The backoff state, if the last transaction failed.
+    backoff: Option<Backoff>,
+}
+
+impl DestinationQueue {
+    fn has_pending(&self) -> bool {
+        self.in_flight.is_some() || !self.pdus.is_empty() || !self.edus.is_empty()
+    }
+}
+
+/// The backoff state of a destination.
+#[derive(Debug)]
+struct Backoff {
+    /// The number of consecutive failed transactions.
+    failures: u32,
+
+    /// The time when the destination can be retried.
+    retry_at: Instant,
+}
+
+/// Guard resetting the sending state of a destination when dropped.
+struct SendingGuard<'a, T> {
+    sender: &'a TransactionSender<T>,
+    destination: &'a ServerName,
+}
+
+impl<T> Drop for SendingGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some(queue) = self.sender.lock().get_mut(self.destination) {
+            queue.sending = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeSet,
+        future::{ready, Future},
+        sync::Mutex,
+        time::Duration,
+    };
+
+    use assert_matches2::assert_matches;
+    use ruma_common::{
+        owned_server_name, serde::Raw, server_name, OwnedServerName, OwnedTransactionId, ServerName,
+    };
+    use ruma_federation_api::transactions::send_transaction_message;
+    use serde_json::{json, value::to_raw_value as to_raw_json_value};
+
+    use super::{SendError, TransactionSender, TransactionTransport};
+
+    /// A fake destination that records the transactions it receives.
+    #[derive(Default)]
+    struct FakeDestinations {
+        down: Mutex<BTreeSet<OwnedServerName>>,
+        received: Mutex<Vec<(OwnedServerName, send_transaction_message::v1::Request)>>,
+    }
+
+    impl FakeDestinations {
+        fn set_down(&self, destination: &ServerName, down: bool) {
+            let mut down_destinations = self.down.lock().unwrap();
+            if down {
+                down_destinations.insert(destination.to_owned());
+            } else {
+                down_destinations.remove(destination);
+            }
+        }
+
+        /// The transaction IDs and the number of PDUs and EDUs of the received transactions.
+        fn received(&self) -> Vec<(OwnedServerName, OwnedTransactionId, usize, usize)> {
+            self.received
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(destination, request)| {
+                    (
+                        destination.clone(),
+                        request.transaction_id.clone(),
+                        request.pdus.len(),
+                        request.edus.len(),
+                    )
+                })
+                .collect()
+        }
+    }
+
+    impl TransactionTransport for FakeDestinations {
+        type Error = String;
+
+        fn send_transaction(
+            &self,
+            destination: &ServerName,
+            request: send_transaction_message::v1::Request,
+        ) -> impl Future<Output = Result<send_transaction_message::v1::Response, Self::Error>> + Send
+        {
+            ready(if self.down.lock().unwrap().contains(destination) {
+                Err("connection refused".to_owned())
+            } else {
+                self.received.lock().unwrap().push((destination.to_owned(), request));
+                Ok(send_transaction_message::v1::Response::default())
+            })
+        }
+    }
+
+    fn sender() -> TransactionSender<FakeDestinations> {
+        TransactionSender::new(owned_server_name!("origin.local"), FakeDestinations::default())
+    }
+
+    fn queue(sender: &TransactionSender<FakeDestinations>, destination: &ServerName, n: usize) {
+        for i in 0..n {
+            let pdu = to_raw_json_value(&json!({ "type": "m.room.message", "depth": i })).unwrap();
+            sender.queue_pdu(destination, pdu);
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_pdus_and_edus() {
+        let sender = sender();
+        let destination = server_name!("remote.local");
+
+        queue(&sender, destination, 120);
+        for _ in 0..150 {
+            let edu = to_raw_json_value(&json!({ "edu_type": "m.presence", "content": {} }));
+            sender.queue_edu(destination, Raw::from_json(edu.unwrap()));
+        }
+
+        assert!(sender.flush().await.is_empty());
+        assert!(!sender.has_pending(destination));
+
+        let received = sender.transport.received();
+        let sizes: Vec<_> = received.iter().map(|(_, _, pdus, edus)| (*pdus, *edus)).collect();
+        assert_eq!(sizes, [(50, 100), (50, 50), (20, 0)]);
+
+        let transaction_ids: BTreeSet<_> = received.iter().map(|(_, id, _, _)| id).collect();
+        assert_eq!(transaction_ids.len(), 3);
+
+        let (_, request) = &sender.transport.received.lock().unwrap()[0];
+        assert_eq!(request.origin, "origin.local");
+        assert_eq!(request.pdus[0].get(), r#"{"depth":0,"type":"m.room.message"}"#);
+    }
+
+    #[tokio::test]
+    async fn retry_down_destination() {
+        let sender = sender().backoff(Duration::from_secs(60), Duration::from_secs(600));
+        let up = server_name!("up.local");
+        let down = server_name!("down.local");
+        sender.transport.set_down(down, true);
+
+        queue(&sender, up, 1);
+        queue(&sender, down, 60);
+
+        // The failure of a destination doesn't block the others.
+        let errors = sender.flush().await;
+        assert_eq!(errors.len(), 1);
+        assert_matches!(&errors[down], SendError::Transport(_));
+        assert!(!sender.is_down(up));
+        assert!(sender.is_down(down));
+        assert_eq!(sender.down_destinations(), [down]);
+        assert_eq!(sender.next_retry(), sender.retry_at(down));
+        assert_eq!(sender.transport.received().len(), 1);
+
+        // The destination is not retried before the end of the backoff.
+        queue(&sender, down, 1);
+        assert!(sender.flush().await.is_empty());
+        assert_matches!(sender.send_pending(down).await, Err(SendError::Backoff(_)));
+        assert_eq!(sender.transport.received().len(), 1);
+
+        // Once it's up, the failed transaction is replayed first.
+        sender.transport.set_down(down, false);
+        sender.mark_up(down);
+        assert!(sender.flush().await.is_empty());
+        assert!(!sender.is_down(down));
+
+        let received = sender.transport.received();
+        let sizes: Vec<_> = received[1..].iter().map(|(_, _, pdus, _)| *pdus).collect();
+        assert_eq!(sizes, [50, 11]);
+    }
+
+    #[tokio::test]
+    async fn replay_with_same_transaction_id() {
+        let sender = sender().backoff(Duration::ZERO, Duration::ZERO);
+        let destination = server_name!("remote.local");
+        sender.transport.set_down(destination, true);
+        queue(&sender, destination, 1);
+
+        assert_matches!(sender.send_pending(destination).await, Err(SendError::Transport(_)));
+        let transaction_id =
+            sender.lock()[destination].in_flight.as_ref().unwrap().transaction_id.clone();
+
+        // New data is not added to the unacknowledged transaction.
+        queue(&sender, destination, 1);
+        sender.transport.set_down(destination, false);
+        assert_matches!(sender.send_pending(destination).await, Ok(Some(_)));
+        assert_matches!(sender.send_pending(destination).await, Ok(Some(_)));
+        assert_matches!(sender.send_pending(destination).await, Ok(None));
+
+        let received = sender.transport.received();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].1, transaction_id);
+        assert_ne!(received[1].1, transaction_id);
+    }
+}
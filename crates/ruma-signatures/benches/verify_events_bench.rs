@@ -0,0 +1,96 @@
+// Because of criterion `cargo bench` works,
+// but if you use `cargo bench -- --save-baseline <name>`
+// or pass any other args to it, it fails with the error
+// `cargo bench unknown option --save-baseline`.
+// To pass args to criterion, use this form
+// `cargo bench --bench <name of the bench> -- --save-baseline <name>`.
+
+use std::collections::BTreeMap;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use ruma_common::{serde::Base64, CanonicalJsonObject, RoomVersionId};
+use ruma_signatures::{
+    hash_and_sign_event, verify_event, verify_events, Ed25519KeyPair, PublicKeyMap,
+};
+use serde_json::json;
+
+const EVENT_COUNT: usize = 1000;
+
+fn verify_events_one_by_one(c: &mut Criterion) {
+    let (public_key_map, events) = signed_events();
+
+    c.bench_function("verify 1000 events one by one", |b| {
+        b.iter_batched(
+            || events.clone(),
+            |events| {
+                for event in &events {
+                    verify_event(&public_key_map, event, &RoomVersionId::V10).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        );
+    });
+}
+
+fn verify_events_in_batch(c: &mut Criterion) {
+    let (public_key_map, events) = signed_events();
+
+    c.bench_function("verify 1000 events in batch", |b| {
+        b.iter_batched(
+            || events.clone(),
+            |events| {
+                for result in verify_events(&public_key_map, &events, &RoomVersionId::V10) {
+                    result.unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        );
+    });
+}
+
+criterion_group!(benches, verify_events_one_by_one, verify_events_in_batch);
+
+criterion_main!(benches);
+
+/// Generate events signed by two servers, and the public keys to verify them.
+fn signed_events() -> (PublicKeyMap, Vec<CanonicalJsonObject>) {
+    let key_pairs: Vec<_> = ["alice.example", "bob.example"]
+        .into_iter()
+        .map(|server| {
+            let document = Ed25519KeyPair::generate().unwrap();
+            (server, Ed25519KeyPair::from_der(&document, "1".to_owned()).unwrap())
+        })
+        .collect();
+
+    let public_key_map = key_pairs
+        .iter()
+        .map(|(server, key_pair)| {
+            let public_key = Base64::new(key_pair.public_key().to_vec());
+            ((*server).to_owned(), BTreeMap::from([("ed25519:1".to_owned(), public_key)]))
+        })
+        .collect();
+
+    let events = (0..EVENT_COUNT)
+        .map(|i| {
+            let (server, key_pair) = &key_pairs[i % key_pairs.len()];
+            let mut event = serde_json::from_value(json!({
+                "auth_events": [],
+                "content": {
+                    "body": format!("Message {i}"),
+                    "msgtype": "m.text",
+                },
+                "depth": i,
+                "origin_server_ts": 1_000_000,
+                "prev_events": [],
+                "room_id": "!room:alice.example",
+                "sender": format!("@user:{server}"),
+                "type": "m.room.message",
+            }))
+            .unwrap();
+            hash_and_sign_event(server, key_pair, &mut event, &RoomVersionId::V10).unwrap();
+            event
+        })
+        .collect();
+
+    (public_key_map, events)
+}
@@ -22,6 +22,10 @@ pub enum Error {
     #[error("Parse error: {0}")]
     Parse(#[from] ParseError),
 
+    /// The signer failed to sign the data.
+    #[error("Signer error: {0}")]
+    Signer(Box<dyn std::error::Error + Send + Sync>),
+
     /// Wrapper for [`pkcs8::Error`].
     #[error("DER Parse error: {0}")]
     DerParse(pkcs8::Error),
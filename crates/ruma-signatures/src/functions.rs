@@ -1,22 +1,25 @@
 //! Functions for signing and verifying JSON and events.
 
 use std::{
-    borrow::Cow,
     collections::{BTreeMap, BTreeSet},
     mem,
 };
 
 use base64::{alphabet, Engine};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use ed25519_dalek::{Verifier as _, VerifyingKey};
 use ruma_common::{
     canonical_json::{redact, JsonType},
+    room_version_rules::{EventIdFormatVersion, RoomVersionRules},
     serde::{base64::Standard, Base64},
     CanonicalJsonObject, CanonicalJsonValue, OwnedEventId, OwnedServerName, RoomVersionId, UserId,
 };
-use serde_json::{from_str as from_json_str, to_string as to_json_string};
+use serde_json::to_string as to_json_string;
 use sha2::{digest::Digest, Sha256};
 
 use crate::{
-    keys::{KeyPair, PublicKeyMap},
+    keys::{AsyncKeyPair, KeyPair, PublicKeyMap},
+    signatures::Signature,
     split_id,
     verification::{Ed25519Verifier, Verified, Verifier},
     Error, JsonError, ParseError, VerificationError,
@@ -95,23 +98,58 @@ pub fn sign_json<K>(
     object: &mut CanonicalJsonObject,
 ) -> Result<(), Error>
 where
-    K: KeyPair,
+    K: KeyPair + ?Sized,
 {
-    let (signatures_key, mut signature_map) = match object.remove_entry("signatures") {
-        Some((key, CanonicalJsonValue::Object(signatures))) => (Cow::Owned(key), signatures),
-        Some(_) => return Err(JsonError::not_of_type("signatures", JsonType::Object)),
-        None => (Cow::Borrowed("signatures"), BTreeMap::new()),
-    };
-
-    let maybe_unsigned_entry = object.remove_entry("unsigned");
-
     // Get the canonical JSON string.
-    let json = to_json_string(object).map_err(JsonError::Serde)?;
+    let json = canonical_json(object)?;
 
     // Sign the canonical JSON string.
     let signature = key_pair.sign(json.as_bytes());
 
-    // Insert the new signature in the map we pulled out (or created) previously.
+    insert_signature(entity_id, signature, object)
+}
+
+/// Signs an arbitrary JSON object with an [`AsyncKeyPair`] and adds the signature to an object
+/// under the key `signatures`.
+///
+/// This is the same as [`sign_json`], but it allows to use signers that don't have access to the
+/// secret key, like a hardware security module or a signing service.
+///
+/// # Errors
+///
+/// Returns an error if:
+///
+/// * `object` contains a field called `signatures` that is not a JSON object.
+/// * The signer failed to sign the JSON.
+pub async fn sign_json_async<K>(
+    entity_id: &str,
+    key_pair: &K,
+    object: &mut CanonicalJsonObject,
+) -> Result<(), Error>
+where
+    K: AsyncKeyPair + ?Sized,
+{
+    let json = canonical_json(object)?;
+    let signature = key_pair.sign_async(json.as_bytes()).await?;
+
+    insert_signature(entity_id, signature, object)
+}
+
+/// Adds the given signature of the entity to the `signatures` of the object.
+fn insert_signature(
+    entity_id: &str,
+    signature: Signature,
+    object: &mut CanonicalJsonObject,
+) -> Result<(), Error> {
+    let signature_map = match object
+        .entry("signatures".to_owned())
+        .or_insert_with(|| CanonicalJsonValue::Object(BTreeMap::new()))
+    {
+        CanonicalJsonValue::Object(signatures) => signatures,
+        _ => return Err(JsonError::not_of_type("signatures", JsonType::Object)),
+    };
+
+    // Insert the new signature in the map of the entity.
     let signature_set = signature_map
         .entry(entity_id.to_owned())
         .or_insert_with(|| CanonicalJsonValue::Object(BTreeMap::new()));
@@ -123,13 +161,6 @@ where
 
     signature_set.insert(signature.id(), CanonicalJsonValue::String(signature.base64()));
 
-    // Put `signatures` and `unsigned` back in.
-    object.insert(signatures_key.into(), CanonicalJsonValue::Object(signature_map));
-
-    if let Some((k, v)) = maybe_unsigned_entry {
-        object.insert(k, v);
-    }
-
     Ok(())
 }
 
@@ -333,8 +364,8 @@ pub fn reference_hash(
 
     let hash = Sha256::digest(json.as_bytes());
 
-    let base64_alphabet = match version {
-        RoomVersionId::V1 | RoomVersionId::V2 | RoomVersionId::V3 => alphabet::STANDARD,
+    let base64_alphabet = match rules_for(version).event_id_format {
+        EventIdFormatVersion::V1 | EventIdFormatVersion::V2 => alphabet::STANDARD,
         // Room versions higher than version 3 are url safe base64 encoded
         _ => alphabet::URL_SAFE,
     };
@@ -448,8 +479,51 @@ pub fn hash_and_sign_event<K>(
     version: &RoomVersionId,
 ) -> Result<(), Error>
 where
-    K: KeyPair,
+    K: KeyPair + ?Sized,
+{
+    let mut redacted = hash_event(object, version)?;
+
+    sign_json(entity_id, key_pair, &mut redacted)?;
+
+    object.insert("signatures".into(), mem::take(redacted.get_mut("signatures").unwrap()));
+
+    Ok(())
+}
+
+/// Hashes and signs an event with an [`AsyncKeyPair`] and adds the hash and signature to objects
+/// under the keys `hashes` and `signatures`, respectively.
+///
+/// This is the same as [`hash_and_sign_event`], but it allows to use signers that don't have
+/// access to the secret key, like a hardware security module or a signing service.
+///
+/// # Errors
+///
+/// Returns an error if the event is too large, if redaction fails or if the signer failed to sign
+/// the event.
+pub async fn hash_and_sign_event_async<K>(
+    entity_id: &str,
+    key_pair: &K,
+    object: &mut CanonicalJsonObject,
+    version: &RoomVersionId,
+) -> Result<(), Error>
+where
+    K: AsyncKeyPair + ?Sized,
 {
+    let mut redacted = hash_event(object, version)?;
+
+    sign_json_async(entity_id, key_pair, &mut redacted).await?;
+
+    object.insert("signatures".into(), mem::take(redacted.get_mut("signatures").unwrap()));
+
+    Ok(())
+}
+
+/// Adds the content hash of the event to its `hashes` and returns the redacted event, which is
+/// what needs to be signed.
+fn hash_event(
+    object: &mut CanonicalJsonObject,
+    version: &RoomVersionId,
+) -> Result<CanonicalJsonObject, Error> {
     let hash = content_hash(object)?;
 
     let hashes_value = object
@@ -463,13 +537,7 @@ where
         _ => return Err(JsonError::not_of_type("hashes", JsonType::Object)),
     };
 
-    let mut redacted = redact(object.clone(), version, None)?;
-
-    sign_json(entity_id, key_pair, &mut redacted)?;
-
-    object.insert("signatures".into(), mem::take(redacted.get_mut("signatures").unwrap()));
-
-    Ok(())
+    Ok(redact(object.clone(), version, None)?)
 }
 
 /// Verifies that the signed event contains all the required valid signatures.
@@ -544,6 +612,140 @@ pub fn verify_event(
     object: &CanonicalJsonObject,
     version: &RoomVersionId,
 ) -> Result<Verified, Error> {
+    prepare_event_verification(public_key_map, object, version)?.verify()
+}
+
+/// Verifies the signatures and content hashes of many events at once.
+///
+/// This gives the same results as calling [`verify_event`] for each event, but the signatures of
+/// all the events are checked together with ed25519 batch verification, which is much faster when
+/// verifying a large number of events, for example when joining a room.
+///
+/// Returns the result of the verification of each event, in the same order as `objects`.
+///
+/// If one of the signatures is invalid, the batch verification fails as a whole. In that case the
+/// signatures are checked again one event at a time, to find which events are invalid.
+///
+/// Batch verification can accept signatures that [`verify_event`] rejects when the public key or
+/// the signature have a small-order component, so such signatures are checked one at a time
+/// instead.
+///
+/// # Parameters
+///
+/// * public_key_map: A map from entity identifiers to a map from key identifiers to public keys,
+///   like for [`verify_event`]. It must contain the keys needed to verify all the events.
+/// * objects: The JSON objects of the events that were signed.
+/// * version: Room version of the given events.
+pub fn verify_events<'a>(
+    public_key_map: &PublicKeyMap,
+    objects: impl IntoIterator<Item = &'a CanonicalJsonObject>,
+    version: &RoomVersionId,
+) -> Vec<Result<Verified, Error>> {
+    let prepared_events: Vec<_> = objects
+        .into_iter()
+        .map(|object| prepare_event_verification(public_key_map, object, version))
+        .collect();
+
+    let mut messages = Vec::new();
+    let mut signatures = Vec::new();
+    let mut verifying_keys = Vec::new();
+
+    for prepared_event in prepared_events.iter().flatten() {
+        for (verifying_key, signature) in &prepared_event.signatures {
+            if !can_batch_verify(verifying_key, signature) {
+                continue;
+            }
+
+            messages.push(prepared_event.canonical_json.as_bytes());
+            signatures.push(*signature);
+            verifying_keys.push(*verifying_key);
+        }
+    }
+
+    if messages.is_empty()
+        || ed25519_dalek::verify_batch(&messages, &signatures, &verifying_keys).is_ok()
+    {
+        return prepared_events
+            .into_iter()
+            .map(|prepared_event| {
+                prepared_event.and_then(|prepared_event| prepared_event.verify_unbatchable())
+            })
+            .collect();
+    }
+
+    // At least one signature is invalid, look for the events that have invalid signatures.
+    prepared_events
+        .into_iter()
+        .map(|prepared_event| prepared_event.and_then(|prepared_event| prepared_event.verify()))
+        .collect()
+}
+
+/// An event whose signatures are ready to be verified.
+struct PreparedEvent {
+    /// The canonical JSON of the redacted event, which is what is signed.
+    canonical_json: String,
+
+    /// The signatures to verify, with their public key.
+    signatures: Vec<(VerifyingKey, ed25519_dalek::Signature)>,
+
+    /// The result of the verification if all the signatures are valid.
+    verified: Verified,
+}
+
+impl PreparedEvent {
+    /// Verify the signatures of this event one by one.
+    fn verify(self) -> Result<Verified, Error> {
+        self.verify_matching(|_, _| true)
+    }
+
+    /// Verify the signatures of this event that could not be batch verified one by one.
+    fn verify_unbatchable(self) -> Result<Verified, Error> {
+        self.verify_matching(|verifying_key, signature| !can_batch_verify(verifying_key, signature))
+    }
+
+    /// Verify the signatures of this event that match the given predicate one by one.
+    fn verify_matching(
+        self,
+        predicate: impl Fn(&VerifyingKey, &ed25519_dalek::Signature) -> bool,
+    ) -> Result<Verified, Error> {
+        for (verifying_key, signature) in &self.signatures {
+            if !predicate(verifying_key, signature) {
+                continue;
+            }
+
+            verifying_key
+                .verify(self.canonical_json.as_bytes(), signature)
+                .map_err(VerificationError::Signature)?;
+        }
+
+        Ok(self.verified)
+    }
+}
+
+/// Whether batch verification gives the same result as [`VerifyingKey::verify()`] for the given
+/// signature.
+///
+/// Both verifications agree when the public key is not weak and neither the public key nor the `R`
+/// component of the signature have a small-order component.
+fn can_batch_verify(verifying_key: &VerifyingKey, signature: &ed25519_dalek::Signature) -> bool {
+    let is_torsion_free = |bytes: &[u8; 32]| {
+        CompressedEdwardsY(*bytes).decompress().is_some_and(|point| point.is_torsion_free())
+    };
+
+    !verifying_key.is_weak()
+        && is_torsion_free(verifying_key.as_bytes())
+        && is_torsion_free(signature.r_bytes())
+}
+
+/// Get the signatures that need to be verified for the given event, and compare its content hash.
+///
+/// Returns an error if the event is malformed, if the required signatures are missing or if the
+/// public keys of the signatures are not in the `public_key_map`.
+fn prepare_event_verification(
+    public_key_map: &PublicKeyMap,
+    object: &CanonicalJsonObject,
+    version: &RoomVersionId,
+) -> Result<PreparedEvent, Error> {
     let redacted = redact(object.clone(), version, None)?;
 
     let hash = match object.get("hashes") {
@@ -567,7 +769,8 @@ pub fn verify_event(
     };
 
     let servers_to_check = servers_to_check_signatures(object, version)?;
-    let canonical_json = from_json_str(&canonical_json(&redacted)?).map_err(JsonError::from)?;
+    let canonical_json = canonical_json(&redacted)?;
+    let mut signatures = Vec::new();
 
     for entity_id in servers_to_check {
         let signature_set = match signature_map.get(entity_id.as_str()) {
@@ -603,12 +806,17 @@ pub fn verify_event(
             let signature = Base64::<Standard>::parse(signature)
                 .map_err(|e| ParseError::base64("signature", signature, e))?;
 
-            verify_json_with(
-                &Ed25519Verifier,
-                public_key.as_bytes(),
-                signature.as_bytes(),
-                &canonical_json,
-            )?;
+            let verifying_key = VerifyingKey::from_bytes(
+                public_key
+                    .as_bytes()
+                    .try_into()
+                    .map_err(|_| ParseError::PublicKey(ed25519_dalek::SignatureError::new()))?,
+            )
+            .map_err(ParseError::PublicKey)?;
+            let signature = ed25519_dalek::Signature::try_from(signature.as_bytes())
+                .map_err(ParseError::Signature)?;
+
+            signatures.push((verifying_key, signature));
             checked = true;
         }
 
@@ -619,13 +827,12 @@ pub fn verify_event(
 
     let calculated_hash = content_hash(object)?;
 
-    if let Ok(hash) = Base64::<Standard>::parse(hash) {
-        if hash.as_bytes() == calculated_hash.as_bytes() {
-            return Ok(Verified::All);
-        }
-    }
+    let verified = match Base64::<Standard>::parse(hash) {
+        Ok(hash) if hash.as_bytes() == calculated_hash.as_bytes() => Verified::All,
+        _ => Verified::Signatures,
+    };
 
-    Ok(Verified::Signatures)
+    Ok(PreparedEvent { canonical_json, signatures, verified })
 }
 
 /// Internal implementation detail of the canonical JSON algorithm.
@@ -669,8 +876,10 @@ fn servers_to_check_signatures(
         };
     }
 
-    match version {
-        RoomVersionId::V1 | RoomVersionId::V2 => match object.get("event_id") {
+    let rules = rules_for(version).signatures;
+
+    if rules.check_event_id_server {
+        match object.get("event_id") {
             Some(CanonicalJsonValue::String(raw_event_id)) => {
                 let event_id: OwnedEventId =
                     raw_event_id.parse().map_err(|e| Error::from(ParseError::EventId(e)))?;
@@ -685,34 +894,36 @@ fn servers_to_check_signatures(
             _ => {
                 return Err(JsonError::field_missing_from_object("event_id"));
             }
-        },
-        RoomVersionId::V3
-        | RoomVersionId::V4
-        | RoomVersionId::V5
-        | RoomVersionId::V6
-        | RoomVersionId::V7 => {}
-        // TODO: And for all future versions that have join_authorised_via_users_server
-        RoomVersionId::V8 | RoomVersionId::V9 | RoomVersionId::V10 | RoomVersionId::V11 => {
-            if let Some(authorized_user) = object
-                .get("content")
-                .and_then(|c| c.as_object())
-                .and_then(|c| c.get("join_authorised_via_users_server"))
-            {
-                let authorized_user = authorized_user.as_str().ok_or_else(|| {
-                    JsonError::not_of_type("join_authorised_via_users_server", JsonType::String)
-                })?;
-                let authorized_user = <&UserId>::try_from(authorized_user)
-                    .map_err(|e| Error::from(ParseError::UserId(e)))?;
+        }
+    }
 
-                servers_to_check.insert(authorized_user.server_name().to_owned());
-            }
+    if rules.check_join_authorised_via_users_server {
+        if let Some(authorized_user) = object
+            .get("content")
+            .and_then(|c| c.as_object())
+            .and_then(|c| c.get("join_authorised_via_users_server"))
+        {
+            let authorized_user = authorized_user.as_str().ok_or_else(|| {
+                JsonError::not_of_type("join_authorised_via_users_server", JsonType::String)
+            })?;
+            let authorized_user = <&UserId>::try_from(authorized_user)
+                .map_err(|e| Error::from(ParseError::UserId(e)))?;
+
+            servers_to_check.insert(authorized_user.server_name().to_owned());
         }
-        _ => unimplemented!(),
     }
 
     Ok(servers_to_check)
 }
 
+/// The rules of the given room version.
+///
+/// Like for redaction, custom room versions whose rules were not registered use the rules of the
+/// latest room version.
+fn rules_for(version: &RoomVersionId) -> RoomVersionRules {
+    version.rules().unwrap_or(RoomVersionRules::V11)
+}
+
 /// Checks if `object` contains an event of type `m.room.third_party_invite`
 fn is_third_party_invite(object: &CanonicalJsonObject) -> Result<bool, Error> {
     match object.get("type") {
@@ -726,15 +937,24 @@ mod tests {
     use std::collections::BTreeMap;
 
     use assert_matches2::assert_matches;
+    use curve25519_dalek::{constants::EIGHT_TORSION, EdwardsPoint, Scalar};
+    use ed25519_dalek::{Verifier as _, VerifyingKey};
     use ruma_common::{
-        serde::Base64, CanonicalJsonValue, RoomVersionId, ServerSigningKeyId, SigningKeyAlgorithm,
+        canonical_json::redact,
+        room_version_rules::{
+            EventIdFormatVersion, RoomVersionRules, ScopedCustomRoomVersion, SignaturesRules,
+        },
+        serde::{base64::Standard, Base64},
+        CanonicalJsonObject, CanonicalJsonValue, RoomVersionId, ServerSigningKeyId,
+        SigningKeyAlgorithm,
     };
     use serde_json::json;
 
     use super::canonical_json;
     use crate::{
-        sign_json, verify_event, Ed25519KeyPair, Error, PublicKeyMap, PublicKeySet,
-        VerificationError, Verified,
+        hash_and_sign_event, hash_and_sign_event_async, reference_hash, sign_json, sign_json_async,
+        verify_event, verify_events, AsyncKeyPair, Ed25519KeyPair, Error, KeyPair, PublicKeyMap,
+        PublicKeySet, Signature, VerificationError, Verified,
     };
 
     #[test]
@@ -917,6 +1137,64 @@ mod tests {
         assert_eq!(server, "domain-authorized");
     }
 
+    #[test]
+    fn verify_event_with_custom_room_version() {
+        let room_version = RoomVersionId::try_from("org.example.custom").unwrap();
+        let _guard = ScopedCustomRoomVersion::register(
+            room_version.clone(),
+            RoomVersionRules {
+                event_id_format: EventIdFormatVersion::V2,
+                signatures: SignaturesRules { check_event_id_server: true, ..SignaturesRules::V8 },
+                ..RoomVersionRules::V11
+            },
+        )
+        .unwrap();
+
+        let key_pair_sender = generate_key_pair("1");
+        let mut signed_event = serde_json::from_str(
+            r#"{
+                "event_id": "$event_id:domain-event",
+                "auth_events": [],
+                "content": {},
+                "depth": 3,
+                "hashes": {
+                    "sha256": "5jM4wQpv6lnBo7CLIghJuHdW+s2CMBJPUOGOC89ncos"
+                },
+                "origin_server_ts": 1000000,
+                "prev_events": [],
+                "room_id": "!x:domain",
+                "sender": "@name:domain-sender",
+                "type": "X",
+                "unsigned": {
+                    "age_ts": 1000000
+                }
+            }"#,
+        )
+        .unwrap();
+        sign_json("domain-sender", &key_pair_sender, &mut signed_event).unwrap();
+
+        let mut public_key_map = BTreeMap::new();
+        add_key_to_map(&mut public_key_map, "domain-sender", &key_pair_sender);
+
+        // The signature of the server of the event ID is required by the custom rules.
+        let verification_result = verify_event(&public_key_map, &signed_event, &room_version);
+        assert_matches!(
+            verification_result,
+            Err(Error::Verification(VerificationError::SignatureNotFound(server)))
+        );
+        assert_eq!(server, "domain-event");
+
+        // It is not required in room version 11.
+        let verification =
+            verify_event(&public_key_map, &signed_event, &RoomVersionId::V11).unwrap();
+        assert_eq!(verification, Verified::Signatures);
+
+        // The reference hash uses the standard base64 alphabet of the custom event ID format.
+        let hash = reference_hash(&signed_event, &room_version).unwrap();
+        let url_safe_hash = reference_hash(&signed_event, &RoomVersionId::V11).unwrap();
+        assert_eq!(hash.replace('+', "-").replace('/', "_"), url_safe_hash);
+    }
+
     #[test]
     fn verification_fails_if_required_keys_are_not_given() {
         let key_pair_sender = generate_key_pair("1");
@@ -1151,6 +1429,229 @@ mod tests {
         );
     }
 
+    #[test]
+    fn verify_events_in_batch() {
+        let key_pair = generate_key_pair("1");
+        let mut public_key_map = BTreeMap::new();
+        add_key_to_map(&mut public_key_map, "domain", &key_pair);
+
+        let events: Vec<CanonicalJsonObject> = (0..10)
+            .map(|i| {
+                let mut event = serde_json::from_value(json!({
+                    "auth_events": [],
+                    "content": { "body": format!("Message {i}") },
+                    "depth": i,
+                    "origin_server_ts": 1_000_000,
+                    "prev_events": [],
+                    "room_id": "!x:domain",
+                    "sender": "@name:domain",
+                    "type": "m.room.message",
+                }))
+                .unwrap();
+                hash_and_sign_event("domain", &key_pair, &mut event, &RoomVersionId::V6).unwrap();
+                event
+            })
+            .collect();
+
+        // All the events are valid.
+        let results = verify_events(&public_key_map, &events, &RoomVersionId::V6);
+        assert_eq!(results.len(), 10);
+        for result in results {
+            assert_eq!(result.unwrap(), Verified::All);
+        }
+
+        let mut invalid_events = events;
+        // The content was modified, only the hash doesn't match.
+        invalid_events[1].insert(
+            "content".to_owned(),
+            CanonicalJsonValue::Object([("body".to_owned(), "Edited".into())].into()),
+        );
+        // The signed fields were modified, the signature is invalid.
+        invalid_events[4].insert("depth".to_owned(), CanonicalJsonValue::Integer(42_u32.into()));
+        // The signature is missing.
+        invalid_events[7].remove("signatures");
+
+        let results = verify_events(&public_key_map, &invalid_events, &RoomVersionId::V6);
+        assert_eq!(results.len(), 10);
+        for (i, result) in results.into_iter().enumerate() {
+            match i {
+                1 => assert_eq!(result.unwrap(), Verified::Signatures),
+                4 => {
+                    assert_matches!(
+                        result,
+                        Err(Error::Verification(VerificationError::Signature(_)))
+                    );
+                }
+                7 => {
+                    assert_matches!(result, Err(Error::Json(_)));
+                }
+                _ => assert_eq!(result.unwrap(), Verified::All),
+            }
+
+            // The results are the same as with `verify_event`.
+            assert_eq!(
+                verify_event(&public_key_map, &invalid_events[i], &RoomVersionId::V6).ok(),
+                verify_events(&public_key_map, [&invalid_events[i]], &RoomVersionId::V6)
+                    .pop()
+                    .unwrap()
+                    .ok()
+            );
+        }
+    }
+
+    #[test]
+    fn verify_events_with_weak_key() {
+        let key_pair = generate_key_pair("1");
+        let mut event: CanonicalJsonObject = serde_json::from_value(json!({
+            "auth_events": [],
+            "content": { "body": "Hello" },
+            "depth": 1,
+            "origin_server_ts": 1_000_000,
+            "prev_events": [],
+            "room_id": "!x:domain",
+            "sender": "@name:domain",
+            "type": "m.room.message",
+        }))
+        .unwrap();
+        hash_and_sign_event("domain", &key_pair, &mut event, &RoomVersionId::V6).unwrap();
+
+        // A public key of order 8, with a signature that is accepted by batch verification but not
+        // by the single verification.
+        let weak_key = VerifyingKey::from_bytes(&EIGHT_TORSION[1].compress().to_bytes()).unwrap();
+        let message = canonical_json(&redact(event.clone(), &RoomVersionId::V6, None).unwrap())
+            .unwrap()
+            .into_bytes();
+        let signature = (1_u8..=u8::MAX)
+            .map(|s| {
+                let s = Scalar::from(s);
+                ed25519_dalek::Signature::from_components(
+                    EdwardsPoint::mul_base(&s).compress().to_bytes(),
+                    s.to_bytes(),
+                )
+            })
+            .find(|signature| {
+                ed25519_dalek::verify_batch(&[&message], &[*signature], &[weak_key]).is_ok()
+                    && weak_key.verify(&message, signature).is_err()
+            })
+            .unwrap();
+
+        let CanonicalJsonValue::Object(signatures) = event.get_mut("signatures").unwrap() else {
+            panic!("signatures should be an object");
+        };
+        signatures.insert(
+            "domain".to_owned(),
+            CanonicalJsonValue::Object(
+                [(
+                    "ed25519:1".to_owned(),
+                    Base64::<Standard, _>::new(signature.to_bytes()).encode().into(),
+                )]
+                .into(),
+            ),
+        );
+
+        let mut public_key_map = BTreeMap::new();
+        public_key_map.insert(
+            "domain".to_owned(),
+            [("ed25519:1".to_owned(), Base64::new(weak_key.to_bytes().to_vec()))].into(),
+        );
+
+        assert_matches!(
+            verify_event(&public_key_map, &event, &RoomVersionId::V6),
+            Err(Error::Verification(VerificationError::Signature(_)))
+        );
+        assert_matches!(
+            verify_events(&public_key_map, [&event], &RoomVersionId::V6).pop().unwrap(),
+            Err(Error::Verification(VerificationError::Signature(_)))
+        );
+    }
+
+    /// A signer that doesn't give access to its key, like a signing service.
+    struct RemoteSigner {
+        key_pair: Option<Ed25519KeyPair>,
+    }
+
+    impl AsyncKeyPair for RemoteSigner {
+        async fn sign_async(&self, message: &[u8]) -> Result<Signature, Error> {
+            tokio::task::yield_now().await;
+
+            match &self.key_pair {
+                Some(key_pair) => Ok(key_pair.sign(message)),
+                None => Err(Error::Signer("the signing service is unavailable".into())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn sign_event_with_async_key_pair() {
+        let event: CanonicalJsonObject = serde_json::from_value(json!({
+            "auth_events": [],
+            "content": {},
+            "depth": 3,
+            "origin_server_ts": 1_000_000,
+            "prev_events": [],
+            "room_id": "!x:domain",
+            "sender": "@name:domain",
+            "type": "X",
+        }))
+        .unwrap();
+
+        let key_pair = generate_key_pair("1");
+        let mut public_key_map = BTreeMap::new();
+        add_key_to_map(&mut public_key_map, "domain", &key_pair);
+
+        let mut signed_event = event.clone();
+        hash_and_sign_event("domain", &key_pair, &mut signed_event, &RoomVersionId::V6).unwrap();
+
+        // Ed25519 signatures are deterministic.
+        let signer = RemoteSigner { key_pair: Some(key_pair) };
+        let mut async_signed_event = event.clone();
+        hash_and_sign_event_async("domain", &signer, &mut async_signed_event, &RoomVersionId::V6)
+            .await
+            .unwrap();
+        assert_eq!(async_signed_event, signed_event);
+        assert_eq!(
+            verify_event(&public_key_map, &async_signed_event, &RoomVersionId::V6).unwrap(),
+            Verified::All
+        );
+
+        let mut object = event.clone();
+        sign_json_async("domain", &signer, &mut object).await.unwrap();
+        assert_eq!(
+            object["signatures"].as_object().unwrap()["domain"].as_object().unwrap().len(),
+            1
+        );
+
+        // Key pairs can be used as async key pairs.
+        let key_pair = signer.key_pair.unwrap();
+        let mut async_signed_event = event.clone();
+        hash_and_sign_event_async("domain", &key_pair, &mut async_signed_event, &RoomVersionId::V6)
+            .await
+            .unwrap();
+        assert_eq!(async_signed_event, signed_event);
+
+        // The error of the signer is forwarded.
+        let signer = RemoteSigner { key_pair: None };
+        let mut object = event;
+        assert_matches!(
+            hash_and_sign_event_async("domain", &signer, &mut object, &RoomVersionId::V6).await,
+            Err(Error::Signer(_))
+        );
+        assert!(!object.contains_key("signatures"));
+    }
+
+    #[test]
+    fn sign_json_with_key_pair_trait_object() {
+        let key_pair: Box<dyn KeyPair> = Box::new(generate_key_pair("1"));
+
+        let mut object = CanonicalJsonObject::new();
+        sign_json("domain", &*key_pair, &mut object).unwrap();
+
+        assert_eq!(
+            object["signatures"].as_object().unwrap()["domain"].as_object().unwrap().len(),
+            1
+        );
+    }
+
     fn generate_key_pair(name: &str) -> Ed25519KeyPair {
         let key_content = Ed25519KeyPair::generate().unwrap();
         Ed25519KeyPair::from_der(&key_content, name.to_owned())
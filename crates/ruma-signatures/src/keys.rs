@@ -3,6 +3,7 @@
 use std::{
     collections::BTreeMap,
     fmt::{Debug, Formatter, Result as FmtResult},
+    future::{ready, Future},
 };
 
 use ed25519_dalek::{pkcs8::ALGORITHM_OID, SecretKey, Signer, SigningKey, PUBLIC_KEY_LENGTH};
@@ -17,7 +18,11 @@ use crate::{signatures::Signature, Algorithm, Error, ParseError};
 mod compat;
 
 /// A cryptographic key pair for digitally signing data.
-pub trait KeyPair: Sized {
+///
+/// This can be implemented for signers that don't expose their secret key, for example a callback
+/// to a PKCS#11 token. Signers that can fail or that need to wait for the signature should
+/// implement [`AsyncKeyPair`] instead.
+pub trait KeyPair {
     /// Signs a JSON object.
     ///
     /// # Parameters
@@ -26,6 +31,36 @@ pub trait KeyPair: Sized {
     fn sign(&self, message: &[u8]) -> Signature;
 }
 
+/// A cryptographic key pair for digitally signing data asynchronously.
+///
+/// This is meant for signers that keep the secret key out of the process, like a hardware
+/// security module or a signing service, and is used by [`sign_json_async`] and
+/// [`hash_and_sign_event_async`].
+///
+/// It is implemented for all the types that implement [`KeyPair`].
+///
+/// [`sign_json_async`]: crate::sign_json_async
+/// [`hash_and_sign_event_async`]: crate::hash_and_sign_event_async
+pub trait AsyncKeyPair: Sync {
+    /// Signs a JSON object.
+    ///
+    /// # Parameters
+    ///
+    /// * message: An arbitrary series of bytes to sign.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the signer failed to sign the message, which should use the
+    /// [`Error::Signer`] variant.
+    fn sign_async(&self, message: &[u8]) -> impl Future<Output = Result<Signature, Error>> + Send;
+}
+
+impl<K: KeyPair + Sync + ?Sized> AsyncKeyPair for K {
+    fn sign_async(&self, message: &[u8]) -> impl Future<Output = Result<Signature, Error>> + Send {
+        ready(Ok(self.sign(message)))
+    }
+}
+
 /// An Ed25519 key pair.
 pub struct Ed25519KeyPair {
     signing_key: SigningKey,
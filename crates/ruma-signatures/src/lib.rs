@@ -49,10 +49,10 @@ use ruma_common::serde::{AsRefStr, DisplayAsRefStr};
 pub use self::{
     error::{Error, JsonError, ParseError, VerificationError},
     functions::{
-        canonical_json, content_hash, hash_and_sign_event, reference_hash, sign_json, verify_event,
-        verify_json,
+        canonical_json, content_hash, hash_and_sign_event, hash_and_sign_event_async,
+        reference_hash, sign_json, sign_json_async, verify_event, verify_events, verify_json,
     },
-    keys::{Ed25519KeyPair, KeyPair, PublicKeyMap, PublicKeySet},
+    keys::{AsyncKeyPair, Ed25519KeyPair, KeyPair, PublicKeyMap, PublicKeySet},
     signatures::Signature,
     verification::Verified,
 };
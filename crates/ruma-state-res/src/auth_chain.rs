@@ -0,0 +1,381 @@
+//! Computation of the auth chains of events.
+//!
+//! The auth chain of an event is the set of its auth events, their auth events, and so on
+//! recursively. The auth chains of the events in each state set are needed by [`resolve`].
+//!
+//! [`resolve`]: crate::resolve
+
+use std::{
+    borrow::Borrow,
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    sync::Arc,
+};
+
+use futures_util::{stream, Future, StreamExt};
+use ruma_common::EventId;
+use tracing::debug;
+
+use crate::{Error, Event, Result, StateMap};
+
+/// The default maximum number of events fetched concurrently.
+const DEFAULT_MAX_CONCURRENT_FETCHES: usize = 32;
+
+/// A cache of the auth chains of events.
+///
+/// The auth chain of every event that is visited while computing the auth chain of another event
+/// is kept, so it doesn't need to be computed again. Since the auth events of an event never
+/// change, a cache can be reused for all the state resolutions in a room.
+#[derive(Clone, Debug)]
+pub struct AuthChainCache<Id> {
+    chains: HashMap<Id, Arc<HashSet<Id>>>,
+    max_concurrent_fetches: usize,
+}
+
+impl<Id> AuthChainCache<Id>
+where
+    Id: Borrow<EventId> + Clone + Eq + Hash,
+{
+    /// Creates an empty `AuthChainCache`.
+    ///
+    /// By default, at most 32 events are fetched concurrently.
+    pub fn new() -> Self {
+        Self { chains: HashMap::new(), max_concurrent_fetches: DEFAULT_MAX_CONCURRENT_FETCHES }
+    }
+
+    /// Set the maximum number of events that are fetched concurrently.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_concurrent_fetches` is 0.
+    pub fn max_concurrent_fetches(self, max_concurrent_fetches: usize) -> Self {
+        assert!(max_concurrent_fetches > 0, "max_concurrent_fetches must be greater than 0");
+        Self { max_concurrent_fetches, ..self }
+    }
+
+    /// Get the cached auth chain of the given event.
+    pub fn get(&self, event_id: &EventId) -> Option<Arc<HashSet<Id>>> {
+        self.chains.get(event_id).cloned()
+    }
+
+    /// The number of events whose auth chain is cached.
+    pub fn len(&self) -> usize {
+        self.chains.len()
+    }
+
+    /// Whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.chains.is_empty()
+    }
+
+    /// Remove the auth chains from the cache.
+    pub fn clear(&mut self) {
+        self.chains.clear();
+    }
+
+    /// Get the auth chain of the given event.
+    ///
+    /// The auth chain doesn't include the event itself.
+    ///
+    /// `fetch_event` is called for every event whose auth chain is not cached yet.
+    ///
+    /// Returns an error if an event of the auth chain cannot be fetched or if the auth events form
+    /// a cycle.
+    pub async fn auth_chain<E, Fetch, FetchFut>(
+        &mut self,
+        event_id: Id,
+        fetch_event: &Fetch,
+    ) -> Result<Arc<HashSet<Id>>>
+    where
+        Fetch: Fn(Id) -> FetchFut + Sync,
+        FetchFut: Future<Output = Option<E>> + Send,
+        E: Event<Id = Id> + Send,
+        Id: Send + Sync,
+    {
+        self.compute_auth_chains(vec![event_id.clone()], fetch_event).await?;
+        Ok(self.chains[event_id.borrow()].clone())
+    }
+
+    /// Get the auth chains of the given state sets, as expected by [`resolve`].
+    ///
+    /// The auth chain of a state set is the union of the auth chains of the events in the state
+    /// set.
+    ///
+    /// `fetch_event` is called for every event whose auth chain is not cached yet.
+    ///
+    /// Returns an error if an event of the auth chains cannot be fetched or if the auth events
+    /// form a cycle.
+    ///
+    /// [`resolve`]: crate::resolve
+    pub async fn auth_chain_sets<'a, E, Fetch, FetchFut>(
+        &mut self,
+        state_sets: impl IntoIterator<Item = &'a StateMap<Id>>,
+        fetch_event: &Fetch,
+    ) -> Result<Vec<HashSet<Id>>>
+    where
+        Fetch: Fn(Id) -> FetchFut + Sync,
+        FetchFut: Future<Output = Option<E>> + Send,
+        E: Event<Id = Id> + Send,
+        Id: Send + Sync + 'a,
+    {
+        let state_sets = state_sets.into_iter().collect::<Vec<_>>();
+
+        self.compute_auth_chains(
+            state_sets.iter().flat_map(|state_set| state_set.values().cloned()).collect(),
+            fetch_event,
+        )
+        .await?;
+
+        Ok(state_sets
+            .into_iter()
+            .map(|state_set| {
+                state_set
+                    .values()
+                    .flat_map(|event_id| self.chains[event_id.borrow()].iter().cloned())
+                    .collect()
+            })
+            .collect())
+    }
+
+    /// Compute the auth chains of the given events and of all the events in their auth chains,
+    /// if they are not cached yet.
+    async fn compute_auth_chains<E, Fetch, FetchFut>(
+        &mut self,
+        event_ids: Vec<Id>,
+        fetch_event: &Fetch,
+    ) -> Result<()>
+    where
+        Fetch: Fn(Id) -> FetchFut + Sync,
+        FetchFut: Future<Output = Option<E>> + Send,
+        E: Event<Id = Id> + Send,
+        Id: Send + Sync,
+    {
+        // Fetch the auth events of all the events whose auth chain is not cached yet, level by
+        // level.
+        let mut auth_events: HashMap<Id, Vec<Id>> = HashMap::new();
+        let mut seen = HashSet::new();
+        let mut to_fetch = event_ids
+            .into_iter()
+            .filter(|event_id| !self.chains.contains_key(event_id.borrow()))
+            .filter(|event_id| seen.insert(event_id.clone()))
+            .collect::<Vec<_>>();
+
+        while !to_fetch.is_empty() {
+            debug!("fetching {} events to compute auth chains", to_fetch.len());
+
+            let fetched = stream::iter(to_fetch)
+                .map(|event_id| async move {
+                    let event = fetch_event(event_id.clone()).await;
+                    (event_id, event)
+                })
+                .buffer_unordered(self.max_concurrent_fetches)
+                .collect::<Vec<_>>()
+                .await;
+
+            to_fetch = Vec::new();
+            for (event_id, event) in fetched {
+                let event = event.ok_or_else(|| {
+                    let event_id: &EventId = event_id.borrow();
+                    Error::NotFound(format!("Failed to find {event_id}"))
+                })?;
+                let event_auth_events = event.auth_events().cloned().collect::<Vec<_>>();
+
+                to_fetch.extend(
+                    event_auth_events
+                        .iter()
+                        .filter(|aid| !self.chains.contains_key((*aid).borrow()))
+                        .filter(|aid| seen.insert((*aid).clone()))
+                        .cloned(),
+                );
+                auth_events.insert(event_id, event_auth_events);
+            }
+        }
+
+        // Compute the auth chains, auth events first.
+        let mut in_progress = HashSet::new();
+        for event_id in auth_events.keys() {
+            let mut stack = vec![(event_id, false)];
+
+            while let Some((event_id, auth_events_done)) = stack.pop() {
+                if self.chains.contains_key(event_id.borrow()) {
+                    continue;
+                }
+
+                let event_auth_events = &auth_events[event_id.borrow()];
+
+                if auth_events_done {
+                    let mut chain = HashSet::new();
+                    for aid in event_auth_events {
+                        chain.insert(aid.clone());
+                        chain.extend(self.chains[aid.borrow()].iter().cloned());
+                    }
+
+                    in_progress.remove(event_id);
+                    self.chains.insert(event_id.clone(), Arc::new(chain));
+                } else {
+                    if !in_progress.insert(event_id) {
+                        let event_id: &EventId = event_id.borrow();
+                        return Err(Error::InvalidPdu(format!(
+                            "The auth events of {event_id} form a cycle"
+                        )));
+                    }
+
+                    stack.push((event_id, true));
+                    stack.extend(
+                        event_auth_events
+                            .iter()
+                            .filter(|aid| !self.chains.contains_key((*aid).borrow()))
+                            .map(|aid| (aid, false)),
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<Id> Default for AuthChainCache<Id>
+where
+    Id: Borrow<EventId> + Clone + Eq + Hash,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Get the auth chains of the given state sets, as expected by [`resolve`].
+///
+/// This is a convenience function that uses a new [`AuthChainCache`]. To reuse the auth chains
+/// computed for other state resolutions, use [`AuthChainCache::auth_chain_sets`] instead.
+///
+/// [`resolve`]: crate::resolve
+pub async fn auth_chain_sets<'a, E, Fetch, FetchFut>(
+    state_sets: impl IntoIterator<Item = &'a StateMap<E::Id>>,
+    fetch_event: &Fetch,
+) -> Result<Vec<HashSet<E::Id>>>
+where
+    Fetch: Fn(E::Id) -> FetchFut + Sync,
+    FetchFut: Future<Output = Option<E>> + Send,
+    E: Event + Send,
+    E::Id: Send + Sync + 'a,
+{
+    AuthChainCache::new().auth_chain_sets(state_sets, fetch_event).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use assert_matches2::assert_matches;
+    use futures_util::future::ready;
+    use maplit::hashset;
+    use ruma_common::{OwnedEventId, RoomVersionId};
+    use ruma_events::TimelineEventType;
+    use serde_json::{json, value::to_raw_value as to_raw_json_value};
+
+    use super::{auth_chain_sets, AuthChainCache};
+    use crate::{
+        test_utils::{alice, event_id, to_pdu_event, PduEvent, TestStore, INITIAL_EVENTS},
+        Error,
+    };
+
+    #[tokio::test]
+    async fn memoize_auth_chains() {
+        let events = INITIAL_EVENTS();
+        let fetch_count = AtomicUsize::new(0);
+        let fetcher = |id: OwnedEventId| {
+            fetch_count.fetch_add(1, Ordering::SeqCst);
+            ready(events.get(&id).cloned())
+        };
+
+        let mut cache = AuthChainCache::new().max_concurrent_fetches(2);
+        let chain = cache.auth_chain(event_id("IMB"), &fetcher).await.unwrap();
+        assert_eq!(
+            *chain,
+            hashset![event_id("CREATE"), event_id("IMA"), event_id("IPOWER"), event_id("IJR")]
+        );
+        assert_eq!(fetch_count.load(Ordering::SeqCst), 5);
+        assert_eq!(cache.len(), 5);
+        assert_eq!(
+            *cache.get(&event_id("IPOWER")).unwrap(),
+            hashset![event_id("CREATE"), event_id("IMA")]
+        );
+
+        // Only the new event is fetched.
+        let chain = cache.auth_chain(event_id("IMC"), &fetcher).await.unwrap();
+        assert_eq!(
+            *chain,
+            hashset![event_id("CREATE"), event_id("IMA"), event_id("IPOWER"), event_id("IJR")]
+        );
+        assert_eq!(fetch_count.load(Ordering::SeqCst), 6);
+    }
+
+    #[tokio::test]
+    async fn auth_chain_sets_for_resolve() {
+        let mut store = TestStore::<PduEvent>(HashMap::new());
+        let (state_at_bob, state_at_charlie, expected) = store.set_up();
+        let state_sets = [state_at_bob, state_at_charlie];
+
+        let fetcher = |id: OwnedEventId| ready(store.0.get(&id).cloned());
+        let exists = |id: OwnedEventId| ready(store.0.contains_key(&id));
+
+        let auth_chain_sets = auth_chain_sets(&state_sets, &fetcher).await.unwrap();
+        assert_eq!(
+            auth_chain_sets[0],
+            hashset![event_id("CREATE"), event_id("IMA"), event_id("IJR")]
+        );
+
+        let resolved =
+            crate::resolve(&RoomVersionId::V6, &state_sets, &auth_chain_sets, &fetcher, &exists)
+                .await
+                .unwrap();
+        assert_eq!(resolved, expected);
+    }
+
+    #[tokio::test]
+    async fn missing_auth_event() {
+        let mut events = INITIAL_EVENTS();
+        events.remove(&event_id("IMA"));
+        let fetcher = |id: OwnedEventId| ready(events.get(&id).cloned());
+
+        let mut cache = AuthChainCache::new();
+        let error = cache.auth_chain(event_id("IJR"), &fetcher).await.unwrap_err();
+        assert_matches!(error, Error::NotFound(_));
+    }
+
+    #[tokio::test]
+    async fn auth_events_cycle() {
+        let events = [
+            to_pdu_event(
+                "A",
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+                &["B"],
+                &[],
+            ),
+            to_pdu_event(
+                "B",
+                alice(),
+                TimelineEventType::RoomTopic,
+                Some(""),
+                to_raw_json_value(&json!({})).unwrap(),
+                &["A"],
+                &[],
+            ),
+        ]
+        .into_iter()
+        .map(|ev| (ev.event_id.clone(), ev))
+        .collect::<HashMap<_, _>>();
+        let fetcher = |id: OwnedEventId| ready(events.get(&id).cloned());
+
+        let mut cache = AuthChainCache::new();
+        let error = cache.auth_chain(event_id("A"), &fetcher).await.unwrap_err();
+        assert_matches!(error, Error::InvalidPdu(_));
+    }
+}
@@ -1,3 +1,5 @@
+use js_int::Int;
+use ruma_events::room::member::MembershipState;
 use serde_json::Error as JsonError;
 use thiserror::Error;
 
@@ -24,6 +26,10 @@ pub enum Error {
     #[error("Invalid PDU: {0}")]
     InvalidPdu(String),
 
+    /// The event failed the authorization checks.
+    #[error("Event failed the authorization checks: {0}")]
+    AuthCheck(#[from] AuthCheckError),
+
     /// A custom error.
     #[error("{0}")]
     Custom(Box<dyn std::error::Error + Send>),
@@ -34,3 +40,99 @@ impl Error {
         Self::Custom(Box::new(e))
     }
 }
+
+/// The reason why an event failed the [authorization rules].
+///
+/// [authorization rules]: https://spec.matrix.org/latest/server-server-api/#authorization-rules
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AuthCheckError {
+    /// The `m.room.create` event is invalid.
+    #[error("invalid m.room.create event: {0}")]
+    BadCreateEvent(&'static str),
+
+    /// The `m.room.create` event is missing from the state or from the auth events of the event.
+    #[error("m.room.create event is missing")]
+    MissingCreateEvent,
+
+    /// The room is not federated and the sender's server is not the one of the room creator.
+    #[error("room is not federated and the sender's server is not the creator's server")]
+    NotFederated,
+
+    /// The state key of an `m.room.aliases` event is not the sender's server.
+    #[error("state key of m.room.aliases event does not match the sender's server")]
+    InvalidAliasesStateKey,
+
+    /// The state key of an `m.room.member` event is missing or not a valid user ID.
+    #[error("state key of m.room.member event is missing or invalid")]
+    InvalidMemberStateKey,
+
+    /// The content of an `m.room.member` event doesn't have a valid `membership`.
+    #[error("m.room.member event has no valid membership")]
+    InvalidMembership,
+
+    /// The sender of the event is not joined to the room.
+    #[error("sender is not joined to the room")]
+    SenderNotJoined,
+
+    /// The sender of a membership event cannot change the membership of another user.
+    #[error("sender cannot change the membership of another user")]
+    SenderIsNotTarget,
+
+    /// The target of a membership event is banned from the room.
+    #[error("user is banned from the room")]
+    UserBanned,
+
+    /// The target of an invite is already joined to the room.
+    #[error("user is already joined to the room")]
+    UserAlreadyJoined,
+
+    /// The join rule of the room doesn't allow this membership.
+    #[error("join rule of the room does not allow this membership")]
+    InvalidJoinRule,
+
+    /// The user in `join_authorised_via_users_server` cannot authorize a restricted join.
+    #[error("user authorizing the join is not joined or cannot invite users")]
+    InvalidJoinAuthorisation,
+
+    /// The third-party invite doesn't match an `m.room.third_party_invite` event.
+    #[error("third-party invite is invalid")]
+    InvalidThirdPartyInvite,
+
+    /// The membership cannot change from the current one to the new one.
+    #[error("membership cannot change from {from} to {to}")]
+    InvalidMembershipTransition {
+        /// The current membership of the target.
+        from: MembershipState,
+
+        /// The membership of the event.
+        to: MembershipState,
+    },
+
+    /// The sender's power level is lower than the one required for this event.
+    #[error("sender has power level {actual}, {required} is required")]
+    InsufficientPowerLevel {
+        /// The power level required to send this event.
+        required: Int,
+
+        /// The power level of the sender.
+        actual: Int,
+    },
+
+    /// The sender's power level is not higher than the target's power level.
+    #[error("sender's power level is not higher than the target's")]
+    TargetPowerLevelTooHigh,
+
+    /// The state key starts with `@` and is not the sender's user ID.
+    #[error("state key is a user ID that does not match the sender")]
+    StateKeyNotSender,
+
+    /// The content of an `m.room.power_levels` event is invalid.
+    #[error("m.room.power_levels event is invalid")]
+    InvalidPowerLevels,
+
+    /// The `m.room.power_levels` event changes a power level that the sender is not allowed to
+    /// change.
+    #[error("sender is not allowed to change the power levels this way")]
+    PowerLevelsChangeNotAllowed,
+}
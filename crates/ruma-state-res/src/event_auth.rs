@@ -26,7 +26,7 @@ use crate::{
         deserialize_power_levels_content_invite, deserialize_power_levels_content_redact,
     },
     room_version::RoomVersion,
-    Error, Event, Result, StateEventType, TimelineEventType,
+    AuthCheckError, Error, Event, Result, StateEventType, TimelineEventType,
 };
 
 // FIXME: field extracting could be bundled for `content`
@@ -122,12 +122,16 @@ pub fn auth_types_for_event(
 ///
 /// The `fetch_state` closure should gather state from a state snapshot. We need to know if the
 /// event passes auth against some state not a recursive collection of auth_events fields.
+///
+/// Returns an [`Error::AuthCheck`] with the reason of the rejection if the event fails the checks.
+/// Other errors mean that the checks couldn't be performed, for example because an event has
+/// invalid content.
 pub async fn auth_check<F, Fut, Fetched, Incoming>(
     room_version: &RoomVersion,
     incoming_event: &Incoming,
     current_third_party_invite: Option<&Incoming>,
     fetch_state: F,
-) -> Result<bool>
+) -> Result<()>
 where
     F: Fn(&'static StateEventType, &str) -> Fut,
     Fut: Future<Output = Option<Fetched>> + Send,
@@ -165,37 +169,40 @@ where
         // If it has any previous events, reject
         if incoming_event.prev_events().next().is_some() {
             warn!("the room creation event had previous events");
-            return Ok(false);
+            return Err(AuthCheckError::BadCreateEvent("has previous events").into());
         }
 
         // If the domain of the room_id does not match the domain of the sender, reject
         let Some(room_id_server_name) = incoming_event.room_id().server_name() else {
             warn!("room ID has no servername");
-            return Ok(false);
+            return Err(AuthCheckError::BadCreateEvent("room ID has no server name").into());
         };
 
         if room_id_server_name != sender.server_name() {
             warn!("servername of room ID does not match servername of sender");
-            return Ok(false);
+            return Err(AuthCheckError::BadCreateEvent(
+                "server name of room ID does not match server name of sender",
+            )
+            .into());
         }
 
         // If content.room_version is present and is not a recognized version, reject
         let content: RoomCreateContentFields = from_json_str(incoming_event.content().get())?;
         if content.room_version.map(|v| v.deserialize().is_err()).unwrap_or(false) {
             warn!("invalid room version found in m.room.create event");
-            return Ok(false);
+            return Err(AuthCheckError::BadCreateEvent("invalid room version").into());
         }
 
         if !room_version.use_room_create_sender {
             // If content has no creator field, reject
             if content.creator.is_none() {
                 warn!("no creator field found in m.room.create content");
-                return Ok(false);
+                return Err(AuthCheckError::BadCreateEvent("missing creator field").into());
             }
         }
 
         debug!("m.room.create event was allowed");
-        return Ok(true);
+        return Ok(());
     }
 
     /*
@@ -226,7 +233,7 @@ where
     let room_create_event = match fetch_state(&StateEventType::RoomCreate, "").await {
         None => {
             warn!("no m.room.create event in auth chain");
-            return Ok(false);
+            return Err(AuthCheckError::MissingCreateEvent.into());
         }
         Some(e) => e,
     };
@@ -235,7 +242,7 @@ where
     if !incoming_event.auth_events().any(|id| id.borrow() == room_create_event.event_id().borrow())
     {
         warn!("no m.room.create event in auth events");
-        return Ok(false);
+        return Err(AuthCheckError::MissingCreateEvent.into());
     }
 
     // If the create event content has the field m.federate set to false and the sender domain of
@@ -251,7 +258,7 @@ where
         && room_create_event.sender().server_name() != incoming_event.sender().server_name()
     {
         warn!("room is not federated and event's sender domain does not match create event's sender domain");
-        return Ok(false);
+        return Err(AuthCheckError::NotFederated.into());
     }
 
     // Only in some room versions 6 and below
@@ -263,11 +270,11 @@ where
             // If sender's domain doesn't matches state_key, reject
             if incoming_event.state_key() != Some(sender.server_name().as_str()) {
                 warn!("state_key does not match sender");
-                return Ok(false);
+                return Err(AuthCheckError::InvalidAliasesStateKey.into());
             }
 
             debug!("m.room.aliases event was allowed");
-            return Ok(true);
+            return Ok(());
         }
     }
 
@@ -280,7 +287,7 @@ where
         let state_key = match incoming_event.state_key() {
             None => {
                 warn!("no statekey in member event");
-                return Ok(false);
+                return Err(AuthCheckError::InvalidMemberStateKey.into());
             }
             Some(s) => s,
         };
@@ -288,7 +295,7 @@ where
         let content: RoomMemberContentFields = from_json_str(incoming_event.content().get())?;
         if content.membership.as_ref().and_then(|m| m.deserialize().ok()).is_none() {
             warn!("no valid membership field found for m.room.member event content");
-            return Ok(false);
+            return Err(AuthCheckError::InvalidMembership.into());
         }
 
         let target_user =
@@ -308,7 +315,7 @@ where
             .map(|mem| mem.membership)
             .unwrap_or(MembershipState::Leave);
 
-        if !valid_membership_change(
+        valid_membership_change(
             room_version,
             target_user,
             fetch_state(&StateEventType::RoomMember, target_user.as_str()).await.as_ref(),
@@ -321,12 +328,10 @@ where
             user_for_join_auth.as_deref(),
             &user_for_join_auth_membership,
             room_create_event,
-        )? {
-            return Ok(false);
-        }
+        )?;
 
         debug!("m.room.member event was allowed");
-        return Ok(true);
+        return Ok(());
     }
 
     // If the sender's current membership state is not join, reject
@@ -334,7 +339,7 @@ where
         Some(mem) => mem,
         None => {
             warn!("sender not found in room");
-            return Ok(false);
+            return Err(AuthCheckError::SenderNotJoined.into());
         }
     };
 
@@ -347,7 +352,7 @@ where
 
     if !matches!(membership_state, MembershipState::Join) {
         warn!("sender's membership is not join");
-        return Ok(false);
+        return Err(AuthCheckError::SenderNotJoined.into());
     }
 
     // If type is m.room.third_party_invite
@@ -388,37 +393,38 @@ where
 
         if sender_power_level < invite_level {
             warn!("sender's cannot send invites in this room");
-            return Ok(false);
+            return Err(AuthCheckError::InsufficientPowerLevel {
+                required: invite_level,
+                actual: sender_power_level,
+            }
+            .into());
         }
 
         debug!("m.room.third_party_invite event was allowed");
-        return Ok(true);
+        return Ok(());
     }
 
     // If the event type's required power level is greater than the sender's power level, reject
     // If the event has a state_key that starts with an @ and does not match the sender, reject.
-    if !can_send_event(&incoming_event, power_levels_event.as_ref(), sender_power_level) {
+    if let Err(error) =
+        can_send_event(incoming_event, power_levels_event.as_ref(), sender_power_level)
+    {
         warn!("user cannot send event");
-        return Ok(false);
+        return Err(error.into());
     }
 
     // If type is m.room.power_levels
     if *incoming_event.event_type() == TimelineEventType::RoomPowerLevels {
         debug!("starting m.room.power_levels check");
 
-        if let Some(required_pwr_lvl) = check_power_levels(
+        if let Err(error) = check_power_levels(
             room_version,
             &incoming_event,
             power_levels_event.as_ref(),
             sender_power_level,
         ) {
-            if !required_pwr_lvl {
-                warn!("power level was not allowed");
-                return Ok(false);
-            }
-        } else {
             warn!("power level was not allowed");
-            return Ok(false);
+            return Err(error.into());
         }
         debug!("power levels event allowed");
     }
@@ -441,12 +447,17 @@ where
         };
 
         if !check_redaction(room_version, incoming_event, sender_power_level, redact_level)? {
-            return Ok(false);
+            warn!("user cannot redact event");
+            return Err(AuthCheckError::InsufficientPowerLevel {
+                required: redact_level,
+                actual: sender_power_level,
+            }
+            .into());
         }
     }
 
     debug!("allowing event passed all checks");
-    Ok(true)
+    Ok(())
 }
 
 // TODO deserializing the member, power, join_rules event contents is done in conduit
@@ -472,7 +483,7 @@ fn valid_membership_change(
     user_for_join_auth: Option<&UserId>,
     user_for_join_auth_membership: &MembershipState,
     create_room: impl Event,
-) -> Result<bool> {
+) -> Result<()> {
     #[derive(Deserialize)]
     struct GetThirdPartyInvite {
         third_party_invite: Option<Raw<ThirdPartyInvite>>,
@@ -543,7 +554,9 @@ fn valid_membership_change(
         false
     };
 
-    Ok(match target_membership {
+    let sender_power_level = sender_power.copied().unwrap_or_default();
+
+    let result = match target_membership {
         MembershipState::Join => {
             // 1. If the only previous event is an m.room.create and the state_key is the creator,
             // allow
@@ -571,25 +584,25 @@ fn valid_membership_change(
                 };
 
                 if is_creator {
-                    return Ok(true);
+                    return Ok(());
                 }
             }
 
             if sender != target_user {
                 // If the sender does not match state_key, reject.
                 warn!("Can't make other user join");
-                false
+                Err(AuthCheckError::SenderIsNotTarget)
             } else if let MembershipState::Ban = target_user_current_membership {
                 // If the sender is banned, reject.
                 warn!(?target_user_membership_event_id, "Banned user can't join");
-                false
+                Err(AuthCheckError::UserBanned)
             } else if (join_rules == JoinRule::Invite
                     || room_version.allow_knocking && join_rules == JoinRule::Knock)
                 // If the join_rule is invite then allow if membership state is invite or join
                     && (target_user_current_membership == MembershipState::Join
                         || target_user_current_membership == MembershipState::Invite)
             {
-                true
+                Ok(())
             } else if room_version.restricted_join_rules
                 && matches!(join_rules, JoinRule::Restricted(_))
                 || room_version.knock_restricted_join_rule
@@ -601,17 +614,23 @@ fn valid_membership_change(
                     MembershipState::Invite | MembershipState::Join
                 ) {
                     // If membership state is join or invite, allow.
-                    true
-                } else {
+                    Ok(())
+                } else if user_for_join_auth_is_valid {
                     // If the join_authorised_via_users_server key in content is not a user with
                     // sufficient permission to invite other users, reject.
                     // Otherwise, allow.
-                    user_for_join_auth_is_valid
+                    Ok(())
+                } else {
+                    warn!("Restricted join is not authorised by a user that can invite");
+                    Err(AuthCheckError::InvalidJoinAuthorisation)
                 }
-            } else {
+            } else if join_rules == JoinRule::Public {
                 // If the join_rule is public, allow.
+                Ok(())
+            } else {
                 // Otherwise, reject.
-                join_rules == JoinRule::Public
+                warn!("Join rule does not allow the user to join");
+                Err(AuthCheckError::InvalidJoinRule)
             }
         }
         MembershipState::Invite => {
@@ -619,18 +638,17 @@ fn valid_membership_change(
             if let Some(tp_id) = third_party_invite.and_then(|i| i.deserialize().ok()) {
                 if target_user_current_membership == MembershipState::Ban {
                     warn!(?target_user_membership_event_id, "Can't invite banned user");
-                    false
+                    Err(AuthCheckError::UserBanned)
+                } else if verify_third_party_invite(
+                    Some(target_user),
+                    sender,
+                    &tp_id,
+                    current_third_party_invite,
+                ) {
+                    Ok(())
                 } else {
-                    let allow = verify_third_party_invite(
-                        Some(target_user),
-                        sender,
-                        &tp_id,
-                        current_third_party_invite,
-                    );
-                    if !allow {
-                        warn!("Third party invite invalid");
-                    }
-                    allow
+                    warn!("Third party invite invalid");
+                    Err(AuthCheckError::InvalidThirdPartyInvite)
                 }
             } else if !sender_is_joined
                 || target_user_current_membership == MembershipState::Join
@@ -642,27 +660,40 @@ fn valid_membership_change(
                     "Can't invite user if sender not joined or the user is currently joined or \
                      banned",
                 );
-                false
+                Err(if !sender_is_joined {
+                    AuthCheckError::SenderNotJoined
+                } else if target_user_current_membership == MembershipState::Join {
+                    AuthCheckError::UserAlreadyJoined
+                } else {
+                    AuthCheckError::UserBanned
+                })
+            } else if sender_power.filter(|&p| p >= &power_levels.invite).is_some() {
+                Ok(())
             } else {
-                let allow = sender_power.filter(|&p| p >= &power_levels.invite).is_some();
-                if !allow {
-                    warn!(
-                        ?target_user_membership_event_id,
-                        ?power_levels_event_id,
-                        "User does not have enough power to invite",
-                    );
-                }
-                allow
+                warn!(
+                    ?target_user_membership_event_id,
+                    ?power_levels_event_id,
+                    "User does not have enough power to invite",
+                );
+                Err(AuthCheckError::InsufficientPowerLevel {
+                    required: power_levels.invite,
+                    actual: sender_power_level,
+                })
             }
         }
         MembershipState::Leave => {
             if sender == target_user {
-                let allow = target_user_current_membership == MembershipState::Join
-                    || target_user_current_membership == MembershipState::Invite;
-                if !allow {
+                if target_user_current_membership == MembershipState::Join
+                    || target_user_current_membership == MembershipState::Invite
+                {
+                    Ok(())
+                } else {
                     warn!(?target_user_membership_event_id, "Can't leave if not invited or joined");
+                    Err(AuthCheckError::InvalidMembershipTransition {
+                        from: target_user_current_membership,
+                        to: MembershipState::Leave,
+                    })
                 }
-                allow
             } else if !sender_is_joined
                 || target_user_current_membership == MembershipState::Ban
                     && sender_power.filter(|&p| p < &power_levels.ban).is_some()
@@ -672,35 +703,58 @@ fn valid_membership_change(
                     ?sender_membership_event_id,
                     "Can't kick if sender not joined or user is already banned",
                 );
-                false
+                Err(if !sender_is_joined {
+                    AuthCheckError::SenderNotJoined
+                } else {
+                    AuthCheckError::InsufficientPowerLevel {
+                        required: power_levels.ban,
+                        actual: sender_power_level,
+                    }
+                })
+            } else if sender_power.filter(|&p| p >= &power_levels.kick).is_none() {
+                warn!(
+                    ?target_user_membership_event_id,
+                    ?power_levels_event_id,
+                    "User does not have enough power to kick",
+                );
+                Err(AuthCheckError::InsufficientPowerLevel {
+                    required: power_levels.kick,
+                    actual: sender_power_level,
+                })
+            } else if target_power >= sender_power {
+                warn!(
+                    ?target_user_membership_event_id,
+                    ?power_levels_event_id,
+                    "User does not have enough power to kick",
+                );
+                Err(AuthCheckError::TargetPowerLevelTooHigh)
             } else {
-                let allow = sender_power.filter(|&p| p >= &power_levels.kick).is_some()
-                    && target_power < sender_power;
-                if !allow {
-                    warn!(
-                        ?target_user_membership_event_id,
-                        ?power_levels_event_id,
-                        "User does not have enough power to kick",
-                    );
-                }
-                allow
+                Ok(())
             }
         }
         MembershipState::Ban => {
             if !sender_is_joined {
                 warn!(?sender_membership_event_id, "Can't ban user if sender is not joined");
-                false
+                Err(AuthCheckError::SenderNotJoined)
+            } else if sender_power.filter(|&p| p >= &power_levels.ban).is_none() {
+                warn!(
+                    ?target_user_membership_event_id,
+                    ?power_levels_event_id,
+                    "User does not have enough power to ban",
+                );
+                Err(AuthCheckError::InsufficientPowerLevel {
+                    required: power_levels.ban,
+                    actual: sender_power_level,
+                })
+            } else if target_power >= sender_power {
+                warn!(
+                    ?target_user_membership_event_id,
+                    ?power_levels_event_id,
+                    "User does not have enough power to ban",
+                );
+                Err(AuthCheckError::TargetPowerLevelTooHigh)
             } else {
-                let allow = sender_power.filter(|&p| p >= &power_levels.ban).is_some()
-                    && target_power < sender_power;
-                if !allow {
-                    warn!(
-                        ?target_user_membership_event_id,
-                        ?power_levels_event_id,
-                        "User does not have enough power to ban",
-                    );
-                }
-                allow
+                Ok(())
             }
         }
         MembershipState::Knock if room_version.allow_knocking => {
@@ -710,7 +764,7 @@ fn valid_membership_change(
                     && matches!(join_rules, JoinRule::KnockRestricted(_))
             {
                 warn!("Join rule is not set to knock or knock_restricted, knocking is not allowed");
-                false
+                Err(AuthCheckError::InvalidJoinRule)
             } else if sender != target_user {
                 // 2. If `sender` does not match `state_key`, reject.
                 warn!(
@@ -718,7 +772,7 @@ fn valid_membership_change(
                     ?target_user,
                     "Can't make another user knock, sender did not match target"
                 );
-                false
+                Err(AuthCheckError::SenderIsNotTarget)
             } else if matches!(
                 sender_membership,
                 MembershipState::Ban | MembershipState::Invite | MembershipState::Join
@@ -729,37 +783,50 @@ fn valid_membership_change(
                     ?target_user_membership_event_id,
                     "Membership state of ban, invite or join are invalid",
                 );
-                false
+                Err(AuthCheckError::InvalidMembershipTransition {
+                    from: sender_membership,
+                    to: MembershipState::Knock,
+                })
             } else {
-                true
+                Ok(())
             }
         }
         _ => {
             warn!("Unknown membership transition");
-            false
+            Err(AuthCheckError::InvalidMembership)
         }
-    })
+    };
+
+    result.map_err(Into::into)
 }
 
 /// Is the user allowed to send a specific event based on the rooms power levels.
 ///
 /// Does the event have the correct userId as its state_key if it's not the "" state_key.
-fn can_send_event(event: impl Event, ple: Option<impl Event>, user_level: Int) -> bool {
+fn can_send_event(
+    event: impl Event,
+    ple: Option<impl Event>,
+    user_level: Int,
+) -> std::result::Result<(), AuthCheckError> {
     let event_type_power_level = get_send_level(event.event_type(), event.state_key(), ple);
 
     debug!("{} ev_type {event_type_power_level} usr {user_level}", event.event_id());
 
     if user_level < event_type_power_level {
-        return false;
+        return Err(AuthCheckError::InsufficientPowerLevel {
+            required: event_type_power_level,
+            actual: user_level,
+        });
     }
 
     if event.state_key().is_some_and(|k| k.starts_with('@'))
         && event.state_key() != Some(event.sender().as_str())
     {
-        return false; // permission required to post in this room
+        // permission required to post in this room
+        return Err(AuthCheckError::StateKeyNotSender);
     }
 
-    true
+    Ok(())
 }
 
 /// Confirm that the event sender has the required power levels.
@@ -768,16 +835,16 @@ fn check_power_levels(
     power_event: impl Event,
     previous_power_event: Option<impl Event>,
     user_level: Int,
-) -> Option<bool> {
+) -> std::result::Result<(), AuthCheckError> {
     match power_event.state_key() {
         Some("") => {}
         Some(key) => {
             error!("m.room.power_levels event has non-empty state key: {key}");
-            return None;
+            return Err(AuthCheckError::InvalidPowerLevels);
         }
         None => {
             error!("check_power_levels requires an m.room.power_levels *state* event argument");
-            return None;
+            return Err(AuthCheckError::InvalidPowerLevels);
         }
     }
 
@@ -788,7 +855,8 @@ fn check_power_levels(
     // - If users key in content is not a dictionary with keys that are valid user IDs with values
     //   that are integers, reject.
     let user_content: RoomPowerLevelsEventContent =
-        deserialize_power_levels(power_event.content().get(), room_version)?;
+        deserialize_power_levels(power_event.content().get(), room_version)
+            .ok_or(AuthCheckError::InvalidPowerLevels)?;
 
     // Validation of users is done in Ruma, synapse for loops validating user_ids and integers here
     debug!("validation of power event finished");
@@ -796,11 +864,12 @@ fn check_power_levels(
     let current_state = match previous_power_event {
         Some(current_state) => current_state,
         // If there is no previous m.room.power_levels event in the room, allow
-        None => return Some(true),
+        None => return Ok(()),
     };
 
     let current_content: RoomPowerLevelsEventContent =
-        deserialize_power_levels(current_state.content().get(), room_version)?;
+        deserialize_power_levels(current_state.content().get(), room_version)
+            .ok_or(AuthCheckError::InvalidPowerLevels)?;
 
     let mut user_levels_to_check = BTreeSet::new();
     let old_list = &current_content.users;
@@ -838,7 +907,8 @@ fn check_power_levels(
         // If the current value is equal to the sender's current power level, reject
         if user != power_event.sender() && old_level == Some(&user_level) {
             warn!("m.room.power_level cannot remove ops == to own");
-            return Some(false); // cannot remove ops level == to own
+            // cannot remove ops level == to own
+            return Err(AuthCheckError::PowerLevelsChangeNotAllowed);
         }
 
         // If the current value is higher than the sender's current power level, reject
@@ -847,7 +917,8 @@ fn check_power_levels(
         let new_level_too_big = new_level > Some(&user_level);
         if old_level_too_big || new_level_too_big {
             warn!("m.room.power_level failed to add ops > than own");
-            return Some(false); // cannot add ops greater than own
+            // cannot add ops greater than own
+            return Err(AuthCheckError::PowerLevelsChangeNotAllowed);
         }
     }
 
@@ -865,7 +936,8 @@ fn check_power_levels(
         let new_level_too_big = new_level > Some(&user_level);
         if old_level_too_big || new_level_too_big {
             warn!("m.room.power_level failed to add ops > than own");
-            return Some(false); // cannot add ops greater than own
+            // cannot add ops greater than own
+            return Err(AuthCheckError::PowerLevelsChangeNotAllowed);
         }
     }
 
@@ -880,7 +952,8 @@ fn check_power_levels(
             let new_level_too_big = new_level > user_level;
             if old_level_too_big || new_level_too_big {
                 warn!("m.room.power_level failed to add ops > than own");
-                return Some(false); // cannot add ops greater than own
+                // cannot add ops greater than own
+                return Err(AuthCheckError::PowerLevelsChangeNotAllowed);
             }
         }
     }
@@ -896,12 +969,12 @@ fn check_power_levels(
 
             if old_level_too_big || new_level_too_big {
                 warn!("cannot add ops > than own");
-                return Some(false);
+                return Err(AuthCheckError::PowerLevelsChangeNotAllowed);
             }
         }
     }
 
-    Some(true)
+    Ok(())
 }
 
 fn get_deserialize_levels(
@@ -1021,6 +1094,12 @@ fn verify_third_party_invite(
 mod tests {
     use std::sync::Arc;
 
+    use assert_matches2::assert_matches;
+    use js_int::int;
+    use ruma_common::{
+        room_version_rules::{AuthorizationRules, RoomVersionRules, ScopedCustomRoomVersion},
+        RoomVersionId,
+    };
     use ruma_events::{
         room::{
             join_rules::{
@@ -1038,7 +1117,7 @@ mod tests {
             alice, charlie, ella, event_id, member_content_ban, member_content_join, room_id,
             to_pdu_event, PduEvent, INITIAL_EVENTS, INITIAL_EVENTS_CREATE_ROOM,
         },
-        Event, EventTypeExt, RoomVersion, StateMap,
+        AuthCheckError, Error, Event, EventTypeExt, RoomVersion, StateMap,
     };
 
     #[test]
@@ -1066,7 +1145,7 @@ mod tests {
         let target_user = charlie();
         let sender = alice();
 
-        assert!(valid_membership_change(
+        valid_membership_change(
             &RoomVersion::V6,
             target_user,
             fetch_state(StateEventType::RoomMember, target_user.to_string()),
@@ -1080,7 +1159,7 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap();
     }
 
     #[test]
@@ -1108,21 +1187,23 @@ mod tests {
         let target_user = charlie();
         let sender = charlie();
 
-        assert!(!valid_membership_change(
-            &RoomVersion::V6,
-            target_user,
-            fetch_state(StateEventType::RoomMember, target_user.to_string()),
-            sender,
-            fetch_state(StateEventType::RoomMember, sender.to_string()),
-            &requester,
-            None::<PduEvent>,
-            fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
-            fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
-            None,
-            &MembershipState::Leave,
-            fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
-        )
-        .unwrap());
+        assert_matches!(
+            valid_membership_change(
+                &RoomVersion::V6,
+                target_user,
+                fetch_state(StateEventType::RoomMember, target_user.to_string()),
+                sender,
+                fetch_state(StateEventType::RoomMember, sender.to_string()),
+                &requester,
+                None::<PduEvent>,
+                fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+                fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+                None,
+                &MembershipState::Leave,
+                fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+            ),
+            Err(Error::AuthCheck(AuthCheckError::InvalidJoinRule))
+        );
     }
 
     #[test]
@@ -1150,7 +1231,7 @@ mod tests {
         let target_user = alice();
         let sender = alice();
 
-        assert!(valid_membership_change(
+        valid_membership_change(
             &RoomVersion::V6,
             target_user,
             fetch_state(StateEventType::RoomMember, target_user.to_string()),
@@ -1164,7 +1245,7 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap();
     }
 
     #[test]
@@ -1192,21 +1273,25 @@ mod tests {
         let target_user = alice();
         let sender = charlie();
 
-        assert!(!valid_membership_change(
-            &RoomVersion::V6,
-            target_user,
-            fetch_state(StateEventType::RoomMember, target_user.to_string()),
-            sender,
-            fetch_state(StateEventType::RoomMember, sender.to_string()),
-            &requester,
-            None::<PduEvent>,
-            fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
-            fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
-            None,
-            &MembershipState::Leave,
-            fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
-        )
-        .unwrap());
+        assert_matches!(
+            valid_membership_change(
+                &RoomVersion::V6,
+                target_user,
+                fetch_state(StateEventType::RoomMember, target_user.to_string()),
+                sender,
+                fetch_state(StateEventType::RoomMember, sender.to_string()),
+                &requester,
+                None::<PduEvent>,
+                fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+                fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+                None,
+                &MembershipState::Leave,
+                fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+            ),
+            Err(Error::AuthCheck(AuthCheckError::InsufficientPowerLevel { required, actual }))
+        );
+        assert_eq!(required, int!(50));
+        assert_eq!(actual, int!(0));
     }
 
     #[test]
@@ -1251,7 +1336,7 @@ mod tests {
         let target_user = ella();
         let sender = ella();
 
-        assert!(valid_membership_change(
+        valid_membership_change(
             &RoomVersion::V9,
             target_user,
             fetch_state(StateEventType::RoomMember, target_user.to_string()),
@@ -1265,23 +1350,25 @@ mod tests {
             &MembershipState::Join,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
-
-        assert!(!valid_membership_change(
-            &RoomVersion::V9,
-            target_user,
-            fetch_state(StateEventType::RoomMember, target_user.to_string()),
-            sender,
-            fetch_state(StateEventType::RoomMember, sender.to_string()),
-            &requester,
-            None::<PduEvent>,
-            fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
-            fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
-            Some(ella()),
-            &MembershipState::Leave,
-            fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
-        )
-        .unwrap());
+        .unwrap();
+
+        assert_matches!(
+            valid_membership_change(
+                &RoomVersion::V9,
+                target_user,
+                fetch_state(StateEventType::RoomMember, target_user.to_string()),
+                sender,
+                fetch_state(StateEventType::RoomMember, sender.to_string()),
+                &requester,
+                None::<PduEvent>,
+                fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+                fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+                Some(ella()),
+                &MembershipState::Leave,
+                fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+            ),
+            Err(Error::AuthCheck(AuthCheckError::InvalidJoinAuthorisation))
+        );
     }
 
     #[test]
@@ -1318,7 +1405,7 @@ mod tests {
         let target_user = ella();
         let sender = ella();
 
-        assert!(valid_membership_change(
+        valid_membership_change(
             &RoomVersion::V7,
             target_user,
             fetch_state(StateEventType::RoomMember, target_user.to_string()),
@@ -1332,6 +1419,73 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap();
+    }
+
+    #[test]
+    fn test_knock_custom_room_version() {
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let mut events = INITIAL_EVENTS();
+        *events.get_mut(&event_id("IJR")).unwrap() = to_pdu_event(
+            "IJR",
+            alice(),
+            TimelineEventType::RoomJoinRules,
+            Some(""),
+            to_raw_json_value(&RoomJoinRulesEventContent::new(JoinRule::Knock)).unwrap(),
+            &["CREATE", "IMA", "IPOWER"],
+            &["IPOWER"],
+        );
+
+        let auth_events = events
+            .values()
+            .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), Arc::clone(ev)))
+            .collect::<StateMap<_>>();
+
+        let requester = to_pdu_event(
+            "HELLO",
+            ella(),
+            TimelineEventType::RoomMember,
+            Some(ella().as_str()),
+            to_raw_json_value(&RoomMemberEventContent::new(MembershipState::Knock)).unwrap(),
+            &[],
+            &["IMC"],
+        );
+
+        let fetch_state = |ty, key| auth_events.get(&(ty, key)).cloned();
+        let target_user = ella();
+        let sender = ella();
+
+        // A custom room version without knocking.
+        let custom_room_version = RoomVersionId::try_from("org.example.no-knocking").unwrap();
+        let _guard = ScopedCustomRoomVersion::register(
+            custom_room_version.clone(),
+            RoomVersionRules {
+                authorization: AuthorizationRules {
+                    allow_knocking: false,
+                    ..AuthorizationRules::V7
+                },
+                ..RoomVersionRules::V7
+            },
+        )
+        .unwrap();
+
+        assert_matches!(
+            valid_membership_change(
+                &RoomVersion::new(&custom_room_version).unwrap(),
+                target_user,
+                fetch_state(StateEventType::RoomMember, target_user.to_string()),
+                sender,
+                fetch_state(StateEventType::RoomMember, sender.to_string()),
+                &requester,
+                None::<PduEvent>,
+                fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+                fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+                None,
+                &MembershipState::Leave,
+                fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+            ),
+            Err(Error::AuthCheck(AuthCheckError::InvalidMembership))
+        );
     }
 }
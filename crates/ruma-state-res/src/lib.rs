@@ -16,6 +16,7 @@ use ruma_events::{
 use serde_json::from_str as from_json_str;
 use tracing::{debug, trace, warn};
 
+pub mod auth_chain;
 mod error;
 pub mod event_auth;
 mod power_levels;
@@ -23,20 +24,26 @@ pub mod room_version;
 mod state_event;
 #[cfg(test)]
 mod test_utils;
+mod trace;
+mod v1;
 
-pub use error::{Error, Result};
+pub use error::{AuthCheckError, Error, Result};
 pub use event_auth::{auth_check, auth_types_for_event};
 use power_levels::PowerLevelsContentFields;
 pub use room_version::RoomVersion;
+use room_version::StateResolutionVersion;
 pub use state_event::Event;
+pub use trace::{RejectedEvent, ResolutionTrace};
 
 /// A mapping of event type and state_key to some value `T`, usually an `EventId`.
 pub type StateMap<T> = HashMap<(StateEventType, String), T>;
 
 /// Resolve sets of state events as they come in.
 ///
-/// Internally `StateResolution` builds a graph and an auth chain to allow for state conflict
-/// resolution.
+/// The state resolution algorithm is chosen according to the room version. For rooms using
+/// [`StateResolutionVersion::V2`], internally `StateResolution` builds a graph and an auth chain to
+/// allow for state conflict resolution. For rooms using [`StateResolutionVersion::V1`], the
+/// conflicted events are ordered by depth and authorized against the unconflicted state.
 ///
 /// ## Arguments
 ///
@@ -44,11 +51,16 @@ pub type StateMap<T> = HashMap<(StateEventType, String), T>;
 ///   the state of a room.
 ///
 /// * `auth_chain_sets` - The full recursive set of `auth_events` for each event in the
-///   `state_sets`.
+///   `state_sets`. It can be computed with the [`auth_chain`] module. This is not used by the room
+///   version 1 algorithm.
 ///
 /// * `fetch_event` - Any event not found in the `event_map` will defer to this closure to find the
 ///   event.
 ///
+/// * `event_exists` - Whether an event is available. Events of the auth chain difference that do
+///   not exist are ignored. This is not used by the room version 1 algorithm, which ignores the
+///   conflicted events that cannot be fetched instead.
+///
 /// ## Invariants
 ///
 /// The caller of `resolve` must ensure that all the events are from the same room. Although this
@@ -60,6 +72,62 @@ pub async fn resolve<'a, E, SetIter, Fetch, FetchFut, Exists, ExistsFut>(
     event_fetch: &Fetch,
     event_exists: &Exists,
 ) -> Result<StateMap<E::Id>>
+where
+    Fetch: Fn(E::Id) -> FetchFut + Sync,
+    FetchFut: Future<Output = Option<E>> + Send,
+    Exists: Fn(E::Id) -> ExistsFut,
+    ExistsFut: Future<Output = bool> + Send,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone + Send,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Send + Sync,
+    for<'b> &'b E: Send,
+{
+    resolve_inner(room_version, state_sets, auth_chain_sets, event_fetch, event_exists, None).await
+}
+
+/// Resolve sets of state events as they come in, and explain how the state was resolved.
+///
+/// This is the same as [`resolve`], but it also returns a [`ResolutionTrace`] with the conflicted
+/// state, the events that were sorted and the events that were rejected during the resolution.
+pub async fn resolve_with_trace<'a, E, SetIter, Fetch, FetchFut, Exists, ExistsFut>(
+    room_version: &RoomVersionId,
+    state_sets: impl IntoIterator<IntoIter = SetIter> + Send,
+    auth_chain_sets: &'a Vec<HashSet<E::Id>>,
+    event_fetch: &Fetch,
+    event_exists: &Exists,
+) -> Result<(StateMap<E::Id>, ResolutionTrace<E::Id>)>
+where
+    Fetch: Fn(E::Id) -> FetchFut + Sync,
+    FetchFut: Future<Output = Option<E>> + Send,
+    Exists: Fn(E::Id) -> ExistsFut,
+    ExistsFut: Future<Output = bool> + Send,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone + Send,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Send + Sync,
+    for<'b> &'b E: Send,
+{
+    let mut trace = ResolutionTrace::default();
+    let resolved_state = resolve_inner(
+        room_version,
+        state_sets,
+        auth_chain_sets,
+        event_fetch,
+        event_exists,
+        Some(&mut trace),
+    )
+    .await?;
+
+    Ok((resolved_state, trace))
+}
+
+async fn resolve_inner<'a, E, SetIter, Fetch, FetchFut, Exists, ExistsFut>(
+    room_version: &RoomVersionId,
+    state_sets: impl IntoIterator<IntoIter = SetIter> + Send,
+    auth_chain_sets: &'a Vec<HashSet<E::Id>>,
+    event_fetch: &Fetch,
+    event_exists: &Exists,
+    mut trace: Option<&mut ResolutionTrace<E::Id>>,
+) -> Result<StateMap<E::Id>>
 where
     Fetch: Fn(E::Id) -> FetchFut + Sync,
     FetchFut: Future<Output = Option<E>> + Send,
@@ -72,6 +140,8 @@ where
 {
     debug!("State resolution starting");
 
+    let room_version = RoomVersion::new(room_version)?;
+
     // Split non-conflicting and conflicting state
     let (clean, conflicting) = separate(state_sets.into_iter());
 
@@ -86,8 +156,20 @@ where
     debug!("conflicting events: {}", conflicting.len());
     debug!("{conflicting:?}");
 
+    if let Some(trace) = trace.as_deref_mut() {
+        trace.conflicted_state.clone_from(&conflicting);
+    }
+
+    if matches!(room_version.state_res, StateResolutionVersion::V1) {
+        return v1::resolve(&room_version, clean, conflicting, event_fetch, trace).await;
+    }
+
+    if let Some(trace) = trace.as_deref_mut() {
+        trace.auth_chain_difference = get_auth_chain_diff(auth_chain_sets).collect();
+    }
+
     let auth_chain_diff =
-        get_auth_chain_diff(&auth_chain_sets).chain(conflicting.into_values().flatten());
+        get_auth_chain_diff(auth_chain_sets).chain(conflicting.into_values().flatten());
 
     // `all_conflicted` contains unique items
     // synapse says `full_set = {eid for eid in full_conflicted_set if eid in event_map}`
@@ -100,6 +182,10 @@ where
     debug!("full conflicted set: {}", all_conflicted.len());
     debug!("{all_conflicted:?}");
 
+    if let Some(trace) = trace.as_deref_mut() {
+        trace.full_conflicted_set.clone_from(&all_conflicted);
+    }
+
     // We used to check that all events are events from the correct room
     // this is now a check the caller of `resolve` must make.
 
@@ -117,11 +203,19 @@ where
     debug!("sorted control events: {}", sorted_control_levels.len());
     trace!("{sorted_control_levels:?}");
 
-    let room_version = RoomVersion::new(room_version)?;
+    if let Some(trace) = trace.as_deref_mut() {
+        trace.sorted_control_events.clone_from(&sorted_control_levels);
+    }
+
     // Sequentially auth check each control event.
-    let resolved_control =
-        iterative_auth_check(&room_version, &sorted_control_levels, clean.clone(), &event_fetch)
-            .await?;
+    let resolved_control = iterative_auth_check(
+        &room_version,
+        &sorted_control_levels,
+        clean.clone(),
+        &event_fetch,
+        trace.as_deref_mut(),
+    )
+    .await?;
 
     debug!("resolved control events: {}", resolved_control.len());
     trace!("{resolved_control:?}");
@@ -146,16 +240,25 @@ where
 
     debug!("power event: {power_event:?}");
 
+    if let Some(trace) = trace.as_deref_mut() {
+        trace.mainline = get_mainline(power_event.cloned(), &event_fetch).await?;
+    }
+
     let sorted_left_events =
         mainline_sort(&events_to_resolve, power_event.cloned(), &event_fetch).await?;
 
     trace!("events left, sorted: {sorted_left_events:?}");
 
+    if let Some(trace) = trace.as_deref_mut() {
+        trace.sorted_other_events.clone_from(&sorted_left_events);
+    }
+
     let mut resolved_state = iterative_auth_check(
         &room_version,
         &sorted_left_events,
         resolved_control, // The control events are added to the final resolved state
         &event_fetch,
+        trace,
     )
     .await?;
 
@@ -414,6 +517,7 @@ async fn iterative_auth_check<E, F, Fut>(
     events_to_check: &[E::Id],
     unconflicted_state: StateMap<E::Id>,
     fetch_event: &F,
+    mut trace: Option<&mut ResolutionTrace<E::Id>>,
 ) -> Result<StateMap<E::Id>>
 where
     F: Fn(E::Id) -> Fut,
@@ -478,12 +582,29 @@ where
             future::ready(auth_events.get(&ty.with_state_key(key)))
         };
 
-        if auth_check(room_version, &event, current_third_party, fetch_state).await? {
-            // add event to resolved state map
-            resolved_state.insert(event.event_type().with_state_key(state_key), event_id.clone());
-        } else {
-            // synapse passes here on AuthError. We do not add this event to resolved_state.
-            warn!("event {event_id} failed the authentication check");
+        match auth_check(room_version, &event, current_third_party, fetch_state).await {
+            Ok(()) => {
+                // add event to resolved state map
+                resolved_state
+                    .insert(event.event_type().with_state_key(state_key), event_id.clone());
+            }
+            Err(Error::AuthCheck(error)) => {
+                // synapse passes here on AuthError. We do not add this event to resolved_state.
+                warn!("event {event_id} failed the authentication check: {error}");
+
+                if let Some(trace) = trace.as_deref_mut() {
+                    let auth_events = auth_events
+                        .iter()
+                        .map(|(key, ev)| (key.clone(), ev.event_id().clone()))
+                        .collect();
+                    trace.rejected_events.push(RejectedEvent::new(
+                        event_id.clone(),
+                        auth_events,
+                        error,
+                    ));
+                }
+            }
+            Err(error) => return Err(error),
         }
 
         // TODO: if these functions are ever made async here
@@ -518,28 +639,7 @@ where
         return Ok(vec![]);
     }
 
-    let mut mainline = vec![];
-    let mut pl = resolved_power_level;
-    while let Some(p) = pl {
-        mainline.push(p.clone());
-
-        let event = fetch_event(p.clone())
-            .await
-            .ok_or_else(|| Error::NotFound(format!("Failed to find {p}")))?;
-        pl = None;
-        for aid in event.auth_events() {
-            let ev = fetch_event(aid.clone())
-                .await
-                .ok_or_else(|| Error::NotFound(format!("Failed to find {aid}")))?;
-            if is_type_and_key(&ev, &TimelineEventType::RoomPowerLevels, "") {
-                pl = Some(aid.to_owned());
-                break;
-            }
-        }
-        // TODO: if these functions are ever made async here
-        // is a good place to yield every once in a while so other
-        // tasks can make progress
-    }
+    let mainline = get_mainline(resolved_power_level, fetch_event).await?;
 
     let mainline_map = mainline
         .iter()
@@ -576,6 +676,46 @@ where
     Ok(sort_event_ids)
 }
 
+/// Get the mainline of the given power level event.
+///
+/// The mainline starts with the given event, followed by the power level event in its auth events,
+/// recursively.
+async fn get_mainline<E, F, Fut>(
+    resolved_power_level: Option<E::Id>,
+    fetch_event: &F,
+) -> Result<Vec<E::Id>>
+where
+    F: Fn(E::Id) -> Fut,
+    Fut: Future<Output = Option<E>> + Send,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Clone + Send,
+{
+    let mut mainline = vec![];
+    let mut pl = resolved_power_level;
+    while let Some(p) = pl {
+        mainline.push(p.clone());
+
+        let event = fetch_event(p.clone())
+            .await
+            .ok_or_else(|| Error::NotFound(format!("Failed to find {p}")))?;
+        pl = None;
+        for aid in event.auth_events() {
+            let ev = fetch_event(aid.clone())
+                .await
+                .ok_or_else(|| Error::NotFound(format!("Failed to find {aid}")))?;
+            if is_type_and_key(&ev, &TimelineEventType::RoomPowerLevels, "") {
+                pl = Some(aid.to_owned());
+                break;
+            }
+        }
+        // TODO: if these functions are ever made async here
+        // is a good place to yield every once in a while so other
+        // tasks can make progress
+    }
+
+    Ok(mainline)
+}
+
 /// Get the mainline depth from the `mainline_map` or finds a power_level event that has an
 /// associated mainline depth.
 async fn get_mainline_depth<E, F, Fut>(
@@ -727,7 +867,7 @@ mod tests {
             alice, bob, charlie, do_check, ella, event_id, member_content_ban, member_content_join,
             room_id, to_init_pdu_event, to_pdu_event, zara, PduEvent, TestStore, INITIAL_EVENTS,
         },
-        Event, EventTypeExt, StateMap,
+        AuthCheckError, Event, EventTypeExt, StateMap,
     };
 
     async fn test_event_sort() {
@@ -761,6 +901,7 @@ mod tests {
             &sorted_power_events,
             HashMap::new(), // unconflicted events
             &fetcher,
+            None,
         )
         .await
         .expect("iterative auth check failed on resolved events");
@@ -1257,6 +1398,89 @@ mod tests {
         assert_eq!(expected.len(), resolved.len());
     }
 
+    #[tokio::test]
+    async fn ban_with_auth_chains2_trace() {
+        use futures_util::future::ready;
+
+        let _ =
+            tracing::subscriber::set_default(tracing_subscriber::fmt().with_test_writer().finish());
+        let init = INITIAL_EVENTS();
+        let ban = BAN_STATE_SET();
+
+        let mut inner = init.clone();
+        inner.extend(ban);
+        let store = TestStore(inner.clone());
+
+        let state_set_a = [
+            inner.get(&event_id("CREATE")).unwrap(),
+            inner.get(&event_id("IJR")).unwrap(),
+            inner.get(&event_id("IMA")).unwrap(),
+            inner.get(&event_id("IMB")).unwrap(),
+            inner.get(&event_id("IMC")).unwrap(),
+            inner.get(&event_id("MB")).unwrap(),
+            inner.get(&event_id("PA")).unwrap(),
+        ]
+        .iter()
+        .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone()))
+        .collect::<StateMap<_>>();
+
+        let state_set_b = [
+            inner.get(&event_id("CREATE")).unwrap(),
+            inner.get(&event_id("IJR")).unwrap(),
+            inner.get(&event_id("IMA")).unwrap(),
+            inner.get(&event_id("IMB")).unwrap(),
+            inner.get(&event_id("IMC")).unwrap(),
+            inner.get(&event_id("IME")).unwrap(),
+            inner.get(&event_id("PA")).unwrap(),
+        ]
+        .iter()
+        .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone()))
+        .collect::<StateMap<_>>();
+
+        let ev_map = &store.0;
+        let state_sets = [state_set_a, state_set_b];
+        let auth_chain = state_sets
+            .iter()
+            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
+            .collect();
+
+        let fetcher = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).cloned());
+        let exists = |id: <PduEvent as Event>::Id| ready(ev_map.get(&id).is_some());
+        let (resolved, trace) = match crate::resolve_with_trace(
+            &RoomVersionId::V6,
+            &state_sets,
+            &auth_chain,
+            &fetcher,
+            &exists,
+        )
+        .await
+        {
+            Ok(res) => res,
+            Err(e) => panic!("{e}"),
+        };
+
+        let ella_key = (StateEventType::RoomMember, ella().to_string());
+        assert_eq!(trace.conflicted_state.len(), 1);
+        assert_eq!(
+            trace.conflicted_state[&ella_key].iter().cloned().collect::<HashSet<_>>(),
+            hashset![event_id("MB"), event_id("IME")]
+        );
+        assert!(trace.full_conflicted_set.contains(&event_id("MB")));
+        assert!(trace.full_conflicted_set.contains(&event_id("IME")));
+        assert_eq!(trace.sorted_control_events, [event_id("PB"), event_id("MB")]);
+        assert_eq!(trace.mainline, [event_id("PB"), event_id("IPOWER")]);
+        assert_eq!(trace.sorted_other_events, [event_id("IME")]);
+        // Ella was banned so she cannot join.
+        assert_eq!(trace.rejected_events.len(), 1);
+        let rejected = &trace.rejected_events[0];
+        assert_eq!(rejected.event_id, event_id("IME"));
+        assert_eq!(rejected.auth_events[&ella_key], event_id("MB"));
+        assert_eq!(rejected.error, AuthCheckError::UserBanned);
+
+        // The trace does not change the result of the resolution.
+        assert_eq!(resolved[&ella_key], event_id("MB"));
+    }
+
     #[tokio::test]
     async fn join_rule_with_auth_chain() {
         let join_rule = JOIN_RULE();
@@ -1,4 +1,10 @@
-use ruma_common::RoomVersionId;
+use ruma_common::{
+    room_version_rules::{
+        EventIdFormatVersion, RoomVersionDisposition, RoomVersionRules,
+        StateResolutionVersion as StateResolutionRules,
+    },
+    RoomVersionId,
+};
 
 use crate::{Error, Result};
 
@@ -125,6 +131,10 @@ impl RoomVersion {
 
     pub const V11: Self = Self { use_room_create_sender: true, ..Self::V10 };
 
+    /// The rules of the given room version.
+    ///
+    /// Custom room versions are supported if their rules were registered with
+    /// [`RoomVersionRules::register_custom()`].
     pub fn new(version: &RoomVersionId) -> Result<Self> {
         Ok(match version {
             RoomVersionId::V1 => Self::V1,
@@ -138,7 +148,57 @@ impl RoomVersion {
             RoomVersionId::V9 => Self::V9,
             RoomVersionId::V10 => Self::V10,
             RoomVersionId::V11 => Self::V11,
-            ver => return Err(Error::Unsupported(format!("found version `{ver}`"))),
+            ver => match ver.rules() {
+                Some(rules) => Self::from_rules(&rules)?,
+                None => return Err(Error::Unsupported(format!("found version `{ver}`"))),
+            },
+        })
+    }
+
+    /// Convert the given [`RoomVersionRules`].
+    ///
+    /// Returns an error if the rules use an event ID format or a state resolution algorithm that
+    /// is not supported by this crate.
+    pub fn from_rules(rules: &RoomVersionRules) -> Result<Self> {
+        let disposition = match rules.disposition {
+            RoomVersionDisposition::Stable => RoomDisposition::Stable,
+            RoomVersionDisposition::Unstable => RoomDisposition::Unstable,
+        };
+        let event_format = match rules.event_id_format {
+            EventIdFormatVersion::V1 => EventFormatVersion::V1,
+            EventIdFormatVersion::V2 => EventFormatVersion::V2,
+            EventIdFormatVersion::V3 => EventFormatVersion::V3,
+            #[allow(unreachable_patterns)]
+            format => {
+                return Err(Error::Unsupported(format!("found event ID format `{format:?}`")))
+            }
+        };
+        let state_res = match rules.state_res {
+            StateResolutionRules::V1 => StateResolutionVersion::V1,
+            StateResolutionRules::V2 => StateResolutionVersion::V2,
+            #[allow(unreachable_patterns)]
+            state_res => {
+                return Err(Error::Unsupported(format!(
+                    "found state resolution algorithm `{state_res:?}`"
+                )))
+            }
+        };
+
+        let authorization = &rules.authorization;
+        Ok(Self {
+            disposition,
+            event_format,
+            state_res,
+            enforce_key_validity: rules.enforce_key_validity,
+            special_case_aliases_auth: authorization.special_case_aliases_auth,
+            strict_canonicaljson: rules.strict_canonical_json,
+            limit_notifications_power_levels: authorization.limit_notifications_power_levels,
+            extra_redaction_checks: authorization.extra_redaction_checks,
+            allow_knocking: authorization.allow_knocking,
+            restricted_join_rules: authorization.restricted_join_rules,
+            knock_restricted_join_rule: authorization.knock_restricted_join_rule,
+            integer_power_levels: authorization.integer_power_levels,
+            use_room_create_sender: authorization.use_room_create_sender,
         })
     }
 }
@@ -5,6 +5,7 @@ use std::{
     sync::Arc,
 };
 
+use js_int::UInt;
 use ruma_common::{EventId, MilliSecondsSinceUnixEpoch, RoomId, UserId};
 use ruma_events::TimelineEventType;
 use serde_json::value::RawValue as RawJsonValue;
@@ -28,6 +29,11 @@ pub trait Event {
     /// The event type.
     fn event_type(&self) -> &TimelineEventType;
 
+    /// The depth of this event in the room DAG.
+    ///
+    /// This is only used to order events in the state resolution algorithm of room version 1.
+    fn depth(&self) -> UInt;
+
     /// The event's content.
     fn content(&self) -> &RawJsonValue;
 
@@ -69,6 +75,10 @@ impl<T: Event> Event for &T {
         (*self).event_type()
     }
 
+    fn depth(&self) -> UInt {
+        (*self).depth()
+    }
+
     fn content(&self) -> &RawJsonValue {
         (*self).content()
     }
@@ -113,6 +123,10 @@ impl<T: Event> Event for Arc<T> {
         (**self).event_type()
     }
 
+    fn depth(&self) -> UInt {
+        (**self).depth()
+    }
+
     fn content(&self) -> &RawJsonValue {
         (**self).content()
     }
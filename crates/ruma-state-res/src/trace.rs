@@ -0,0 +1,90 @@
+use std::collections::HashSet;
+
+use crate::{AuthCheckError, StateMap};
+
+/// The steps taken by [`resolve_with_trace`] to resolve the state.
+///
+/// This can be used to understand why an event was chosen for a given state key, for example to
+/// debug diverging state between servers.
+///
+/// [`resolve_with_trace`]: crate::resolve_with_trace
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct ResolutionTrace<Id> {
+    /// The conflicted state.
+    ///
+    /// For each state key that doesn't have the same event in all the state sets, the events it
+    /// has in the state sets.
+    pub conflicted_state: StateMap<Vec<Id>>,
+
+    /// The auth chain difference of the state sets.
+    ///
+    /// These are the events that are in some of the auth chains of the state sets, but not all of
+    /// them.
+    ///
+    /// This is empty with the room version 1 algorithm.
+    pub auth_chain_difference: HashSet<Id>,
+
+    /// The full conflicted set.
+    ///
+    /// These are the conflicted events and the events of the auth chain difference that exist.
+    ///
+    /// This is empty with the room version 1 algorithm.
+    pub full_conflicted_set: HashSet<Id>,
+
+    /// The control events of the full conflicted set, in the order they were authorized.
+    ///
+    /// This is empty with the room version 1 algorithm.
+    pub sorted_control_events: Vec<Id>,
+
+    /// The mainline of the resolved `m.room.power_levels` event.
+    ///
+    /// It starts with the resolved power levels event, followed by the power levels events in its
+    /// auth events, recursively.
+    ///
+    /// This is empty with the room version 1 algorithm.
+    pub mainline: Vec<Id>,
+
+    /// The other events of the full conflicted set, in the order they were authorized according
+    /// to the mainline.
+    ///
+    /// This is empty with the room version 1 algorithm.
+    pub sorted_other_events: Vec<Id>,
+
+    /// The events that failed the authorization checks, in the order they were checked.
+    pub rejected_events: Vec<RejectedEvent<Id>>,
+}
+
+impl<Id> Default for ResolutionTrace<Id> {
+    fn default() -> Self {
+        Self {
+            conflicted_state: StateMap::new(),
+            auth_chain_difference: HashSet::new(),
+            full_conflicted_set: HashSet::new(),
+            sorted_control_events: Vec::new(),
+            mainline: Vec::new(),
+            sorted_other_events: Vec::new(),
+            rejected_events: Vec::new(),
+        }
+    }
+}
+
+/// An event that failed the authorization checks during state resolution.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct RejectedEvent<Id> {
+    /// The ID of the event.
+    pub event_id: Id,
+
+    /// The state the event was authorized against.
+    pub auth_events: StateMap<Id>,
+
+    /// The reason why the event was rejected.
+    pub error: AuthCheckError,
+}
+
+impl<Id> RejectedEvent<Id> {
+    pub(crate) fn new(event_id: Id, auth_events: StateMap<Id>, error: AuthCheckError) -> Self {
+        Self { event_id, auth_events, error }
+    }
+}
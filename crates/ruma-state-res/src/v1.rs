@@ -0,0 +1,483 @@
+//! The state resolution algorithm of [room version 1].
+//!
+//! [room version 1]: https://spec.matrix.org/latest/rooms/v1/#state-resolution
+
+use std::{borrow::Borrow, cmp::Reverse, collections::HashSet};
+
+use futures_util::{future, Future};
+use itertools::Itertools;
+use ruma_common::EventId;
+use ruma_events::{StateEventType, TimelineEventType};
+use sha1::{Digest, Sha1};
+use tracing::{debug, trace, warn};
+
+use crate::{
+    auth_check, auth_types_for_event, AuthCheckError, Error, Event, EventTypeExt, RejectedEvent,
+    ResolutionTrace, Result, RoomVersion, StateMap,
+};
+
+/// Resolve the conflicted state with the room version 1 algorithm.
+///
+/// The conflicted `m.room.power_levels` event is resolved first, then the `m.room.join_rules`
+/// events, then the `m.room.member` events, each against the state resolved before them. The
+/// remaining events are resolved last.
+///
+/// Keys that have a single event, because they are missing from some state sets, are not
+/// conflicted. Conflicted events that cannot be fetched are ignored.
+pub(crate) async fn resolve<E, Fetch, FetchFut>(
+    room_version: &RoomVersion,
+    mut unconflicted_state: StateMap<E::Id>,
+    conflicted_state: StateMap<Vec<E::Id>>,
+    event_fetch: &Fetch,
+    mut trace: Option<&mut ResolutionTrace<E::Id>>,
+) -> Result<StateMap<E::Id>>
+where
+    Fetch: Fn(E::Id) -> FetchFut + Sync,
+    FetchFut: Future<Output = Option<E>> + Send,
+    E: Event + Send,
+    E::Id: Borrow<EventId> + Send + Sync,
+    for<'b> &'b E: Send,
+{
+    debug!("resolving conflicted state with the room version 1 algorithm");
+
+    let mut conflicted_events = StateMap::new();
+    let mut resolved_state = StateMap::new();
+
+    for (key, event_ids) in conflicted_state {
+        let event_ids = event_ids.into_iter().unique().collect_vec();
+
+        // A key that is missing from some state sets has a single event, so it is not conflicted
+        // and its event can be used to authorize the conflicted events.
+        if let [event_id] = event_ids.as_slice() {
+            unconflicted_state.insert(key, event_id.clone());
+            continue;
+        }
+
+        let mut events = Vec::new();
+        for event_id in event_ids {
+            match event_fetch(event_id.clone()).await {
+                Some(event) => events.push(event),
+                None => warn!("conflicted event {event_id} is missing"),
+            }
+        }
+
+        match events.len() {
+            0 => {}
+            // Only one of the events is known, so there is no conflict after all.
+            1 => {
+                unconflicted_state.insert(key, events[0].event_id().clone());
+            }
+            _ => {
+                conflicted_events.insert(key, events);
+            }
+        }
+    }
+
+    // The unconflicted events that are needed to authorize the conflicted events.
+    let mut auth_events = StateMap::new();
+    for event in conflicted_events.values().flatten() {
+        for key in auth_types_for_event(
+            event.event_type(),
+            event.sender(),
+            event.state_key(),
+            event.content(),
+        )? {
+            if auth_events.contains_key(&key) {
+                continue;
+            }
+
+            if let Some(event_id) = unconflicted_state.get(&key) {
+                if let Some(auth_event) = event_fetch(event_id.clone()).await {
+                    auth_events.insert(key, auth_event);
+                }
+            }
+        }
+    }
+
+    // The control events are resolved by type, and each type is authorized against the control
+    // events resolved before it.
+    for event_type in
+        [StateEventType::RoomPowerLevels, StateEventType::RoomJoinRules, StateEventType::RoomMember]
+    {
+        let keys =
+            conflicted_events.keys().filter(|(ty, _)| *ty == event_type).cloned().collect_vec();
+
+        let mut resolved_events = Vec::new();
+        for key in keys {
+            let events = conflicted_events.remove(&key).expect("key comes from the map");
+            let event =
+                resolve_auth_events(room_version, events, &auth_events, trace.as_deref_mut())
+                    .await?;
+            trace!("resolved {key:?} to {}", event.event_id());
+
+            resolved_events.push((key, event));
+        }
+
+        for (key, event) in resolved_events {
+            resolved_state.insert(key.clone(), event.event_id().clone());
+            auth_events.insert(key, event);
+        }
+    }
+
+    for (key, events) in conflicted_events {
+        let event =
+            resolve_normal_events(room_version, events, &auth_events, trace.as_deref_mut()).await?;
+        trace!("resolved {key:?} to {}", event.event_id());
+
+        resolved_state.insert(key, event.event_id().clone());
+    }
+
+    resolved_state.extend(unconflicted_state);
+    Ok(resolved_state)
+}
+
+/// Resolve conflicted control events.
+///
+/// Starting from the event with the lowest depth, each event is authorized against the previous
+/// one. The last event that passes the authorization checks is chosen.
+async fn resolve_auth_events<E>(
+    room_version: &RoomVersion,
+    mut events: Vec<E>,
+    auth_events: &StateMap<E>,
+    trace: Option<&mut ResolutionTrace<E::Id>>,
+) -> Result<E>
+where
+    E: Event + Send,
+    for<'b> &'b E: Send,
+{
+    sort_events(&mut events);
+
+    let chosen_idx = {
+        let mut auth_events = relevant_auth_events(&events, auth_events)?;
+
+        let mut prev_idx = events.len() - 1;
+        for idx in (0..events.len() - 1).rev() {
+            let prev_event = &events[prev_idx];
+            let state_key = prev_event.state_key().unwrap_or_default();
+            auth_events.insert(prev_event.event_type().with_state_key(state_key), prev_event);
+
+            if let Err(error) = is_authorized(room_version, &events[idx], &auth_events).await? {
+                if let Some(trace) = trace {
+                    trace.rejected_events.push(rejected_event(&events[idx], &auth_events, error));
+                }
+                break;
+            }
+
+            prev_idx = idx;
+        }
+
+        prev_idx
+    };
+
+    Ok(events.swap_remove(chosen_idx))
+}
+
+/// Resolve conflicted non-control events.
+///
+/// The event with the highest depth that passes the authorization checks is chosen. If none of
+/// the events pass the authorization checks, the one with the lowest depth is chosen.
+async fn resolve_normal_events<E>(
+    room_version: &RoomVersion,
+    mut events: Vec<E>,
+    auth_events: &StateMap<E>,
+    mut trace: Option<&mut ResolutionTrace<E::Id>>,
+) -> Result<E>
+where
+    E: Event + Send,
+    for<'b> &'b E: Send,
+{
+    sort_events(&mut events);
+
+    let chosen_idx = {
+        let auth_events = relevant_auth_events(&events, auth_events)?;
+
+        let mut chosen_idx = events.len() - 1;
+        for (idx, event) in events.iter().enumerate() {
+            let error = match is_authorized(room_version, event, &auth_events).await? {
+                Ok(()) => {
+                    chosen_idx = idx;
+                    break;
+                }
+                Err(error) => error,
+            };
+
+            if let Some(trace) = trace.as_deref_mut() {
+                trace.rejected_events.push(rejected_event(event, &auth_events, error));
+            }
+        }
+
+        chosen_idx
+    };
+
+    Ok(events.swap_remove(chosen_idx))
+}
+
+/// Sort the events by descending depth, then by the SHA-1 hash of their event ID.
+fn sort_events<E: Event>(events: &mut [E]) {
+    events.sort_by_cached_key(|event| {
+        let event_id: &EventId = event.event_id().borrow();
+        (Reverse(event.depth()), Sha1::digest(event_id.as_bytes()))
+    });
+}
+
+/// Get the events of `auth_events` that are needed to authorize the given events.
+fn relevant_auth_events<'a, E: Event>(
+    events: &[E],
+    auth_events: &'a StateMap<E>,
+) -> Result<StateMap<&'a E>> {
+    let mut auth_types = HashSet::new();
+    for event in events {
+        auth_types.extend(auth_types_for_event(
+            event.event_type(),
+            event.sender(),
+            event.state_key(),
+            event.content(),
+        )?);
+    }
+
+    Ok(auth_types
+        .into_iter()
+        .filter_map(|key| {
+            let event = auth_events.get(&key)?;
+            Some((key, event))
+        })
+        .collect())
+}
+
+/// Record that the event failed the authorization checks against the given auth events.
+fn rejected_event<E: Event>(
+    event: &E,
+    auth_events: &StateMap<&E>,
+    error: AuthCheckError,
+) -> RejectedEvent<E::Id> {
+    let auth_events =
+        auth_events.iter().map(|(key, ev)| (key.clone(), ev.event_id().clone())).collect();
+    RejectedEvent::new(event.event_id().clone(), auth_events, error)
+}
+
+/// Check whether the event passes the authorization checks against the given auth events.
+///
+/// Returns the reason of the rejection if it doesn't.
+async fn is_authorized<E>(
+    room_version: &RoomVersion,
+    event: &E,
+    auth_events: &StateMap<&E>,
+) -> Result<std::result::Result<(), AuthCheckError>>
+where
+    E: Event + Send,
+    for<'b> &'b E: Send,
+{
+    let current_third_party = auth_events
+        .values()
+        .find(|ev| *ev.event_type() == TimelineEventType::RoomThirdPartyInvite)
+        .copied();
+
+    let fetch_state = |ty: &StateEventType, key: &str| {
+        future::ready(auth_events.get(&ty.with_state_key(key)).copied())
+    };
+
+    match auth_check(room_version, event, current_third_party, fetch_state).await {
+        Ok(()) => Ok(Ok(())),
+        Err(Error::AuthCheck(error)) => {
+            warn!("event {} failed the authentication check: {error}", event.event_id());
+            Ok(Err(error))
+        }
+        Err(error) => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use futures_util::future::ready;
+    use js_int::UInt;
+    use ruma_common::{OwnedEventId, RoomVersionId};
+    use ruma_events::{pdu::Pdu, TimelineEventType};
+    use serde_json::{json, value::to_raw_value as to_raw_json_value};
+
+    use crate::{
+        test_utils::{
+            alice, bob, event_id, member_content_join, room_id, to_pdu_event, zara, PduEvent,
+            TestStore, INITIAL_EVENTS,
+        },
+        AuthCheckError, Event, EventTypeExt, ResolutionTrace, StateMap,
+    };
+
+    fn with_depth(event: Arc<PduEvent>, depth: u32) -> Arc<PduEvent> {
+        let mut event = (*event).clone();
+        if let Pdu::RoomV3Pdu(pdu) = &mut event.rest {
+            pdu.depth = UInt::from(depth);
+        }
+        Arc::new(event)
+    }
+
+    /// The state of the room after the initial events, with the given events added.
+    fn state_with(store: &TestStore<PduEvent>, event_ids: &[&str]) -> StateMap<OwnedEventId> {
+        ["CREATE", "IMA", "IPOWER", "IJR", "IMB", "IMC"]
+            .iter()
+            .chain(event_ids)
+            .map(|id| {
+                let ev = &store.0[&event_id(id)];
+                (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+            })
+            .collect()
+    }
+
+    async fn resolve_v1(
+        store: &TestStore<PduEvent>,
+        state_sets: &[StateMap<OwnedEventId>],
+    ) -> (StateMap<OwnedEventId>, ResolutionTrace<OwnedEventId>) {
+        let auth_chain_sets = state_sets
+            .iter()
+            .map(|map| store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap())
+            .collect();
+
+        let fetcher = |id: OwnedEventId| ready(store.0.get(&id).cloned());
+        let exists = |id: OwnedEventId| ready(store.0.contains_key(&id));
+
+        crate::resolve_with_trace(
+            &RoomVersionId::V1,
+            state_sets,
+            &auth_chain_sets,
+            &fetcher,
+            &exists,
+        )
+        .await
+        .unwrap()
+    }
+
+    fn store_with(events: Vec<Arc<PduEvent>>) -> TestStore<PduEvent> {
+        let mut events_map: HashMap<_, _> = INITIAL_EVENTS();
+        events_map.extend(events.into_iter().map(|ev| (ev.event_id.clone(), ev)));
+        TestStore(events_map)
+    }
+
+    #[tokio::test]
+    async fn missing_keys_are_not_conflicted() {
+        let mut store = TestStore::<PduEvent>(HashMap::new());
+        let (state_at_bob, state_at_charlie, expected) = store.set_up();
+
+        let (resolved, _) = resolve_v1(&store, &[state_at_bob, state_at_charlie]).await;
+        assert_eq!(resolved, expected);
+    }
+
+    #[tokio::test]
+    async fn power_levels_authorized_against_each_other() {
+        let store = store_with(vec![
+            with_depth(
+                to_pdu_event(
+                    "PA",
+                    alice(),
+                    TimelineEventType::RoomPowerLevels,
+                    Some(""),
+                    to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 50 } })).unwrap(),
+                    &["CREATE", "IMA", "IPOWER"],
+                    &["IMC"],
+                ),
+                5,
+            ),
+            // Bob tries to raise his own power level above the one he was given.
+            with_depth(
+                to_pdu_event(
+                    "PB",
+                    bob(),
+                    TimelineEventType::RoomPowerLevels,
+                    Some(""),
+                    to_raw_json_value(&json!({ "users": { alice(): 100, bob(): 100 } })).unwrap(),
+                    &["CREATE", "IMB", "IPOWER"],
+                    &["IMC"],
+                ),
+                6,
+            ),
+        ]);
+
+        let (resolved, _) =
+            resolve_v1(&store, &[state_with(&store, &["PA"]), state_with(&store, &["PB"])]).await;
+        assert_eq!(resolved, state_with(&store, &["PA"]));
+    }
+
+    #[tokio::test]
+    async fn deepest_authorized_normal_event_wins() {
+        let topic = |id, sender, depth| {
+            with_depth(
+                to_pdu_event(
+                    id,
+                    sender,
+                    TimelineEventType::RoomTopic,
+                    Some(""),
+                    to_raw_json_value(&json!({ "topic": id })).unwrap(),
+                    &["CREATE", "IMA", "IPOWER"],
+                    &["IMC"],
+                ),
+                depth,
+            )
+        };
+
+        let store = store_with(vec![topic("T1", alice(), 3), topic("T2", alice(), 4)]);
+        let (resolved, _) =
+            resolve_v1(&store, &[state_with(&store, &["T1"]), state_with(&store, &["T2"])]).await;
+        assert_eq!(resolved, state_with(&store, &["T2"]));
+
+        // Zara is not in the room, so her topic is rejected even though it is the deepest.
+        let store = store_with(vec![topic("T1", alice(), 3), topic("T2", zara(), 4)]);
+        let (resolved, trace) =
+            resolve_v1(&store, &[state_with(&store, &["T1"]), state_with(&store, &["T2"])]).await;
+        assert_eq!(resolved, state_with(&store, &["T1"]));
+        assert_eq!(trace.rejected_events.len(), 1);
+        assert_eq!(trace.rejected_events[0].event_id, event_id("T2"));
+        assert_eq!(trace.rejected_events[0].error, AuthCheckError::SenderNotJoined);
+        assert!(trace.mainline.is_empty());
+    }
+
+    #[tokio::test]
+    async fn member_event_of_one_fork_authorizes_conflicted_events() {
+        let topic = |id, sender, depth| {
+            with_depth(
+                to_pdu_event(
+                    id,
+                    sender,
+                    TimelineEventType::RoomTopic,
+                    Some(""),
+                    to_raw_json_value(&json!({ "topic": id })).unwrap(),
+                    &["CREATE", "IMA", "IPOWER"],
+                    &["IMC"],
+                ),
+                depth,
+            )
+        };
+
+        let store = store_with(vec![
+            // Zara is allowed to change the topic once she joined the room.
+            to_pdu_event(
+                "PZ",
+                alice(),
+                TimelineEventType::RoomPowerLevels,
+                Some(""),
+                to_raw_json_value(&json!({ "users": { alice(): 100, zara(): 50 } })).unwrap(),
+                &["CREATE", "IMA", "IPOWER"],
+                &["IMC"],
+            ),
+            to_pdu_event(
+                "IMZ",
+                zara(),
+                TimelineEventType::RoomMember,
+                Some(zara().as_str()),
+                member_content_join(),
+                &["CREATE", "IJR", "IPOWER"],
+                &["IMC"],
+            ),
+            topic("T1", alice(), 3),
+            topic("T2", zara(), 4),
+        ]);
+
+        // Only the first fork knows that Zara joined the room, which authorizes her topic.
+        let (resolved, trace) = resolve_v1(
+            &store,
+            &[state_with(&store, &["PZ", "IMZ", "T2"]), state_with(&store, &["PZ", "T1"])],
+        )
+        .await;
+        assert_eq!(resolved, state_with(&store, &["PZ", "IMZ", "T2"]));
+        assert!(trace.rejected_events.is_empty());
+    }
+}